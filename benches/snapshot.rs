@@ -0,0 +1,96 @@
+use heap_engine::{
+    CommandId, Snapshot, TransactionId, TransactionManager, Visibility, VisibilityMode,
+};
+use std::hint::black_box;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+const SNAPSHOTS: u32 = 2_000;
+const CHECKS_PER_SNAPSHOT: u32 = 100;
+
+fn per_op(elapsed: Duration, ops: u32) -> f64 {
+    elapsed.as_nanos() as f64 / ops as f64
+}
+
+// The previous scheme: every snapshot copies the running xids under the
+// lock, and every check scans the copy.
+fn xip_array(running: u32) -> (f64, f64) {
+    let manager = TransactionManager::new();
+    let in_progress: Vec<TransactionId> = (0..running).map(|_| manager.begin().unwrap()).collect();
+    let xmin = in_progress.first().map_or(3, |xid| xid.0);
+    let xmax = manager.current_xid().0;
+    let in_progress = RwLock::new(in_progress);
+    let oracle = manager.oracle(TransactionId::invalid());
+
+    let start = Instant::now();
+    let mut snapshot = Snapshot::invalid();
+    for _ in 0..SNAPSHOTS {
+        let xip = in_progress
+            .read()
+            .unwrap()
+            .iter()
+            .map(|xid| xid.0)
+            .collect();
+        snapshot = black_box(Snapshot::new(xmin, xmax, xip, 1, VisibilityMode::MVCC));
+    }
+    let taken = start.elapsed();
+
+    let start = Instant::now();
+    for _ in 0..SNAPSHOTS {
+        for i in 0..CHECKS_PER_SNAPSHOT {
+            let xid = TransactionId(xmin + i % running.max(1));
+            black_box(Visibility::xid_in_snapshot(xid, &snapshot, &oracle));
+        }
+    }
+    let checked = start.elapsed();
+
+    (
+        per_op(taken, SNAPSHOTS),
+        per_op(checked, SNAPSHOTS * CHECKS_PER_SNAPSHOT),
+    )
+}
+
+fn csn(running: u32) -> (f64, f64) {
+    let manager = TransactionManager::new();
+    let in_progress: Vec<TransactionId> = (0..running).map(|_| manager.begin().unwrap()).collect();
+    let xmin = in_progress.first().map_or(3, |xid| xid.0);
+    let oracle = manager.oracle(TransactionId::invalid());
+    let owner = manager.begin_virtual();
+
+    let start = Instant::now();
+    let mut snapshot = Snapshot::invalid();
+    for _ in 0..SNAPSHOTS {
+        snapshot = black_box(manager.get_statement_snapshot(owner, CommandId(1)));
+    }
+    let taken = start.elapsed();
+
+    let start = Instant::now();
+    for _ in 0..SNAPSHOTS {
+        for i in 0..CHECKS_PER_SNAPSHOT {
+            let xid = TransactionId(xmin + i % running.max(1));
+            black_box(Visibility::xid_in_snapshot(xid, &snapshot, &oracle));
+        }
+    }
+    let checked = start.elapsed();
+    manager.end_virtual(owner);
+
+    (
+        per_op(taken, SNAPSHOTS),
+        per_op(checked, SNAPSHOTS * CHECKS_PER_SNAPSHOT),
+    )
+}
+
+fn main() {
+    println!(
+        "{:>8}  {:>14}  {:>14}  {:>14}  {:>14}",
+        "running", "xip snap ns", "csn snap ns", "xip check ns", "csn check ns"
+    );
+    for running in [10, 100, 1_000, 5_000] {
+        let (xip_snapshot, xip_check) = xip_array(running);
+        let (csn_snapshot, csn_check) = csn(running);
+        println!(
+            "{:>8}  {:>14.1}  {:>14.1}  {:>14.1}  {:>14.1}",
+            running, xip_snapshot, csn_snapshot, xip_check, csn_check
+        );
+    }
+}
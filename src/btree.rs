@@ -1,10 +1,13 @@
 use crate::constants::*;
 use crate::error::{HeapError, Result};
 use crate::page::Page;
+use crate::recovery::Recovery;
 use crate::relation::Relation;
+use crate::transaction::TransactionManager;
 use crate::types::*;
+use crate::wal::{XLogBlockRef, XLogRecord, XLogRecordType};
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 use std::sync::{Arc, RwLock};
 
@@ -120,7 +123,7 @@ impl BTreePage {
             let key_len = u16::from_le_bytes([buf[offset], buf[offset + 1]]) as usize;
             offset += 2;
 
-            if buf.len() < offset + key_len + 8 {
+            if buf.len() < offset + key_len + 6 {
                 break;
             }
             let key = buf[offset..offset + key_len].to_vec();
@@ -175,148 +178,292 @@ impl BTreePage {
     }
 }
 
+// Each block holds one node, as its page's only item. The root stays at
+// block 0 and leaves are chained left to right through their siblings;
+// sibling 0 means none, as the root is never anyone's sibling.
 pub struct BTreeIndex {
     pub relation: Relation,
     pub root_block: RwLock<Option<u32>>,
-    pub key_count: RwLock<usize>,
+    pub tx_manager: Arc<TransactionManager>,
 }
 
 impl BTreeIndex {
     pub fn create(path: PathBuf) -> Result<(Self, u32)> {
-        let (relation, rel_node) = Relation::create(path, 0)?;
-
-        let index = Self {
-            relation,
-            root_block: RwLock::new(None),
-            key_count: RwLock::new(0),
-        };
+        let tx_manager = Arc::new(TransactionManager::open(path.clone())?);
+        let rel_node = tx_manager.assign_rel_node()?;
+        let index = Self::create_with(path, rel_node, tx_manager)?;
+        Ok((index, rel_node))
+    }
 
-        let mut root_page = BTreePage::new(true);
-        root_page.is_root = true;
-        index.relation.write_page(0, &Page::new(BLCKSZ))?;
+    pub fn create_with(
+        path: PathBuf,
+        rel_node: u32,
+        tx_manager: Arc<TransactionManager>,
+    ) -> Result<Self> {
+        let relation = Relation::create_with_node(path, 0, rel_node)?;
+        Ok(Self::with_relation(relation, tx_manager))
+    }
 
-        *index.root_block.write().unwrap() = Some(0);
+    // Like a heap opened on its own, an index opened on its own is
+    // recovered here.
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let tx_manager = Arc::new(TransactionManager::open(path.clone())?);
+        let index = Self::open_with(path, tx_manager)?;
 
-        Ok((index, rel_node))
+        let mut recovery = Recovery::new(&index.tx_manager);
+        recovery.add_relation(&index.relation);
+        if recovery.run()?.changed() {
+            index.tx_manager.checkpoint(|| Ok(()))?;
+        }
+        Ok(index)
     }
 
-    pub fn open(path: PathBuf) -> Result<Self> {
+    pub fn open_with(path: PathBuf, tx_manager: Arc<TransactionManager>) -> Result<Self> {
         let relation = Relation::open(path)?;
+        Ok(Self::with_relation(relation, tx_manager))
+    }
 
-        Ok(Self {
+    fn with_relation(relation: Relation, tx_manager: Arc<TransactionManager>) -> Self {
+        let mut relation = relation.with_data_checksums(tx_manager.data_checksums());
+        if let Some(wal) = tx_manager.wal() {
+            relation = relation.with_wal(wal.clone());
+        }
+        Self {
             relation,
             root_block: RwLock::new(Some(0)),
-            key_count: RwLock::new(0),
-        })
+            tx_manager,
+        }
     }
 
-    pub fn insert(&self, key: Vec<u8>, ctid: ItemPointerData) -> Result<()> {
-        let root = *self.root_block.read().unwrap();
+    fn root(&self) -> Result<u32> {
+        self.root_block
+            .read()
+            .unwrap()
+            .ok_or_else(|| HeapError::InvalidOperation("Index not initialized".to_string()))
+    }
 
-        if root.is_none() {
-            return Err(HeapError::InvalidOperation(
-                "Index not initialized".to_string(),
-            ));
+    // A block never written since it was allocated reads as an empty leaf.
+    fn read_node(&self, block_num: u32) -> Result<BTreePage> {
+        let page = self.relation.read_page(block_num)?;
+        match page.get_item(1) {
+            Some(item) => BTreePage::deserialize(item),
+            None => {
+                let mut node = BTreePage::new(true);
+                node.is_root = Some(block_num) == *self.root_block.read().unwrap();
+                Ok(node)
+            }
         }
-
-        let mut page = self.relation.read_page(root.unwrap())?;
-        let btree_page = BTreePage::new(true);
-
-        let mut keys = btree_page.keys.clone();
-        keys.push(BTreeKey::new(key, ctid));
-        keys.sort();
-
-        let mut count = self.key_count.write().unwrap();
-        *count += 1;
-
-        Ok(())
     }
 
-    pub fn search(&self, key: &[u8]) -> Result<Vec<ItemPointerData>> {
-        let root = *self.root_block.read().unwrap();
-
-        if root.is_none() {
-            return Ok(Vec::new());
+    fn node(&self, block_num: u32, dirty: &BTreeMap<u32, BTreePage>) -> Result<BTreePage> {
+        match dirty.get(&block_num) {
+            Some(node) => Ok(node.clone()),
+            None => self.read_node(block_num),
         }
-
-        self.search_page(root.unwrap(), key)
     }
 
-    fn search_page(&self, block_num: u32, key: &[u8]) -> Result<Vec<ItemPointerData>> {
-        let page_data = self.relation.read_page(block_num)?;
-        let items = page_data.get_item(1);
+    fn fits(node: &BTreePage) -> bool {
+        node.serialize().len() + 4 <= Page::new(BLCKSZ).free_space()
+    }
 
-        if items.is_none() {
-            return Ok(Vec::new());
+    pub fn insert(&self, key: Vec<u8>, ctid: ItemPointerData) -> Result<()> {
+        if key.len() > BTREE_MAX_KEY_SIZE {
+            return Err(HeapError::InvalidOperation(format!(
+                "index row size {} exceeds maximum {}",
+                key.len(),
+                BTREE_MAX_KEY_SIZE
+            )));
         }
+        let root = self.root()?;
 
-        let btree_page = BTreePage::deserialize(items.unwrap())?;
-        let mut results = Vec::new();
+        let _guard = self.relation.lock_content();
+        let mut dirty = BTreeMap::new();
+        self.insert_into(root, BTreeKey::new(key, ctid), &mut dirty)?;
+        self.log_nodes(XLogRecordType::BTreeInsert, dirty)
+    }
 
-        for tree_key in &btree_page.keys {
-            if tree_key.key == key {
-                results.push(tree_key.ctid);
-            }
+    // Inserts after any equal keys. Returns the separator and the new
+    // right sibling when the node had to split.
+    fn insert_into(
+        &self,
+        block_num: u32,
+        key: BTreeKey,
+        dirty: &mut BTreeMap<u32, BTreePage>,
+    ) -> Result<Option<(BTreeKey, u32)>> {
+        let mut node = self.node(block_num, dirty)?;
+        let pos = node.keys.partition_point(|k| k.key <= key.key);
+        if node.is_leaf {
+            node.keys.insert(pos, key);
+        } else if let Some((separator, right)) = self.insert_into(node.children[pos], key, dirty)? {
+            node.keys.insert(pos, separator);
+            node.children.insert(pos + 1, right);
         }
 
-        if !btree_page.is_leaf {
-            for &child in &btree_page.children {
-                let mut child_results = self.search_page(child, key)?;
-                results.extend(child_results);
-            }
+        if Self::fits(&node) {
+            dirty.insert(block_num, node);
+            return Ok(None);
         }
-
-        Ok(results)
+        self.split(block_num, node, dirty)
     }
 
-    pub fn delete(&self, key: &[u8]) -> Result<bool> {
-        let root = *self.root_block.read().unwrap();
+    // Moves the upper half of `node` to a new right sibling. A leaf's
+    // separator is a copy of the right half's first key, while an internal
+    // node gives up its middle key. The root keeps its block: both halves
+    // move out and it becomes their parent.
+    fn split(
+        &self,
+        block_num: u32,
+        mut node: BTreePage,
+        dirty: &mut BTreeMap<u32, BTreePage>,
+    ) -> Result<Option<(BTreeKey, u32)>> {
+        let is_root = node.is_root;
+        let left_block = if is_root {
+            self.relation.allocate_page()?
+        } else {
+            block_num
+        };
+        let right_block = self.relation.allocate_page()?;
 
-        if root.is_none() {
-            return Ok(false);
-        }
+        let mid = node.keys.len() / 2;
+        let mut right = BTreePage::new(node.is_leaf);
+        let separator = if node.is_leaf {
+            right.keys = node.keys.split_off(mid);
+            right.keys[0].clone()
+        } else {
+            right.keys = node.keys.split_off(mid + 1);
+            right.children = node.children.split_off(mid + 1);
+            node.keys.pop().unwrap()
+        };
 
-        let mut count = self.key_count.write().unwrap();
-        if *count > 0 {
-            *count -= 1;
-            return Ok(true);
+        right.left_sibling = left_block;
+        right.right_sibling = node.right_sibling;
+        if node.right_sibling != 0 {
+            let mut next = self.node(node.right_sibling, dirty)?;
+            next.left_sibling = right_block;
+            dirty.insert(node.right_sibling, next);
         }
+        node.right_sibling = right_block;
+        node.is_root = false;
+        dirty.insert(left_block, node);
+        dirty.insert(right_block, right);
 
-        Ok(false)
+        if !is_root {
+            return Ok(Some((separator, right_block)));
+        }
+        let mut root = BTreePage::new(false);
+        root.is_root = true;
+        root.keys.push(separator);
+        root.children = vec![left_block, right_block];
+        dirty.insert(block_num, root);
+        Ok(None)
     }
 
-    pub fn scan(&self) -> Result<Vec<(Vec<u8>, ItemPointerData)>> {
-        let root = *self.root_block.read().unwrap();
+    // Every node a change touched goes in one record, so redo never sees
+    // half a split.
+    fn log_nodes(
+        &self,
+        record_type: XLogRecordType,
+        dirty: BTreeMap<u32, BTreePage>,
+    ) -> Result<()> {
+        if dirty.is_empty() {
+            return Ok(());
+        }
 
-        if root.is_none() {
-            return Ok(Vec::new());
+        let _logged = self.tx_manager.log_change();
+        let mut blocks = Vec::new();
+        let mut pages = Vec::new();
+        for (block_num, node) in dirty {
+            let page_lsn = self.relation.read_page(block_num)?.header.pd_lsn;
+            let item = node.serialize();
+            let mut page = Page::new(BLCKSZ);
+            page.add_item(&item)?;
+
+            let mut block = XLogBlockRef::new(self.relation.rel_node, block_num);
+            block.tuples.push((1, item));
+            if self.tx_manager.needs_page_image(page_lsn) {
+                block.image = Some(page.serialize());
+            }
+            blocks.push(block);
+            pages.push((block_num, page));
         }
 
-        self.scan_page(root.unwrap())
+        if let Some(wal) = self.tx_manager.wal() {
+            let lsn = wal.append(&XLogRecord::with_blocks(0, record_type, &blocks))?;
+            for (_, page) in &mut pages {
+                page.header.pd_lsn = lsn;
+            }
+        }
+        for (block_num, page) in &pages {
+            self.relation.write_page(*block_num, page)?;
+        }
+        Ok(())
     }
 
-    fn scan_page(&self, block_num: u32) -> Result<Vec<(Vec<u8>, ItemPointerData)>> {
-        let page_data = self.relation.read_page(block_num)?;
-        let items = page_data.get_item(1);
-
-        if items.is_none() {
-            return Ok(Vec::new());
+    // The leftmost leaf that could hold `key`, or the leftmost leaf of all.
+    // Equal keys may continue into the leaves to its right.
+    fn first_leaf(&self, key: Option<&[u8]>) -> Result<(u32, BTreePage)> {
+        let mut block_num = self.root()?;
+        loop {
+            let node = self.read_node(block_num)?;
+            if node.is_leaf {
+                return Ok((block_num, node));
+            }
+            let pos = key.map_or(0, |key| {
+                node.keys.partition_point(|k| k.key.as_slice() < key)
+            });
+            block_num = node.children[pos];
         }
+    }
 
-        let btree_page = BTreePage::deserialize(items.unwrap())?;
+    pub fn search(&self, key: &[u8]) -> Result<Vec<ItemPointerData>> {
+        let _guard = self.relation.lock_content();
+        let (_, mut node) = self.first_leaf(Some(key))?;
         let mut results = Vec::new();
-
-        for tree_key in &btree_page.keys {
-            results.push((tree_key.key.clone(), tree_key.ctid));
+        loop {
+            results.extend(node.keys.iter().filter(|k| k.key == key).map(|k| k.ctid));
+            if node.right_sibling == 0 || node.keys.iter().any(|k| k.key.as_slice() > key) {
+                return Ok(results);
+            }
+            node = self.read_node(node.right_sibling)?;
         }
+    }
 
-        if !btree_page.is_leaf {
-            for &child in &btree_page.children {
-                let mut child_results = self.scan_page(child)?;
-                results.extend(child_results);
+    // Removes every entry for `key`. Emptied leaves stay in the chain.
+    pub fn delete(&self, key: &[u8]) -> Result<bool> {
+        let _guard = self.relation.lock_content();
+        let (mut block_num, mut node) = self.first_leaf(Some(key))?;
+        let mut dirty = BTreeMap::new();
+        loop {
+            let next = node.right_sibling;
+            let done = next == 0 || node.keys.iter().any(|k| k.key.as_slice() > key);
+            let count = node.keys.len();
+            node.keys.retain(|k| k.key != key);
+            if node.keys.len() != count {
+                dirty.insert(block_num, node);
             }
+            if done {
+                break;
+            }
+            block_num = next;
+            node = self.read_node(block_num)?;
         }
 
-        Ok(results)
+        let deleted = !dirty.is_empty();
+        self.log_nodes(XLogRecordType::BTreeDelete, dirty)?;
+        Ok(deleted)
+    }
+
+    // Every entry in key order.
+    pub fn scan(&self) -> Result<Vec<(Vec<u8>, ItemPointerData)>> {
+        let _guard = self.relation.lock_content();
+        let (_, mut node) = self.first_leaf(None)?;
+        let mut results = Vec::new();
+        loop {
+            results.extend(node.keys.iter().map(|k| (k.key.clone(), k.ctid)));
+            if node.right_sibling == 0 {
+                return Ok(results);
+            }
+            node = self.read_node(node.right_sibling)?;
+        }
     }
 }
@@ -0,0 +1,212 @@
+use crate::constants::*;
+use crate::error::{HeapError, Result};
+use crate::page::Page;
+use crate::storage::StorageRef;
+use crate::wal::WALRef;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BufferTag {
+    pub rel_node: u32,
+    pub block_num: u32,
+}
+
+impl BufferTag {
+    pub fn new(rel_node: u32, block_num: u32) -> Self {
+        Self {
+            rel_node,
+            block_num,
+        }
+    }
+}
+
+struct BufferDesc {
+    tag: Option<BufferTag>,
+    storage: Option<StorageRef>,
+    data: Vec<u8>,
+    dirty: bool,
+    usage_count: u8,
+}
+
+struct BufferPoolState {
+    buffers: Vec<BufferDesc>,
+    lookup: HashMap<BufferTag, usize>,
+    next_victim: usize,
+}
+
+// Shared page cache for every relation of a database. Writes stay in the
+// pool until the page is evicted or flushed, so a hot page is written back
+// once instead of on every change.
+pub struct BufferPool {
+    state: Mutex<BufferPoolState>,
+    wal: Option<WALRef>,
+}
+
+impl BufferPool {
+    pub fn new(num_buffers: usize) -> Result<Self> {
+        if num_buffers == 0 {
+            return Err(HeapError::InvalidOperation(
+                "buffer pool needs at least one buffer".to_string(),
+            ));
+        }
+
+        let buffers = (0..num_buffers)
+            .map(|_| BufferDesc {
+                tag: None,
+                storage: None,
+                data: Vec::new(),
+                dirty: false,
+                usage_count: 0,
+            })
+            .collect();
+
+        Ok(Self {
+            state: Mutex::new(BufferPoolState {
+                buffers,
+                lookup: HashMap::new(),
+                next_victim: 0,
+            }),
+            wal: None,
+        })
+    }
+
+    // Pages written back are first covered by a WAL flush up to their LSN.
+    pub fn with_wal(mut self, wal: WALRef) -> Self {
+        self.wal = Some(wal);
+        self
+    }
+
+    pub fn num_buffers(&self) -> usize {
+        self.state.lock().unwrap().buffers.len()
+    }
+
+    pub fn dirty_count(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.buffers.iter().filter(|buf| buf.dirty).count()
+    }
+
+    pub fn read_page(&self, storage: &StorageRef, tag: BufferTag) -> Result<Page> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(&id) = state.lookup.get(&tag) {
+            let buf = &mut state.buffers[id];
+            buf.usage_count = (buf.usage_count + 1).min(BM_MAX_USAGE_COUNT);
+            return Page::from_raw(buf.data.clone());
+        }
+
+        let page = storage.read_page(tag.block_num)?;
+        let id = self.get_victim(&mut state)?;
+        Self::install(&mut state, id, storage, tag, page.serialize(), false);
+        Ok(page)
+    }
+
+    pub fn write_page(&self, storage: &StorageRef, tag: BufferTag, page: &Page) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let id = match state.lookup.get(&tag) {
+            Some(&id) => id,
+            None => self.get_victim(&mut state)?,
+        };
+        Self::install(&mut state, id, storage, tag, page.serialize(), true);
+        Ok(())
+    }
+
+    fn install(
+        state: &mut BufferPoolState,
+        id: usize,
+        storage: &StorageRef,
+        tag: BufferTag,
+        data: Vec<u8>,
+        dirty: bool,
+    ) {
+        let buf = &mut state.buffers[id];
+        buf.tag = Some(tag);
+        buf.storage = Some(Arc::clone(storage));
+        buf.data = data;
+        buf.dirty |= dirty;
+        buf.usage_count = (buf.usage_count + 1).min(BM_MAX_USAGE_COUNT);
+        state.lookup.insert(tag, id);
+    }
+
+    // Clock sweep: every buffer passed over loses one usage count, and the
+    // first one found at zero is reused. A dirty victim is written back
+    // before its slot is handed out.
+    fn get_victim(&self, state: &mut BufferPoolState) -> Result<usize> {
+        loop {
+            let id = state.next_victim;
+            state.next_victim = (id + 1) % state.buffers.len();
+
+            let buf = &mut state.buffers[id];
+            if buf.tag.is_some() && buf.usage_count > 0 {
+                buf.usage_count -= 1;
+                continue;
+            }
+
+            self.write_back(buf)?;
+            if let Some(tag) = buf.tag.take() {
+                state.lookup.remove(&tag);
+            }
+            let buf = &mut state.buffers[id];
+            buf.storage = None;
+            buf.data = Vec::new();
+            buf.usage_count = 0;
+            return Ok(id);
+        }
+    }
+
+    // WAL before data: the page's changes reach the WAL before the page
+    // itself reaches disk, so redo can always finish what a crash cut off.
+    fn write_back(&self, buf: &mut BufferDesc) -> Result<()> {
+        if !buf.dirty {
+            return Ok(());
+        }
+
+        if let (Some(tag), Some(storage)) = (buf.tag, buf.storage.as_ref()) {
+            let page = Page::from_raw(buf.data.clone())?;
+            if let Some(ref wal) = self.wal {
+                wal.flush(page.header.pd_lsn)?;
+            }
+            storage.write_page(tag.block_num, &page)?;
+        }
+        buf.dirty = false;
+        Ok(())
+    }
+
+    pub fn flush_relation(&self, rel_node: u32) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        for buf in state.buffers.iter_mut() {
+            if buf.tag.is_some_and(|tag| tag.rel_node == rel_node) {
+                self.write_back(buf)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush_all(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        for buf in state.buffers.iter_mut() {
+            self.write_back(buf)?;
+        }
+        Ok(())
+    }
+
+    // Forgets the relation's pages without writing them back, for when its
+    // files are being removed.
+    pub fn drop_relation(&self, rel_node: u32) {
+        let mut state = self.state.lock().unwrap();
+        let BufferPoolState {
+            buffers, lookup, ..
+        } = &mut *state;
+        for buf in buffers.iter_mut() {
+            if let Some(tag) = buf.tag.filter(|tag| tag.rel_node == rel_node) {
+                lookup.remove(&tag);
+                buf.tag = None;
+                buf.storage = None;
+                buf.data = Vec::new();
+                buf.dirty = false;
+                buf.usage_count = 0;
+            }
+        }
+    }
+}
+
+pub type BufferPoolRef = Arc<BufferPool>;
@@ -0,0 +1,114 @@
+use crate::constants::*;
+use crate::error::Result;
+use crate::slru::Slru;
+use crate::types::*;
+use crate::wal::{WALRef, XLogRecord, XLogRecordType};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+pub struct CommitLog {
+    slru: Slru,
+    wal: Option<WALRef>,
+    latest_page: Mutex<Option<u32>>,
+}
+
+impl CommitLog {
+    pub fn open(dir: PathBuf, wal: Option<WALRef>) -> Result<Self> {
+        Ok(Self {
+            slru: Slru::open(dir.join("pg_xact"), NUM_CLOG_BUFFERS)?.with_wal(wal.clone()),
+            wal,
+            latest_page: Mutex::new(None),
+        })
+    }
+
+    pub fn in_memory() -> Self {
+        Self {
+            slru: Slru::in_memory(NUM_CLOG_BUFFERS),
+            wal: None,
+            latest_page: Mutex::new(None),
+        }
+    }
+
+    pub fn page_number(xid: TransactionId) -> u32 {
+        xid.0 / CLOG_XACTS_PER_PAGE
+    }
+
+    pub fn page_precedes(page1: u32, page2: u32) -> bool {
+        let xid1 = TransactionId(page1 * CLOG_XACTS_PER_PAGE + FIRST_NORMAL_TRANSACTION_ID);
+        let xid2 = TransactionId(page2 * CLOG_XACTS_PER_PAGE + FIRST_NORMAL_TRANSACTION_ID);
+        xid1.precedes(xid2)
+    }
+
+    fn entry(xid: TransactionId) -> (usize, u32) {
+        let index = xid.0 % CLOG_XACTS_PER_PAGE;
+        let byte = (index / CLOG_XACTS_PER_BYTE) as usize;
+        let shift = (index % CLOG_XACTS_PER_BYTE) * CLOG_BITS_PER_XACT;
+        (byte, shift)
+    }
+
+    pub fn get_status(&self, xid: TransactionId) -> Result<XidStatus> {
+        let (byte, shift) = Self::entry(xid);
+        let bits = self
+            .slru
+            .read(Self::page_number(xid), |page| (page[byte] >> shift) & 0x03)?;
+        Ok(XidStatus::from_bits(bits))
+    }
+
+    // `lsn` is the record that decided the status; the page is not written
+    // back before the WAL is flushed past it, so an async commit can never
+    // reach disk in the clog ahead of its commit record.
+    pub fn set_status(&self, xid: TransactionId, status: XidStatus, lsn: u64) -> Result<()> {
+        let (byte, shift) = Self::entry(xid);
+        self.slru.write_logged(Self::page_number(xid), lsn, |page| {
+            page[byte] = (page[byte] & !(0x03 << shift)) | (status.to_bits() << shift);
+        })
+    }
+
+    // Makes sure the page holding `xid` exists before the xid is handed out.
+    // A fresh page is zeroed and the zeroing is WAL-logged, so replay can
+    // recreate it.
+    pub fn extend(&self, xid: TransactionId) -> Result<()> {
+        let page_number = Self::page_number(xid);
+        let mut latest_page = self.latest_page.lock().unwrap();
+        if *latest_page == Some(page_number) {
+            return Ok(());
+        }
+
+        if !self.slru.page_exists(page_number) {
+            if let Some(ref wal) = self.wal {
+                let record = XLogRecord::new(0, XLogRecordType::ClogZeroPage, page_number, vec![]);
+                wal.append(&record)?;
+            }
+            self.slru.zero_page(page_number)?;
+        }
+
+        *latest_page = Some(page_number);
+        Ok(())
+    }
+
+    pub fn truncate(&self, oldest_xid: TransactionId) -> Result<()> {
+        let cutoff_page = Self::page_number(oldest_xid);
+        if let Some(ref wal) = self.wal {
+            let record = XLogRecord::new(0, XLogRecordType::ClogTruncate, cutoff_page, vec![]);
+            wal.append(&record)?;
+        }
+        self.slru.truncate(cutoff_page, Self::page_precedes)
+    }
+
+    // Replay of a ClogZeroPage record: the page is only recreated if it
+    // never reached disk.
+    pub fn redo_zero_page(&self, page_number: u32) -> Result<()> {
+        if !self.slru.page_exists(page_number) {
+            self.slru.zero_page(page_number)?;
+        }
+        Ok(())
+    }
+
+    pub fn redo_truncate(&self, cutoff_page: u32) -> Result<()> {
+        self.slru.truncate(cutoff_page, Self::page_precedes)
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.slru.flush()
+    }
+}
@@ -0,0 +1,52 @@
+use crate::error::{HeapError, Result};
+use crate::types::*;
+use std::collections::HashMap;
+
+// A tuple has room for one command id. When a transaction deletes a tuple it
+// inserted itself, both cmin and cmax matter to its own later commands, so
+// the pair is stored here and the tuple gets the pair's combo id instead.
+// Combo ids only mean something to the transaction that made them.
+#[derive(Debug, Clone, Default)]
+pub struct ComboCids {
+    combos: Vec<(CommandId, CommandId)>,
+    lookup: HashMap<(CommandId, CommandId), CommandId>,
+}
+
+impl ComboCids {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get_or_create(&mut self, cmin: CommandId, cmax: CommandId) -> Result<CommandId> {
+        if let Some(&combo) = self.lookup.get(&(cmin, cmax)) {
+            return Ok(combo);
+        }
+
+        let combo = u32::try_from(self.combos.len())
+            .map(CommandId)
+            .map_err(|_| {
+                HeapError::InvalidTransaction(
+                    "cannot have more than 2^32-1 combo command ids in a transaction".to_string(),
+                )
+            })?;
+        self.combos.push((cmin, cmax));
+        self.lookup.insert((cmin, cmax), combo);
+        Ok(combo)
+    }
+
+    pub fn cmin(&self, combo: CommandId) -> CommandId {
+        self.combos[combo.0 as usize].0
+    }
+
+    pub fn cmax(&self, combo: CommandId) -> CommandId {
+        self.combos[combo.0 as usize].1
+    }
+
+    pub fn len(&self) -> usize {
+        self.combos.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.combos.is_empty()
+    }
+}
@@ -0,0 +1,209 @@
+use crate::constants::*;
+use crate::error::Result;
+use crate::slru::Slru;
+use crate::types::*;
+use crate::wal::{WALRef, XLogRecord, XLogRecordType};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Commit time of every xid committed while tracking is on, in microseconds
+// since the Unix epoch. Zero means no timestamp was recorded.
+pub struct CommitTsLog {
+    slru: Slru,
+    wal: Option<WALRef>,
+    latest_page: Mutex<Option<u32>>,
+    latest: Mutex<Option<(TransactionId, u64)>>,
+    last_assigned: Mutex<u64>,
+}
+
+impl CommitTsLog {
+    pub fn open(dir: PathBuf, wal: Option<WALRef>) -> Result<Self> {
+        Ok(Self {
+            slru: Slru::open(dir.join("pg_commit_ts"), NUM_COMMIT_TS_BUFFERS)?
+                .with_wal(wal.clone()),
+            wal,
+            latest_page: Mutex::new(None),
+            latest: Mutex::new(None),
+            last_assigned: Mutex::new(0),
+        })
+    }
+
+    pub fn in_memory() -> Self {
+        Self {
+            slru: Slru::in_memory(NUM_COMMIT_TS_BUFFERS),
+            wal: None,
+            latest_page: Mutex::new(None),
+            latest: Mutex::new(None),
+            last_assigned: Mutex::new(0),
+        }
+    }
+
+    pub fn page_number(xid: TransactionId) -> u32 {
+        xid.0 / COMMIT_TS_XACTS_PER_PAGE
+    }
+
+    pub fn page_precedes(page1: u32, page2: u32) -> bool {
+        let xid1 = TransactionId(page1 * COMMIT_TS_XACTS_PER_PAGE + FIRST_NORMAL_TRANSACTION_ID);
+        let xid2 = TransactionId(page2 * COMMIT_TS_XACTS_PER_PAGE + FIRST_NORMAL_TRANSACTION_ID);
+        xid1.precedes(xid2)
+    }
+
+    fn entry(xid: TransactionId) -> usize {
+        (xid.0 % COMMIT_TS_XACTS_PER_PAGE) as usize * 8
+    }
+
+    // In microseconds since the Unix epoch, like commit timestamps.
+    pub fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0)
+    }
+
+    // Pages are created on demand at commit, since xids handed out while
+    // tracking is off never get an entry.
+    fn extend(&self, xid: TransactionId) -> Result<()> {
+        let page_number = Self::page_number(xid);
+        let mut latest_page = self.latest_page.lock().unwrap();
+        if *latest_page == Some(page_number) {
+            return Ok(());
+        }
+
+        if !self.slru.page_exists(page_number) {
+            if let Some(ref wal) = self.wal {
+                let record =
+                    XLogRecord::new(0, XLogRecordType::CommitTsZeroPage, page_number, vec![]);
+                wal.append(&record)?;
+            }
+            self.slru.zero_page(page_number)?;
+        }
+
+        *latest_page = Some(page_number);
+        Ok(())
+    }
+
+    // A commit time for `xid` and its committed subtransactions, to go in
+    // their commit record. Their pages are created first, so redo finds
+    // them in place before it replays the record. Timestamps never go
+    // backwards, so a later commit always sorts after an earlier one even
+    // if the clock steps back.
+    pub fn assign(&self, xid: TransactionId, subxids: &[TransactionId]) -> Result<u64> {
+        for &x in std::iter::once(&xid).chain(subxids) {
+            self.extend(x)?;
+        }
+        let mut last_assigned = self.last_assigned.lock().unwrap();
+        *last_assigned = Self::now().max(*last_assigned + 1);
+        Ok(*last_assigned)
+    }
+
+    // Stamps `xid` and its committed subtransactions with the commit time
+    // from their commit record, which ends at `lsn`.
+    pub fn set(
+        &self,
+        xid: TransactionId,
+        subxids: &[TransactionId],
+        timestamp: u64,
+        lsn: u64,
+    ) -> Result<()> {
+        for &x in std::iter::once(&xid).chain(subxids) {
+            self.write(x, timestamp, lsn)?;
+        }
+        self.advance_latest(xid, timestamp);
+        Ok(())
+    }
+
+    // Replay of the timestamp in a commit record. An xid whose page is
+    // gone was truncated away after the record was written.
+    pub fn redo_set(
+        &self,
+        xid: TransactionId,
+        subxids: &[TransactionId],
+        timestamp: u64,
+    ) -> Result<()> {
+        for &x in std::iter::once(&xid).chain(subxids) {
+            if self.slru.page_exists(Self::page_number(x)) {
+                self.write(x, timestamp, 0)?;
+            }
+        }
+        self.advance_latest(xid, timestamp);
+        let mut last_assigned = self.last_assigned.lock().unwrap();
+        *last_assigned = (*last_assigned).max(timestamp);
+        Ok(())
+    }
+
+    fn write(&self, xid: TransactionId, timestamp: u64, lsn: u64) -> Result<()> {
+        let entry = Self::entry(xid);
+        self.slru.write_logged(Self::page_number(xid), lsn, |page| {
+            page[entry..entry + 8].copy_from_slice(&timestamp.to_le_bytes());
+        })
+    }
+
+    fn advance_latest(&self, xid: TransactionId, timestamp: u64) {
+        let mut latest = self.latest.lock().unwrap();
+        if latest.is_none_or(|(_, previous)| previous < timestamp) {
+            *latest = Some((xid, timestamp));
+        }
+    }
+
+    pub fn get(&self, xid: TransactionId) -> Result<Option<u64>> {
+        let page_number = Self::page_number(xid);
+        if !self.slru.page_exists(page_number) {
+            return Ok(None);
+        }
+
+        let entry = Self::entry(xid);
+        let timestamp = self.slru.read(page_number, |page| {
+            let mut raw = [0u8; 8];
+            raw.copy_from_slice(&page[entry..entry + 8]);
+            u64::from_le_bytes(raw)
+        })?;
+        Ok(Some(timestamp).filter(|&ts| ts != 0))
+    }
+
+    pub fn latest(&self) -> Option<(TransactionId, u64)> {
+        *self.latest.lock().unwrap()
+    }
+
+    // After a restart, picks the newest commit back up from its xid.
+    pub fn load_latest(&self, xid: TransactionId) -> Result<()> {
+        if xid.is_normal() {
+            if let Some(timestamp) = self.get(xid)? {
+                *self.latest.lock().unwrap() = Some((xid, timestamp));
+                *self.last_assigned.lock().unwrap() = timestamp;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn truncate(&self, oldest_xid: TransactionId) -> Result<()> {
+        let cutoff_page = Self::page_number(oldest_xid);
+        if let Some(ref wal) = self.wal {
+            let record = XLogRecord::new(0, XLogRecordType::CommitTsTruncate, cutoff_page, vec![]);
+            wal.append(&record)?;
+        }
+
+        let mut latest = self.latest.lock().unwrap();
+        if latest.is_some_and(|(xid, _)| xid.precedes(oldest_xid)) {
+            *latest = None;
+        }
+        self.slru.truncate(cutoff_page, Self::page_precedes)
+    }
+
+    // Replay of CommitTsZeroPage: the page is only recreated if it never
+    // reached disk.
+    pub fn redo_zero_page(&self, page_number: u32) -> Result<()> {
+        if !self.slru.page_exists(page_number) {
+            self.slru.zero_page(page_number)?;
+        }
+        Ok(())
+    }
+
+    pub fn redo_truncate(&self, cutoff_page: u32) -> Result<()> {
+        self.slru.truncate(cutoff_page, Self::page_precedes)
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.slru.flush()
+    }
+}
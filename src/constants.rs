@@ -11,12 +11,30 @@ pub const TOAST_TUPLE_THRESHOLD: usize = 2048;
 pub const TOAST_TUPLE_TARGET: usize = 1992;
 pub const TOAST_MAX_CHUNK_SIZE: usize = 1992;
 
+// So a full node always splits into halves that fit a page.
+pub const BTREE_MAX_KEY_SIZE: usize = BLCKSZ / 3;
+
 pub const INVALID_TRANSACTION_ID: u32 = 0;
 pub const BOOTSTRAP_TRANSACTION_ID: u32 = 1;
 pub const FIRST_NORMAL_TRANSACTION_ID: u32 = 2;
 pub const MAX_TRANSACTION_ID: u32 = 0xFFFFFFFF;
 
+pub const XID_PREFETCH: u32 = 1024;
+pub const XID_STOP_MARGIN: u32 = 3_000_000;
+pub const XID_WARN_MARGIN: u32 = 40_000_000;
+
+pub const FIRST_MULTIXACT_ID: u32 = 1;
+
+pub const CSN_LOG_TRIM_THRESHOLD: usize = 1024;
+
+pub const FIRST_NORMAL_REL_NODE: u32 = 16384;
+
+pub const VACUUM_FREEZE_MIN_AGE: u32 = 50_000_000;
+pub const VACUUM_FREEZE_TABLE_AGE: u32 = 150_000_000;
+pub const VACUUM_MULTIXACT_FREEZE_MIN_AGE: u32 = 5_000_000;
+
 pub const INVALID_COMMAND_ID: u32 = 0;
+pub const FIRST_COMMAND_ID: u32 = 1;
 pub const MAX_COMMAND_ID: u32 = 0xFFFFFFFF;
 
 pub const INVALID_OFFSET_NUMBER: u16 = 0;
@@ -29,6 +47,12 @@ pub const PD_HAS_FREE_LINES: u16 = 0x0001;
 pub const HEAP_HASNULL: u16 = 0x0001;
 pub const HEAP_HASVARLENA: u16 = 0x0002;
 pub const HEAP_HASOID: u16 = 0x0004;
+pub const HEAP_XMAX_KEYSHR_LOCK: u16 = 0x0010;
+pub const HEAP_COMBOCID: u16 = 0x0020;
+pub const HEAP_XMAX_EXCL_LOCK: u16 = 0x0040;
+pub const HEAP_XMAX_SHR_LOCK: u16 = HEAP_XMAX_EXCL_LOCK | HEAP_XMAX_KEYSHR_LOCK;
+pub const HEAP_LOCK_MASK: u16 = HEAP_XMAX_SHR_LOCK;
+pub const HEAP_XMAX_IS_MULTI: u16 = 0x0080;
 pub const HEAP_XMIN_COMMITTED: u16 = 0x0100;
 pub const HEAP_XMIN_INVALID: u16 = 0x0200;
 pub const HEAP_XMAX_COMMITTED: u16 = 0x0400;
@@ -53,3 +77,30 @@ pub const SNAPSHOT_MVCC: i32 = 0;
 pub const SNAPSHOT_SELF: i32 = 1;
 pub const SNAPSHOT_ANY: i32 = 2;
 pub const SNAPSHOT_STABLE: i32 = 3;
+
+pub const SLRU_PAGES_PER_SEGMENT: u32 = 32;
+
+pub const CLOG_BITS_PER_XACT: u32 = 2;
+pub const CLOG_XACTS_PER_BYTE: u32 = 4;
+pub const CLOG_XACTS_PER_PAGE: u32 = BLCKSZ as u32 * CLOG_XACTS_PER_BYTE;
+pub const NUM_CLOG_BUFFERS: usize = 8;
+
+pub const COMMIT_TS_XACTS_PER_PAGE: u32 = BLCKSZ as u32 / 8;
+pub const NUM_COMMIT_TS_BUFFERS: usize = 8;
+
+pub const SUBTRANS_XACTS_PER_PAGE: u32 = BLCKSZ as u32 / 4;
+pub const NUM_SUBTRANS_BUFFERS: usize = 8;
+
+pub const MULTIXACT_OFFSETS_PER_PAGE: u32 = BLCKSZ as u32 / 8;
+pub const MULTIXACT_MEMBERS_PER_PAGE: u32 = BLCKSZ as u32 / 8;
+pub const NUM_MULTIXACT_OFFSET_BUFFERS: usize = 8;
+pub const NUM_MULTIXACT_MEMBER_BUFFERS: usize = 16;
+
+pub const WAL_SEGMENT_SIZE: u64 = 16 * 1024 * 1024;
+pub const WAL_WRITER_DELAY_MS: u64 = 200;
+
+pub const NUM_SHARED_BUFFERS: usize = 128;
+pub const BM_MAX_USAGE_COUNT: u8 = 5;
+
+pub const PREDICATE_LOCKS_PER_PAGE: usize = 2;
+pub const PREDICATE_LOCKS_PER_RELATION: usize = 32;
@@ -0,0 +1,147 @@
+use crate::constants::*;
+use crate::error::{HeapError, Result};
+use crate::failpoint;
+use crate::types::RetainHistory;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ControlFileData {
+    #[serde(alias = "next_xid")]
+    pub next_full_xid: u64,
+    pub oldest_xid: u32,
+    pub next_multi: u32,
+    pub next_multi_offset: u32,
+    pub oldest_multi: u32,
+    pub track_commit_timestamp: bool,
+    pub newest_commit_ts_xid: u32,
+    pub data_checksums: bool,
+    pub full_page_writes: bool,
+    pub checkpoint_redo: u64,
+    pub retain_history: RetainHistory,
+    pub next_rel_node: u32,
+}
+
+impl Default for ControlFileData {
+    fn default() -> Self {
+        Self {
+            next_full_xid: FIRST_NORMAL_TRANSACTION_ID as u64,
+            oldest_xid: FIRST_NORMAL_TRANSACTION_ID,
+            next_multi: FIRST_MULTIXACT_ID,
+            next_multi_offset: 0,
+            oldest_multi: FIRST_MULTIXACT_ID,
+            track_commit_timestamp: false,
+            newest_commit_ts_xid: INVALID_TRANSACTION_ID,
+            data_checksums: false,
+            full_page_writes: true,
+            checkpoint_redo: 0,
+            retain_history: RetainHistory::Off,
+            next_rel_node: FIRST_NORMAL_REL_NODE,
+        }
+    }
+}
+
+pub struct ControlFile {
+    path: PathBuf,
+    data: RwLock<ControlFileData>,
+}
+
+impl ControlFile {
+    pub fn open(dir: PathBuf) -> Result<Self> {
+        if !dir.exists() {
+            fs::create_dir_all(&dir)?;
+        }
+
+        let path = dir.join("pg_control");
+        let data = if path.exists() {
+            let raw = fs::read(&path)?;
+            serde_json::from_slice(&raw)
+                .map_err(|e| HeapError::CorruptedData(format!("pg_control: {}", e)))?
+        } else {
+            let data = ControlFileData::default();
+            Self::write_file(&path, &data)?;
+            data
+        };
+
+        Ok(Self {
+            path,
+            data: RwLock::new(data),
+        })
+    }
+
+    pub fn get(&self) -> ControlFileData {
+        self.data.read().unwrap().clone()
+    }
+
+    pub fn update(&self, f: impl FnOnce(&mut ControlFileData)) -> Result<()> {
+        let mut data = self.data.write().unwrap();
+        f(&mut data);
+        Self::write_file(&self.path, &data)
+    }
+
+    fn write_file(path: &PathBuf, data: &ControlFileData) -> Result<()> {
+        let raw = serde_json::to_vec_pretty(data)
+            .map_err(|e| HeapError::StorageError(format!("pg_control: {}", e)))?;
+
+        let tmp_path = path.with_extension("tmp");
+        failpoint::before_write(path)?;
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&raw)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+
+        Ok(())
+    }
+}
+
+// Claims a data directory for one transaction manager, like postmaster.pid:
+// opening it again while the owner is alive fails instead of handing out
+// the same xids twice. A file left behind by a process that died is taken
+// over.
+pub struct DataDirLock {
+    path: PathBuf,
+}
+
+impl DataDirLock {
+    pub fn acquire(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join("postmaster.pid");
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    file.write_all(std::process::id().to_string().as_bytes())?;
+                    file.sync_all()?;
+                    return Ok(Self { path });
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    let owner = fs::read_to_string(&path)
+                        .ok()
+                        .and_then(|raw| raw.trim().parse::<u32>().ok());
+                    if let Some(owner) = owner.filter(|&owner| Self::is_alive(owner)) {
+                        return Err(HeapError::StorageError(format!(
+                            "data directory {} is in use by process {}",
+                            dir.display(),
+                            owner
+                        )));
+                    }
+                    let _ = fs::remove_file(&path);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn is_alive(pid: u32) -> bool {
+        pid == std::process::id() || Path::new("/proc").join(pid.to_string()).exists()
+    }
+}
+
+impl Drop for DataDirLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
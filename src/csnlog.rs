@@ -0,0 +1,79 @@
+use crate::constants::CSN_LOG_TRIM_THRESHOLD;
+use crate::types::*;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+struct CsnLogState {
+    next_csn: CommitSeqNo,
+    csns: HashMap<TransactionId, CommitSeqNo>,
+    // Xids before this have no entry: they committed before the log was
+    // started, before every snapshot still in use, or were truncated away
+    // with the clog.
+    oldest_xid: TransactionId,
+    // Entries at which the next trim is due.
+    trim_at: usize,
+}
+
+// Maps each committed xid to its commit sequence number. Handing out a CSN
+// and recording it happen under one lock, so a snapshot that reads the
+// counter finds every lower CSN already recorded.
+pub struct CsnLog {
+    state: RwLock<CsnLogState>,
+}
+
+impl CsnLog {
+    pub fn new(oldest_xid: TransactionId) -> Self {
+        Self {
+            state: RwLock::new(CsnLogState {
+                next_csn: CommitSeqNo::first_normal(),
+                csns: HashMap::new(),
+                oldest_xid,
+                trim_at: CSN_LOG_TRIM_THRESHOLD,
+            }),
+        }
+    }
+
+    // The CSN a snapshot taken now sees up to, exclusive.
+    pub fn snapshot_csn(&self) -> CommitSeqNo {
+        self.state.read().unwrap().next_csn
+    }
+
+    // Commits `xids` together, as a transaction and its subtransactions.
+    pub fn assign(&self, xids: &[TransactionId]) -> CommitSeqNo {
+        let mut state = self.state.write().unwrap();
+        let csn = state.next_csn;
+        state.next_csn = csn.next();
+        for &xid in xids {
+            state.csns.insert(xid, csn);
+        }
+        csn
+    }
+
+    // None when `xid` did not commit, or when it is older than the log;
+    // `predates` tells the two apart.
+    pub fn get(&self, xid: TransactionId) -> Option<CommitSeqNo> {
+        self.state.read().unwrap().csns.get(&xid).copied()
+    }
+
+    pub fn predates(&self, xid: TransactionId) -> bool {
+        xid.precedes(self.state.read().unwrap().oldest_xid)
+    }
+
+    // Whether the log has doubled since it was last trimmed, so that the
+    // horizon a trim needs is computed once per that many commits.
+    pub fn needs_trim(&self) -> bool {
+        let state = self.state.read().unwrap();
+        state.csns.len() >= state.trim_at
+    }
+
+    // Drops the entries before `oldest_xid`: no snapshot in use has an xmin
+    // before it, and before its xmin a snapshot goes by the clog alone.
+    pub fn truncate(&self, oldest_xid: TransactionId) {
+        let mut state = self.state.write().unwrap();
+        if oldest_xid.follows(state.oldest_xid) {
+            state.csns.retain(|xid, _| !xid.precedes(oldest_xid));
+            state.oldest_xid = oldest_xid;
+        }
+        state.trim_at = (state.csns.len() * 2).max(CSN_LOG_TRIM_THRESHOLD);
+    }
+}
@@ -0,0 +1,408 @@
+use crate::buffer::{BufferPool, BufferPoolRef};
+use crate::constants::*;
+use crate::error::{HeapError, Result};
+use crate::failpoint;
+use crate::heap::{HeapEngine, HeapRelation, VacuumOptions, VacuumStats};
+use crate::heap_tuple::HeapTuple;
+use crate::lock::TableLockMode;
+use crate::recovery::Recovery;
+use crate::transaction::{Transaction, TransactionManager};
+use crate::types::*;
+use crate::wal::WALRef;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CatalogEntry {
+    pub name: String,
+    pub natts: u16,
+}
+
+// One database directory: a single transaction manager (and so one WAL and
+// one set of SLRUs), a buffer pool shared by every relation, and a catalog
+// naming the relations stored under base/.
+pub struct Database {
+    dir: PathBuf,
+    tx_manager: Arc<TransactionManager>,
+    buffer_pool: BufferPoolRef,
+    relations: RwLock<HashMap<String, Arc<HeapRelation>>>,
+    catalog_lock: Mutex<()>,
+}
+
+impl Database {
+    pub fn open(dir: PathBuf) -> Result<Arc<Self>> {
+        Self::open_with(dir, NUM_SHARED_BUFFERS)
+    }
+
+    pub fn open_with(dir: PathBuf, num_buffers: usize) -> Result<Arc<Self>> {
+        fs::create_dir_all(dir.join("base"))?;
+
+        let tx_manager = Arc::new(TransactionManager::open(dir.clone())?);
+        let mut buffer_pool = BufferPool::new(num_buffers)?;
+        if let Some(wal) = tx_manager.wal() {
+            buffer_pool = buffer_pool.with_wal(wal.clone());
+        }
+        let buffer_pool = Arc::new(buffer_pool);
+
+        let mut relations = HashMap::new();
+        for entry in Self::read_catalog(&dir)? {
+            let heap = HeapRelation::open_with(
+                dir.join("base").join(&entry.name),
+                entry.natts,
+                tx_manager.clone(),
+                Some(buffer_pool.clone()),
+            )?;
+            if Self::node_in_use(&relations, heap.relation.rel_node) {
+                return Err(HeapError::CorruptedData(format!(
+                    "relation \"{}\" shares rel_node {} with another relation",
+                    entry.name, heap.relation.rel_node
+                )));
+            }
+            relations.insert(entry.name, Arc::new(heap));
+        }
+
+        let mut recovery = Recovery::new(&tx_manager);
+        for heap in relations.values() {
+            recovery.add_relation(&heap.relation);
+        }
+        if recovery.run()?.changed() {
+            tx_manager.checkpoint(|| buffer_pool.flush_all())?;
+        }
+
+        Ok(Arc::new(Self {
+            dir,
+            tx_manager,
+            buffer_pool,
+            relations: RwLock::new(relations),
+            catalog_lock: Mutex::new(()),
+        }))
+    }
+
+    fn read_catalog(dir: &std::path::Path) -> Result<Vec<CatalogEntry>> {
+        let path = dir.join("pg_catalog");
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let raw = fs::read(&path)?;
+        serde_json::from_slice(&raw)
+            .map_err(|e| HeapError::CorruptedData(format!("pg_catalog: {}", e)))
+    }
+
+    fn write_catalog(&self, relations: &HashMap<String, Arc<HeapRelation>>) -> Result<()> {
+        let mut entries: Vec<CatalogEntry> = relations
+            .iter()
+            .map(|(name, heap)| CatalogEntry {
+                name: name.clone(),
+                natts: heap.natts,
+            })
+            .collect();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let raw = serde_json::to_vec_pretty(&entries)
+            .map_err(|e| HeapError::StorageError(format!("pg_catalog: {}", e)))?;
+
+        let path = self.dir.join("pg_catalog");
+        let tmp_path = path.with_extension("tmp");
+        failpoint::before_write(&path)?;
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&raw)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+
+        Ok(())
+    }
+
+    fn node_in_use(relations: &HashMap<String, Arc<HeapRelation>>, rel_node: u32) -> bool {
+        relations
+            .values()
+            .any(|heap| heap.relation.rel_node == rel_node)
+    }
+
+    fn check_name(name: &str) -> Result<()> {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(HeapError::InvalidOperation(format!(
+                "invalid relation name \"{}\"",
+                name
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn create_relation(&self, name: &str, natts: u16) -> Result<Arc<HeapRelation>> {
+        Self::check_name(name)?;
+        let _guard = self.catalog_lock.lock().unwrap();
+        if self.relations.read().unwrap().contains_key(name) {
+            return Err(HeapError::InvalidOperation(format!(
+                "relation \"{}\" already exists",
+                name
+            )));
+        }
+
+        // A node still held by a relation created before nodes came from the
+        // control file is skipped.
+        let mut relations = self.relations.read().unwrap().clone();
+        let mut rel_node = self.tx_manager.assign_rel_node()?;
+        while Self::node_in_use(&relations, rel_node) {
+            rel_node = self.tx_manager.assign_rel_node()?;
+        }
+
+        let heap = Arc::new(HeapRelation::create_with(
+            self.dir.join("base").join(name),
+            natts,
+            rel_node,
+            self.tx_manager.clone(),
+            Some(self.buffer_pool.clone()),
+        )?);
+
+        relations.insert(name.to_string(), heap.clone());
+        self.write_catalog(&relations)?;
+        *self.relations.write().unwrap() = relations;
+
+        Ok(heap)
+    }
+
+    pub fn relation(&self, name: &str) -> Result<Arc<HeapRelation>> {
+        self.relations
+            .read()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| {
+                HeapError::InvalidOperation(format!("relation \"{}\" does not exist", name))
+            })
+    }
+
+    pub fn relation_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.relations.read().unwrap().keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    // Waits for an AccessExclusive lock so no session is still using the
+    // relation, then removes it from the catalog and from disk.
+    pub fn drop_relation(&self, name: &str) -> Result<()> {
+        let _guard = self.catalog_lock.lock().unwrap();
+        let heap = self.relation(name)?;
+        let engine = HeapEngine {
+            heap: heap.clone(),
+            current_tx: None,
+        };
+        engine.drop()?;
+
+        let mut relations = self.relations.read().unwrap().clone();
+        relations.remove(name);
+        self.write_catalog(&relations)?;
+        *self.relations.write().unwrap() = relations;
+
+        fs::remove_dir_all(self.dir.join("base").join(name))?;
+        Ok(())
+    }
+
+    // The datfrozenxid and datminmxid equivalents: every relation may still
+    // hold xids and multixacts down to its own relfrozenxid and relminmxid,
+    // so only those older than all of them can be forgotten.
+    fn update_frozen_ids(&self) -> Result<()> {
+        let frozen_ids = self
+            .relations
+            .read()
+            .unwrap()
+            .values()
+            .map(|heap| (heap.relation.relfrozenxid(), heap.relation.relminmxid()))
+            .reduce(|(xid, multi), (other_xid, other_multi)| {
+                (xid.older(other_xid), multi.older(other_multi))
+            });
+        if let Some((frozen_xid, min_multi)) = frozen_ids {
+            self.tx_manager.set_oldest_xid(frozen_xid)?;
+            self.tx_manager.multixact().set_oldest(min_multi)?;
+        }
+        Ok(())
+    }
+
+    pub fn session(self: &Arc<Self>) -> Session {
+        Session {
+            db: self.clone(),
+            current_tx: None,
+        }
+    }
+
+    pub fn tx_manager(&self) -> &Arc<TransactionManager> {
+        &self.tx_manager
+    }
+
+    pub fn buffer_pool(&self) -> &BufferPoolRef {
+        &self.buffer_pool
+    }
+
+    pub fn wal(&self) -> Option<&WALRef> {
+        self.tx_manager.wal()
+    }
+
+    // Writes back every dirty buffer and the transaction status files, and
+    // moves the redo point up to where the checkpoint started.
+    pub fn checkpoint(&self) -> Result<()> {
+        self.tx_manager.checkpoint(|| self.buffer_pool.flush_all())
+    }
+
+    pub fn close(&self) -> Result<()> {
+        self.checkpoint()
+    }
+}
+
+impl Drop for Database {
+    fn drop(&mut self) {
+        let _ = self.checkpoint();
+    }
+}
+
+// A connection to a database. Each session runs at most one transaction at
+// a time; sessions can be moved to other threads and run concurrently.
+pub struct Session {
+    db: Arc<Database>,
+    current_tx: Option<Transaction>,
+}
+
+impl Session {
+    pub fn database(&self) -> &Arc<Database> {
+        &self.db
+    }
+
+    pub fn transaction(&self) -> Option<&Transaction> {
+        self.current_tx.as_ref()
+    }
+
+    pub fn begin(&mut self) -> Result<Transaction> {
+        self.begin_with(IsolationLevel::ReadCommitted)
+    }
+
+    pub fn begin_with(&mut self, isolation: IsolationLevel) -> Result<Transaction> {
+        if self.current_tx.is_some() {
+            return Err(HeapError::InvalidTransaction(
+                "there is already a transaction in progress".to_string(),
+            ));
+        }
+
+        let tx = Transaction::with_isolation(self.db.tx_manager.clone(), isolation)?;
+        self.current_tx = Some(tx.clone());
+        Ok(tx)
+    }
+
+    pub fn commit(&mut self) -> Result<()> {
+        match self.current_tx.take() {
+            Some(tx) => tx.commit(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn abort(&mut self) -> Result<()> {
+        match self.current_tx.take() {
+            Some(tx) => tx.abort(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn prepare(&mut self, gid: &str) -> Result<()> {
+        match self.current_tx.take() {
+            Some(tx) => tx.prepare(gid),
+            None => Err(HeapError::InvalidTransaction(
+                "no active transaction".to_string(),
+            )),
+        }
+    }
+
+    pub fn savepoint(&self, name: &str) -> Result<TransactionId> {
+        self.active_transaction()?.savepoint(name)
+    }
+
+    pub fn rollback_to(&self, name: &str) -> Result<TransactionId> {
+        self.active_transaction()?.rollback_to(name)
+    }
+
+    pub fn release(&self, name: &str) -> Result<()> {
+        self.active_transaction()?.release(name)
+    }
+
+    fn active_transaction(&self) -> Result<&Transaction> {
+        self.current_tx
+            .as_ref()
+            .ok_or_else(|| HeapError::InvalidTransaction("no active transaction".to_string()))
+    }
+
+    // Statements run through a short-lived engine on the named relation that
+    // shares this session's transaction, so locking, predicate locks and
+    // retries behave exactly as they do for a single-relation engine.
+    fn engine(&self, relation: &str) -> Result<HeapEngine> {
+        Ok(HeapEngine {
+            heap: self.db.relation(relation)?,
+            current_tx: self.current_tx.clone(),
+        })
+    }
+
+    pub fn insert(&self, relation: &str, data: &[u8]) -> Result<ItemPointerData> {
+        self.engine(relation)?.insert(data)
+    }
+
+    pub fn update(
+        &self,
+        relation: &str,
+        ctid: ItemPointerData,
+        new_data: &[u8],
+    ) -> Result<Option<ItemPointerData>> {
+        self.engine(relation)?.update(ctid, new_data)
+    }
+
+    pub fn delete(&self, relation: &str, ctid: ItemPointerData) -> Result<bool> {
+        self.engine(relation)?.delete(ctid)
+    }
+
+    pub fn get(&self, relation: &str, ctid: ItemPointerData) -> Result<Option<HeapTuple>> {
+        self.engine(relation)?.get(ctid)
+    }
+
+    pub fn scan(&self, relation: &str) -> Result<Vec<(ItemPointerData, HeapTuple)>> {
+        self.engine(relation)?.scan()
+    }
+
+    pub fn scan_as_of(
+        &self,
+        relation: &str,
+        as_of: AsOf,
+    ) -> Result<Vec<(ItemPointerData, HeapTuple)>> {
+        self.engine(relation)?.scan_as_of(as_of)
+    }
+
+    pub fn lock_tuple(
+        &self,
+        relation: &str,
+        ctid: ItemPointerData,
+        mode: LockMode,
+        wait_policy: WaitPolicy,
+    ) -> Result<UpdateResult> {
+        self.engine(relation)?.lock_tuple(ctid, mode, wait_policy)
+    }
+
+    pub fn lock_table(
+        &self,
+        relation: &str,
+        mode: TableLockMode,
+        wait_policy: WaitPolicy,
+    ) -> Result<bool> {
+        self.engine(relation)?.lock_table(mode, wait_policy)
+    }
+
+    pub fn vacuum(&self, relation: &str) -> Result<u32> {
+        Ok(self
+            .vacuum_with(relation, VacuumOptions::default())?
+            .removed)
+    }
+
+    pub fn vacuum_with(&self, relation: &str, options: VacuumOptions) -> Result<VacuumStats> {
+        let stats = self.engine(relation)?.vacuum_relation(options)?;
+        self.db.update_frozen_ids()?;
+        Ok(stats)
+    }
+}
@@ -34,6 +34,15 @@ pub enum HeapError {
 
     #[error("Lock error: {0}")]
     LockError(String),
+
+    #[error("Deadlock: {0}")]
+    Deadlock(String),
+
+    #[error("Serialization failure: {0}")]
+    SerializationFailure(String),
+
+    #[error("Transaction ID wraparound: {0}")]
+    XidWraparound(String),
 }
 
 pub type Result<T> = std::result::Result<T, HeapError>;
@@ -0,0 +1,194 @@
+use crate::error::Result;
+use std::path::Path;
+
+// Kill points for crash tests. Once a data directory is armed, a given
+// number of writes under it go through and then the process "dies": that
+// write and every later one under the directory fail, as if nothing after
+// the kill reached disk, except that a page write the kill lands on may be
+// torn: half of it written, as after a power loss between its sectors.
+// Buffered writes made before the kill are lost with it unless their file
+// was synced since, as the OS cache would lose them.
+// Without the testing feature every check passes.
+#[cfg(any(test, feature = "testing"))]
+mod kill {
+    use crate::error::{HeapError, Result};
+    use std::collections::HashMap;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+    use std::sync::Mutex;
+
+    struct KillPoint {
+        dir: PathBuf,
+        writes_left: u64,
+        torn: bool,
+        // What each file with buffered writes held when last synced, or
+        // None if it did not exist yet.
+        unsynced: HashMap<PathBuf, Option<Vec<u8>>>,
+    }
+
+    static KILL_POINTS: Mutex<Vec<KillPoint>> = Mutex::new(Vec::new());
+
+    pub fn kill_after(dir: &Path, writes: u64) {
+        arm(dir, writes, false);
+    }
+
+    pub fn tear_after(dir: &Path, writes: u64) {
+        arm(dir, writes, true);
+    }
+
+    fn arm(dir: &Path, writes: u64, torn: bool) {
+        let mut points = KILL_POINTS.lock().unwrap();
+        points.retain(|point| point.dir != dir);
+        points.push(KillPoint {
+            dir: dir.to_path_buf(),
+            writes_left: writes,
+            torn,
+            unsynced: HashMap::new(),
+        });
+    }
+
+    pub fn revive(dir: &Path) {
+        KILL_POINTS.lock().unwrap().retain(|point| point.dir != dir);
+    }
+
+    pub fn is_dead(dir: &Path) -> bool {
+        KILL_POINTS
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|point| point.dir == dir && point.writes_left == 0)
+    }
+
+    pub fn check(path: &Path, counts: bool) -> Result<()> {
+        match fire(path, counts) {
+            Some(_) => Err(killed(path)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn check_buffered(path: &Path) -> Result<()> {
+        check(path, true)?;
+        let mut points = KILL_POINTS.lock().unwrap();
+        for point in points
+            .iter_mut()
+            .filter(|point| path.starts_with(&point.dir))
+        {
+            point
+                .unsynced
+                .entry(path.to_path_buf())
+                .or_insert_with(|| fs::read(path).ok());
+        }
+        Ok(())
+    }
+
+    pub fn check_sync(path: &Path) -> Result<()> {
+        check(path, false)?;
+        let mut points = KILL_POINTS.lock().unwrap();
+        for point in points.iter_mut() {
+            point.unsynced.retain(|file, _| !file.starts_with(path));
+        }
+        Ok(())
+    }
+
+    pub fn check_page(path: &Path, data: &[u8]) -> Result<()> {
+        match fire(path, true) {
+            Some(true) => {
+                // The rest of the old page stays behind the new half.
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .truncate(false)
+                    .open(path)?;
+                file.write_all(&data[..data.len() / 2])?;
+                Err(killed(path))
+            }
+            Some(false) => Err(killed(path)),
+            None => Ok(()),
+        }
+    }
+
+    // Some once the kill point for `path` has fired, saying whether this
+    // is the write it landed on and that write is to be torn.
+    fn fire(path: &Path, counts: bool) -> Option<bool> {
+        let mut points = KILL_POINTS.lock().unwrap();
+        for point in points
+            .iter_mut()
+            .filter(|point| path.starts_with(&point.dir))
+        {
+            if point.writes_left == 0 {
+                lose_unsynced(point);
+                return Some(std::mem::take(&mut point.torn));
+            }
+            if counts {
+                point.writes_left -= 1;
+            }
+        }
+        None
+    }
+
+    fn lose_unsynced(point: &mut KillPoint) {
+        for (path, synced) in point.unsynced.drain() {
+            let _ = match synced {
+                Some(data) => fs::write(&path, data),
+                None => fs::remove_file(&path),
+            };
+        }
+    }
+
+    fn killed(path: &Path) -> HeapError {
+        HeapError::StorageError(format!("killed before writing {}", path.display()))
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+pub use kill::{is_dead, kill_after, revive, tear_after};
+
+// Called before writing under `path`; each call uses up one of the writes
+// left before an armed kill point fires.
+#[cfg(any(test, feature = "testing"))]
+pub fn before_write(path: &Path) -> Result<()> {
+    kill::check(path, true)
+}
+
+// Called before a write under `path` that is not synced before it returns.
+// Counts like any other write.
+#[cfg(any(test, feature = "testing"))]
+pub fn before_buffered_write(path: &Path) -> Result<()> {
+    kill::check_buffered(path)
+}
+
+// Called before writing the page `data` to `path`. The write a torn kill
+// lands on gets half of `data` to disk before failing.
+#[cfg(any(test, feature = "testing"))]
+pub fn before_page_write(path: &Path, data: &[u8]) -> Result<()> {
+    kill::check_page(path, data)
+}
+
+// Called before an fsync under `path`, which fails once the kill point has
+// fired but does not count as a write. Buffered writes under `path` count
+// as synced from here on.
+#[cfg(any(test, feature = "testing"))]
+pub fn before_sync(path: &Path) -> Result<()> {
+    kill::check_sync(path)
+}
+
+#[cfg(not(any(test, feature = "testing")))]
+pub fn before_write(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(not(any(test, feature = "testing")))]
+pub fn before_buffered_write(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(not(any(test, feature = "testing")))]
+pub fn before_page_write(_path: &Path, _data: &[u8]) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(not(any(test, feature = "testing")))]
+pub fn before_sync(_path: &Path) -> Result<()> {
+    Ok(())
+}
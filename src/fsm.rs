@@ -1,26 +1,21 @@
-use crate::constants::*;
 use crate::error::Result;
 use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
 
 pub struct FreeSpaceMap {
     page_free_space: RwLock<HashMap<u32, u16>>,
-    page_size: usize,
 }
 
 impl FreeSpaceMap {
-    pub fn new(page_size: usize) -> Self {
+    pub fn new(_page_size: usize) -> Self {
         Self {
             page_free_space: RwLock::new(HashMap::new()),
-            page_size,
         }
     }
 
     pub fn update(&self, block_num: u32, free_space: u16) -> Result<()> {
         let mut fsm = self.page_free_space.write().unwrap();
 
-        let vm_page_num = block_num / 256;
-
         if free_space < 32 {
             fsm.insert(block_num, 0);
         } else {
@@ -44,7 +39,7 @@ impl FreeSpaceMap {
             .map(|(&block, &space)| (block, space))
             .collect();
 
-        candidates.sort_by(|a, b| b.1.cmp(&a.1));
+        candidates.sort_by_key(|&(_, space)| std::cmp::Reverse(space));
 
         candidates.first().map(|(block, _)| *block)
     }
@@ -1,13 +1,87 @@
+use crate::buffer::BufferPoolRef;
+use crate::combocid::ComboCids;
 use crate::constants::*;
 use crate::error::{HeapError, Result};
 use crate::heap_tuple::{HeapTuple, HeapTupleHeaderData};
+use crate::lock::{LockTag, TableLockMode};
 use crate::page::Page;
+use crate::predicate::PredicateLockTag;
+use crate::recovery::Recovery;
 use crate::relation::Relation;
-use crate::transaction::{Transaction, TransactionManager};
+use crate::transaction::{Transaction, TransactionManager, VirtualTransaction};
+use crate::twophase::PreparedTransaction;
 use crate::types::*;
-use crate::visibility::Visibility;
+use crate::visibility::{TransactionOracle, Visibility};
+use crate::wal::{XLogBlockRef, XLogRecord, XLogRecordType};
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::{Arc, RwLockReadGuard};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VacuumOptions {
+    pub freeze_min_age: u32,
+    pub freeze_table_age: u32,
+    pub multixact_freeze_min_age: u32,
+    pub aggressive: bool,
+}
+
+impl VacuumOptions {
+    // VACUUM FREEZE: freeze every tuple that is visible to everyone.
+    pub fn freeze() -> Self {
+        Self {
+            freeze_min_age: 0,
+            multixact_freeze_min_age: 0,
+            aggressive: true,
+            ..Self::default()
+        }
+    }
+}
+
+impl Default for VacuumOptions {
+    fn default() -> Self {
+        Self {
+            freeze_min_age: VACUUM_FREEZE_MIN_AGE,
+            freeze_table_age: VACUUM_FREEZE_TABLE_AGE,
+            multixact_freeze_min_age: VACUUM_MULTIXACT_FREEZE_MIN_AGE,
+            aggressive: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VacuumStats {
+    pub removed: u32,
+    pub frozen: u32,
+    pub aggressive: bool,
+    pub relfrozenxid: TransactionId,
+    pub relminmxid: MultiXactId,
+}
+
+// Hint bits a scan worked out for one tuple, written back only if the
+// tuple's xmin and xmax are still the ones it looked at.
+struct HintUpdate {
+    offset: u16,
+    xmin: TransactionId,
+    xmax: TransactionId,
+    xmin_status: HeapTupleStatus,
+    xmax_status: HeapTupleStatus,
+}
+
+struct UpdateCheck {
+    result: UpdateResult,
+    blocker: Option<TransactionId>,
+    lockers: Vec<MultiXactMember>,
+}
+
+impl UpdateCheck {
+    fn done(result: UpdateResult) -> Self {
+        Self {
+            result,
+            blocker: None,
+            lockers: Vec::new(),
+        }
+    }
+}
 
 pub struct HeapRelation {
     pub relation: Relation,
@@ -17,23 +91,68 @@ pub struct HeapRelation {
 
 impl HeapRelation {
     pub fn create(path: PathBuf, natts: u16) -> Result<(Self, u32)> {
-        let tx_manager = Arc::new(TransactionManager::new());
+        let tx_manager = Arc::new(TransactionManager::open(path.clone())?);
+        let rel_node = tx_manager.assign_rel_node()?;
+        let heap = Self::create_with(path, natts, rel_node, tx_manager, None)?;
+        Ok((heap, rel_node))
+    }
 
-        let (relation, rel_node) = Relation::create(path, natts)?;
+    // Creates the relation under a transaction manager, and optionally a
+    // buffer pool, shared with other relations of the same database.
+    pub fn create_with(
+        path: PathBuf,
+        natts: u16,
+        rel_node: u32,
+        tx_manager: Arc<TransactionManager>,
+        buffer_pool: Option<BufferPoolRef>,
+    ) -> Result<Self> {
+        let relation = Relation::create_with_node(path, natts, rel_node)?;
+        let mut relation = relation.with_data_checksums(tx_manager.data_checksums());
+        if let Some(pool) = buffer_pool {
+            relation = relation.with_buffer_pool(pool);
+        }
+        if let Some(wal) = tx_manager.wal() {
+            relation = relation.with_wal(wal.clone());
+        }
+        relation.set_frozen_ids(
+            tx_manager.current_xid(),
+            tx_manager.multixact().next_multi(),
+        )?;
 
-        let heap = Self {
+        Ok(Self {
             relation,
             natts,
             tx_manager,
-        };
-
-        Ok((heap, rel_node))
+        })
     }
 
+    // A relation opened on its own is the only one its WAL covers, so it
+    // is recovered here; under a Database the database recovers them all.
     pub fn open(path: PathBuf, natts: u16) -> Result<Self> {
-        let tx_manager = Arc::new(TransactionManager::new());
+        let tx_manager = Arc::new(TransactionManager::open(path.clone())?);
+        let heap = Self::open_with(path, natts, tx_manager, None)?;
 
-        let relation = Relation::open(path)?;
+        let mut recovery = Recovery::new(&heap.tx_manager);
+        recovery.add_relation(&heap.relation);
+        if recovery.run()?.changed() {
+            heap.tx_manager.checkpoint(|| Ok(()))?;
+        }
+        Ok(heap)
+    }
+
+    pub fn open_with(
+        path: PathBuf,
+        natts: u16,
+        tx_manager: Arc<TransactionManager>,
+        buffer_pool: Option<BufferPoolRef>,
+    ) -> Result<Self> {
+        let mut relation = Relation::open(path)?.with_data_checksums(tx_manager.data_checksums());
+        if let Some(pool) = buffer_pool {
+            relation = relation.with_buffer_pool(pool);
+        }
+        if let Some(wal) = tx_manager.wal() {
+            relation = relation.with_wal(wal.clone());
+        }
 
         Ok(Self {
             relation,
@@ -48,6 +167,30 @@ impl HeapRelation {
         cid: CommandId,
         data: &[u8],
     ) -> Result<ItemPointerData> {
+        let _guard = self.relation.lock_content();
+        let (mut page, ctid, tuple) = self.place_tuple(xid, cid, data)?;
+
+        let mut block = XLogBlockRef::new(self.relation.rel_node, ctid.block_number);
+        block.tuples.push((ctid.offset_number, tuple));
+        let _logged = self.log_heap(
+            xid,
+            XLogRecordType::HeapInsert,
+            vec![block],
+            &mut [&mut page],
+        )?;
+
+        self.relation.write_page(ctid.block_number, &page)?;
+        Ok(ctid)
+    }
+
+    // Adds a new tuple to a page with room for it. The page is returned
+    // with the tuple and its bytes for the caller to log and write.
+    fn place_tuple(
+        &self,
+        xid: TransactionId,
+        cid: CommandId,
+        data: &[u8],
+    ) -> Result<(Page, ItemPointerData, Vec<u8>)> {
         let tuple_size = HeapTupleHeaderData::size() + data.len();
 
         let page_count = self.relation.page_count();
@@ -63,13 +206,11 @@ impl HeapRelation {
             }
         }
 
-        if !found_page {
-            if page_count > 0 {
-                let last_page = self.relation.read_page(page_count - 1)?;
-                if last_page.free_space() >= tuple_size + 4 {
-                    block_num = page_count - 1;
-                    found_page = true;
-                }
+        if !found_page && page_count > 0 {
+            let last_page = self.relation.read_page(page_count - 1)?;
+            if last_page.free_space() >= tuple_size + 4 {
+                block_num = page_count - 1;
+                found_page = true;
             }
         }
 
@@ -102,83 +243,485 @@ impl HeapRelation {
             tuple_data[..serialized.len()].copy_from_slice(&serialized);
         }
 
-        self.relation.write_page(block_num, &page)?;
-
-        Ok(ItemPointerData {
+        let ctid = ItemPointerData {
             block_number: block_num,
             offset_number: offset,
-        })
+        };
+        Ok((page, ctid, serialized))
+    }
+
+    // WAL-logs a change to `blocks` and stamps `pages` with the record's
+    // LSN. The pages may only be written after this, and before the
+    // returned guard goes, so a checkpoint cannot start in between. A page
+    // not logged since the last checkpoint goes into the record whole.
+    fn log_heap(
+        &self,
+        xid: TransactionId,
+        record_type: XLogRecordType,
+        mut blocks: Vec<XLogBlockRef>,
+        pages: &mut [&mut Page],
+    ) -> Result<RwLockReadGuard<'_, ()>> {
+        let logged = self.tx_manager.log_change();
+        if let Some(wal) = self.tx_manager.wal() {
+            for (block, page) in blocks.iter_mut().zip(pages.iter()) {
+                if self.tx_manager.needs_page_image(page.header.pd_lsn) {
+                    block.image = Some(page.serialize());
+                }
+            }
+            let lsn = wal.append(&XLogRecord::with_blocks(xid.0, record_type, &blocks))?;
+            for page in pages {
+                page.header.pd_lsn = lsn;
+            }
+        }
+        Ok(logged)
     }
 
+    // Replaces the tuple at `old_ctid` with a new version. With `wait`, a
+    // concurrent writer is waited out and the tuple checked again; without
+    // it the caller gets BeingModified. The new ctid is returned only on Ok.
     pub fn update(
         &self,
         xid: TransactionId,
         cid: CommandId,
         old_ctid: ItemPointerData,
         new_data: &[u8],
-    ) -> Result<Option<ItemPointerData>> {
-        let mut old_page = self.relation.read_page(old_ctid.block_number)?;
+        wait: bool,
+    ) -> Result<(UpdateResult, Option<ItemPointerData>)> {
+        loop {
+            let guard = self.relation.lock_content();
+
+            let old_page = self.relation.read_page(old_ctid.block_number)?;
+
+            let old_tuple_data = old_page
+                .get_item(old_ctid.offset_number)
+                .ok_or_else(|| HeapError::InvalidTuple("failed to get old item".to_string()))?;
+
+            let mut old_tuple = HeapTuple::deserialize(old_tuple_data, self.natts)?;
+
+            let status = MultiXactStatus::NoKeyUpdate;
+            let check = self.satisfies_update(&old_tuple, old_ctid, xid, cid, status)?;
+            match (check.result, check.blocker) {
+                (UpdateResult::Ok, _) => {}
+                (UpdateResult::BeingModified, Some(blocker)) if wait => {
+                    drop(guard);
+                    self.tx_manager.wait_for(xid, blocker)?;
+                    continue;
+                }
+                (result, _) => return Ok((result, None)),
+            }
 
-        let old_tuple_data = old_page
-            .get_item(old_ctid.offset_number)
-            .ok_or_else(|| HeapError::InvalidTuple("failed to get old item".to_string()))?;
+            self.set_xmax(&mut old_tuple, xid, status, check.lockers)?;
+            self.set_cmax(&mut old_tuple, xid, cid)?;
 
-        let mut old_tuple = HeapTuple::deserialize(old_tuple_data, self.natts)?;
+            let (new_page, new_ctid, new_tuple) = self.place_tuple(xid, cid, new_data)?;
+
+            old_tuple.header.t_ctid = new_ctid;
+
+            // The new version may have landed on the same page.
+            let mut pages = vec![(new_ctid.block_number, new_page)];
+            if old_ctid.block_number != new_ctid.block_number {
+                let old_page = self.relation.read_page(old_ctid.block_number)?;
+                pages.push((old_ctid.block_number, old_page));
+            }
 
-        if !old_tuple.xmax().is_invalid() {
-            return Ok(None);
+            let serialized = old_tuple.serialize();
+            let (_, old_page) = pages.last_mut().unwrap();
+            let tuple_data = old_page
+                .get_item_mut(old_ctid.offset_number)
+                .ok_or_else(|| {
+                    HeapError::InvalidTuple("failed to get old item for update".to_string())
+                })?;
+            tuple_data[..serialized.len()].copy_from_slice(&serialized);
+
+            let mut blocks: Vec<_> = pages
+                .iter()
+                .map(|&(block_num, _)| XLogBlockRef::new(self.relation.rel_node, block_num))
+                .collect();
+            blocks[0].tuples.push((new_ctid.offset_number, new_tuple));
+            let old_block = blocks.last_mut().unwrap();
+            old_block.tuples.push((old_ctid.offset_number, serialized));
+            let _logged = self.log_heap(
+                xid,
+                XLogRecordType::HeapUpdate,
+                blocks,
+                &mut pages.iter_mut().map(|(_, page)| page).collect::<Vec<_>>(),
+            )?;
+
+            for (block_num, page) in &pages {
+                self.relation.write_page(*block_num, page)?;
+            }
+
+            return Ok((UpdateResult::Ok, Some(new_ctid)));
         }
+    }
 
-        old_tuple.header.t_xmax = xid.0;
-        old_tuple.header.t_cid = cid.0;
+    pub fn delete(
+        &self,
+        xid: TransactionId,
+        cid: CommandId,
+        ctid: ItemPointerData,
+        wait: bool,
+    ) -> Result<UpdateResult> {
+        loop {
+            let guard = self.relation.lock_content();
+
+            let mut page = self.relation.read_page(ctid.block_number)?;
 
-        let new_ctid = self.insert(xid, cid, new_data)?;
+            let tuple_data = page.get_item(ctid.offset_number).ok_or_else(|| {
+                HeapError::InvalidTuple("failed to get item for delete".to_string())
+            })?;
+
+            let mut heap_tuple = HeapTuple::deserialize(tuple_data, self.natts)?;
+
+            let status = MultiXactStatus::Update;
+            let check = self.satisfies_update(&heap_tuple, ctid, xid, cid, status)?;
+            match (check.result, check.blocker) {
+                (UpdateResult::Ok, _) => {}
+                (UpdateResult::BeingModified, Some(blocker)) if wait => {
+                    drop(guard);
+                    self.tx_manager.wait_for(xid, blocker)?;
+                    continue;
+                }
+                (result, _) => return Ok(result),
+            }
 
-        old_tuple.header.t_ctid = new_ctid;
+            self.set_xmax(&mut heap_tuple, xid, status, check.lockers)?;
+            self.set_cmax(&mut heap_tuple, xid, cid)?;
 
-        let serialized = old_tuple.serialize();
-        let tuple_data = old_page
-            .get_item_mut(old_ctid.offset_number)
-            .ok_or_else(|| {
-                HeapError::InvalidTuple("failed to get old item for update".to_string())
+            let serialized = heap_tuple.serialize();
+            let tuple_data = page.get_item_mut(ctid.offset_number).ok_or_else(|| {
+                HeapError::InvalidTuple("failed to get item for delete".to_string())
             })?;
-        tuple_data[..serialized.len()].copy_from_slice(&serialized);
+            tuple_data[..serialized.len()].copy_from_slice(&serialized);
+
+            let mut block = XLogBlockRef::new(self.relation.rel_node, ctid.block_number);
+            block.tuples.push((ctid.offset_number, serialized));
+            let _logged = self.log_heap(
+                xid,
+                XLogRecordType::HeapDelete,
+                vec![block],
+                &mut [&mut page],
+            )?;
 
-        self.relation.write_page(old_ctid.block_number, &old_page)?;
+            self.relation.write_page(ctid.block_number, &page)?;
 
-        Ok(Some(new_ctid))
+            return Ok(UpdateResult::Ok);
+        }
     }
 
-    pub fn delete(
+    // Locks the row in `mode` for `xid` by recording the lock in xmax.
+    // WouldBlock means the row was skipped under SKIP LOCKED.
+    pub fn lock_tuple(
         &self,
         xid: TransactionId,
+        ctid: ItemPointerData,
+        mode: LockMode,
+        wait_policy: WaitPolicy,
+    ) -> Result<UpdateResult> {
+        loop {
+            let guard = self.relation.lock_content();
+
+            let mut page = self.relation.read_page(ctid.block_number)?;
+            let tuple_data = page.get_item(ctid.offset_number).ok_or_else(|| {
+                HeapError::InvalidTuple("failed to get item for lock".to_string())
+            })?;
+            let mut heap_tuple = HeapTuple::deserialize(tuple_data, self.natts)?;
+
+            let mut held = LockMode::NoLock;
+            let mut blocker = None;
+            let mut others = Vec::new();
+
+            for member in self.xmax_members(&heap_tuple)? {
+                let member_mode = member.status.lock_mode();
+                if self.is_own_xid(member.xid, xid)? {
+                    if member.status.is_update() {
+                        return Ok(UpdateResult::SelfModified);
+                    }
+                    if member_mode.is_stronger_than(held) {
+                        held = member_mode;
+                    }
+                    if member.xid != xid {
+                        others.push(member);
+                    }
+                } else if member.status.is_update() && !self.tx_manager.is_in_progress(member.xid) {
+                    return Ok(Self::replaced_result(&heap_tuple, ctid));
+                } else if mode.conflicts_with(member_mode) {
+                    blocker.get_or_insert(member.xid);
+                } else {
+                    others.push(member);
+                }
+            }
+
+            if let Some(holder) = blocker {
+                match wait_policy {
+                    WaitPolicy::Block => {
+                        drop(guard);
+                        self.tx_manager.wait_for(xid, holder)?;
+                        continue;
+                    }
+                    WaitPolicy::SkipLocked => return Ok(UpdateResult::WouldBlock),
+                    WaitPolicy::NoWait => {
+                        return Err(HeapError::LockError(format!(
+                            "could not obtain lock on row {}",
+                            ctid
+                        )));
+                    }
+                }
+            }
+
+            if held != LockMode::NoLock && !mode.is_stronger_than(held) {
+                return Ok(UpdateResult::Ok);
+            }
+
+            self.set_xmax(
+                &mut heap_tuple,
+                xid,
+                MultiXactStatus::from_lock_mode(mode),
+                others,
+            )?;
+
+            let serialized = heap_tuple.serialize();
+            let tuple_data = page.get_item_mut(ctid.offset_number).ok_or_else(|| {
+                HeapError::InvalidTuple("failed to get item for lock".to_string())
+            })?;
+            tuple_data[..serialized.len()].copy_from_slice(&serialized);
+
+            let mut block = XLogBlockRef::new(self.relation.rel_node, ctid.block_number);
+            block.tuples.push((ctid.offset_number, serialized));
+            let _logged =
+                self.log_heap(xid, XLogRecordType::HeapLock, vec![block], &mut [&mut page])?;
+
+            self.relation.write_page(ctid.block_number, &page)?;
+            return Ok(UpdateResult::Ok);
+        }
+    }
+
+    // The transactions recorded in xmax that still matter, with a multixact
+    // expanded into its members. Finished lockers and aborted updaters drop
+    // out, so an empty list means xmax can be overwritten.
+    fn xmax_members(&self, heap_tuple: &HeapTuple) -> Result<Vec<MultiXactMember>> {
+        let header = &heap_tuple.header;
+        let xmax = heap_tuple.xmax();
+        if xmax.is_invalid() || header.xmax_invalid() {
+            return Ok(Vec::new());
+        }
+
+        let members = if header.xmax_is_multi() {
+            self.tx_manager
+                .multixact()
+                .get_members(MultiXactId(xmax.0))?
+        } else {
+            let status = if header.xmax_is_locked_only() {
+                MultiXactStatus::from_lock_mode(header.xmax_lock_mode())
+            } else if header.keys_updated() {
+                MultiXactStatus::Update
+            } else {
+                MultiXactStatus::NoKeyUpdate
+            };
+            vec![MultiXactMember::new(xmax, status)]
+        };
+
+        Ok(members
+            .into_iter()
+            .filter(|member| {
+                if member.status.is_update() {
+                    !self.tx_manager.did_abort(member.xid)
+                } else {
+                    self.tx_manager.is_in_progress(member.xid)
+                }
+            })
+            .collect())
+    }
+
+    // Records `cid` as the tuple's cmax. A tuple this transaction inserted
+    // keeps its cmin as well, behind a combo command id.
+    fn set_cmax(
+        &self,
+        heap_tuple: &mut HeapTuple,
+        xid: TransactionId,
         cid: CommandId,
+    ) -> Result<()> {
+        let xmin = heap_tuple.xmin();
+        let own_insert = !heap_tuple.header.xmin_frozen()
+            && self.tx_manager.is_in_progress(xmin)
+            && self.is_own_xid(xmin, xid)?;
+
+        if own_insert {
+            let cmin = heap_tuple.cmin(&self.combo_cids(heap_tuple, xid)?);
+            let combo = self.tx_manager.combo_cid(xid, cmin, cid)?;
+            heap_tuple.set_cid(combo);
+            heap_tuple.header.set_combo_cid(true);
+        } else {
+            heap_tuple.set_cid(cid);
+        }
+        Ok(())
+    }
+
+    // The combo command ids needed to read this tuple's cmin and cmax.
+    fn combo_cids(&self, heap_tuple: &HeapTuple, xid: TransactionId) -> Result<ComboCids> {
+        if heap_tuple.header.is_combo_cid() {
+            self.tx_manager.combo_cids(xid)
+        } else {
+            Ok(ComboCids::new())
+        }
+    }
+
+    fn is_own_xid(&self, holder: TransactionId, xid: TransactionId) -> Result<bool> {
+        if holder == xid {
+            return Ok(true);
+        }
+        Ok(self.tx_manager.get_topmost(holder)? == self.tx_manager.get_topmost(xid)?)
+    }
+
+    // Whether `xid` may update or delete the tuple with `status` at command
+    // `cid`. On Ok, `lockers` are the other transactions' row locks that stay
+    // on the tuple; on BeingModified, `blocker` is the transaction to wait for.
+    fn satisfies_update(
+        &self,
+        heap_tuple: &HeapTuple,
         ctid: ItemPointerData,
-    ) -> Result<bool> {
-        let mut page = self.relation.read_page(ctid.block_number)?;
+        xid: TransactionId,
+        cid: CommandId,
+        status: MultiXactStatus,
+    ) -> Result<UpdateCheck> {
+        let header = &heap_tuple.header;
+        let xmin = heap_tuple.xmin();
+        let combo_cids = self.combo_cids(heap_tuple, xid)?;
+
+        if !header.xmin_frozen() && !header.xmin_committed() {
+            let visible = if header.xmin_invalid() {
+                false
+            } else if self.tx_manager.is_in_progress(xmin) {
+                self.is_own_xid(xmin, xid)? && heap_tuple.cmin(&combo_cids).0 < cid.0
+            } else {
+                !self.tx_manager.did_abort(xmin)
+            };
+            if !visible {
+                return Ok(UpdateCheck::done(UpdateResult::Invisible));
+            }
+        }
 
-        let tuple_data = page
-            .get_item(ctid.offset_number)
-            .ok_or_else(|| HeapError::InvalidTuple("failed to get item for delete".to_string()))?;
+        let mut check = UpdateCheck::done(UpdateResult::Ok);
+
+        for member in self.xmax_members(heap_tuple)? {
+            if self.is_own_xid(member.xid, xid)? {
+                if member.status.is_update() {
+                    let result = if heap_tuple.cmax(&combo_cids).0 >= cid.0 {
+                        UpdateResult::SelfModified
+                    } else {
+                        UpdateResult::Invisible
+                    };
+                    return Ok(UpdateCheck::done(result));
+                }
+            } else if member.status.is_update() && !self.tx_manager.is_in_progress(member.xid) {
+                return Ok(UpdateCheck::done(Self::replaced_result(heap_tuple, ctid)));
+            } else if member.status.is_update()
+                || status.lock_mode().conflicts_with(member.status.lock_mode())
+            {
+                check.result = UpdateResult::BeingModified;
+                check.blocker.get_or_insert(member.xid);
+            } else {
+                check.lockers.push(member);
+            }
+        }
 
-        let mut heap_tuple = HeapTuple::deserialize(tuple_data, self.natts)?;
+        Ok(check)
+    }
 
-        if !heap_tuple.xmax().is_invalid() {
-            return Ok(false);
+    // Result for a tuple whose updater has committed: a new version's ctid,
+    // or Deleted when the chain ends here.
+    fn replaced_result(heap_tuple: &HeapTuple, ctid: ItemPointerData) -> UpdateResult {
+        let next = heap_tuple.header.t_ctid;
+        if next == ctid {
+            UpdateResult::Deleted
+        } else {
+            UpdateResult::Updated(next)
         }
+    }
 
-        heap_tuple.header.t_xmax = xid.0;
-        heap_tuple.header.t_cid = cid.0;
+    // Follows t_ctid links from `ctid` through committed updates to the
+    // newest version of the row, which READ COMMITTED rechecks after a
+    // concurrent update. None when a link no longer leads to the successor.
+    pub fn follow_update_chain(
+        &self,
+        ctid: ItemPointerData,
+    ) -> Result<Option<(ItemPointerData, HeapTuple)>> {
+        let mut current = ctid;
+        let mut prior_xmax = TransactionId::invalid();
+
+        loop {
+            let heap_tuple = match self.get(current)? {
+                Some(heap_tuple) => heap_tuple,
+                None => return Ok(None),
+            };
+
+            if prior_xmax.is_valid() && heap_tuple.xmin() != prior_xmax {
+                return Ok(None);
+            }
 
-        let serialized = heap_tuple.serialize();
-        let tuple_data = page
-            .get_item_mut(ctid.offset_number)
-            .ok_or_else(|| HeapError::InvalidTuple("failed to get item for delete".to_string()))?;
-        tuple_data[..serialized.len()].copy_from_slice(&serialized);
+            let next = heap_tuple.header.t_ctid;
+            let updater = self
+                .xmax_members(&heap_tuple)?
+                .into_iter()
+                .find(|member| member.status.is_update())
+                .filter(|member| self.tx_manager.is_committed(member.xid));
+
+            match updater {
+                Some(member) if next != current => {
+                    prior_xmax = member.xid;
+                    current = next;
+                }
+                _ => return Ok(Some((current, heap_tuple))),
+            }
+        }
+    }
 
-        self.relation.write_page(ctid.block_number, &page)?;
+    // Records `xid` in xmax next to `others`, switching to a multixact when
+    // more than one transaction has to be remembered.
+    fn set_xmax(
+        &self,
+        heap_tuple: &mut HeapTuple,
+        xid: TransactionId,
+        status: MultiXactStatus,
+        mut others: Vec<MultiXactMember>,
+    ) -> Result<()> {
+        others.push(MultiXactMember::new(xid, status));
+        let members = others;
+
+        let strongest =
+            members
+                .iter()
+                .map(|m| m.status.lock_mode())
+                .fold(
+                    LockMode::NoLock,
+                    |a, b| if b.is_stronger_than(a) { b } else { a },
+                );
+
+        let header = &mut heap_tuple.header;
+        header.set_xmax_committed(false);
+        header.set_xmax_invalid(false);
+        header.set_xmax_is_locked_only(!members.iter().any(|m| m.status.is_update()));
+        header.set_xmax_lock_mode(strongest);
+
+        if members.len() == 1 {
+            header.t_xmax = xid.0;
+            header.set_xmax_is_multi(false);
+        } else {
+            let multi = self.tx_manager.multixact().create(&members)?;
+            header.t_xmax = multi.0;
+            header.set_xmax_is_multi(true);
+        }
 
-        Ok(true)
+        Ok(())
+    }
+
+    fn clear_xmax(heap_tuple: &mut HeapTuple) {
+        let header = &mut heap_tuple.header;
+        header.t_xmax = INVALID_TRANSACTION_ID;
+        header.set_xmax_is_multi(false);
+        header.set_xmax_is_locked_only(false);
+        header.set_xmax_lock_mode(LockMode::NoLock);
+        header.set_xmax_invalid(true);
     }
 
     pub fn get(&self, ctid: ItemPointerData) -> Result<Option<HeapTuple>> {
@@ -195,17 +738,79 @@ impl HeapRelation {
         }
     }
 
+    // Fetches the tuple at `ctid` if a dirty snapshot taken by `cur_xid`
+    // sees it, along with whichever writers are still running.
+    pub fn fetch_dirty(
+        &self,
+        ctid: ItemPointerData,
+        cur_xid: TransactionId,
+    ) -> Result<Option<(HeapTuple, DirtyWriters)>> {
+        let mut heap_tuple = match self.get(ctid)? {
+            Some(t) => t,
+            None => return Ok(None),
+        };
+        self.resolve_multi(&mut heap_tuple)?;
+
+        let oracle = self.tx_manager.oracle(cur_xid);
+        Ok(Visibility::heap_tuple_satisfies_dirty(&heap_tuple, &oracle)
+            .map(|writers| (heap_tuple, writers)))
+    }
+
     pub fn scan(
         &self,
         snapshot: &Snapshot,
         cur_xid: TransactionId,
+    ) -> Result<Vec<(ItemPointerData, HeapTuple)>> {
+        let combo_cids = if self.tx_manager.is_in_progress(cur_xid) {
+            self.tx_manager.combo_cids(cur_xid)?
+        } else {
+            ComboCids::new()
+        };
+
+        let oracle = self.tx_manager.oracle(cur_xid);
+        self.scan_where(|heap_tuple| match snapshot.mode {
+            VisibilityMode::Any => Visibility::heap_tuple_satisfies_any(heap_tuple, &oracle),
+            VisibilityMode::Self_ => Visibility::heap_tuple_satisfies_self(
+                heap_tuple,
+                &oracle,
+                snapshot.curcid,
+                &combo_cids,
+            ),
+            VisibilityMode::Dirty => {
+                Visibility::heap_tuple_satisfies_dirty(heap_tuple, &oracle).is_some()
+            }
+            VisibilityMode::Toast => Visibility::heap_tuple_satisfies_toast(heap_tuple),
+            VisibilityMode::NonVacuumable => {
+                Visibility::heap_tuple_satisfies_non_vacuumable(heap_tuple, snapshot.xmin, &oracle)
+            }
+            VisibilityMode::MVCC => {
+                Visibility::heap_tuple_satisfies_mvcc(heap_tuple, snapshot, &oracle, &combo_cids)
+            }
+        })
+    }
+
+    // The table as it stood at `as_of`, which retain_history must cover.
+    pub fn scan_as_of(&self, as_of: AsOf) -> Result<Vec<(ItemPointerData, HeapTuple)>> {
+        let oracle = self.tx_manager.historic_oracle(as_of)?;
+        self.scan_where(|heap_tuple| Visibility::heap_tuple_satisfies_historic(heap_tuple, &oracle))
+    }
+
+    // Every tuple `visible` accepts once hint bits are set and a multixact
+    // xmax is resolved to its updater. Hints found along the way are
+    // written back.
+    fn scan_where(
+        &self,
+        visible: impl Fn(&HeapTuple) -> bool,
     ) -> Result<Vec<(ItemPointerData, HeapTuple)>> {
         let mut results = Vec::new();
 
+        let oracle = self.tx_manager.oracle(TransactionId::invalid());
+        let commit_hints = self.tx_manager.commit_hints_allowed();
         let page_count = self.relation.page_count();
 
         for block_num in 0..page_count {
             let page = self.relation.read_page(block_num)?;
+            let mut hints = Vec::new();
 
             for offset_idx in 0..page.item_count() {
                 let offset = (offset_idx + 1) as u16;
@@ -215,25 +820,18 @@ impl HeapRelation {
                     None => continue,
                 };
 
-                let heap_tuple = match HeapTuple::deserialize(tuple_data, self.natts) {
+                let mut heap_tuple = match HeapTuple::deserialize(tuple_data, self.natts) {
                     Ok(t) => t,
                     Err(_) => continue,
                 };
 
-                let visible = match snapshot.mode {
-                    VisibilityMode::Any => Visibility::heap_tuple_satisfies_any(&heap_tuple),
-                    VisibilityMode::Self_ => {
-                        Visibility::heap_tuple_satisfies_self(&heap_tuple, cur_xid, snapshot.curcid)
-                    }
-                    VisibilityMode::Stable => {
-                        Visibility::heap_tuple_satisfies_stable(&heap_tuple, snapshot)
-                    }
-                    VisibilityMode::MVCC => {
-                        Visibility::heap_tuple_satisfies_mvcc(&heap_tuple, snapshot, cur_xid)
-                    }
-                };
+                if let Some(hint) = Self::hint_update(&heap_tuple, offset, &oracle, commit_hints) {
+                    Visibility::set_hint_bits(&mut heap_tuple, hint.xmin_status, hint.xmax_status);
+                    hints.push(hint);
+                }
+                self.resolve_multi(&mut heap_tuple)?;
 
-                if visible {
+                if visible(&heap_tuple) {
                     results.push((
                         ItemPointerData {
                             block_number: block_num,
@@ -243,41 +841,315 @@ impl HeapRelation {
                     ));
                 }
             }
+
+            if !hints.is_empty() {
+                self.write_hint_bits(block_num, &hints)?;
+            }
         }
 
         Ok(results)
     }
 
+    // The hint bits a scan may set on this tuple now that the oracle knows
+    // how its xmin and xmax ended.
+    fn hint_update(
+        heap_tuple: &HeapTuple,
+        offset: u16,
+        oracle: &dyn TransactionOracle,
+        commit_hints: bool,
+    ) -> Option<HintUpdate> {
+        let header = &heap_tuple.header;
+        let hint = |xid: TransactionId| match oracle.status(xid) {
+            XidStatus::Committed if commit_hints => HeapTupleStatus::Comitted,
+            XidStatus::Aborted => HeapTupleStatus::Aborted,
+            _ => HeapTupleStatus::Unknown,
+        };
+
+        let xmin = heap_tuple.xmin();
+        let xmin_status = if !xmin.is_normal()
+            || header.xmin_frozen()
+            || header.xmin_committed()
+            || header.xmin_invalid()
+        {
+            HeapTupleStatus::Unknown
+        } else {
+            hint(xmin)
+        };
+
+        let xmax = heap_tuple.xmax();
+        let xmax_status = if !xmax.is_normal()
+            || header.xmax_committed()
+            || header.xmax_invalid()
+            || header.xmax_is_multi()
+        {
+            HeapTupleStatus::Unknown
+        } else if header.xmax_is_locked_only() {
+            // A row lock is gone once its holder ends, however it ended.
+            match oracle.status(xmax) {
+                XidStatus::InProgress => HeapTupleStatus::Unknown,
+                _ => HeapTupleStatus::Aborted,
+            }
+        } else {
+            hint(xmax)
+        };
+
+        if xmin_status == HeapTupleStatus::Unknown && xmax_status == HeapTupleStatus::Unknown {
+            return None;
+        }
+
+        Some(HintUpdate {
+            offset,
+            xmin,
+            xmax,
+            xmin_status,
+            xmax_status,
+        })
+    }
+
+    // Hint bits only repeat what the clog says, so setting them is not
+    // WAL-logged. With checksums on, though, a torn write of the page would
+    // fail verification, so the first hint change after a checkpoint logs a
+    // full-page image that redo can restore.
+    fn write_hint_bits(&self, block_num: u32, hints: &[HintUpdate]) -> Result<()> {
+        let _guard = self.relation.lock_content();
+        let mut page = self.relation.read_page(block_num)?;
+
+        let mut changed = false;
+        for hint in hints {
+            let tuple_data = match page.get_item_mut(hint.offset) {
+                Some(data) => data,
+                None => continue,
+            };
+            let mut heap_tuple = match HeapTuple::deserialize(tuple_data, self.natts) {
+                Ok(t) => t,
+                Err(_) => continue,
+            };
+            if heap_tuple.xmin() != hint.xmin || heap_tuple.xmax() != hint.xmax {
+                continue;
+            }
+
+            let infomask = heap_tuple.header.t_infomask;
+            Visibility::set_hint_bits(&mut heap_tuple, hint.xmin_status, hint.xmax_status);
+            if heap_tuple.header.t_infomask != infomask {
+                let serialized = heap_tuple.serialize();
+                tuple_data[..serialized.len()].copy_from_slice(&serialized);
+                changed = true;
+            }
+        }
+
+        if !changed {
+            return Ok(());
+        }
+
+        let _logged = self.tx_manager.log_change();
+        if self.relation.data_checksums() {
+            if let Some(wal) = self.tx_manager.wal() {
+                if self.tx_manager.needs_page_image(page.header.pd_lsn) {
+                    let mut data = self.relation.rel_node.to_le_bytes().to_vec();
+                    data.extend_from_slice(&page.serialize());
+                    let record = XLogRecord::new(0, XLogRecordType::FpiForHint, block_num, data);
+                    page.header.pd_lsn = wal.append(&record)?;
+                }
+            }
+        }
+
+        self.relation.write_page(block_num, &page)
+    }
+
+    // Visibility only cares about the updater of a multixact xmax, so put
+    // its xid in place on the scan's copy of the tuple.
+    fn resolve_multi(&self, heap_tuple: &mut HeapTuple) -> Result<()> {
+        if !heap_tuple.header.xmax_is_multi() || heap_tuple.header.xmax_invalid() {
+            return Ok(());
+        }
+
+        let multi = MultiXactId(heap_tuple.header.t_xmax);
+        let members = self.tx_manager.multixact().get_members(multi)?;
+        if let Some(updater) = members.iter().find(|m| m.status.is_update()) {
+            heap_tuple.header.t_xmax = updater.xid.0;
+            heap_tuple.header.set_xmax_is_multi(false);
+            heap_tuple.header.set_xmax_is_locked_only(false);
+        }
+
+        Ok(())
+    }
+
     pub fn vacuum(&self) -> Result<u32> {
-        let mut removed_count = 0u32;
+        Ok(self.vacuum_with(VacuumOptions::default())?.removed)
+    }
+
+    // A relation with a transaction manager of its own is the whole
+    // database, so the xids and multixacts below its relfrozenxid and
+    // relminmxid can be forgotten.
+    pub fn vacuum_with(&self, options: VacuumOptions) -> Result<VacuumStats> {
+        let stats = self.vacuum_relation(options)?;
+        self.tx_manager.set_oldest_xid(stats.relfrozenxid)?;
+        self.tx_manager.multixact().set_oldest(stats.relminmxid)?;
+        Ok(stats)
+    }
+
+    // Vacuums this relation and advances its relfrozenxid and relminmxid,
+    // leaving the transaction manager's oldest xid and multixact to whoever
+    // knows every relation.
+    pub fn vacuum_relation(&self, options: VacuumOptions) -> Result<VacuumStats> {
+        let oldest_xmin = self.tx_manager.oldest_xmin();
+        let oracle = self.tx_manager.oracle(TransactionId::invalid());
+        let relfrozenxid = self.relation.relfrozenxid();
+
+        let table_age = oldest_xmin.0.wrapping_sub(relfrozenxid.0);
+        let aggressive = options.aggressive || table_age > options.freeze_table_age;
+        let freeze_min_age = if aggressive {
+            0
+        } else {
+            options.freeze_min_age
+        };
+
+        let mut freeze_limit = TransactionId(oldest_xmin.0.wrapping_sub(freeze_min_age));
+        if !freeze_limit.is_normal() {
+            freeze_limit = TransactionId::first_normal();
+        }
+
+        let relminmxid = self.relation.relminmxid();
+        let next_multi = self.tx_manager.multixact().next_multi();
+        let multi_freeze_min_age = if aggressive {
+            0
+        } else {
+            options.multixact_freeze_min_age
+        };
+        let mut multi_cutoff = MultiXactId(next_multi.0.wrapping_sub(multi_freeze_min_age));
+        if !multi_cutoff.is_valid() {
+            multi_cutoff = MultiXactId::first();
+        }
+
+        let mut stats = VacuumStats {
+            removed: 0,
+            frozen: 0,
+            aggressive,
+            relfrozenxid: oldest_xmin,
+            relminmxid: next_multi,
+        };
+
+        let _guard = self.relation.lock_content();
         let page_count = self.relation.page_count();
 
         for block_num in 0..page_count {
             let mut page = self.relation.read_page(block_num)?;
+            let mut block = XLogBlockRef::new(self.relation.rel_node, block_num);
 
             for offset_idx in 0..page.item_count() {
                 let offset = (offset_idx + 1) as u16;
 
-                if let Some(tuple_data) = page.get_item(offset) {
-                    if let Ok(heap_tuple) = HeapTuple::deserialize(tuple_data, self.natts) {
-                        let xmax = heap_tuple.xmax();
-                        if !xmax.is_invalid() {
-                            page.remove_item(offset)?;
-                            removed_count += 1;
+                let mut heap_tuple = match page.get_item(offset) {
+                    Some(tuple_data) => match HeapTuple::deserialize(tuple_data, self.natts) {
+                        Ok(t) => t,
+                        Err(_) => continue,
+                    },
+                    None => continue,
+                };
+
+                let xmin = heap_tuple.xmin();
+                let mut resolved = heap_tuple.clone();
+                self.resolve_multi(&mut resolved)?;
+
+                let mut tuple_modified = false;
+                match Visibility::heap_tuple_satisfies_vacuum(&resolved, oldest_xmin, &oracle) {
+                    HtsvResult::Dead => {
+                        page.remove_item(offset)?;
+                        block.removed.push(offset);
+                        stats.removed += 1;
+                        continue;
+                    }
+                    HtsvResult::RecentlyDead | HtsvResult::DeleteInProgress => {
+                        stats.relfrozenxid = stats.relfrozenxid.older(resolved.xmax());
+                    }
+                    HtsvResult::Live => {
+                        // A plain xmax left on a live tuple was an aborted
+                        // deleter; the tuple no longer needs it.
+                        if !resolved.xmax().is_invalid()
+                            && !resolved.header.xmax_invalid()
+                            && !heap_tuple.header.xmax_is_locked_only()
+                            && !heap_tuple.header.xmax_is_multi()
+                        {
+                            Self::clear_xmax(&mut heap_tuple);
+                            tuple_modified = true;
                         }
                     }
+                    HtsvResult::InsertInProgress => {}
+                }
+
+                if heap_tuple.header.xmax_is_locked_only() {
+                    let mut lockers = self.xmax_members(&heap_tuple)?;
+                    if lockers.is_empty() {
+                        Self::clear_xmax(&mut heap_tuple);
+                        tuple_modified = true;
+                    } else {
+                        for locker in &lockers {
+                            stats.relfrozenxid = stats.relfrozenxid.older(locker.xid);
+                        }
+
+                        if heap_tuple.header.xmax_is_multi() {
+                            // Replace a multixact older than the cutoff with
+                            // one holding just the lockers still running.
+                            if MultiXactId(heap_tuple.header.t_xmax).precedes(multi_cutoff) {
+                                let last = lockers.pop().unwrap();
+                                self.set_xmax(&mut heap_tuple, last.xid, last.status, lockers)?;
+                                tuple_modified = true;
+                            }
+                            if heap_tuple.header.xmax_is_multi() {
+                                let multi = MultiXactId(heap_tuple.header.t_xmax);
+                                stats.relminmxid = stats.relminmxid.older(multi);
+                            }
+                        }
+                    }
+                }
+
+                if !heap_tuple.header.xmin_frozen() && xmin.is_normal() {
+                    if xmin.precedes(freeze_limit) && self.tx_manager.is_committed(xmin) {
+                        heap_tuple.header.set_xmin_frozen(true);
+                        stats.frozen += 1;
+                        tuple_modified = true;
+                    } else {
+                        stats.relfrozenxid = stats.relfrozenxid.older(xmin);
+                    }
+                }
+
+                if tuple_modified {
+                    let serialized = heap_tuple.serialize();
+                    if let Some(tuple_data) = page.get_item_mut(offset) {
+                        tuple_data[..serialized.len()].copy_from_slice(&serialized);
+                    }
+                    block.tuples.push((offset, serialized));
                 }
             }
 
-            if removed_count > 0 {
+            if !block.tuples.is_empty() || !block.removed.is_empty() {
+                let _logged = self.log_heap(
+                    TransactionId::invalid(),
+                    XLogRecordType::HeapVacuum,
+                    vec![block],
+                    &mut [&mut page],
+                )?;
                 self.relation.write_page(block_num, &page)?;
             }
         }
 
-        Ok(removed_count)
+        if !stats.relfrozenxid.follows(relfrozenxid) {
+            stats.relfrozenxid = relfrozenxid;
+        }
+        if !stats.relminmxid.follows(relminmxid) {
+            stats.relminmxid = relminmxid;
+        }
+
+        if stats.relfrozenxid != relfrozenxid || stats.relminmxid != relminmxid {
+            self.relation
+                .set_frozen_ids(stats.relfrozenxid, stats.relminmxid)?;
+        }
+
+        Ok(stats)
     }
 
     pub fn close(&self) -> Result<()> {
+        self.tx_manager.flush()?;
         self.relation.close()
     }
 
@@ -287,7 +1159,7 @@ impl HeapRelation {
 }
 
 pub struct HeapEngine {
-    pub heap: HeapRelation,
+    pub heap: Arc<HeapRelation>,
     pub current_tx: Option<Transaction>,
 }
 
@@ -297,7 +1169,7 @@ impl HeapEngine {
 
         Ok((
             Self {
-                heap,
+                heap: Arc::new(heap),
                 current_tx: None,
             },
             rel_node,
@@ -308,36 +1180,59 @@ impl HeapEngine {
         let heap = HeapRelation::open(path, natts)?;
 
         Ok(Self {
-            heap,
+            heap: Arc::new(heap),
             current_tx: None,
         })
     }
 
-    pub fn begin(&mut self) -> Transaction {
-        let tx = Transaction::new(self.heap.tx_manager.clone());
+    pub fn begin(&mut self) -> Result<Transaction> {
+        self.begin_with(IsolationLevel::ReadCommitted)
+    }
+
+    pub fn begin_with(&mut self, isolation: IsolationLevel) -> Result<Transaction> {
+        let tx = Transaction::with_isolation(self.heap.tx_manager.clone(), isolation)?;
         self.current_tx = Some(tx.clone());
-        tx
+        Ok(tx)
     }
 
-    pub fn commit(&mut self) {
-        if let Some(tx) = self.current_tx.take() {
-            tx.commit();
+    pub fn commit(&mut self) -> Result<()> {
+        match self.current_tx.take() {
+            Some(tx) => tx.commit(),
+            None => Ok(()),
         }
     }
 
-    pub fn abort(&mut self) {
-        if let Some(tx) = self.current_tx.take() {
-            tx.abort();
+    pub fn abort(&mut self) -> Result<()> {
+        match self.current_tx.take() {
+            Some(tx) => tx.abort(),
+            None => Ok(()),
         }
     }
 
+    pub fn set_synchronous_commit(&self, on: bool) -> Result<()> {
+        self.active_transaction()?.set_synchronous_commit(on);
+        Ok(())
+    }
+
+    pub fn export_snapshot(&self) -> Result<String> {
+        self.active_transaction()?.export_snapshot()
+    }
+
+    pub fn import_snapshot(&self, id: &str) -> Result<()> {
+        self.active_transaction()?.import_snapshot(id)
+    }
+
     pub fn insert(&self, data: &[u8]) -> Result<ItemPointerData> {
         let tx = self
             .current_tx
             .as_ref()
             .ok_or_else(|| HeapError::InvalidTransaction("no active transaction".to_string()))?;
 
-        self.heap.insert(tx.xid(), tx.get_cid(), data)
+        self.lock_relation(tx, TableLockMode::RowExclusive)?;
+        self.check_conflict_in(tx, PredicateLockTag::Relation(self.rel_node()))?;
+        let ctid = self.heap.insert(tx.current_xid(), tx.get_cid(), data)?;
+        tx.command_counter_increment()?;
+        Ok(ctid)
     }
 
     pub fn update(
@@ -350,7 +1245,31 @@ impl HeapEngine {
             .as_ref()
             .ok_or_else(|| HeapError::InvalidTransaction("no active transaction".to_string()))?;
 
-        self.heap.update(tx.xid(), tx.get_cid(), ctid, new_data)
+        self.lock_relation(tx, TableLockMode::RowExclusive)?;
+
+        let mut target = ctid;
+        let new_ctid = loop {
+            self.check_conflict_in(tx, PredicateLockTag::Tuple(self.rel_node(), target))?;
+            match self
+                .heap
+                .update(tx.current_xid(), tx.get_cid(), target, new_data, true)?
+            {
+                (UpdateResult::Ok, new_ctid) => break new_ctid,
+                (result @ (UpdateResult::Updated(_) | UpdateResult::Deleted), _)
+                    if tx.isolation.uses_transaction_snapshot() =>
+                {
+                    return Err(Self::concurrent_update(result));
+                }
+                (UpdateResult::Updated(_), _) => match self.latest_version(target)? {
+                    Some(latest) => target = latest,
+                    None => break None,
+                },
+                _ => break None,
+            }
+        };
+
+        tx.command_counter_increment()?;
+        Ok(new_ctid)
     }
 
     pub fn delete(&self, ctid: ItemPointerData) -> Result<bool> {
@@ -359,33 +1278,276 @@ impl HeapEngine {
             .as_ref()
             .ok_or_else(|| HeapError::InvalidTransaction("no active transaction".to_string()))?;
 
-        self.heap.delete(tx.xid(), tx.get_cid(), ctid)
+        self.lock_relation(tx, TableLockMode::RowExclusive)?;
+
+        let mut target = ctid;
+        let deleted = loop {
+            self.check_conflict_in(tx, PredicateLockTag::Tuple(self.rel_node(), target))?;
+            match self
+                .heap
+                .delete(tx.current_xid(), tx.get_cid(), target, true)?
+            {
+                UpdateResult::Ok => break true,
+                result @ (UpdateResult::Updated(_) | UpdateResult::Deleted)
+                    if tx.isolation.uses_transaction_snapshot() =>
+                {
+                    return Err(Self::concurrent_update(result));
+                }
+                UpdateResult::Updated(_) => match self.latest_version(target)? {
+                    Some(latest) => target = latest,
+                    None => break false,
+                },
+                _ => break false,
+            }
+        };
+
+        tx.command_counter_increment()?;
+        Ok(deleted)
+    }
+
+    // READ COMMITTED: a row replaced by a committed update is retried on its
+    // newest version.
+    fn latest_version(&self, ctid: ItemPointerData) -> Result<Option<ItemPointerData>> {
+        Ok(self
+            .heap
+            .follow_update_chain(ctid)?
+            .map(|(latest, _)| latest)
+            .filter(|&latest| latest != ctid))
+    }
+
+    // REPEATABLE READ and SERIALIZABLE cannot move on to a row version
+    // their snapshot does not see.
+    fn concurrent_update(result: UpdateResult) -> HeapError {
+        let action = match result {
+            UpdateResult::Deleted => "delete",
+            _ => "update",
+        };
+        HeapError::SerializationFailure(format!(
+            "could not serialize access due to concurrent {}",
+            action
+        ))
+    }
+
+    fn rel_node(&self) -> u32 {
+        self.heap.relation.rel_node
+    }
+
+    fn check_conflict_in(&self, tx: &Transaction, tag: PredicateLockTag) -> Result<()> {
+        self.heap
+            .tx_manager
+            .predicate_locks()
+            .check_conflict_in(tx.xid(), tag)
+    }
+
+    // A SERIALIZABLE read takes a predicate lock on what it covers and
+    // records an rw-conflict with every concurrent writer of the tuples it
+    // read whose changes its snapshot does not include.
+    fn predicate_read<'a>(
+        &self,
+        tx: &Transaction,
+        tag: PredicateLockTag,
+        tuples: impl IntoIterator<Item = &'a HeapTuple>,
+    ) -> Result<()> {
+        if tx.isolation != IsolationLevel::Serializable {
+            return Ok(());
+        }
+
+        let predicate_locks = self.heap.tx_manager.predicate_locks();
+        predicate_locks.lock(tx.xid(), tag)?;
+
+        let snapshot = tx.snapshot();
+        for heap_tuple in tuples {
+            let header = &heap_tuple.header;
+            let mut writers = Vec::new();
+            if !header.xmin_frozen() {
+                writers.push(heap_tuple.xmin());
+            }
+            if !header.xmax_invalid() && !header.xmax_is_locked_only() && !header.xmax_is_multi() {
+                writers.push(heap_tuple.xmax());
+            }
+
+            for writer in writers {
+                if !writer.is_normal() || writer.precedes(snapshot.xmin) {
+                    continue;
+                }
+                let writer = self.heap.tx_manager.get_topmost(writer)?;
+                if writer != tx.xid() {
+                    predicate_locks.check_conflict_out(tx.xid(), writer)?;
+                }
+            }
+        }
+
+        Ok(())
     }
 
     pub fn get(&self, ctid: ItemPointerData) -> Result<Option<HeapTuple>> {
-        self.heap.get(ctid)
+        self.with_relation_lock(TableLockMode::AccessShare, |tx| {
+            let heap_tuple = self.heap.get(ctid)?;
+            if let Some(tx) = tx {
+                self.predicate_read(
+                    tx,
+                    PredicateLockTag::Tuple(self.rel_node(), ctid),
+                    heap_tuple.as_ref(),
+                )?;
+            }
+            Ok(heap_tuple)
+        })
+    }
+
+    pub fn lock_tuple(
+        &self,
+        ctid: ItemPointerData,
+        mode: LockMode,
+        wait_policy: WaitPolicy,
+    ) -> Result<UpdateResult> {
+        let tx = self.active_transaction()?;
+        self.lock_relation(tx, TableLockMode::RowShare)?;
+        match self
+            .heap
+            .lock_tuple(tx.current_xid(), ctid, mode, wait_policy)?
+        {
+            result @ (UpdateResult::Updated(_) | UpdateResult::Deleted)
+                if tx.isolation.uses_transaction_snapshot() =>
+            {
+                Err(Self::concurrent_update(result))
+            }
+            result => Ok(result),
+        }
+    }
+
+    // LOCK TABLE: holds `mode` on the relation until the transaction ends.
+    pub fn lock_table(&self, mode: TableLockMode, wait_policy: WaitPolicy) -> Result<bool> {
+        let tx = self.active_transaction()?;
+        self.heap.tx_manager.lock(
+            tx.xid(),
+            LockTag::Relation(self.rel_node()),
+            mode,
+            wait_policy,
+        )
+    }
+
+    pub fn set_lock_timeout(&self, timeout: Option<Duration>) {
+        self.heap.tx_manager.set_lock_timeout(timeout);
+    }
+
+    pub fn prepare(&mut self, gid: &str) -> Result<()> {
+        match self.current_tx.take() {
+            Some(tx) => tx.prepare(gid),
+            None => Err(HeapError::InvalidTransaction(
+                "no active transaction".to_string(),
+            )),
+        }
+    }
+
+    pub fn commit_prepared(&self, gid: &str) -> Result<()> {
+        self.heap.tx_manager.commit_prepared(gid)
+    }
+
+    pub fn rollback_prepared(&self, gid: &str) -> Result<()> {
+        self.heap.tx_manager.rollback_prepared(gid)
+    }
+
+    pub fn prepared_transactions(&self) -> Vec<PreparedTransaction> {
+        self.heap.tx_manager.prepared_transactions()
+    }
+
+    pub fn savepoint(&self, name: &str) -> Result<TransactionId> {
+        self.active_transaction()?.savepoint(name)
+    }
+
+    pub fn rollback_to(&self, name: &str) -> Result<TransactionId> {
+        self.active_transaction()?.rollback_to(name)
+    }
+
+    pub fn release(&self, name: &str) -> Result<()> {
+        self.active_transaction()?.release(name)
+    }
+
+    fn active_transaction(&self) -> Result<&Transaction> {
+        self.current_tx
+            .as_ref()
+            .ok_or_else(|| HeapError::InvalidTransaction("no active transaction".to_string()))
+    }
+
+    fn lock_relation(&self, tx: &Transaction, mode: TableLockMode) -> Result<()> {
+        self.heap.tx_manager.lock(
+            tx.xid(),
+            LockTag::Relation(self.rel_node()),
+            mode,
+            WaitPolicy::Block,
+        )?;
+        Ok(())
+    }
+
+    // Locks the relation for a statement outside a transaction block, which
+    // gets no xid and writes no commit record.
+    fn virtual_transaction(&self, mode: TableLockMode) -> Result<VirtualTransaction> {
+        let vxact = VirtualTransaction::new(self.heap.tx_manager.clone());
+        vxact.lock(LockTag::Relation(self.rel_node()), mode, WaitPolicy::Block)?;
+        Ok(vxact)
+    }
+
+    // Runs `f` holding `mode` on the relation, inside the active transaction
+    // or, without one, under a virtual transaction that ends with `f`.
+    fn with_relation_lock<T>(
+        &self,
+        mode: TableLockMode,
+        f: impl FnOnce(Option<&Transaction>) -> Result<T>,
+    ) -> Result<T> {
+        if let Some(ref tx) = self.current_tx {
+            self.lock_relation(tx, mode)?;
+            return f(Some(tx));
+        }
+
+        let _vxact = self.virtual_transaction(mode)?;
+        f(None)
     }
 
     pub fn scan(&self) -> Result<Vec<(ItemPointerData, HeapTuple)>> {
-        let tx_manager = self.heap.tx_manager.clone();
-        let current_cid = if let Some(ref tx) = self.current_tx {
-            tx.get_cid()
-        } else {
-            CommandId::invalid()
+        let Some(ref tx) = self.current_tx else {
+            let vxact = self.virtual_transaction(TableLockMode::AccessShare)?;
+            return self.heap.scan(&vxact.snapshot(), TransactionId::invalid());
         };
 
-        let snapshot = tx_manager.get_snapshot(current_cid);
-        let cur_xid = if let Some(ref tx) = self.current_tx {
-            tx.xid()
-        } else {
-            TransactionId::first_normal()
-        };
+        self.lock_relation(tx, TableLockMode::AccessShare)?;
+        let rows = self.heap.scan(&tx.snapshot(), tx.xid())?;
+        self.predicate_read(
+            tx,
+            PredicateLockTag::Relation(self.rel_node()),
+            rows.iter().map(|(_, heap_tuple)| heap_tuple),
+        )?;
+        Ok(rows)
+    }
 
-        self.heap.scan(&snapshot, cur_xid)
+    pub fn scan_as_of(&self, as_of: AsOf) -> Result<Vec<(ItemPointerData, HeapTuple)>> {
+        self.with_relation_lock(TableLockMode::AccessShare, |_| self.heap.scan_as_of(as_of))
     }
 
     pub fn vacuum(&self) -> Result<u32> {
-        self.heap.vacuum()
+        Ok(self.vacuum_with(VacuumOptions::default())?.removed)
+    }
+
+    pub fn vacuum_with(&self, options: VacuumOptions) -> Result<VacuumStats> {
+        self.check_vacuum()?;
+        self.with_relation_lock(TableLockMode::ShareUpdateExclusive, |_| {
+            self.heap.vacuum_with(options)
+        })
+    }
+
+    pub fn vacuum_relation(&self, options: VacuumOptions) -> Result<VacuumStats> {
+        self.check_vacuum()?;
+        self.with_relation_lock(TableLockMode::ShareUpdateExclusive, |_| {
+            self.heap.vacuum_relation(options)
+        })
+    }
+
+    fn check_vacuum(&self) -> Result<()> {
+        if self.current_tx.is_some() {
+            return Err(HeapError::InvalidOperation(
+                "VACUUM cannot run inside a transaction block".to_string(),
+            ));
+        }
+        Ok(())
     }
 
     pub fn close(&self) -> Result<()> {
@@ -393,6 +1555,8 @@ impl HeapEngine {
     }
 
     pub fn drop(&self) -> Result<()> {
-        self.heap.drop()
+        self.with_relation_lock(TableLockMode::AccessExclusive, |_| {
+            HeapRelation::drop(&self.heap)
+        })
     }
 }
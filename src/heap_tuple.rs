@@ -1,8 +1,8 @@
+use crate::combocid::ComboCids;
 use crate::constants::*;
 use crate::error::{HeapError, Result};
 use crate::types::*;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use std::fmt;
 
 #[derive(Debug, Clone)]
 pub struct HeapTupleHeaderData {
@@ -29,10 +29,10 @@ impl HeapTupleHeaderData {
         }
     }
 
-    pub fn compute_hoff(natts: u16, has_null: bool, has_varlena: bool) -> u8 {
+    pub fn compute_hoff(natts: u16, has_null: bool, _has_varlena: bool) -> u8 {
         let mut off: usize = HEAP_FIXED_HEADER_SIZE;
         if has_null {
-            off += (natts as usize + 7) / 8;
+            off += (natts as usize).div_ceil(8);
         }
         off = (off + 7) & !7;
         off as u8
@@ -74,6 +74,19 @@ impl HeapTupleHeaderData {
         }
     }
 
+    pub fn xmin_frozen(&self) -> bool {
+        (self.t_infomask & HEAP_TUPLE_FROZEN) != 0
+    }
+
+    pub fn set_xmin_frozen(&mut self, frozen: bool) {
+        if frozen {
+            self.t_infomask |= HEAP_TUPLE_FROZEN | HEAP_XMIN_COMMITTED;
+            self.t_infomask &= !HEAP_XMIN_INVALID;
+        } else {
+            self.t_infomask &= !HEAP_TUPLE_FROZEN;
+        }
+    }
+
     pub fn xmax_committed(&self) -> bool {
         (self.t_infomask & HEAP_XMAX_COMMITTED) != 0
     }
@@ -110,6 +123,61 @@ impl HeapTupleHeaderData {
         }
     }
 
+    pub fn xmax_is_multi(&self) -> bool {
+        (self.t_infomask & HEAP_XMAX_IS_MULTI) != 0
+    }
+
+    pub fn set_xmax_is_multi(&mut self, multi: bool) {
+        if multi {
+            self.t_infomask |= HEAP_XMAX_IS_MULTI;
+        } else {
+            self.t_infomask &= !HEAP_XMAX_IS_MULTI;
+        }
+    }
+
+    pub fn is_combo_cid(&self) -> bool {
+        (self.t_infomask & HEAP_COMBOCID) != 0
+    }
+
+    pub fn set_combo_cid(&mut self, combo: bool) {
+        if combo {
+            self.t_infomask |= HEAP_COMBOCID;
+        } else {
+            self.t_infomask &= !HEAP_COMBOCID;
+        }
+    }
+
+    // Strength of the lock (or update) recorded in xmax.
+    pub fn xmax_lock_mode(&self) -> LockMode {
+        if !self.xmax_is_locked_only() || self.t_infomask & HEAP_LOCK_MASK == HEAP_XMAX_EXCL_LOCK {
+            if self.keys_updated() {
+                return LockMode::ForUpdate;
+            }
+            return LockMode::ForNoKeyUpdate;
+        }
+
+        match self.t_infomask & HEAP_LOCK_MASK {
+            HEAP_XMAX_SHR_LOCK => LockMode::ForShare,
+            HEAP_XMAX_KEYSHR_LOCK => LockMode::ForKeyShare,
+            _ => LockMode::NoLock,
+        }
+    }
+
+    pub fn set_xmax_lock_mode(&mut self, mode: LockMode) {
+        self.t_infomask &= !HEAP_LOCK_MASK;
+        self.set_keys_updated(false);
+        match mode {
+            LockMode::ForUpdate => {
+                self.t_infomask |= HEAP_XMAX_EXCL_LOCK;
+                self.set_keys_updated(true);
+            }
+            LockMode::ForNoKeyUpdate => self.t_infomask |= HEAP_XMAX_EXCL_LOCK,
+            LockMode::ForShare => self.t_infomask |= HEAP_XMAX_SHR_LOCK,
+            LockMode::ForKeyShare => self.t_infomask |= HEAP_XMAX_KEYSHR_LOCK,
+            LockMode::NoLock => {}
+        }
+    }
+
     pub fn keys_updated(&self) -> bool {
         (self.t_infomask2 & HEAP_KEYS_UPDATED) != 0
     }
@@ -182,11 +250,11 @@ pub struct HeapTuple {
 
 impl HeapTuple {
     pub fn new(natts: u16) -> Self {
-        let mut header = HeapTupleHeaderData::new(natts);
+        let header = HeapTupleHeaderData::new(natts);
         let has_null = false;
 
         let null_bitmap = if has_null && natts > 0 {
-            Some(vec![0u8; (natts as usize + 7) / 8])
+            Some(vec![0u8; (natts as usize).div_ceil(8)])
         } else {
             None
         };
@@ -208,7 +276,7 @@ impl HeapTuple {
         }
 
         let null_bitmap = if has_null && natts > 0 {
-            Some(vec![0u8; (natts as usize + 7) / 8])
+            Some(vec![0u8; (natts as usize).div_ceil(8)])
         } else {
             None
         };
@@ -283,7 +351,7 @@ impl HeapTuple {
 
         let has_null = header.has_null();
         let null_bitmap_size = if has_null {
-            (natts as usize + 7) / 8
+            (natts as usize).div_ceil(8)
         } else {
             0
         };
@@ -342,6 +410,25 @@ impl HeapTuple {
 
     pub fn set_cid(&mut self, cid: CommandId) {
         self.header.t_cid = cid.0;
+        self.header.set_combo_cid(false);
+    }
+
+    // Only meaningful to the transaction that inserted the tuple.
+    pub fn cmin(&self, combo_cids: &ComboCids) -> CommandId {
+        if self.header.is_combo_cid() {
+            combo_cids.cmin(self.cid())
+        } else {
+            self.cid()
+        }
+    }
+
+    // Only meaningful to the transaction that deleted the tuple.
+    pub fn cmax(&self, combo_cids: &ComboCids) -> CommandId {
+        if self.header.is_combo_cid() {
+            combo_cids.cmax(self.cid())
+        } else {
+            self.cid()
+        }
     }
 
     pub fn ctid(&self) -> ItemPointerData {
@@ -359,6 +446,6 @@ impl Default for HeapTuple {
     }
 }
 
-pub fn heap_tuple_get_struct(heap_tuple: &HeapTuple, natts: u16) -> Result<HeapTupleHeaderData> {
+pub fn heap_tuple_get_struct(heap_tuple: &HeapTuple, _natts: u16) -> Result<HeapTupleHeaderData> {
     Ok(heap_tuple.header.clone())
 }
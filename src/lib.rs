@@ -1,29 +1,62 @@
 pub mod btree;
+pub mod buffer;
+pub mod clog;
+pub mod combocid;
+pub mod commit_ts;
 pub mod constants;
+pub mod control;
+pub mod csnlog;
+pub mod database;
 pub mod error;
+pub mod failpoint;
 pub mod fsm;
 pub mod heap;
 pub mod heap_tuple;
+pub mod lock;
+pub mod multixact;
 pub mod page;
+pub mod predicate;
+pub mod procarray;
+pub mod recovery;
 pub mod relation;
+pub mod slot;
+pub mod slru;
 pub mod storage;
+pub mod subtrans;
 pub mod toast;
 pub mod transaction;
+pub mod twophase;
 pub mod types;
 pub mod visibility;
 pub mod visibility_map;
 pub mod wal;
 
 pub use btree::*;
+pub use buffer::*;
+pub use clog::*;
+pub use combocid::*;
+pub use commit_ts::*;
+pub use control::*;
+pub use csnlog::*;
+pub use database::*;
 pub use error::HeapError;
 pub use fsm::*;
 pub use heap::*;
 pub use heap_tuple::*;
+pub use lock::*;
+pub use multixact::*;
 pub use page::*;
+pub use predicate::*;
+pub use procarray::*;
+pub use recovery::*;
 pub use relation::*;
+pub use slot::*;
+pub use slru::*;
 pub use storage::*;
+pub use subtrans::*;
 pub use toast::*;
 pub use transaction::*;
+pub use twophase::*;
 pub use types::*;
 pub use visibility::*;
 pub use visibility_map::*;
@@ -35,21 +68,41 @@ mod tests {
     use tempfile::TempDir;
 
     use super::btree::BTreeIndex;
+    use super::clog::CommitLog;
+    use super::combocid::ComboCids;
     use super::constants::*;
-    use super::error::Result;
+    use super::control::ControlFile;
+    use super::database::{Database, Session};
+    use super::error::HeapError;
+    use super::failpoint;
     use super::fsm::FreeSpaceMap;
-    use super::heap::{HeapEngine, HeapRelation};
+    use super::heap::{HeapEngine, HeapRelation, VacuumOptions};
     use super::heap_tuple::{HeapTuple, HeapTupleHeaderData};
+    use super::lock::{LockManager, LockOwner, LockTag, TableLockMode};
     use super::page::{ItemIdData, Page};
+    use super::predicate::{PredicateLockManager, PredicateLockTag};
+    use super::procarray::{XminHolder, XminHorizon};
+    use super::recovery::Recovery;
     use super::relation::Relation;
+    use super::slru::Slru;
     use super::storage::Storage;
     use super::toast::ToastTable;
-    use super::transaction::{Transaction, TransactionManager};
+    use super::transaction::{
+        Transaction, TransactionManager, VirtualTransaction, XidLimitStatus, XidLimits,
+    };
     use super::types::*;
     use super::visibility::Visibility;
     use super::visibility_map::VisibilityMap;
     use super::wal::{XLogRecord, XLogRecordType, WAL};
 
+    // A crash: nothing `owner` would still write on its way down reaches
+    // disk, and its data directory is free to open again.
+    fn crash<T>(dir: &std::path::Path, owner: T) {
+        failpoint::kill_after(dir, 0);
+        drop(owner);
+        failpoint::revive(dir);
+    }
+
     #[test]
     fn test_page_creation() {
         let page = Page::new(8192);
@@ -145,7 +198,7 @@ mod tests {
         heap_tuple.header.t_xmax = 0;
 
         let serialized = heap_tuple.serialize();
-        assert!(serialized.len() > 0);
+        assert!(!serialized.is_empty());
 
         let restored = HeapTuple::deserialize(&serialized, 2).unwrap();
         assert_eq!(restored.header.t_xmin, 100);
@@ -167,13 +220,13 @@ mod tests {
     fn test_transaction_manager() {
         let manager = TransactionManager::new();
 
-        let xid1 = manager.begin();
+        let xid1 = manager.begin().unwrap();
         assert!(xid1.is_valid());
 
         let cid1 = manager.get_cid();
         assert!(cid1.0 > 0);
 
-        manager.commit(xid1);
+        manager.commit(xid1).unwrap();
         assert!(manager.is_committed(xid1));
     }
 
@@ -181,24 +234,395 @@ mod tests {
     fn test_transaction() {
         let manager = Arc::new(TransactionManager::new());
 
-        let xid = manager.begin();
+        let xid = manager.begin().unwrap();
         assert!(xid.is_valid());
 
-        manager.commit(xid);
+        manager.commit(xid).unwrap();
 
         assert!(manager.is_committed(xid));
     }
 
+    #[test]
+    fn test_slru_write_back_waits_for_wal() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().to_path_buf();
+        let wal = Arc::new(WAL::new(path.clone()).unwrap());
+        let slru = Slru::open(path.join("pg_test"), 1)
+            .unwrap()
+            .with_wal(Some(wal.clone()));
+
+        // Evicting a page flushes the WAL up to its newest record first.
+        let record = XLogRecord::new(1, XLogRecordType::TransactionCommit, 0, vec![0; 8]);
+        let lsn = wal.append(&record).unwrap();
+        slru.zero_page(0).unwrap();
+        slru.write_logged(0, lsn, |page| page[0] = 1).unwrap();
+        assert!(wal.flushed_lsn() < lsn);
+        slru.read(1, |_| ()).unwrap();
+        assert!(wal.flushed_lsn() >= lsn);
+
+        // And so does a flush of the whole cache.
+        let lsn = wal.append(&record).unwrap();
+        slru.write_logged(1, lsn, |page| page[0] = 1).unwrap();
+        slru.write(1, |page| page[1] = 1).unwrap();
+        assert!(wal.flushed_lsn() < lsn);
+        slru.flush().unwrap();
+        assert!(wal.flushed_lsn() >= lsn);
+    }
+
+    #[test]
+    fn test_commit_log_survives_reopen() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().to_path_buf();
+
+        let (committed, aborted, running) = {
+            let manager = TransactionManager::open(path.clone()).unwrap();
+            let committed = manager.begin().unwrap();
+            let aborted = manager.begin().unwrap();
+            let running = manager.begin().unwrap();
+            manager.commit(committed).unwrap();
+            manager.abort(aborted).unwrap();
+            // A crash: the clog pages never reach disk, only the WAL.
+            crash(&path, manager);
+            (committed, aborted, running)
+        };
+
+        let manager = TransactionManager::open(path).unwrap();
+        assert!(manager.is_committed(committed));
+        assert_eq!(manager.status(committed).unwrap(), XidStatus::Committed);
+        assert_eq!(manager.status(aborted).unwrap(), XidStatus::Aborted);
+        assert_eq!(manager.status(running).unwrap(), XidStatus::InProgress);
+        assert!(!manager.is_in_progress(running));
+
+        let next = manager.begin().unwrap();
+        assert!(next.0 > running.0);
+    }
+
+    #[test]
+    fn test_transaction_handle_commit() {
+        let manager = Arc::new(TransactionManager::new());
+
+        let tx = Transaction::new(manager.clone()).unwrap();
+        let xid = tx.xid();
+        let handle = tx.clone();
+        tx.commit().unwrap();
+        drop(handle);
+        assert_eq!(manager.status(xid).unwrap(), XidStatus::Committed);
+
+        let tx = Transaction::new(manager.clone()).unwrap();
+        let xid = tx.xid();
+        drop(tx);
+        assert_eq!(manager.status(xid).unwrap(), XidStatus::Aborted);
+    }
+
+    #[test]
+    fn test_commit_log_buffer_eviction() {
+        let temp_dir = TempDir::new().unwrap();
+        let clog = CommitLog::open(temp_dir.path().to_path_buf(), None).unwrap();
+
+        let xids: Vec<TransactionId> = (0..NUM_CLOG_BUFFERS as u32 * 2)
+            .map(|page| TransactionId(page * CLOG_XACTS_PER_PAGE + 7))
+            .collect();
+
+        for (i, &xid) in xids.iter().enumerate() {
+            clog.extend(xid).unwrap();
+            let status = if i % 2 == 0 {
+                XidStatus::Committed
+            } else {
+                XidStatus::Aborted
+            };
+            clog.set_status(xid, status, 0).unwrap();
+        }
+
+        for (i, &xid) in xids.iter().enumerate() {
+            let expected = if i % 2 == 0 {
+                XidStatus::Committed
+            } else {
+                XidStatus::Aborted
+            };
+            assert_eq!(clog.get_status(xid).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_xid_comparison_wraps() {
+        let old = TransactionId(u32::MAX - 10);
+        let new = TransactionId(5);
+        assert!(old.precedes(new));
+        assert!(new.follows(old));
+        assert_eq!(old.older(new), old);
+        assert!(TransactionId::bootstrap().precedes(old));
+        assert_eq!(
+            TransactionId(u32::MAX).next(),
+            TransactionId::first_normal()
+        );
+
+        let full = FullTransactionId::new(0, TransactionId(u32::MAX)).next();
+        assert_eq!(full.epoch(), 1);
+        assert_eq!(full.xid(), TransactionId::first_normal());
+        assert_eq!(
+            FullTransactionId::from_relative(full, old),
+            FullTransactionId::new(0, old)
+        );
+    }
+
+    #[test]
+    fn test_xid_wraparound_limits() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().to_path_buf();
+
+        let limits = XidLimits::new(TransactionId(1000));
+        ControlFile::open(path.clone())
+            .unwrap()
+            .update(|data| {
+                data.oldest_xid = 1000;
+                data.next_full_xid = FullTransactionId::new(3, limits.stop_limit).0 - 2;
+            })
+            .unwrap();
+
+        let manager = TransactionManager::open(path.clone()).unwrap();
+        assert!(matches!(
+            manager.xid_limit_status(),
+            XidLimitStatus::Warning { .. }
+        ));
+
+        let xid = manager.begin().unwrap();
+        manager.commit(xid).unwrap();
+        let last = manager.begin().unwrap();
+        assert_eq!(manager.xid_limit_status(), XidLimitStatus::Stopped);
+        assert!(matches!(manager.begin(), Err(HeapError::XidWraparound(_))));
+
+        let snapshot = manager.get_snapshot(CommandId(1));
+        assert_eq!(snapshot.full_xmax.epoch(), 3);
+        assert_eq!(snapshot.xmin, last);
+
+        manager.set_oldest_xid(limits.stop_limit).unwrap();
+        assert_eq!(manager.xid_limit_status(), XidLimitStatus::Ok);
+        let next = manager.begin().unwrap();
+        assert!(next.follows(last));
+
+        drop(manager);
+        let manager = TransactionManager::open(path).unwrap();
+        assert_eq!(manager.xid_limits().oldest_xid, limits.stop_limit);
+        assert!(manager.next_full_xid() > FullTransactionId::new(3, next));
+    }
+
     #[test]
     fn test_snapshot() {
         let manager = Arc::new(TransactionManager::new());
 
-        manager.begin();
+        manager.begin().unwrap();
 
         let snapshot = manager.get_snapshot(CommandId(1));
         assert!(snapshot.xmin.is_valid());
     }
 
+    #[test]
+    fn test_subtransaction_snapshot() {
+        let manager = Arc::new(TransactionManager::new());
+
+        let top = manager.begin().unwrap();
+        let sub = manager.begin_subtransaction(top).unwrap();
+        assert_eq!(manager.get_parent(sub).unwrap(), top);
+        assert_eq!(manager.get_topmost(sub).unwrap(), top);
+        assert!(manager.is_current_transaction(top, sub));
+
+        let snapshot = manager.get_snapshot(CommandId(1));
+        let oracle = manager.oracle(TransactionId::invalid());
+        assert!(Visibility::xid_in_snapshot(top, &snapshot, &oracle));
+        assert!(Visibility::xid_in_snapshot(sub, &snapshot, &oracle));
+
+        manager.commit(top).unwrap();
+        assert_eq!(manager.status(sub).unwrap(), XidStatus::Committed);
+        assert!(!manager.is_in_progress(sub));
+    }
+
+    #[test]
+    fn test_csn_snapshots() {
+        let manager = Arc::new(TransactionManager::new());
+        let oracle = manager.oracle(TransactionId::invalid());
+
+        let first = manager.begin().unwrap();
+        let second = manager.begin().unwrap();
+        let sub = manager.begin_subtransaction(second).unwrap();
+        let aborted = manager.begin().unwrap();
+
+        let before = manager.get_snapshot(CommandId(1));
+        assert!(before.xip.is_empty());
+        assert_eq!(before.xmin, first);
+
+        manager.commit(second).unwrap();
+        manager.abort(aborted).unwrap();
+        manager.commit(first).unwrap();
+
+        // Commits after the snapshot stay invisible to it.
+        for xid in [first, second, sub, aborted] {
+            assert!(Visibility::xid_in_snapshot(xid, &before, &oracle));
+        }
+
+        let after = manager.get_snapshot(CommandId(1));
+        assert!(!Visibility::xid_in_snapshot(first, &after, &oracle));
+        assert!(!Visibility::xid_in_snapshot(second, &after, &oracle));
+        assert!(!Visibility::xid_in_snapshot(sub, &after, &oracle));
+
+        assert!(manager.commit_csn(second) < manager.commit_csn(first));
+        assert_eq!(manager.commit_csn(sub), manager.commit_csn(second));
+        assert!(!manager.commit_csn(aborted).is_valid());
+    }
+
+    #[test]
+    fn test_csn_log_trim() {
+        let manager = Arc::new(TransactionManager::new());
+        let commit = |count| {
+            for _ in 0..count {
+                let xid = manager.begin().unwrap();
+                manager.commit(xid).unwrap();
+            }
+        };
+
+        let early = manager.begin().unwrap();
+        manager.commit(early).unwrap();
+        let reader =
+            Transaction::with_isolation(manager.clone(), IsolationLevel::RepeatableRead).unwrap();
+        let later = manager.begin().unwrap();
+        manager.commit(later).unwrap();
+        commit(CSN_LOG_TRIM_THRESHOLD);
+
+        // Entries before the reader's snapshot go; the ones it still needs
+        // to tell later commits apart stay.
+        assert_eq!(manager.commit_csn(early), CommitSeqNo::frozen());
+        assert!(manager.commit_csn(later) > CommitSeqNo::frozen());
+        let oracle = manager.oracle(reader.xid());
+        assert!(Visibility::xid_in_snapshot(
+            later,
+            &reader.snapshot(),
+            &oracle
+        ));
+
+        reader.commit().unwrap();
+        commit(2 * CSN_LOG_TRIM_THRESHOLD);
+        assert_eq!(manager.commit_csn(later), CommitSeqNo::frozen());
+    }
+
+    #[test]
+    fn test_savepoint_rollback_hides_rows() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().to_path_buf();
+
+        let (mut engine, _) = HeapEngine::create(path, 2).unwrap();
+
+        engine.begin().unwrap();
+        engine.insert(b"good1").unwrap();
+
+        engine.savepoint("row").unwrap();
+        engine.insert(b"bad").unwrap();
+        engine.rollback_to("row").unwrap();
+
+        engine.insert(b"good2").unwrap();
+        engine.release("row").unwrap();
+
+        let own: Vec<Vec<u8>> = engine
+            .scan()
+            .unwrap()
+            .into_iter()
+            .map(|(_, t)| t.data)
+            .collect();
+        assert_eq!(own, vec![b"good1".to_vec(), b"good2".to_vec()]);
+
+        engine.commit().unwrap();
+
+        let results = engine.scan().unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|(_, t)| t.data != b"bad"));
+    }
+
+    #[test]
+    fn test_rollback_to_aborts_released_children() {
+        let manager = Arc::new(TransactionManager::new());
+        let tx = Transaction::new(manager.clone()).unwrap();
+
+        let outer = tx.savepoint("outer").unwrap();
+        let inner = tx.savepoint("inner").unwrap();
+        tx.release("inner").unwrap();
+        assert_eq!(tx.current_xid(), outer);
+
+        tx.rollback_to("outer").unwrap();
+        assert_eq!(manager.status(outer).unwrap(), XidStatus::Aborted);
+        assert_eq!(manager.status(inner).unwrap(), XidStatus::Aborted);
+        assert!(tx.release("missing").is_err());
+
+        let top = tx.xid();
+        tx.commit().unwrap();
+        assert!(manager.is_committed(top));
+    }
+
+    #[test]
+    fn test_prepared_transaction_survives_restart() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().to_path_buf();
+
+        let prepared_xid = {
+            let (mut engine, _) = HeapEngine::create(path.clone(), 2).unwrap();
+            let tx = engine.begin().unwrap();
+            engine.insert(b"in_doubt").unwrap();
+            engine.prepare("xa-1").unwrap();
+            assert!(engine.heap.tx_manager.is_in_progress(tx.xid()));
+            engine.close().unwrap();
+            tx.xid()
+        };
+
+        let mut engine = HeapEngine::open(path.clone(), 2).unwrap();
+        let pending = engine.prepared_transactions();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].gid, "xa-1");
+        assert_eq!(pending[0].xid, prepared_xid);
+        assert!(engine.heap.tx_manager.is_in_progress(prepared_xid));
+
+        engine.begin().unwrap();
+        assert_eq!(engine.scan().unwrap().len(), 0);
+        engine.commit().unwrap();
+
+        engine.commit_prepared("xa-1").unwrap();
+        assert!(engine.heap.tx_manager.is_committed(prepared_xid));
+        assert!(engine.prepared_transactions().is_empty());
+        assert!(engine.commit_prepared("xa-1").is_err());
+
+        engine.begin().unwrap();
+        assert_eq!(engine.scan().unwrap().len(), 1);
+        engine.commit().unwrap();
+        engine.close().unwrap();
+        drop(engine);
+
+        let engine = HeapEngine::open(path, 2).unwrap();
+        assert!(engine.prepared_transactions().is_empty());
+    }
+
+    #[test]
+    fn test_rollback_prepared_from_other_session() {
+        let manager = Arc::new(TransactionManager::new());
+
+        let tx = Transaction::new(manager.clone()).unwrap();
+        let sub = tx.savepoint("s1").unwrap();
+        let xid = tx.xid();
+        tx.prepare("xa-2").unwrap();
+
+        let duplicate = Transaction::new(manager.clone()).unwrap();
+        assert!(duplicate.prepare("xa-2").is_err());
+
+        let snapshot = manager.get_snapshot(CommandId(1));
+        let oracle = manager.oracle(TransactionId::invalid());
+        assert!(Visibility::xid_in_snapshot(xid, &snapshot, &oracle));
+        assert!(Visibility::xid_in_snapshot(sub, &snapshot, &oracle));
+
+        let other_session = manager.clone();
+        std::thread::spawn(move || other_session.rollback_prepared("xa-2").unwrap())
+            .join()
+            .unwrap();
+
+        assert_eq!(manager.status(xid).unwrap(), XidStatus::Aborted);
+        assert_eq!(manager.status(sub).unwrap(), XidStatus::Aborted);
+        assert!(!manager.is_in_progress(xid));
+    }
+
     #[test]
     fn test_visibility_mvcc() {
         let mut heap_tuple = HeapTuple::with_data(1, b"test".to_vec(), false);
@@ -206,8 +630,13 @@ mod tests {
 
         let snapshot = Snapshot::new(10, 100, vec![], 5, VisibilityMode::MVCC);
 
-        let result =
-            Visibility::heap_tuple_satisfies_mvcc(&heap_tuple, &snapshot, TransactionId(5));
+        let tx_manager = TransactionManager::new();
+        let result = Visibility::heap_tuple_satisfies_mvcc(
+            &heap_tuple,
+            &snapshot,
+            &tx_manager.oracle(TransactionId(5)),
+            &ComboCids::new(),
+        );
         assert!(result);
     }
 
@@ -216,8 +645,13 @@ mod tests {
         let mut heap_tuple = HeapTuple::with_data(1, b"test".to_vec(), false);
         heap_tuple.header.t_xmin = 100;
 
-        let result =
-            Visibility::heap_tuple_satisfies_self(&heap_tuple, TransactionId(100), CommandId(1));
+        let tx_manager = TransactionManager::new();
+        let result = Visibility::heap_tuple_satisfies_self(
+            &heap_tuple,
+            &tx_manager.oracle(TransactionId(100)),
+            CommandId(1),
+            &ComboCids::new(),
+        );
         assert!(result);
     }
 
@@ -284,9 +718,10 @@ mod tests {
             .insert(TransactionId(100), CommandId(1), b"original")
             .unwrap();
 
-        let new_ctid = heap
-            .update(TransactionId(101), CommandId(2), ctid, b"updated")
+        let (result, new_ctid) = heap
+            .update(TransactionId(101), CommandId(2), ctid, b"updated", true)
             .unwrap();
+        assert_eq!(result, UpdateResult::Ok);
         assert!(new_ctid.is_some());
 
         let old_tuple = heap.get(ctid).unwrap().unwrap();
@@ -304,8 +739,10 @@ mod tests {
             .insert(TransactionId(100), CommandId(1), b"test")
             .unwrap();
 
-        let deleted = heap.delete(TransactionId(101), CommandId(2), ctid).unwrap();
-        assert!(deleted);
+        let deleted = heap
+            .delete(TransactionId(101), CommandId(2), ctid, true)
+            .unwrap();
+        assert_eq!(deleted, UpdateResult::Ok);
 
         let tuple = heap.get(ctid).unwrap().unwrap();
         assert!(!tuple.xmax().is_invalid());
@@ -318,12 +755,11 @@ mod tests {
 
         let (heap, _) = HeapRelation::create(path, 2).unwrap();
 
-        heap.insert(TransactionId(100), CommandId(1), b"data1")
-            .unwrap();
-        heap.insert(TransactionId(101), CommandId(1), b"data2")
-            .unwrap();
-        heap.insert(TransactionId(102), CommandId(1), b"data3")
-            .unwrap();
+        for data in [b"data1", b"data2", b"data3"] {
+            let xid = heap.tx_manager.begin().unwrap();
+            heap.insert(xid, CommandId(1), data).unwrap();
+            heap.tx_manager.commit(xid).unwrap();
+        }
 
         let snapshot = Snapshot::new(1, 200, vec![], 10, VisibilityMode::MVCC);
         let results = heap.scan(&snapshot, TransactionId(150)).unwrap();
@@ -337,151 +773,1895 @@ mod tests {
         let path = temp_dir.path().to_path_buf();
 
         let (heap, _) = HeapRelation::create(path, 2).unwrap();
+        let tx_manager = heap.tx_manager.clone();
 
-        let ctid1 = heap
-            .insert(TransactionId(100), CommandId(1), b"data1")
-            .unwrap();
-        let ctid2 = heap
-            .insert(TransactionId(101), CommandId(1), b"data2")
-            .unwrap();
+        let xid = tx_manager.begin().unwrap();
+        let ctid1 = heap.insert(xid, CommandId(1), b"data1").unwrap();
+        let _ctid2 = heap.insert(xid, CommandId(1), b"data2").unwrap();
+        tx_manager.commit(xid).unwrap();
 
-        heap.delete(TransactionId(102), CommandId(2), ctid1)
-            .unwrap();
+        let xid = tx_manager.begin().unwrap();
+        heap.delete(xid, CommandId(1), ctid1, true).unwrap();
+        tx_manager.commit(xid).unwrap();
 
         let removed = heap.vacuum().unwrap();
         assert!(removed > 0);
     }
 
     #[test]
-    fn test_heap_engine_full_workflow() {
+    fn test_vacuum_oldest_xmin() {
+        let temp_dir = TempDir::new().unwrap();
+        let (heap, _) = HeapRelation::create(temp_dir.path().to_path_buf(), 2).unwrap();
+        let tx_manager = heap.tx_manager.clone();
+
+        let xid = tx_manager.begin().unwrap();
+        let live = heap.insert(xid, CommandId(1), b"live").unwrap();
+        let recent = heap.insert(xid, CommandId(1), b"recent").unwrap();
+        let rolled_back = heap.insert(xid, CommandId(1), b"rolled back").unwrap();
+        let running = heap.insert(xid, CommandId(1), b"running").unwrap();
+        tx_manager.commit(xid).unwrap();
+
+        // The reader's snapshot holds the horizon back.
+        let reader =
+            Transaction::with_isolation(tx_manager.clone(), IsolationLevel::RepeatableRead)
+                .unwrap();
+        assert!(!reader.snapshot().xmin.follows(tx_manager.oldest_xmin()));
+
+        let xid = tx_manager.begin().unwrap();
+        heap.delete(xid, CommandId(1), recent, true).unwrap();
+        tx_manager.commit(xid).unwrap();
+
+        let xid = tx_manager.begin().unwrap();
+        heap.delete(xid, CommandId(1), rolled_back, true).unwrap();
+        tx_manager.abort(xid).unwrap();
+
+        let deleter = tx_manager.begin().unwrap();
+        heap.delete(deleter, CommandId(1), running, true).unwrap();
+        let inserted = heap.insert(deleter, CommandId(2), b"inserted").unwrap();
+
+        let classify = |ctid| {
+            Visibility::heap_tuple_satisfies_vacuum(
+                &heap.get(ctid).unwrap().unwrap(),
+                tx_manager.oldest_xmin(),
+                &tx_manager.oracle(TransactionId::invalid()),
+            )
+        };
+        assert_eq!(classify(live), HtsvResult::Live);
+        assert_eq!(classify(recent), HtsvResult::RecentlyDead);
+        assert_eq!(classify(rolled_back), HtsvResult::Live);
+        assert_eq!(classify(running), HtsvResult::DeleteInProgress);
+        assert_eq!(classify(inserted), HtsvResult::InsertInProgress);
+
+        // Nothing is dead yet; the aborted deleter's xmax is cleared.
+        assert_eq!(heap.vacuum().unwrap(), 0);
+        assert!(heap
+            .get(rolled_back)
+            .unwrap()
+            .unwrap()
+            .header
+            .xmax_invalid());
+
+        reader.commit().unwrap();
+        tx_manager.abort(deleter).unwrap();
+        assert_eq!(classify(recent), HtsvResult::Dead);
+        assert_eq!(classify(inserted), HtsvResult::Dead);
+        assert_eq!(heap.vacuum().unwrap(), 2);
+        assert!(heap.get(live).unwrap().is_some());
+        assert!(heap.get(running).unwrap().is_some());
+        assert!(heap.get(recent).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_oldest_xmin_holders() {
         let temp_dir = TempDir::new().unwrap();
         let path = temp_dir.path().to_path_buf();
+        let tx_manager = Arc::new(TransactionManager::open(path.clone()).unwrap());
+        let holder = || tx_manager.compute_oldest_xmin().holder;
 
-        let (mut engine, rel_id) = HeapEngine::create(path, 2).unwrap();
-        assert!(rel_id > 0);
+        assert_eq!(
+            tx_manager.compute_oldest_xmin(),
+            XminHorizon::new(tx_manager.current_xid())
+        );
 
-        engine.begin();
+        // An exported snapshot outlives the exporter's statement snapshot.
+        let oldest = Transaction::new(tx_manager.clone()).unwrap();
+        let exporter = Transaction::new(tx_manager.clone()).unwrap();
+        let id = exporter.export_snapshot().unwrap();
+        assert_eq!(holder(), Some(XminHolder::Transaction(oldest.xid)));
+        let held = oldest.xid;
+        oldest.commit().unwrap();
+        assert!(matches!(
+            holder(),
+            Some(XminHolder::Snapshot { owner, .. }) if owner == LockOwner::Transaction(exporter.xid)
+        ));
+        assert_eq!(tx_manager.snapshot_xmin(exporter.xid), Some(held));
+        exporter.snapshot();
+        assert_eq!(tx_manager.snapshot_xmin(exporter.xid), Some(exporter.xid));
+        assert_eq!(holder(), Some(XminHolder::ExportedSnapshot(id.clone())));
+
+        // The importer keeps the snapshot's horizon once the export is gone.
+        let importer =
+            Transaction::with_isolation(tx_manager.clone(), IsolationLevel::RepeatableRead)
+                .unwrap();
+        importer.import_snapshot(&id).unwrap();
+        exporter.commit().unwrap();
+        assert_eq!(tx_manager.oldest_xmin(), held);
+        assert!(matches!(
+            holder(),
+            Some(XminHolder::Snapshot { owner, .. }) if owner == LockOwner::Transaction(importer.xid)
+        ));
+
+        // A registered snapshot stays until unregistered.
+        let cursor_owner = Transaction::new(tx_manager.clone()).unwrap();
+        let cursor = cursor_owner.register_snapshot();
+        importer.commit().unwrap();
+        cursor_owner.snapshot();
+        assert_eq!(
+            holder(),
+            Some(XminHolder::Snapshot {
+                owner: LockOwner::Transaction(cursor_owner.xid),
+                id: cursor
+            })
+        );
+        assert!(cursor_owner.unregister_snapshot(cursor));
+        assert_eq!(holder(), Some(XminHolder::Transaction(cursor_owner.xid)));
+        cursor_owner.commit().unwrap();
+        assert_eq!(holder(), None);
+
+        let prepared = tx_manager.begin().unwrap();
+        tx_manager.prepare(prepared, "gid").unwrap();
+        assert_eq!(
+            holder(),
+            Some(XminHolder::PreparedTransaction("gid".to_string()))
+        );
+        tx_manager.commit_prepared("gid").unwrap();
+
+        // A slot holds the horizon with nothing running, across restarts.
+        let slot = tx_manager.create_replication_slot("standby").unwrap();
+        assert!(tx_manager.create_replication_slot("standby").is_err());
+        assert!(tx_manager.create_replication_slot("Bad-Name").is_err());
+        for _ in 0..3 {
+            let xid = tx_manager.begin().unwrap();
+            tx_manager.commit(xid).unwrap();
+        }
+        assert_eq!(
+            tx_manager.compute_oldest_xmin(),
+            XminHorizon {
+                xmin: slot.xmin,
+                holder: Some(XminHolder::ReplicationSlot("standby".to_string())),
+            }
+        );
+        drop(tx_manager);
 
-        let ctid1 = engine.insert(b"row1").unwrap();
-        let ctid2 = engine.insert(b"row2").unwrap();
-        let ctid3 = engine.insert(b"row3").unwrap();
+        let tx_manager = TransactionManager::open(path).unwrap();
+        assert_eq!(tx_manager.replication_slots(), vec![slot]);
+        assert_eq!(
+            tx_manager.oldest_xmin(),
+            tx_manager.replication_slots()[0].xmin
+        );
+        tx_manager
+            .advance_replication_slot("standby", tx_manager.current_xid())
+            .unwrap();
+        assert_eq!(tx_manager.compute_oldest_xmin().holder, None);
+        tx_manager.drop_replication_slot("standby").unwrap();
+        assert!(tx_manager.replication_slots().is_empty());
+    }
 
-        let results = engine.scan().unwrap();
-        assert_eq!(results.len(), 3);
+    #[test]
+    fn test_scan_as_of() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().to_path_buf();
+        let (heap, _) = HeapRelation::create(path.clone(), 2).unwrap();
+        let tx_manager = heap.tx_manager.clone();
 
-        let updated = engine.update(ctid1, b"row1_updated").unwrap();
-        assert!(updated.is_some());
+        assert_eq!(
+            "1h".parse::<RetainHistory>().unwrap(),
+            RetainHistory::Duration(std::time::Duration::from_secs(3600))
+        );
+        assert_eq!(
+            "500 transactions".parse::<RetainHistory>().unwrap(),
+            RetainHistory::Transactions(500)
+        );
+        assert!("5 weeks".parse::<RetainHistory>().is_err());
+        assert!(tx_manager
+            .set_retain_history(RetainHistory::Transactions(10))
+            .is_err());
+
+        tx_manager.set_track_commit_timestamp(true).unwrap();
+        // Commits far enough apart to get distinct commit timestamps.
+        let finish = |xid| {
+            tx_manager.commit(xid).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        };
+        let rows = |as_of| {
+            let mut rows: Vec<Vec<u8>> = heap
+                .scan_as_of(as_of)
+                .unwrap()
+                .into_iter()
+                .map(|(_, tuple)| tuple.data)
+                .collect();
+            rows.sort();
+            rows
+        };
 
-        let deleted = engine.delete(ctid2).unwrap();
-        assert!(deleted);
+        let inserted = tx_manager.begin().unwrap();
+        let ctid = heap.insert(inserted, CommandId(1), b"a").unwrap();
+        finish(inserted);
+        assert!(heap.scan_as_of(AsOf::Xid(inserted)).is_err());
+        tx_manager
+            .set_retain_history("1h".parse().unwrap())
+            .unwrap();
 
-        engine.commit();
+        let updated = tx_manager.begin().unwrap();
+        heap.update(updated, CommandId(1), ctid, b"b", true)
+            .unwrap();
+        finish(updated);
+
+        let added = tx_manager.begin().unwrap();
+        let other = heap.insert(added, CommandId(1), b"c").unwrap();
+        finish(added);
+
+        let deleted = tx_manager.begin().unwrap();
+        heap.delete(deleted, CommandId(1), other, true).unwrap();
+        finish(deleted);
+
+        assert_eq!(rows(AsOf::Xid(inserted)), vec![b"a".to_vec()]);
+        assert_eq!(rows(AsOf::Xid(updated)), vec![b"b".to_vec()]);
+        assert_eq!(rows(AsOf::Xid(added)), vec![b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(rows(AsOf::Xid(deleted)), vec![b"b".to_vec()]);
+        let before_update = tx_manager.commit_timestamp(updated).unwrap().unwrap() - 1;
+        assert_eq!(rows(AsOf::Timestamp(before_update)), vec![b"a".to_vec()]);
+
+        // Vacuum keeps every version the window covers.
+        assert_eq!(
+            tx_manager.compute_oldest_xmin().holder,
+            Some(XminHolder::RetainHistory)
+        );
+        assert_eq!(heap.vacuum().unwrap(), 0);
+        assert_eq!(rows(AsOf::Xid(inserted)), vec![b"a".to_vec()]);
 
-        let results = engine.scan().unwrap();
-        assert_eq!(results.len(), 1);
+        // Narrowing the window lets vacuum go, and the old states with it.
+        tx_manager
+            .set_retain_history(RetainHistory::Transactions(1))
+            .unwrap();
+        assert_eq!(heap.vacuum().unwrap(), 1);
+        assert!(heap.scan_as_of(AsOf::Xid(inserted)).is_err());
+        assert_eq!(rows(AsOf::Xid(deleted)), vec![b"b".to_vec()]);
+
+        // A transaction still running when the horizon moved is not passed
+        // over once it commits.
+        let long = tx_manager.begin().unwrap();
+        let later = tx_manager.begin().unwrap();
+        finish(later);
+        tx_manager.compute_oldest_xmin();
+        finish(long);
+        assert!(heap.scan_as_of(AsOf::Xid(later)).is_err());
+        heap.close().unwrap();
+        drop(heap);
+        drop(tx_manager);
+
+        let heap = HeapRelation::open(path, 2).unwrap();
+        assert_eq!(
+            heap.tx_manager.retain_history(),
+            RetainHistory::Transactions(1)
+        );
+    }
 
-        engine.close().unwrap();
+    #[test]
+    fn test_dirty_toast_non_vacuumable_snapshots() {
+        let temp_dir = TempDir::new().unwrap();
+        let (heap, _) = HeapRelation::create(temp_dir.path().to_path_buf(), 2).unwrap();
+        let tx_manager = heap.tx_manager.clone();
+        let no_xid = TransactionId::invalid();
+        let count = |snapshot: &Snapshot| heap.scan(snapshot, no_xid).unwrap().len();
+
+        let xid = tx_manager.begin().unwrap();
+        let live = heap.insert(xid, CommandId(1), b"live").unwrap();
+        let doomed = heap.insert(xid, CommandId(1), b"doomed").unwrap();
+        tx_manager.commit(xid).unwrap();
+
+        let writer = tx_manager.begin().unwrap();
+        let pending = heap.insert(writer, CommandId(1), b"pending").unwrap();
+        heap.delete(writer, CommandId(1), doomed, true).unwrap();
+
+        // Others see the running writer's work and learn whom to wait for.
+        let writers = |ctid, cur_xid| heap.fetch_dirty(ctid, cur_xid).unwrap().map(|(_, w)| w);
+        assert_eq!(writers(live, no_xid), Some(DirtyWriters::default()));
+        assert_eq!(writers(pending, no_xid).unwrap().xmin, writer);
+        assert_eq!(writers(doomed, no_xid).unwrap().xmax, writer);
+        assert_eq!(count(&Snapshot::dirty()), 3);
+        assert_eq!(count(&tx_manager.get_snapshot(CommandId(1))), 2);
+
+        // The writer itself has nobody to wait for.
+        assert_eq!(writers(pending, writer), Some(DirtyWriters::default()));
+        assert_eq!(writers(doomed, writer), None);
+
+        // A recently deleted tuple is not vacuumable while a snapshot that
+        // may see it is still held.
+        let reader =
+            Transaction::with_isolation(tx_manager.clone(), IsolationLevel::RepeatableRead)
+                .unwrap();
+        tx_manager.commit(writer).unwrap();
+        assert_eq!(writers(doomed, no_xid), None);
+        assert_eq!(
+            count(&Snapshot::non_vacuumable(tx_manager.oldest_xmin())),
+            3
+        );
+        reader.commit().unwrap();
+        assert_eq!(
+            count(&Snapshot::non_vacuumable(tx_manager.oldest_xmin())),
+            2
+        );
+
+        // Toast ignores deletion, and only hides a failed insert once a
+        // hint bit records the abort.
+        let xid = tx_manager.begin().unwrap();
+        let chunk = heap.insert(xid, CommandId(1), b"chunk").unwrap();
+        tx_manager.abort(xid).unwrap();
+        assert!(Visibility::heap_tuple_satisfies_toast(
+            &heap.get(chunk).unwrap().unwrap()
+        ));
+        assert_eq!(count(&Snapshot::toast()), 3);
+        assert!(heap.get(chunk).unwrap().unwrap().header.xmin_invalid());
     }
 
     #[test]
-    fn test_mvcc_isolation() {
+    fn test_vacuum_freeze() {
         let temp_dir = TempDir::new().unwrap();
         let path = temp_dir.path().to_path_buf();
 
-        let tx_manager = Arc::new(TransactionManager::new());
+        let (heap, _) = HeapRelation::create(path.clone(), 2).unwrap();
+        let tx_manager = heap.tx_manager.clone();
+
+        let committed = tx_manager.begin().unwrap();
+        let frozen_ctid = heap.insert(committed, CommandId(1), b"old").unwrap();
+        tx_manager.commit(committed).unwrap();
+
+        let aborted = tx_manager.begin().unwrap();
+        heap.insert(aborted, CommandId(1), b"aborted").unwrap();
+        tx_manager.abort(aborted).unwrap();
+
+        let running = tx_manager.begin().unwrap();
+        let running_ctid = heap.insert(running, CommandId(1), b"new").unwrap();
+
+        let stats = heap.vacuum_with(VacuumOptions::default()).unwrap();
+        assert!(!stats.aggressive);
+        assert_eq!(stats.frozen, 0);
+        assert_eq!(stats.removed, 1);
+
+        let stats = heap.vacuum_with(VacuumOptions::freeze()).unwrap();
+        assert!(stats.aggressive);
+        assert_eq!(stats.frozen, 1);
+        assert_eq!(stats.relfrozenxid, running);
+        assert_eq!(heap.relation.relfrozenxid(), running);
+        assert_eq!(tx_manager.xid_limits().oldest_xid, running);
+
+        let frozen = heap.get(frozen_ctid).unwrap().unwrap();
+        assert!(frozen.header.xmin_frozen());
+        assert!(!heap
+            .get(running_ctid)
+            .unwrap()
+            .unwrap()
+            .header
+            .xmin_frozen());
+        assert!(tx_manager.status(committed).is_err());
+
+        tx_manager.commit(running).unwrap();
+        let snapshot = tx_manager.get_snapshot(CommandId(1));
+        let visible = heap.scan(&snapshot, TransactionId::invalid()).unwrap();
+        assert_eq!(visible.len(), 2);
 
-        let heap = HeapRelation {
-            relation: Relation::open(path.clone()).unwrap(),
-            natts: 2,
-            tx_manager: tx_manager.clone(),
+        heap.close().unwrap();
+        drop(heap);
+        drop(tx_manager);
+
+        let heap = HeapRelation::open(path, 2).unwrap();
+        assert_eq!(heap.relation.relfrozenxid(), running);
+        assert_eq!(heap.relation.relminmxid(), MultiXactId::first());
+        assert_eq!(heap.tx_manager.xid_limits().oldest_xid, running);
+    }
+
+    #[test]
+    fn test_commit_log_truncation() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().to_path_buf();
+        let xacts_per_segment = CLOG_XACTS_PER_PAGE * SLRU_PAGES_PER_SEGMENT;
+
+        let old = {
+            let manager = TransactionManager::open(path.clone()).unwrap();
+            let old = manager.begin().unwrap();
+            manager.commit(old).unwrap();
+            old
         };
 
-        let ctid = heap
-            .insert(TransactionId(100), CommandId(1), b"data")
+        ControlFile::open(path.clone())
+            .unwrap()
+            .update(|data| data.next_full_xid = 2 * xacts_per_segment as u64)
             .unwrap();
 
-        let snapshot = Snapshot::new(1, 200, vec![], 10, VisibilityMode::MVCC);
-        let results = heap.scan(&snapshot, TransactionId(150)).unwrap();
-
-        assert_eq!(results.len(), 1);
+        let manager = TransactionManager::open(path.clone()).unwrap();
+        let new = manager.begin().unwrap();
+        manager.commit(new).unwrap();
+        manager.flush().unwrap();
+        assert!(path.join("pg_xact").join("0000").exists());
+
+        manager.set_oldest_xid(new).unwrap();
+        assert!(!path.join("pg_xact").join("0000").exists());
+        assert!(!path.join("pg_subtrans").join("0000").exists());
+        assert!(path.join("pg_xact").join("0002").exists());
+        assert!(manager.status(old).is_err());
+        assert_eq!(manager.status(new).unwrap(), XidStatus::Committed);
+
+        manager.set_oldest_xid(old).unwrap();
+        assert_eq!(manager.xid_limits().oldest_xid, new);
     }
 
     #[test]
-    fn test_concurrent_transactions() {
+    fn test_commit_timestamps() {
         let temp_dir = TempDir::new().unwrap();
         let path = temp_dir.path().to_path_buf();
+        let xacts_per_segment = COMMIT_TS_XACTS_PER_PAGE * SLRU_PAGES_PER_SEGMENT;
+
+        let (first, sub, second) = {
+            let manager = TransactionManager::open(path.clone()).unwrap();
+            let untracked = manager.begin().unwrap();
+            manager.commit(untracked).unwrap();
+            assert!(manager.commit_timestamp(untracked).is_err());
+
+            manager.set_track_commit_timestamp(true).unwrap();
+            assert_eq!(manager.commit_timestamp(untracked).unwrap(), None);
+            assert_eq!(manager.latest_committed().unwrap(), None);
+
+            let first = manager.begin().unwrap();
+            let sub = manager.begin_subtransaction(first).unwrap();
+            manager.commit(first).unwrap();
+            let aborted = manager.begin().unwrap();
+            manager.abort(aborted).unwrap();
+            let second = manager.begin().unwrap();
+            manager.commit(second).unwrap();
+
+            let first_ts = manager.commit_timestamp(first).unwrap().unwrap();
+            let second_ts = manager.commit_timestamp(second).unwrap().unwrap();
+            assert!(second_ts > first_ts);
+            assert_eq!(manager.commit_timestamp(sub).unwrap(), Some(first_ts));
+            assert_eq!(manager.commit_timestamp(aborted).unwrap(), None);
+            assert_eq!(
+                manager.latest_committed().unwrap(),
+                Some((second, second_ts))
+            );
+            manager.flush().unwrap();
+            (first, sub, second)
+        };
 
-        let (mut engine1, _) = HeapEngine::create(path.clone(), 2).unwrap();
-        let (mut engine2, _) = HeapEngine::create(path, 2).unwrap();
+        let manager = TransactionManager::open(path.clone()).unwrap();
+        assert!(manager.track_commit_timestamp());
+        let second_ts = manager.commit_timestamp(second).unwrap().unwrap();
+        assert_eq!(
+            manager.latest_committed().unwrap(),
+            Some((second, second_ts))
+        );
+        assert_eq!(
+            manager.commit_timestamp(sub).unwrap(),
+            manager.commit_timestamp(first).unwrap()
+        );
+        drop(manager);
 
-        engine1.begin();
-        engine1.insert(b"tx1_data").unwrap();
+        ControlFile::open(path.clone())
+            .unwrap()
+            .update(|data| data.next_full_xid = 2 * xacts_per_segment as u64)
+            .unwrap();
 
-        engine2.begin();
-        let results = engine2.scan().unwrap();
-        assert_eq!(results.len(), 0);
+        let manager = TransactionManager::open(path.clone()).unwrap();
+        let new = manager.begin().unwrap();
+        manager.commit(new).unwrap();
+        manager.flush().unwrap();
+        assert!(path.join("pg_commit_ts").join("0000").exists());
+
+        // Freezing past the old xids drops their timestamps with them.
+        manager.set_oldest_xid(new).unwrap();
+        assert!(!path.join("pg_commit_ts").join("0000").exists());
+        assert_eq!(manager.commit_timestamp(first).unwrap(), None);
+        let new_ts = manager.commit_timestamp(new).unwrap().unwrap();
+        assert!(new_ts > second_ts);
+        assert_eq!(manager.latest_committed().unwrap(), Some((new, new_ts)));
+        drop(manager);
+
+        // A crash before the commit_ts page reaches disk loses nothing: the
+        // page and the timestamp are redone from the WAL.
+        ControlFile::open(path.clone())
+            .unwrap()
+            .update(|data| data.next_full_xid = 3 * xacts_per_segment as u64)
+            .unwrap();
+        let manager = TransactionManager::open(path.clone()).unwrap();
+        let crashed = manager.begin().unwrap();
+        let crashed_sub = manager.begin_subtransaction(crashed).unwrap();
+        manager.commit(crashed).unwrap();
+        let crashed_ts = manager.commit_timestamp(crashed).unwrap().unwrap();
+        crash(&path, manager);
+
+        let manager = TransactionManager::open(path).unwrap();
+        assert_eq!(manager.commit_timestamp(crashed).unwrap(), Some(crashed_ts));
+        assert_eq!(
+            manager.commit_timestamp(crashed_sub).unwrap(),
+            Some(crashed_ts)
+        );
+        assert_eq!(
+            manager.latest_committed().unwrap(),
+            Some((crashed, crashed_ts))
+        );
+        let after = manager.begin().unwrap();
+        manager.commit(after).unwrap();
+        assert!(manager.commit_timestamp(after).unwrap().unwrap() > crashed_ts);
+    }
 
-        engine1.commit();
+    #[test]
+    fn test_row_lock_modes() {
+        use LockMode::*;
+        let modes = [ForKeyShare, ForShare, ForNoKeyUpdate, ForUpdate];
+        let expected = [
+            [false, false, false, true],
+            [false, false, true, true],
+            [false, true, true, true],
+            [true, true, true, true],
+        ];
+        for (i, a) in modes.iter().enumerate() {
+            for (j, b) in modes.iter().enumerate() {
+                assert_eq!(a.conflicts_with(*b), expected[i][j], "{:?} vs {:?}", a, b);
+            }
+        }
 
-        engine2.commit();
+        let mut header = HeapTupleHeaderData::new(2);
+        header.set_xmax_is_locked_only(true);
+        for mode in modes {
+            header.set_xmax_lock_mode(mode);
+            assert_eq!(header.xmax_lock_mode(), mode);
+        }
     }
 
     #[test]
-    fn test_page_header_flags() {
-        let mut page = Page::new(8192);
+    fn test_lock_tuple_nowait_and_skip_locked() {
+        let temp_dir = TempDir::new().unwrap();
+        let (heap, _) = HeapRelation::create(temp_dir.path().to_path_buf(), 2).unwrap();
+        let tx_manager = heap.tx_manager.clone();
 
-        assert!(!page.header.has_free_lines());
-        page.header.set_has_free_lines(true);
-        assert!(page.header.has_free_lines());
+        let setup = tx_manager.begin().unwrap();
+        let ctid = heap.insert(setup, CommandId(1), b"row").unwrap();
+        tx_manager.commit(setup).unwrap();
 
-        assert!(!page.header.is_page_full());
-        page.header.set_page_full(true);
-        assert!(page.header.is_page_full());
+        let locker = tx_manager.begin().unwrap();
+        let other = tx_manager.begin().unwrap();
 
-        assert!(!page.header.all_visible());
-        page.header.set_all_visible(true);
-        assert!(page.header.all_visible());
-    }
+        assert_eq!(
+            heap.lock_tuple(locker, ctid, LockMode::ForShare, WaitPolicy::Block)
+                .unwrap(),
+            UpdateResult::Ok
+        );
+        assert_eq!(
+            heap.lock_tuple(locker, ctid, LockMode::ForKeyShare, WaitPolicy::Block)
+                .unwrap(),
+            UpdateResult::Ok
+        );
+        assert_eq!(
+            heap.get(ctid).unwrap().unwrap().header.xmax_lock_mode(),
+            LockMode::ForShare
+        );
 
-    #[test]
-    fn test_item_id_data() {
-        let mut item_id = ItemIdData::new();
+        assert!(matches!(
+            heap.lock_tuple(other, ctid, LockMode::ForUpdate, WaitPolicy::NoWait),
+            Err(HeapError::LockError(_))
+        ));
+        assert_eq!(
+            heap.lock_tuple(other, ctid, LockMode::ForUpdate, WaitPolicy::SkipLocked)
+                .unwrap(),
+            UpdateResult::WouldBlock
+        );
+        assert_eq!(
+            heap.update(other, CommandId(1), ctid, b"blocked", false)
+                .unwrap(),
+            (UpdateResult::BeingModified, None)
+        );
 
-        item_id.set(100, 50, LP_USED);
+        let snapshot = tx_manager.get_snapshot(CommandId(1));
+        assert_eq!(heap.scan(&snapshot, other).unwrap().len(), 1);
 
-        assert_eq!(item_id.offset(), 100);
-        assert_eq!(item_id.length(), 50);
-        assert_eq!(item_id.flags(), LP_USED);
-        assert!(item_id.is_used());
+        assert_eq!(
+            heap.lock_tuple(locker, ctid, LockMode::ForUpdate, WaitPolicy::NoWait)
+                .unwrap(),
+            UpdateResult::Ok
+        );
+        tx_manager.commit(locker).unwrap();
+
+        assert_eq!(
+            heap.lock_tuple(other, ctid, LockMode::ForUpdate, WaitPolicy::NoWait)
+                .unwrap(),
+            UpdateResult::Ok
+        );
+        assert_eq!(
+            heap.delete(other, CommandId(2), ctid, false).unwrap(),
+            UpdateResult::Ok
+        );
+        tx_manager.commit(other).unwrap();
+
+        let late = tx_manager.begin().unwrap();
+        assert_eq!(
+            heap.lock_tuple(late, ctid, LockMode::ForKeyShare, WaitPolicy::NoWait)
+                .unwrap(),
+            UpdateResult::Deleted
+        );
     }
 
     #[test]
-    fn test_heap_tuple_ctid() {
-        let mut heap_tuple = HeapTuple::new(2);
+    fn test_lock_tuple_waits_for_holder() {
+        let temp_dir = TempDir::new().unwrap();
+        let (heap, _) = HeapRelation::create(temp_dir.path().to_path_buf(), 2).unwrap();
+        let heap = Arc::new(heap);
+        let tx_manager = heap.tx_manager.clone();
+
+        let setup = tx_manager.begin().unwrap();
+        let ctid = heap.insert(setup, CommandId(1), b"row").unwrap();
+        tx_manager.commit(setup).unwrap();
+
+        let holder = tx_manager.begin().unwrap();
+        assert_eq!(
+            heap.lock_tuple(holder, ctid, LockMode::ForUpdate, WaitPolicy::Block)
+                .unwrap(),
+            UpdateResult::Ok
+        );
 
-        let ctid = ItemPointerData {
-            block_number: 5,
-            offset_number: 10,
+        let waiter = {
+            let heap = heap.clone();
+            std::thread::spawn(move || {
+                let xid = heap.tx_manager.begin().unwrap();
+                let locked = heap
+                    .lock_tuple(xid, ctid, LockMode::ForUpdate, WaitPolicy::Block)
+                    .unwrap();
+                (xid, locked)
+            })
         };
 
-        heap_tuple.set_ctid(ctid);
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(!waiter.is_finished());
+        tx_manager.abort(holder).unwrap();
 
-        let retrieved_ctid = heap_tuple.ctid();
-        assert_eq!(retrieved_ctid.block_number, 5);
-        assert_eq!(retrieved_ctid.offset_number, 10);
+        let (xid, locked) = waiter.join().unwrap();
+        assert_eq!(locked, UpdateResult::Ok);
+        assert_eq!(heap.get(ctid).unwrap().unwrap().xmax(), xid);
     }
 
     #[test]
-    fn test_visibility_any() {
-        let mut heap_tuple = HeapTuple::new(1);
-        heap_tuple.header.t_xmax = 0;
+    fn test_skip_locked_job_queue() {
+        let temp_dir = TempDir::new().unwrap();
+        let (heap, _) = HeapRelation::create(temp_dir.path().to_path_buf(), 2).unwrap();
+        let heap = Arc::new(heap);
 
-        assert!(Visibility::heap_tuple_satisfies_any(&heap_tuple));
+        let setup = heap.tx_manager.begin().unwrap();
+        for i in 0..20u8 {
+            heap.insert(setup, CommandId(1), &[i]).unwrap();
+        }
+        heap.tx_manager.commit(setup).unwrap();
+
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let heap = heap.clone();
+                std::thread::spawn(move || {
+                    let xid = heap.tx_manager.begin().unwrap();
+                    let snapshot = heap.tx_manager.get_snapshot(CommandId(1));
+                    let mut claimed = Vec::new();
+                    for (ctid, tuple) in heap.scan(&snapshot, xid).unwrap() {
+                        if heap
+                            .lock_tuple(xid, ctid, LockMode::ForUpdate, WaitPolicy::SkipLocked)
+                            .unwrap()
+                            == UpdateResult::Ok
+                        {
+                            claimed.push(tuple.data[0]);
+                        }
+                    }
+                    claimed
+                })
+            })
+            .collect();
+
+        let mut claimed: Vec<u8> = workers
+            .into_iter()
+            .flat_map(|w| w.join().unwrap())
+            .collect();
+        claimed.sort();
+        assert_eq!(claimed, (0..20u8).collect::<Vec<_>>());
+    }
 
-        heap_tuple.header.t_xmax = 100;
-        heap_tuple.header.set_xmax_committed(true);
+    #[test]
+    fn test_multixact_survives_reopen() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().to_path_buf();
+
+        let members = vec![
+            MultiXactMember::new(TransactionId(10), MultiXactStatus::ForKeyShare),
+            MultiXactMember::new(TransactionId(11), MultiXactStatus::ForShare),
+            MultiXactMember::new(TransactionId(12), MultiXactStatus::NoKeyUpdate),
+        ];
+
+        let (first, second) = {
+            let manager = TransactionManager::open(path.clone()).unwrap();
+            let first = manager.multixact().create(&members[..2]).unwrap();
+            let second = manager.multixact().create(&members).unwrap();
+            (first, second)
+        };
+
+        let manager = TransactionManager::open(path).unwrap();
+        let multixact = manager.multixact();
+        assert_eq!(multixact.get_members(first).unwrap(), members[..2]);
+        assert_eq!(multixact.get_members(second).unwrap(), members);
+        assert_eq!(multixact.next_multi(), second.next());
+        assert!(multixact.create(&members[..1]).is_err());
+
+        multixact.set_oldest(second).unwrap();
+        assert!(multixact.get_members(first).is_err());
+        assert_eq!(multixact.get_members(second).unwrap(), members);
+    }
+
+    #[test]
+    fn test_multixact_redo() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().to_path_buf();
+
+        let members = vec![
+            MultiXactMember::new(TransactionId(10), MultiXactStatus::ForKeyShare),
+            MultiXactMember::new(TransactionId(11), MultiXactStatus::ForShare),
+        ];
+
+        let multi = {
+            let manager = TransactionManager::open(path.clone()).unwrap();
+            let multi = manager.multixact().create(&members).unwrap();
+            manager.wal().unwrap().flush(u64::MAX).unwrap();
+            // A crash: the member pages never reach disk, only the WAL.
+            crash(&path, manager);
+            multi
+        };
+
+        let manager = TransactionManager::open(path).unwrap();
+        let stats = Recovery::new(&manager).run().unwrap();
+        assert_eq!(stats.replayed, 1);
+        assert_eq!(manager.multixact().get_members(multi).unwrap(), members);
+        assert_eq!(manager.multixact().next_multi(), multi.next());
+    }
+
+    #[test]
+    fn test_xact_log_redo() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().to_path_buf();
+
+        let (xid, timestamp) = {
+            let manager = TransactionManager::open(path.clone()).unwrap();
+            manager.set_track_commit_timestamp(true).unwrap();
+            let xid = manager.begin().unwrap();
+            manager.commit(xid).unwrap();
+            let timestamp = manager.commit_timestamp(xid).unwrap().unwrap();
+            crash(&path, manager);
+            (xid, timestamp)
+        };
+
+        let manager = TransactionManager::open(path).unwrap();
+        let redo = manager.checkpoint_redo();
+        let records = manager.wal().unwrap().recover().unwrap();
+        let logged = |record_type| {
+            records
+                .iter()
+                .filter(|r| r.lsn > redo && r.record_type == record_type)
+                .count()
+        };
+        assert_eq!(logged(XLogRecordType::CommitTsZeroPage), 1);
+
+        let stats = Recovery::new(&manager).run().unwrap();
+        assert_eq!(
+            stats.replayed,
+            logged(XLogRecordType::TransactionCommit)
+                + logged(XLogRecordType::ClogZeroPage)
+                + logged(XLogRecordType::CommitTsZeroPage)
+        );
+        assert_eq!(manager.status(xid).unwrap(), XidStatus::Committed);
+        assert_eq!(manager.commit_timestamp(xid).unwrap(), Some(timestamp));
+    }
+
+    #[test]
+    fn test_multixact_shared_row_locks() {
+        let temp_dir = TempDir::new().unwrap();
+        let (heap, _) = HeapRelation::create(temp_dir.path().to_path_buf(), 2).unwrap();
+        let tx_manager = heap.tx_manager.clone();
+
+        let setup = tx_manager.begin().unwrap();
+        let ctid = heap.insert(setup, CommandId(1), b"row").unwrap();
+        tx_manager.commit(setup).unwrap();
+
+        let first = tx_manager.begin().unwrap();
+        let second = tx_manager.begin().unwrap();
+        let writer = tx_manager.begin().unwrap();
+
+        for xid in [first, second] {
+            assert_eq!(
+                heap.lock_tuple(xid, ctid, LockMode::ForShare, WaitPolicy::NoWait)
+                    .unwrap(),
+                UpdateResult::Ok
+            );
+        }
+
+        let tuple = heap.get(ctid).unwrap().unwrap();
+        assert!(tuple.header.xmax_is_multi());
+        assert!(tuple.header.xmax_is_locked_only());
+        let members = tx_manager
+            .multixact()
+            .get_members(MultiXactId(tuple.header.t_xmax))
+            .unwrap();
+        assert_eq!(
+            members,
+            vec![
+                MultiXactMember::new(first, MultiXactStatus::ForShare),
+                MultiXactMember::new(second, MultiXactStatus::ForShare),
+            ]
+        );
+
+        assert!(matches!(
+            heap.lock_tuple(writer, ctid, LockMode::ForUpdate, WaitPolicy::NoWait),
+            Err(HeapError::LockError(_))
+        ));
+        assert_eq!(
+            heap.update(writer, CommandId(1), ctid, b"blocked", false)
+                .unwrap(),
+            (UpdateResult::BeingModified, None)
+        );
+
+        tx_manager.commit(first).unwrap();
+        assert_eq!(
+            heap.lock_tuple(writer, ctid, LockMode::ForUpdate, WaitPolicy::SkipLocked)
+                .unwrap(),
+            UpdateResult::WouldBlock
+        );
+
+        tx_manager.abort(second).unwrap();
+        assert_eq!(
+            heap.lock_tuple(writer, ctid, LockMode::ForUpdate, WaitPolicy::NoWait)
+                .unwrap(),
+            UpdateResult::Ok
+        );
+        let tuple = heap.get(ctid).unwrap().unwrap();
+        assert!(!tuple.header.xmax_is_multi());
+        assert_eq!(tuple.xmax(), writer);
+    }
+
+    #[test]
+    fn test_multixact_key_share_with_update() {
+        let temp_dir = TempDir::new().unwrap();
+        let (heap, _) = HeapRelation::create(temp_dir.path().to_path_buf(), 2).unwrap();
+        let tx_manager = heap.tx_manager.clone();
+
+        let setup = tx_manager.begin().unwrap();
+        let ctid = heap.insert(setup, CommandId(1), b"old").unwrap();
+        tx_manager.commit(setup).unwrap();
+
+        let locker = tx_manager.begin().unwrap();
+        assert_eq!(
+            heap.lock_tuple(locker, ctid, LockMode::ForKeyShare, WaitPolicy::NoWait)
+                .unwrap(),
+            UpdateResult::Ok
+        );
+
+        let updater = tx_manager.begin().unwrap();
+        let (result, new_ctid) = heap
+            .update(updater, CommandId(1), ctid, b"new", false)
+            .unwrap();
+        assert_eq!(result, UpdateResult::Ok);
+        let new_ctid = new_ctid.unwrap();
+        assert_eq!(
+            heap.delete(locker, CommandId(2), ctid, false).unwrap(),
+            UpdateResult::BeingModified
+        );
+
+        let tuple = heap.get(ctid).unwrap().unwrap();
+        assert!(tuple.header.xmax_is_multi());
+        assert!(!tuple.header.xmax_is_locked_only());
+
+        let reader = tx_manager.begin().unwrap();
+        let snapshot = tx_manager.get_snapshot(CommandId(1));
+        let rows = heap.scan(&snapshot, reader).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].0, ctid);
+
+        tx_manager.commit(updater).unwrap();
+        let snapshot = tx_manager.get_snapshot(CommandId(1));
+        let rows = heap.scan(&snapshot, reader).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].0, new_ctid);
+    }
+
+    #[test]
+    fn test_vacuum_freezes_multixacts() {
+        let temp_dir = TempDir::new().unwrap();
+        let (heap, _) = HeapRelation::create(temp_dir.path().to_path_buf(), 2).unwrap();
+        let tx_manager = heap.tx_manager.clone();
+
+        let setup = tx_manager.begin().unwrap();
+        let shared = heap.insert(setup, CommandId(1), b"shared").unwrap();
+        let released = heap.insert(setup, CommandId(1), b"released").unwrap();
+        tx_manager.commit(setup).unwrap();
+
+        let first = tx_manager.begin().unwrap();
+        let second = tx_manager.begin().unwrap();
+        for ctid in [shared, released] {
+            for xid in [first, second] {
+                assert_eq!(
+                    heap.lock_tuple(xid, ctid, LockMode::ForShare, WaitPolicy::NoWait)
+                        .unwrap(),
+                    UpdateResult::Ok
+                );
+            }
+        }
+        tx_manager.commit(first).unwrap();
+
+        let third = tx_manager.begin().unwrap();
+        assert_eq!(
+            heap.lock_tuple(third, shared, LockMode::ForKeyShare, WaitPolicy::NoWait)
+                .unwrap(),
+            UpdateResult::Ok
+        );
+        let live_multi = MultiXactId(heap.get(shared).unwrap().unwrap().header.t_xmax);
+        tx_manager.abort(third).unwrap();
+
+        let stats = heap.vacuum_with(VacuumOptions::freeze()).unwrap();
+        assert_eq!(stats.removed, 0);
+
+        let tuple = heap.get(shared).unwrap().unwrap();
+        assert!(!tuple.header.xmax_is_multi());
+        assert_eq!(tuple.xmax(), second);
+        assert!(tuple.header.xmax_is_locked_only());
+        assert_eq!(stats.relfrozenxid, second);
+        assert!(stats.relminmxid.follows(live_multi));
+        assert_eq!(tx_manager.multixact().oldest_multi(), stats.relminmxid);
+        assert!(tx_manager.multixact().get_members(live_multi).is_err());
+
+        tx_manager.commit(second).unwrap();
+        heap.vacuum_with(VacuumOptions::freeze()).unwrap();
+        let tuple = heap.get(released).unwrap().unwrap();
+        assert!(tuple.xmax().is_invalid());
+        assert!(tuple.header.xmin_frozen());
+    }
+
+    #[test]
+    fn test_command_counter_and_combo_cids() {
+        let temp_dir = TempDir::new().unwrap();
+        let (heap, _) = HeapRelation::create(temp_dir.path().to_path_buf(), 2).unwrap();
+        let tx_manager = heap.tx_manager.clone();
+
+        let tx = Transaction::new(tx_manager.clone()).unwrap();
+        let xid = tx.xid();
+        let insert_cid = tx.get_cid();
+        assert_eq!(insert_cid, CommandId(FIRST_COMMAND_ID));
+        let ctid = heap.insert(xid, insert_cid, b"row").unwrap();
+
+        // The inserting command does not see its own row; the next one does.
+        assert!(heap.scan(&tx.snapshot(), xid).unwrap().is_empty());
+        let delete_cid = tx.command_counter_increment().unwrap();
+        assert_eq!(delete_cid.0, insert_cid.0 + 1);
+        assert_eq!(heap.scan(&tx.snapshot(), xid).unwrap().len(), 1);
+
+        assert_eq!(
+            heap.delete(xid, delete_cid, ctid, false).unwrap(),
+            UpdateResult::Ok
+        );
+        let deleted = heap.get(ctid).unwrap().unwrap();
+        assert!(deleted.header.is_combo_cid());
+        let combo_cids = tx_manager.combo_cids(xid).unwrap();
+        assert_eq!(deleted.cmin(&combo_cids), insert_cid);
+        assert_eq!(deleted.cmax(&combo_cids), delete_cid);
+
+        assert_eq!(heap.scan(&tx.snapshot(), xid).unwrap().len(), 1);
+        assert!(!Visibility::heap_tuple_satisfies_self(
+            &deleted,
+            &tx_manager.oracle(xid),
+            delete_cid,
+            &combo_cids
+        ));
+        assert_eq!(
+            heap.delete(xid, delete_cid, ctid, false).unwrap(),
+            UpdateResult::SelfModified
+        );
+
+        let next_cid = tx.command_counter_increment().unwrap();
+        assert!(heap.scan(&tx.snapshot(), xid).unwrap().is_empty());
+        assert_eq!(
+            heap.delete(xid, next_cid, ctid, false).unwrap(),
+            UpdateResult::Invisible
+        );
+
+        tx.commit().unwrap();
+        assert!(tx_manager.combo_cids(xid).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_update_results() {
+        let temp_dir = TempDir::new().unwrap();
+        let (heap, _) = HeapRelation::create(temp_dir.path().to_path_buf(), 2).unwrap();
+        let tx_manager = heap.tx_manager.clone();
+
+        let setup = tx_manager.begin().unwrap();
+        let updated = heap.insert(setup, CommandId(1), b"updated").unwrap();
+        let deleted = heap.insert(setup, CommandId(1), b"deleted").unwrap();
+        tx_manager.commit(setup).unwrap();
+
+        let inserter = tx_manager.begin().unwrap();
+        let uncommitted = heap.insert(inserter, CommandId(1), b"new").unwrap();
+
+        let writer = tx_manager.begin().unwrap();
+        assert_eq!(
+            heap.update(writer, CommandId(1), uncommitted, b"x", false)
+                .unwrap(),
+            (UpdateResult::Invisible, None)
+        );
+        let (result, new_ctid) = heap
+            .update(writer, CommandId(1), updated, b"v2", false)
+            .unwrap();
+        assert_eq!(result, UpdateResult::Ok);
+        assert_eq!(
+            heap.update(writer, CommandId(1), updated, b"v3", false)
+                .unwrap(),
+            (UpdateResult::SelfModified, None)
+        );
+        assert_eq!(
+            heap.delete(writer, CommandId(1), deleted, false).unwrap(),
+            UpdateResult::Ok
+        );
+        tx_manager.commit(writer).unwrap();
+
+        let late = tx_manager.begin().unwrap();
+        assert_eq!(
+            heap.update(late, CommandId(1), updated, b"v3", false)
+                .unwrap(),
+            (UpdateResult::Updated(new_ctid.unwrap()), None)
+        );
+        assert_eq!(
+            heap.delete(late, CommandId(1), deleted, false).unwrap(),
+            UpdateResult::Deleted
+        );
+        assert_eq!(
+            heap.lock_tuple(late, updated, LockMode::ForShare, WaitPolicy::NoWait)
+                .unwrap(),
+            UpdateResult::Updated(new_ctid.unwrap())
+        );
+    }
+
+    #[test]
+    fn test_update_waits_for_writer() {
+        let temp_dir = TempDir::new().unwrap();
+        let (heap, _) = HeapRelation::create(temp_dir.path().to_path_buf(), 2).unwrap();
+        let heap = Arc::new(heap);
+        let tx_manager = heap.tx_manager.clone();
+
+        let setup = tx_manager.begin().unwrap();
+        let ctid = heap.insert(setup, CommandId(1), b"v1").unwrap();
+        tx_manager.commit(setup).unwrap();
+
+        for commit in [false, true] {
+            let first = tx_manager.begin().unwrap();
+            let (result, new_ctid) = heap.update(first, CommandId(1), ctid, b"v2", true).unwrap();
+            assert_eq!(result, UpdateResult::Ok);
+
+            let waiter = {
+                let heap = heap.clone();
+                std::thread::spawn(move || {
+                    let xid = heap.tx_manager.begin().unwrap();
+                    let result = heap.update(xid, CommandId(1), ctid, b"v3", true).unwrap();
+                    heap.tx_manager.abort(xid).unwrap();
+                    result
+                })
+            };
+
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            assert!(!waiter.is_finished());
+
+            if commit {
+                tx_manager.commit(first).unwrap();
+                assert_eq!(
+                    waiter.join().unwrap(),
+                    (UpdateResult::Updated(new_ctid.unwrap()), None)
+                );
+            } else {
+                tx_manager.abort(first).unwrap();
+                let (result, _) = waiter.join().unwrap();
+                assert_eq!(result, UpdateResult::Ok);
+            }
+        }
+    }
+
+    #[test]
+    fn test_follow_update_chain() {
+        let temp_dir = TempDir::new().unwrap();
+        let (mut engine, _) = HeapEngine::create(temp_dir.path().to_path_buf(), 2).unwrap();
+        let heap = &engine.heap;
+        let tx_manager = heap.tx_manager.clone();
+
+        let mut ctid = {
+            let xid = tx_manager.begin().unwrap();
+            let ctid = heap.insert(xid, CommandId(1), b"v0").unwrap();
+            tx_manager.commit(xid).unwrap();
+            ctid
+        };
+        let first = ctid;
+        for version in 1..=3u8 {
+            let xid = tx_manager.begin().unwrap();
+            let (result, new_ctid) = heap
+                .update(xid, CommandId(1), ctid, &[b'v', b'0' + version], true)
+                .unwrap();
+            assert_eq!(result, UpdateResult::Ok);
+            tx_manager.commit(xid).unwrap();
+            ctid = new_ctid.unwrap();
+        }
+
+        let (latest, tuple) = heap.follow_update_chain(first).unwrap().unwrap();
+        assert_eq!(latest, ctid);
+        assert_eq!(tuple.data, b"v3");
+
+        let pending = tx_manager.begin().unwrap();
+        heap.update(pending, CommandId(1), ctid, b"v4", true)
+            .unwrap();
+        assert_eq!(heap.follow_update_chain(first).unwrap().unwrap().0, ctid);
+        tx_manager.abort(pending).unwrap();
+
+        // READ COMMITTED: updating a stale version lands on the newest one.
+        engine.begin().unwrap();
+        let new_ctid = engine.update(first, b"v5").unwrap().unwrap();
+        assert!(!engine.delete(first).unwrap());
+        assert!(engine.delete(new_ctid).unwrap());
+        engine.commit().unwrap();
+
+        let rows = engine.scan().unwrap();
+        assert!(rows.is_empty());
+        assert_eq!(
+            engine.heap.follow_update_chain(first).unwrap().unwrap().0,
+            new_ctid
+        );
+    }
+
+    #[test]
+    fn test_isolation_level_snapshots() {
+        let temp_dir = TempDir::new().unwrap();
+        let (mut engine, _) = HeapEngine::create(temp_dir.path().to_path_buf(), 2).unwrap();
+        let tx_manager = engine.heap.tx_manager.clone();
+
+        let insert_committed = |heap: &HeapRelation, data: &[u8]| {
+            let xid = tx_manager.begin().unwrap();
+            heap.insert(xid, CommandId(1), data).unwrap();
+            tx_manager.commit(xid).unwrap();
+        };
+        insert_committed(&engine.heap, b"first");
+
+        let read_committed = Transaction::new(tx_manager.clone()).unwrap();
+        let repeatable_read = engine.begin_with(IsolationLevel::RepeatableRead).unwrap();
+        assert_eq!(repeatable_read.isolation, IsolationLevel::RepeatableRead);
+        assert_eq!(engine.scan().unwrap().len(), 1);
+
+        insert_committed(&engine.heap, b"second");
+        assert_eq!(engine.scan().unwrap().len(), 1);
+        assert_eq!(
+            engine
+                .heap
+                .scan(&read_committed.snapshot(), read_committed.xid())
+                .unwrap()
+                .len(),
+            2
+        );
+
+        engine.commit().unwrap();
+        read_committed.commit().unwrap();
+        assert_eq!(engine.scan().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_snapshot_export_import() {
+        let temp_dir = TempDir::new().unwrap();
+        let (mut engine, _) = HeapEngine::create(temp_dir.path().to_path_buf(), 2).unwrap();
+        let tx_manager = engine.heap.tx_manager.clone();
+
+        let insert_committed = |heap: &HeapRelation, data: &[u8]| {
+            let xid = tx_manager.begin().unwrap();
+            heap.insert(xid, CommandId(1), data).unwrap();
+            tx_manager.commit(xid).unwrap();
+        };
+        insert_committed(&engine.heap, b"first");
+
+        engine.begin_with(IsolationLevel::RepeatableRead).unwrap();
+        let id = engine.export_snapshot().unwrap();
+        insert_committed(&engine.heap, b"second");
+
+        let heap = &engine.heap;
+        let worker_rows = std::thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    let worker = Transaction::with_isolation(
+                        tx_manager.clone(),
+                        IsolationLevel::RepeatableRead,
+                    )
+                    .unwrap();
+                    assert_eq!(
+                        heap.scan(&worker.snapshot(), worker.xid()).unwrap().len(),
+                        2
+                    );
+                    worker.import_snapshot(&id).unwrap();
+                    let rows = heap.scan(&worker.snapshot(), worker.xid()).unwrap();
+                    worker.commit().unwrap();
+                    rows
+                })
+                .join()
+                .unwrap()
+        });
+        let rows = engine.scan().unwrap();
+        assert_eq!(worker_rows.len(), 1);
+        assert_eq!(rows.len(), 1);
+        assert_eq!(worker_rows[0].1.data, rows[0].1.data);
+
+        let read_committed = Transaction::new(tx_manager.clone()).unwrap();
+        assert!(read_committed.import_snapshot(&id).is_err());
+        read_committed.abort().unwrap();
+        let serializable =
+            Transaction::with_isolation(tx_manager.clone(), IsolationLevel::Serializable).unwrap();
+        assert!(serializable.import_snapshot(&id).is_err());
+        serializable.abort().unwrap();
+
+        // The snapshot goes away with the transaction that exported it.
+        engine.commit().unwrap();
+        let late = Transaction::with_isolation(tx_manager.clone(), IsolationLevel::RepeatableRead)
+            .unwrap();
+        assert!(matches!(
+            late.import_snapshot(&id),
+            Err(HeapError::InvalidTransaction(_))
+        ));
+        late.abort().unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_export_hides_exporter_changes() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = Database::open(temp_dir.path().to_path_buf()).unwrap();
+        db.create_relation("t", 1).unwrap();
+
+        let mut exporter = db.session();
+        exporter.begin_with(IsolationLevel::RepeatableRead).unwrap();
+        exporter.insert("t", b"top").unwrap();
+        exporter.savepoint("sub").unwrap();
+        exporter.insert("t", b"sub").unwrap();
+        let id = exporter.transaction().unwrap().export_snapshot().unwrap();
+
+        let mut importer = db.session();
+        importer.begin_with(IsolationLevel::RepeatableRead).unwrap();
+        importer
+            .transaction()
+            .unwrap()
+            .import_snapshot(&id)
+            .unwrap();
+        assert!(importer.scan("t").unwrap().is_empty());
+
+        // Still running as far as the imported snapshot is concerned, the
+        // subtransaction's rows included.
+        exporter.release("sub").unwrap();
+        exporter.commit().unwrap();
+        assert!(importer.scan("t").unwrap().is_empty());
+        importer.commit().unwrap();
+        assert_eq!(db.session().scan("t").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_repeatable_read_update_conflict() {
+        let temp_dir = TempDir::new().unwrap();
+        let (mut engine, _) = HeapEngine::create(temp_dir.path().to_path_buf(), 2).unwrap();
+        let tx_manager = engine.heap.tx_manager.clone();
+
+        let setup = tx_manager.begin().unwrap();
+        let ctid = engine.heap.insert(setup, CommandId(1), b"v1").unwrap();
+        tx_manager.commit(setup).unwrap();
+
+        for isolation in [
+            IsolationLevel::ReadCommitted,
+            IsolationLevel::RepeatableRead,
+        ] {
+            engine.begin_with(isolation).unwrap();
+            let rows = engine.scan().unwrap();
+            assert_eq!(rows.len(), 1);
+            let target = rows[0].0;
+
+            let other = tx_manager.begin().unwrap();
+            engine
+                .heap
+                .update(other, CommandId(1), target, b"other", true)
+                .unwrap();
+            tx_manager.commit(other).unwrap();
+
+            let result = engine.update(target, b"mine");
+            if isolation == IsolationLevel::ReadCommitted {
+                assert!(result.unwrap().is_some());
+                engine.commit().unwrap();
+            } else {
+                assert!(matches!(result, Err(HeapError::SerializationFailure(_))));
+                assert!(matches!(
+                    engine.delete(target),
+                    Err(HeapError::SerializationFailure(_))
+                ));
+                engine.abort().unwrap();
+            }
+        }
+
+        let rows = engine.scan().unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].1.data, b"other");
+        assert_ne!(rows[0].0, ctid);
+    }
+
+    #[test]
+    fn test_serializable_write_skew() {
+        let temp_dir = TempDir::new().unwrap();
+        let (mut engine, rel_node) = HeapEngine::create(temp_dir.path().to_path_buf(), 2).unwrap();
+        let tx_manager = engine.heap.tx_manager.clone();
+
+        let setup = tx_manager.begin().unwrap();
+        let alice = engine
+            .heap
+            .insert(setup, CommandId(1), b"alice on")
+            .unwrap();
+        let bob = engine.heap.insert(setup, CommandId(1), b"bob on").unwrap();
+        tx_manager.commit(setup).unwrap();
+
+        // Both doctors check that someone else is on call, then go off call.
+        let first = engine.begin_with(IsolationLevel::Serializable).unwrap();
+        assert_eq!(engine.scan().unwrap().len(), 2);
+
+        let second =
+            Transaction::with_isolation(tx_manager.clone(), IsolationLevel::Serializable).unwrap();
+        let predicate_locks = tx_manager.predicate_locks();
+        predicate_locks
+            .lock(second.xid(), PredicateLockTag::Relation(rel_node))
+            .unwrap();
+        predicate_locks
+            .check_conflict_in(second.xid(), PredicateLockTag::Tuple(rel_node, bob))
+            .unwrap();
+        engine
+            .heap
+            .update(second.xid(), second.get_cid(), bob, b"bob off", true)
+            .unwrap();
+
+        engine.update(alice, b"alice off").unwrap();
+
+        second.commit().unwrap();
+        assert!(matches!(
+            engine.commit(),
+            Err(HeapError::SerializationFailure(_))
+        ));
+        assert!(tx_manager.did_abort(first.xid()));
+        assert!(predicate_locks.held_locks(first.xid()).is_empty());
+
+        // A reader alone in front of one writer serializes fine.
+        engine.begin_with(IsolationLevel::Serializable).unwrap();
+        let rows = engine.scan().unwrap();
+        assert_eq!(rows.len(), 2);
+        let writer =
+            Transaction::with_isolation(tx_manager.clone(), IsolationLevel::Serializable).unwrap();
+        predicate_locks
+            .check_conflict_in(writer.xid(), PredicateLockTag::Tuple(rel_node, rows[0].0))
+            .unwrap();
+        writer.commit().unwrap();
+        engine.commit().unwrap();
+    }
+
+    #[test]
+    fn test_predicate_lock_promotion() {
+        let manager = PredicateLockManager::new();
+        let (xid, rel_node) = (TransactionId(10), 7);
+        let tuple = |block_number, offset_number| {
+            PredicateLockTag::Tuple(
+                rel_node,
+                ItemPointerData {
+                    block_number,
+                    offset_number,
+                },
+            )
+        };
+
+        manager.lock(TransactionId(99), tuple(0, 1)).unwrap();
+        assert!(manager.held_locks(TransactionId(99)).is_empty());
+
+        manager.register(xid);
+        for offset in 1..=PREDICATE_LOCKS_PER_PAGE as u16 {
+            manager.lock(xid, tuple(0, offset)).unwrap();
+        }
+        assert_eq!(manager.held_locks(xid).len(), PREDICATE_LOCKS_PER_PAGE);
+
+        manager.lock(xid, tuple(0, 100)).unwrap();
+        assert_eq!(
+            manager.held_locks(xid),
+            vec![PredicateLockTag::Page(rel_node, 0)]
+        );
+        manager.lock(xid, tuple(0, 101)).unwrap();
+        assert_eq!(manager.held_locks(xid).len(), 1);
+
+        for block in 1..=PREDICATE_LOCKS_PER_RELATION as u32 {
+            manager
+                .lock(xid, PredicateLockTag::Page(rel_node, block))
+                .unwrap();
+        }
+        assert_eq!(
+            manager.held_locks(xid),
+            vec![PredicateLockTag::Relation(rel_node)]
+        );
+
+        manager.abort(xid);
+        assert!(!manager.is_registered(xid));
+    }
+
+    #[test]
+    fn test_table_lock_conflicts() {
+        use TableLockMode::*;
+        let modes = [
+            AccessShare,
+            RowShare,
+            RowExclusive,
+            ShareUpdateExclusive,
+            Share,
+            ShareRowExclusive,
+            Exclusive,
+            AccessExclusive,
+        ];
+        let conflicts: [u8; 8] = [
+            0b1000_0000,
+            0b1100_0000,
+            0b1111_0000,
+            0b1111_1000,
+            0b1110_1100,
+            0b1111_1100,
+            0b1111_1110,
+            0b1111_1111,
+        ];
+
+        for (i, &mode) in modes.iter().enumerate() {
+            for (j, &other) in modes.iter().enumerate() {
+                let expected = conflicts[i] & (1 << j) != 0;
+                assert_eq!(
+                    mode.conflicts_with(other),
+                    expected,
+                    "{} vs {}",
+                    mode,
+                    other
+                );
+                assert_eq!(mode.conflicts_with(other), other.conflicts_with(mode));
+            }
+        }
+    }
+
+    #[test]
+    fn test_lock_wait_queue_and_timeout() {
+        let locks = Arc::new(LockManager::new());
+        let tag = LockTag::Relation(1);
+        let (first, second, third) = (TransactionId(10), TransactionId(11), TransactionId(12));
+
+        assert!(locks
+            .acquire(first, tag, TableLockMode::AccessShare, WaitPolicy::Block)
+            .unwrap());
+        assert!(!locks
+            .acquire(
+                second,
+                tag,
+                TableLockMode::AccessExclusive,
+                WaitPolicy::SkipLocked
+            )
+            .unwrap());
+
+        let waiter = {
+            let locks = locks.clone();
+            std::thread::spawn(move || {
+                locks.acquire(
+                    second,
+                    tag,
+                    TableLockMode::AccessExclusive,
+                    WaitPolicy::Block,
+                )
+            })
+        };
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(!waiter.is_finished());
+
+        // Compatible with the holder, but queued behind the waiting exclusive request.
+        assert!(matches!(
+            locks.acquire(third, tag, TableLockMode::AccessShare, WaitPolicy::NoWait),
+            Err(HeapError::LockError(_))
+        ));
+        assert!(locks
+            .acquire(first, tag, TableLockMode::RowShare, WaitPolicy::NoWait)
+            .unwrap());
+
+        locks.release_all(first);
+        assert!(waiter.join().unwrap().unwrap());
+        assert_eq!(
+            locks.held_locks(second),
+            vec![(tag, TableLockMode::AccessExclusive)]
+        );
+
+        locks.set_lock_timeout(Some(std::time::Duration::from_millis(30)));
+        let started = std::time::Instant::now();
+        let result = locks.acquire(third, tag, TableLockMode::AccessShare, WaitPolicy::Block);
+        assert!(matches!(result, Err(HeapError::LockError(ref m)) if m.contains("lock timeout")));
+        assert!(started.elapsed() >= std::time::Duration::from_millis(30));
+
+        locks.release(second, tag);
+        assert!(locks
+            .acquire(third, tag, TableLockMode::AccessShare, WaitPolicy::Block)
+            .unwrap());
+    }
+
+    #[test]
+    fn test_deadlock_aborts_victim() {
+        let manager = Arc::new(TransactionManager::new());
+        let (first_rel, second_rel) = (LockTag::Relation(1), LockTag::Relation(2));
+
+        let first = manager.begin().unwrap();
+        let second = manager.begin().unwrap();
+        manager
+            .lock(
+                first,
+                first_rel,
+                TableLockMode::Exclusive,
+                WaitPolicy::Block,
+            )
+            .unwrap();
+        manager
+            .lock(
+                second,
+                second_rel,
+                TableLockMode::Exclusive,
+                WaitPolicy::Block,
+            )
+            .unwrap();
+
+        let blocked = {
+            let manager = manager.clone();
+            std::thread::spawn(move || {
+                manager.lock(
+                    second,
+                    first_rel,
+                    TableLockMode::Exclusive,
+                    WaitPolicy::Block,
+                )
+            })
+        };
+        std::thread::sleep(std::time::Duration::from_millis(50));
+
+        let result = manager.lock(
+            first,
+            second_rel,
+            TableLockMode::Exclusive,
+            WaitPolicy::Block,
+        );
+        match result {
+            Err(HeapError::Deadlock(message)) => {
+                assert!(message.contains(&first.to_string()));
+                assert!(message.contains(&second.to_string()));
+            }
+            other => panic!("expected deadlock, got {:?}", other),
+        }
+        assert!(manager.did_abort(first));
+        assert!(manager.abort(first).is_ok());
+        assert!(manager.commit(first).is_err());
+
+        assert!(blocked.join().unwrap().unwrap());
+        manager.commit(second).unwrap();
+        assert!(manager.is_committed(second));
+    }
+
+    #[test]
+    fn test_row_lock_deadlock() {
+        let temp_dir = TempDir::new().unwrap();
+        let (heap, _) = HeapRelation::create(temp_dir.path().to_path_buf(), 2).unwrap();
+        let heap = Arc::new(heap);
+        let tx_manager = heap.tx_manager.clone();
+
+        let setup = tx_manager.begin().unwrap();
+        let first_row = heap.insert(setup, CommandId(1), b"a").unwrap();
+        let second_row = heap.insert(setup, CommandId(1), b"b").unwrap();
+        tx_manager.commit(setup).unwrap();
+
+        let first = tx_manager.begin().unwrap();
+        let second = tx_manager.begin().unwrap();
+        heap.lock_tuple(first, first_row, LockMode::ForUpdate, WaitPolicy::Block)
+            .unwrap();
+        heap.lock_tuple(second, second_row, LockMode::ForUpdate, WaitPolicy::Block)
+            .unwrap();
+
+        let blocked = {
+            let heap = heap.clone();
+            std::thread::spawn(move || {
+                heap.lock_tuple(second, first_row, LockMode::ForUpdate, WaitPolicy::Block)
+            })
+        };
+        std::thread::sleep(std::time::Duration::from_millis(50));
+
+        let result = heap.lock_tuple(first, second_row, LockMode::ForUpdate, WaitPolicy::Block);
+        assert!(matches!(result, Err(HeapError::Deadlock(_))));
+        assert!(tx_manager.did_abort(first));
+
+        assert_eq!(blocked.join().unwrap().unwrap(), UpdateResult::Ok);
+        assert_eq!(heap.get(first_row).unwrap().unwrap().xmax(), second);
+    }
+
+    #[test]
+    fn test_heap_engine_relation_locks() {
+        let temp_dir = TempDir::new().unwrap();
+        let (mut engine, rel_node) = HeapEngine::create(temp_dir.path().to_path_buf(), 2).unwrap();
+        let tx_manager = engine.heap.tx_manager.clone();
+        let tag = LockTag::Relation(rel_node);
+
+        engine.begin().unwrap();
+        engine.insert(b"row").unwrap();
+        let xid = engine.current_tx.as_ref().unwrap().xid();
+        assert_eq!(
+            tx_manager.lock_manager().held_locks(xid),
+            vec![
+                (tag, TableLockMode::RowExclusive),
+                (LockTag::Transaction(xid), TableLockMode::Exclusive)
+            ]
+        );
+        assert!(matches!(
+            engine.vacuum(),
+            Err(HeapError::InvalidOperation(_))
+        ));
+        engine.commit().unwrap();
+        assert!(tx_manager.lock_manager().held_locks(xid).is_empty());
+
+        let other = tx_manager.begin().unwrap();
+        tx_manager
+            .lock(
+                other,
+                tag,
+                TableLockMode::AccessExclusive,
+                WaitPolicy::Block,
+            )
+            .unwrap();
+
+        engine.set_lock_timeout(Some(std::time::Duration::from_millis(30)));
+        assert!(matches!(engine.scan(), Err(HeapError::LockError(_))));
+        assert!(matches!(engine.vacuum(), Err(HeapError::LockError(_))));
+
+        tx_manager.commit(other).unwrap();
+        assert_eq!(engine.scan().unwrap().len(), 1);
+        assert_eq!(engine.vacuum().unwrap(), 0);
+
+        engine.begin().unwrap();
+        assert!(engine
+            .lock_table(TableLockMode::Share, WaitPolicy::NoWait)
+            .unwrap());
+        let writer = tx_manager.begin().unwrap();
+        assert!(!tx_manager
+            .lock(
+                writer,
+                tag,
+                TableLockMode::RowExclusive,
+                WaitPolicy::SkipLocked
+            )
+            .unwrap());
+        engine.commit().unwrap();
+        assert!(tx_manager
+            .lock(writer, tag, TableLockMode::RowExclusive, WaitPolicy::NoWait)
+            .unwrap());
+    }
+
+    #[test]
+    fn test_heap_engine_reads_take_no_xid() {
+        let temp_dir = TempDir::new().unwrap();
+        let (mut engine, rel_node) = HeapEngine::create(temp_dir.path().to_path_buf(), 2).unwrap();
+        let tx_manager = engine.heap.tx_manager.clone();
+        let wal = tx_manager.wal().unwrap().clone();
+
+        engine.begin().unwrap();
+        let row = engine.insert(b"row").unwrap();
+        engine.commit().unwrap();
+
+        let next_xid = tx_manager.current_xid();
+        let lsn = wal.get_lsn();
+        let flushes = wal.flush_count();
+        assert_eq!(engine.scan().unwrap().len(), 1);
+        assert!(engine.get(row).unwrap().is_some());
+        assert_eq!(tx_manager.current_xid(), next_xid);
+        assert_eq!(wal.get_lsn(), lsn);
+        assert_eq!(wal.flush_count(), flushes);
+
+        // A virtual owner still waits behind conflicting locks, and holds
+        // nothing once its statement ends.
+        let other = tx_manager.begin().unwrap();
+        let tag = LockTag::Relation(rel_node);
+        tx_manager
+            .lock(
+                other,
+                tag,
+                TableLockMode::AccessExclusive,
+                WaitPolicy::Block,
+            )
+            .unwrap();
+        engine.set_lock_timeout(Some(std::time::Duration::from_millis(30)));
+        assert!(matches!(engine.scan(), Err(HeapError::LockError(_))));
+        tx_manager.commit(other).unwrap();
+
+        let vxact = VirtualTransaction::new(tx_manager.clone());
+        let snapshot = vxact.snapshot();
+        assert_eq!(tx_manager.snapshot_xmin(vxact.owner), Some(snapshot.xmin));
+        assert!(matches!(vxact.owner, LockOwner::Virtual(_)));
+        let owner = vxact.owner;
+        drop(vxact);
+        assert_eq!(tx_manager.snapshot_xmin(owner), None);
+        assert!(tx_manager.lock_manager().held_locks(owner).is_empty());
+    }
+
+    #[test]
+    fn test_heap_engine_full_workflow() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().to_path_buf();
+
+        let (mut engine, rel_id) = HeapEngine::create(path, 2).unwrap();
+        assert!(rel_id > 0);
+
+        engine.begin().unwrap();
+
+        let ctid1 = engine.insert(b"row1").unwrap();
+        let ctid2 = engine.insert(b"row2").unwrap();
+        let _ctid3 = engine.insert(b"row3").unwrap();
+
+        let results = engine.scan().unwrap();
+        assert_eq!(results.len(), 3);
+
+        let updated = engine.update(ctid1, b"row1_updated").unwrap();
+        assert!(updated.is_some());
+
+        let deleted = engine.delete(ctid2).unwrap();
+        assert!(deleted);
+
+        engine.commit().unwrap();
+
+        let results = engine.scan().unwrap();
+        assert_eq!(results.len(), 2);
+
+        engine.close().unwrap();
+    }
+
+    #[test]
+    fn test_mvcc_isolation() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().to_path_buf();
+
+        let tx_manager = Arc::new(TransactionManager::new());
+
+        let heap = HeapRelation {
+            relation: Relation::open(path.clone()).unwrap(),
+            natts: 2,
+            tx_manager: tx_manager.clone(),
+        };
+
+        let xid = tx_manager.begin().unwrap();
+        let _ctid = heap.insert(xid, CommandId(1), b"data").unwrap();
+        tx_manager.commit(xid).unwrap();
+
+        let snapshot = Snapshot::new(1, 200, vec![], 10, VisibilityMode::MVCC);
+        let results = heap.scan(&snapshot, TransactionId(150)).unwrap();
+
+        assert_eq!(results.len(), 1);
+    }
+
+    #[test]
+    fn test_concurrent_transactions() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().to_path_buf();
+        let db = Database::open(path.clone()).unwrap();
+        db.create_relation("t", 1).unwrap();
+
+        // A second open of the live directory would hand out the same xids.
+        assert!(TransactionManager::open(path.clone()).is_err());
+        assert!(Database::open(path).is_err());
+
+        let inserted = std::sync::Barrier::new(2);
+        let scanned = std::sync::Barrier::new(2);
+        let xids = std::thread::scope(|scope| {
+            let writer = scope.spawn(|| {
+                let mut session = db.session();
+                let xid = session.begin().unwrap().xid();
+                session.insert("t", b"tx1_data").unwrap();
+                inserted.wait();
+                scanned.wait();
+                session.commit().unwrap();
+                xid
+            });
+            let reader = scope.spawn(|| {
+                let mut session = db.session();
+                let xid = session.begin().unwrap().xid();
+                inserted.wait();
+                assert!(session.scan("t").unwrap().is_empty());
+                scanned.wait();
+                session.commit().unwrap();
+                xid
+            });
+            [writer.join().unwrap(), reader.join().unwrap()]
+        });
+        assert_ne!(xids[0], xids[1]);
+        assert_eq!(db.session().scan("t").unwrap().len(), 1);
+
+        // Many sessions at once each get their own xid and their own row.
+        let xids: Vec<TransactionId> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..8)
+                .map(|i| {
+                    let db = &db;
+                    scope.spawn(move || {
+                        let mut session = db.session();
+                        let xid = session.begin().unwrap().xid();
+                        session.insert("t", format!("row{}", i).as_bytes()).unwrap();
+                        session.commit().unwrap();
+                        xid
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        let distinct: std::collections::HashSet<_> = xids.iter().collect();
+        assert_eq!(distinct.len(), 8);
+        assert_eq!(db.session().scan("t").unwrap().len(), 9);
+    }
+
+    #[test]
+    fn test_page_header_flags() {
+        let mut page = Page::new(8192);
+
+        assert!(!page.header.has_free_lines());
+        page.header.set_has_free_lines(true);
+        assert!(page.header.has_free_lines());
+
+        assert!(!page.header.is_page_full());
+        page.header.set_page_full(true);
+        assert!(page.header.is_page_full());
+
+        assert!(!page.header.all_visible());
+        page.header.set_all_visible(true);
+        assert!(page.header.all_visible());
+    }
+
+    #[test]
+    fn test_item_id_data() {
+        let mut item_id = ItemIdData::new();
+
+        item_id.set(100, 50, LP_USED);
 
-        assert!(!Visibility::heap_tuple_satisfies_any(&heap_tuple));
+        assert_eq!(item_id.offset(), 100);
+        assert_eq!(item_id.length(), 50);
+        assert_eq!(item_id.flags(), LP_USED);
+        assert!(item_id.is_used());
+    }
+
+    #[test]
+    fn test_heap_tuple_ctid() {
+        let mut heap_tuple = HeapTuple::new(2);
+
+        let ctid = ItemPointerData {
+            block_number: 5,
+            offset_number: 10,
+        };
+
+        heap_tuple.set_ctid(ctid);
+
+        let retrieved_ctid = heap_tuple.ctid();
+        assert_eq!(retrieved_ctid.block_number, 5);
+        assert_eq!(retrieved_ctid.offset_number, 10);
+    }
+
+    #[test]
+    fn test_visibility_any() {
+        let tx_manager = TransactionManager::new();
+        let oracle = tx_manager.oracle(TransactionId::invalid());
+        let mut heap_tuple = HeapTuple::new(1);
+        heap_tuple.header.t_xmax = 0;
+
+        assert!(Visibility::heap_tuple_satisfies_any(&heap_tuple, &oracle));
+
+        heap_tuple.header.t_xmax = 100;
+        heap_tuple.header.set_xmax_committed(true);
+
+        assert!(!Visibility::heap_tuple_satisfies_any(&heap_tuple, &oracle));
     }
 
     #[test]
@@ -537,13 +2717,32 @@ mod tests {
         let path = temp_dir.path().to_path_buf();
 
         let (index, _) = BTreeIndex::create(path).unwrap();
-        let ctid = ItemPointerData {
-            block_number: 1,
+        let key = |i: u32| format!("{:0>48}", i).into_bytes();
+        let ctid = |i: u32| ItemPointerData {
+            block_number: i,
             offset_number: 1,
         };
 
-        let result = index.insert(b"key1".to_vec(), ctid);
-        assert!(result.is_ok() || result.is_err());
+        // Enough keys, out of order, to split leaves and then the root.
+        for i in 0..500 {
+            let i = i * 7 % 500;
+            index.insert(key(i), ctid(i)).unwrap();
+        }
+        index.insert(key(42), ctid(1000)).unwrap();
+        assert!(index.relation.page_count() > 3);
+
+        assert_eq!(index.search(&key(0)).unwrap(), vec![ctid(0)]);
+        assert_eq!(index.search(&key(499)).unwrap(), vec![ctid(499)]);
+        assert_eq!(index.search(&key(42)).unwrap(), vec![ctid(42), ctid(1000)]);
+        assert!(index.search(&key(500)).unwrap().is_empty());
+
+        assert!(index.delete(&key(42)).unwrap());
+        assert!(index.search(&key(42)).unwrap().is_empty());
+        assert!(!index.delete(&key(42)).unwrap());
+        assert_eq!(index.search(&key(43)).unwrap(), vec![ctid(43)]);
+
+        let result = index.insert(vec![0u8; BTREE_MAX_KEY_SIZE + 1], ctid(0));
+        assert!(matches!(result, Err(HeapError::InvalidOperation(_))));
     }
 
     #[test]
@@ -552,9 +2751,81 @@ mod tests {
         let path = temp_dir.path().to_path_buf();
 
         let (index, _) = BTreeIndex::create(path).unwrap();
+        assert!(index.scan().unwrap().is_empty());
+
+        let key = |i: u32| format!("{:0>48}", i).into_bytes();
+        for i in (0..300).rev() {
+            let ctid = ItemPointerData {
+                block_number: i,
+                offset_number: 1,
+            };
+            index.insert(key(i), ctid).unwrap();
+        }
+
+        let scanned = index.scan().unwrap();
+        assert_eq!(scanned.len(), 300);
+        for (i, (k, ctid)) in scanned.into_iter().enumerate() {
+            assert_eq!(k, key(i as u32));
+            assert_eq!(ctid.block_number, i as u32);
+        }
+    }
+
+    #[test]
+    fn test_btree_redo() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().to_path_buf();
+        let key = |i: u32| format!("{:0>48}", i).into_bytes();
+        let ctid = |i: u32| ItemPointerData {
+            block_number: i,
+            offset_number: 1,
+        };
+        let expected = |range: std::ops::Range<u32>| -> Vec<(Vec<u8>, ItemPointerData)> {
+            range
+                .filter(|&i| i != 7)
+                .map(|i| (key(i), ctid(i)))
+                .collect()
+        };
+
+        // Crash with none of the node writes on disk: redo rebuilds every
+        // node from its record, splits included.
+        {
+            let (index, _) = BTreeIndex::create(path.clone()).unwrap();
+            index.tx_manager.set_full_page_writes(false).unwrap();
+            let empty_root = std::fs::read(path.join("0.dat")).unwrap();
+            for i in 0..300 {
+                index.insert(key(i), ctid(i)).unwrap();
+            }
+            assert!(index.delete(&key(7)).unwrap());
+            assert!(index.relation.page_count() > 1);
+            crash(&path, index);
+
+            for entry in std::fs::read_dir(&path).unwrap() {
+                let name = entry.unwrap().file_name().into_string().unwrap();
+                if name.ends_with(".dat") && name != "0.dat" {
+                    std::fs::remove_file(path.join(name)).unwrap();
+                }
+            }
+            std::fs::write(path.join("0.dat"), empty_root).unwrap();
+        }
 
-        let result = index.scan();
-        assert!(result.is_ok() || result.is_err());
+        let index = BTreeIndex::open(path.clone()).unwrap();
+        assert_eq!(index.scan().unwrap(), expected(0..300));
+        assert_eq!(index.search(&key(8)).unwrap(), vec![ctid(8)]);
+
+        // Crash with the node writes on disk: their LSNs tell redo to skip
+        // the records.
+        for i in 300..400 {
+            index.insert(key(i), ctid(i)).unwrap();
+        }
+        crash(&path, index);
+
+        let tx_manager = Arc::new(TransactionManager::open(path.clone()).unwrap());
+        let index = BTreeIndex::open_with(path, tx_manager.clone()).unwrap();
+        let mut recovery = Recovery::new(&tx_manager);
+        recovery.add_relation(&index.relation);
+        let stats = recovery.run().unwrap();
+        assert!(stats.skipped >= 100);
+        assert_eq!(index.scan().unwrap(), expected(0..400));
     }
 
     #[test]
@@ -570,6 +2841,352 @@ mod tests {
         assert!(lsn > 0);
     }
 
+    #[test]
+    fn test_group_and_async_commit() {
+        let temp_dir = TempDir::new().unwrap();
+        let manager = Arc::new(TransactionManager::open(temp_dir.path().to_path_buf()).unwrap());
+        let wal = manager.wal().unwrap().clone();
+
+        let xid = manager.begin().unwrap();
+        manager.commit(xid).unwrap();
+        assert_eq!(wal.flushed_lsn(), wal.get_lsn());
+
+        // Commits arriving together share the leader's fsync.
+        manager.set_commit_delay(std::time::Duration::from_millis(50));
+        let flushes = wal.flush_count();
+        let committers = 8;
+        let barrier = Arc::new(std::sync::Barrier::new(committers));
+        let handles: Vec<_> = (0..committers)
+            .map(|_| {
+                let manager = manager.clone();
+                let barrier = barrier.clone();
+                std::thread::spawn(move || {
+                    let xid = manager.begin().unwrap();
+                    barrier.wait();
+                    manager.commit(xid).unwrap();
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert!(wal.flush_count() - flushes < committers as u64);
+        assert_eq!(wal.flushed_lsn(), wal.get_lsn());
+
+        // An asynchronous commit returns before its record is flushed; the
+        // WAL writer catches up within its delay.
+        manager.set_commit_delay(std::time::Duration::ZERO);
+        let tx = Transaction::new(manager.clone()).unwrap();
+        let xid = tx.xid();
+        tx.set_synchronous_commit(false);
+        tx.commit().unwrap();
+        let commit_lsn = wal.get_lsn();
+        assert!(manager.is_committed(xid));
+        assert!(wal.flushed_lsn() < commit_lsn);
+
+        let deadline =
+            std::time::Instant::now() + std::time::Duration::from_millis(5 * WAL_WRITER_DELAY_MS);
+        while wal.flushed_lsn() < commit_lsn {
+            assert!(std::time::Instant::now() < deadline);
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_async_commit_clog_waits_for_wal() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path().to_path_buf();
+        let tx_manager = TransactionManager::open(dir.clone()).unwrap();
+        let xid = tx_manager.begin().unwrap();
+
+        // The commit record goes into the WAL buffer and the clog page out to
+        // disk; the process dies on the next write, before the WAL writer
+        // gets to flush the record.
+        failpoint::kill_after(&dir, 2);
+        tx_manager.commit_with(xid, false).unwrap();
+        let _ = tx_manager.flush();
+        drop(tx_manager);
+        failpoint::revive(&dir);
+
+        // The clog page could only be written once the record was durable,
+        // so the commit survives with it.
+        let wal = WAL::new(dir.clone()).unwrap();
+        let logged = wal.recover().unwrap().iter().any(|record| {
+            record.record_type == XLogRecordType::TransactionCommit && record.txid == xid.0
+        });
+        drop(wal);
+        assert!(logged);
+        let tx_manager = TransactionManager::open(dir).unwrap();
+        assert!(tx_manager.is_committed(xid));
+    }
+
+    #[test]
+    fn test_database_sessions() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().to_path_buf();
+
+        {
+            // A pool smaller than the data forces dirty pages to be evicted.
+            let db = Database::open_with(path.clone(), 4).unwrap();
+            let accounts = db.create_relation("accounts", 2).unwrap();
+            let audit = db.create_relation("audit", 2).unwrap();
+            assert!(Arc::ptr_eq(&accounts.tx_manager, &audit.tx_manager));
+            assert!(matches!(
+                db.create_relation("accounts", 2),
+                Err(HeapError::InvalidOperation(_))
+            ));
+            assert!(db.relation("missing").is_err());
+
+            let mut s1 = db.session();
+            s1.begin().unwrap();
+            s1.insert("accounts", b"alice").unwrap();
+            s1.insert("audit", b"opened alice").unwrap();
+            assert!(s1.begin().is_err());
+
+            let mut s2 = db.session();
+            let reader = std::thread::spawn(move || {
+                s2.begin().unwrap();
+                let rows = s2.scan("accounts").unwrap().len() + s2.scan("audit").unwrap().len();
+                s2.commit().unwrap();
+                rows
+            });
+            assert_eq!(reader.join().unwrap(), 0);
+            s1.commit().unwrap();
+
+            let writers: Vec<_> = (0..4)
+                .map(|i| {
+                    let mut session = db.session();
+                    std::thread::spawn(move || {
+                        for j in 0..50 {
+                            session.begin().unwrap();
+                            let data = format!("account-{}-{}-{}", i, j, "x".repeat(200));
+                            session.insert("accounts", data.as_bytes()).unwrap();
+                            session.insert("audit", b"opened").unwrap();
+                            session.commit().unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for writer in writers {
+                writer.join().unwrap();
+            }
+
+            let mut s3 = db.session();
+            s3.begin().unwrap();
+            assert_eq!(s3.scan("accounts").unwrap().len(), 201);
+            assert_eq!(s3.scan("audit").unwrap().len(), 201);
+            s3.commit().unwrap();
+            assert!(accounts.relation.page_count() > db.buffer_pool().num_buffers() as u32);
+
+            db.create_relation("scratch", 1).unwrap();
+            db.drop_relation("scratch").unwrap();
+            assert!(db.relation("scratch").is_err());
+        }
+
+        let db = Database::open(path).unwrap();
+        assert_eq!(db.relation_names(), vec!["accounts", "audit"]);
+        let mut session = db.session();
+        session.begin().unwrap();
+        assert_eq!(session.scan("accounts").unwrap().len(), 201);
+        assert_eq!(session.scan("audit").unwrap().len(), 201);
+        session.commit().unwrap();
+    }
+    #[test]
+    fn test_visibility_consults_transaction_status() {
+        let temp_dir = TempDir::new().unwrap();
+        let (heap, _) = HeapRelation::create(temp_dir.path().to_path_buf(), 2).unwrap();
+        let tx_manager = heap.tx_manager.clone();
+
+        let insert_committed = |data: &[u8]| {
+            let xid = tx_manager.begin().unwrap();
+            let ctid = heap.insert(xid, CommandId(1), data).unwrap();
+            tx_manager.commit(xid).unwrap();
+            ctid
+        };
+
+        let live = insert_committed(b"live");
+
+        let aborted = tx_manager.begin().unwrap();
+        let aborted_insert = heap.insert(aborted, CommandId(1), b"aborted").unwrap();
+        tx_manager.abort(aborted).unwrap();
+
+        let kept = insert_committed(b"kept");
+        let deleter = tx_manager.begin().unwrap();
+        heap.delete(deleter, CommandId(1), kept, false).unwrap();
+        tx_manager.abort(deleter).unwrap();
+
+        let gone = insert_committed(b"gone");
+        let deleter = tx_manager.begin().unwrap();
+        heap.delete(deleter, CommandId(1), gone, false).unwrap();
+        tx_manager.commit(deleter).unwrap();
+
+        // Nothing has set hint bits, so every answer comes from the oracle.
+        let snapshot = tx_manager.get_snapshot(CommandId(1));
+        let oracle = tx_manager.oracle(TransactionId::invalid());
+        let combo_cids = ComboCids::new();
+        for (ctid, expected) in [
+            (live, true),
+            (aborted_insert, false),
+            (kept, true),
+            (gone, false),
+        ] {
+            let tuple = heap.get(ctid).unwrap().unwrap();
+            assert!(!tuple.header.xmin_committed() && !tuple.header.xmin_invalid());
+            assert_eq!(
+                Visibility::heap_tuple_satisfies_mvcc(&tuple, &snapshot, &oracle, &combo_cids),
+                expected
+            );
+            assert_eq!(
+                Visibility::heap_tuple_satisfies_non_vacuumable(
+                    &tuple,
+                    tx_manager.oldest_xmin(),
+                    &oracle
+                ),
+                expected
+            );
+            assert_eq!(
+                Visibility::heap_tuple_satisfies_self(&tuple, &oracle, CommandId(1), &combo_cids),
+                expected
+            );
+        }
+        let gone_tuple = heap.get(gone).unwrap().unwrap();
+        assert!(!Visibility::heap_tuple_satisfies_any(&gone_tuple, &oracle));
+        let kept_tuple = heap.get(kept).unwrap().unwrap();
+        assert!(Visibility::heap_tuple_satisfies_any(&kept_tuple, &oracle));
+
+        let rows = heap.scan(&snapshot, TransactionId::invalid()).unwrap();
+        let mut ctids: Vec<_> = rows.iter().map(|(ctid, _)| *ctid).collect();
+        ctids.sort_by_key(|ctid| ctid.offset_number);
+        assert_eq!(ctids, vec![live, kept]);
+
+        // A rolled-back savepoint's insert disappears for its own transaction.
+        let tx = Transaction::new(tx_manager.clone()).unwrap();
+        tx.savepoint("s1").unwrap();
+        heap.insert(tx.current_xid(), tx.get_cid(), b"sub").unwrap();
+        tx.command_counter_increment().unwrap();
+        assert_eq!(heap.scan(&tx.snapshot(), tx.xid()).unwrap().len(), 3);
+        tx.rollback_to("s1").unwrap();
+        assert_eq!(heap.scan(&tx.snapshot(), tx.xid()).unwrap().len(), 2);
+        tx.commit().unwrap();
+    }
+    #[test]
+    fn test_hint_bit_write_back() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().to_path_buf();
+
+        {
+            let (heap, _) = HeapRelation::create(path.clone(), 2).unwrap();
+            heap.tx_manager.set_data_checksums(true).unwrap();
+            heap.close().unwrap();
+        }
+        let heap = HeapRelation::open(path, 2).unwrap();
+        let tx_manager = heap.tx_manager.clone();
+        let wal = tx_manager.wal().unwrap().clone();
+        assert!(heap.relation.data_checksums());
+
+        let fpi_count = || {
+            wal.recover()
+                .unwrap()
+                .iter()
+                .filter(|r| r.record_type == XLogRecordType::FpiForHint)
+                .count()
+        };
+        let insert = |data: &[u8], commit: bool| {
+            let xid = tx_manager.begin().unwrap();
+            let ctid = heap.insert(xid, CommandId(1), data).unwrap();
+            if commit {
+                tx_manager.commit(xid).unwrap();
+            } else {
+                tx_manager.abort(xid).unwrap();
+            }
+            ctid
+        };
+        let scan = || {
+            heap.scan(
+                &tx_manager.get_snapshot(CommandId(1)),
+                TransactionId::invalid(),
+            )
+            .unwrap()
+            .len()
+        };
+
+        let committed = insert(b"committed", true);
+        let aborted = insert(b"aborted", false);
+        let deleted = insert(b"deleted", true);
+        let deleter = tx_manager.begin().unwrap();
+        heap.delete(deleter, CommandId(1), deleted, false).unwrap();
+        tx_manager.commit(deleter).unwrap();
+        assert!(!heap
+            .get(committed)
+            .unwrap()
+            .unwrap()
+            .header
+            .xmin_committed());
+
+        // The first scan after a checkpoint hints every tuple and logs one
+        // image of the page.
+        tx_manager.checkpoint(|| Ok(())).unwrap();
+        assert_eq!(scan(), 1);
+        assert!(heap
+            .get(committed)
+            .unwrap()
+            .unwrap()
+            .header
+            .xmin_committed());
+        assert!(heap.get(aborted).unwrap().unwrap().header.xmin_invalid());
+        let deleted_tuple = heap.get(deleted).unwrap().unwrap();
+        assert!(deleted_tuple.header.xmin_committed());
+        assert!(deleted_tuple.header.xmax_committed());
+        assert_eq!(fpi_count(), 1);
+
+        let page = heap.relation.read_page(0).unwrap();
+        assert!(page.header.pd_lsn > tx_manager.checkpoint_redo());
+        assert_ne!(page.header.pd_checksum, 0);
+        assert!(page.verify_checksum(0));
+
+        // Later hint changes before the next checkpoint need no new image.
+        insert(b"second", true);
+        assert_eq!(scan(), 2);
+        assert_eq!(fpi_count(), 1);
+
+        insert(b"third", true);
+        tx_manager.checkpoint(|| Ok(())).unwrap();
+        assert_eq!(scan(), 3);
+        assert_eq!(fpi_count(), 2);
+
+        // An asynchronous commit is only hinted once its record is flushed.
+        let xid = tx_manager.begin().unwrap();
+        let async_ctid = heap.insert(xid, CommandId(1), b"async").unwrap();
+        tx_manager.commit_with(xid, false).unwrap();
+        assert_eq!(scan(), 4);
+        if !tx_manager.commit_hints_allowed() {
+            assert!(!heap
+                .get(async_ctid)
+                .unwrap()
+                .unwrap()
+                .header
+                .xmin_committed());
+        }
+        wal.flush(wal.get_lsn()).unwrap();
+        assert!(tx_manager.commit_hints_allowed());
+        assert_eq!(scan(), 4);
+        assert!(heap
+            .get(async_ctid)
+            .unwrap()
+            .unwrap()
+            .header
+            .xmin_committed());
+
+        // A page whose checksum no longer matches is refused.
+        let mut corrupted = heap.relation.read_page(0).unwrap();
+        corrupted.header.pd_prune_xid ^= 1;
+        heap.relation.storage.write_page(0, &corrupted).unwrap();
+        assert!(matches!(
+            heap.relation.read_page(0),
+            Err(HeapError::CorruptedData(_))
+        ));
+    }
+
     #[test]
     fn test_wal_recover() {
         let temp_dir = TempDir::new().unwrap();
@@ -581,7 +3198,517 @@ mod tests {
         wal.append(&record).unwrap();
 
         let records = wal.recover().unwrap();
-        assert!(records.len() >= 1);
+        assert!(!records.is_empty());
+    }
+
+    #[test]
+    fn test_heap_wal_logging() {
+        let temp_dir = TempDir::new().unwrap();
+        let (heap, _) = HeapRelation::create(temp_dir.path().to_path_buf(), 2).unwrap();
+        let tx_manager = heap.tx_manager.clone();
+        let wal = tx_manager.wal().unwrap().clone();
+        let rel_node = heap.relation.rel_node;
+
+        let xid = tx_manager.begin().unwrap();
+        let ctid = heap.insert(xid, CommandId(1), b"original").unwrap();
+        let (_, new_ctid) = heap
+            .update(xid, CommandId(2), ctid, b"updated", false)
+            .unwrap();
+        let new_ctid = new_ctid.unwrap();
+        tx_manager.commit(xid).unwrap();
+
+        let xid = tx_manager.begin().unwrap();
+        heap.lock_tuple(xid, new_ctid, LockMode::ForShare, WaitPolicy::Block)
+            .unwrap();
+        heap.delete(xid, CommandId(1), new_ctid, false).unwrap();
+        tx_manager.commit(xid).unwrap();
+        assert_eq!(heap.vacuum().unwrap(), 2);
+
+        let records = wal.recover().unwrap();
+        let heap_records: Vec<_> = records
+            .iter()
+            .filter(|r| {
+                matches!(
+                    r.record_type,
+                    XLogRecordType::HeapInsert
+                        | XLogRecordType::HeapUpdate
+                        | XLogRecordType::HeapLock
+                        | XLogRecordType::HeapDelete
+                        | XLogRecordType::HeapVacuum
+                )
+            })
+            .collect();
+        assert_eq!(
+            heap_records
+                .iter()
+                .map(|r| r.record_type)
+                .collect::<Vec<_>>(),
+            vec![
+                XLogRecordType::HeapInsert,
+                XLogRecordType::HeapUpdate,
+                XLogRecordType::HeapLock,
+                XLogRecordType::HeapDelete,
+                XLogRecordType::HeapVacuum,
+            ]
+        );
+        assert_eq!(
+            records
+                .iter()
+                .filter(|r| r.record_type == XLogRecordType::TransactionCommit)
+                .count(),
+            2
+        );
+
+        // Each record names the pages it changed and carries the tuples.
+        let insert = heap_records[0].blocks().unwrap();
+        assert_eq!(insert.len(), 1);
+        assert_eq!((insert[0].rel_node, insert[0].block_num), (rel_node, 0));
+        let (offset, ref tuple) = insert[0].tuples[0];
+        assert_eq!(offset, ctid.offset_number);
+        assert_eq!(HeapTuple::deserialize(tuple, 2).unwrap().data, b"original");
+
+        let update = heap_records[1].blocks().unwrap();
+        let offsets: Vec<_> = update[0].tuples.iter().map(|(offset, _)| *offset).collect();
+        assert_eq!(offsets, vec![new_ctid.offset_number, ctid.offset_number]);
+
+        let mut vacuumed = heap_records[4].blocks().unwrap()[0].removed.clone();
+        vacuumed.sort();
+        assert_eq!(vacuumed, vec![ctid.offset_number, new_ctid.offset_number]);
+
+        let round_trip = XLogRecord::with_blocks(0, XLogRecordType::HeapUpdate, &update);
+        assert_eq!(round_trip.blocks().unwrap(), update);
+        assert!(
+            XLogRecord::new(0, XLogRecordType::HeapInsert, 0, vec![1, 0, 7])
+                .blocks()
+                .is_err()
+        );
+
+        let page = heap.relation.storage.read_page(0).unwrap();
+        assert!(page.header.pd_lsn > 0);
+        assert!(page.header.pd_lsn <= wal.flushed_lsn());
+    }
+
+    #[test]
+    fn test_database_vacuum_keeps_other_relations_xids() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = Database::open(temp_dir.path().to_path_buf()).unwrap();
+        db.create_relation("a", 1).unwrap();
+        db.create_relation("b", 1).unwrap();
+        let mut session = db.session();
+
+        session.begin().unwrap();
+        session.insert("a", b"keep").unwrap();
+        session.commit().unwrap();
+        session.begin().unwrap();
+        session.insert("a", b"aborted").unwrap();
+        session.abort().unwrap();
+        session.begin().unwrap();
+        session.insert("b", b"row").unwrap();
+        session.commit().unwrap();
+
+        // b's relfrozenxid moves past the aborted xid, but a still holds it.
+        session.vacuum_with("b", VacuumOptions::freeze()).unwrap();
+        assert!(db
+            .tx_manager()
+            .xid_limits()
+            .oldest_xid
+            .precedes_or_equals(db.relation("a").unwrap().relation.relfrozenxid()));
+
+        session.begin().unwrap();
+        let rows: Vec<Vec<u8>> = session
+            .scan("a")
+            .unwrap()
+            .into_iter()
+            .map(|(_, tuple)| tuple.data)
+            .collect();
+        session.commit().unwrap();
+        assert_eq!(rows, vec![b"keep".to_vec()]);
+    }
+
+    #[test]
+    fn test_database_assigns_rel_nodes() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().to_path_buf();
+
+        let (first, second) = {
+            let db = Database::open(path.clone()).unwrap();
+            let first = db.create_relation("first", 1).unwrap().relation.rel_node;
+            db.create_relation("scratch", 1).unwrap();
+            db.drop_relation("scratch").unwrap();
+            let second = db.create_relation("second", 1).unwrap().relation.rel_node;
+            (first, second)
+        };
+        assert_eq!(first, FIRST_NORMAL_REL_NODE);
+        assert_eq!(second, FIRST_NORMAL_REL_NODE + 2);
+
+        {
+            let db = Database::open(path.clone()).unwrap();
+            let third = db.create_relation("third", 1).unwrap().relation.rel_node;
+            assert_eq!(third, second + 1);
+        }
+
+        // A catalog naming two relations with one node is refused.
+        let base = path.join("base");
+        std::fs::create_dir(base.join("copy")).unwrap();
+        for entry in std::fs::read_dir(base.join("first")).unwrap() {
+            let entry = entry.unwrap();
+            std::fs::copy(entry.path(), base.join("copy").join(entry.file_name())).unwrap();
+        }
+        let catalog = std::fs::read_to_string(path.join("pg_catalog")).unwrap();
+        std::fs::write(
+            path.join("pg_catalog"),
+            catalog.replace("\"second\"", "\"copy\""),
+        )
+        .unwrap();
+        assert!(matches!(
+            Database::open(path),
+            Err(HeapError::CorruptedData(_))
+        ));
+    }
+
+    #[test]
+    fn test_wal_before_data() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = Database::open_with(temp_dir.path().to_path_buf(), 1).unwrap();
+        let wal = db.wal().unwrap().clone();
+        let first = db.create_relation("first", 1).unwrap();
+        let second = db.create_relation("second", 1).unwrap();
+
+        // Uncommitted, so nothing but the buffer pool flushes its record.
+        let xid = db.tx_manager().begin().unwrap();
+        first.insert(xid, CommandId(1), b"dirty").unwrap();
+        let lsn = wal.get_lsn();
+
+        // The only buffer is taken over, writing the dirty page back.
+        second.relation.read_page(0).unwrap();
+        let on_disk = first.relation.storage.read_page(0).unwrap();
+        assert_eq!(on_disk.header.pd_lsn, lsn);
+        assert!(wal.flushed_lsn() >= lsn);
+        db.tx_manager().abort(xid).unwrap();
+    }
+
+    #[test]
+    fn test_recovery_aborts_crashed() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().to_path_buf();
+
+        let crashed = {
+            let (heap, _) = HeapRelation::create(path.clone(), 1).unwrap();
+            let tx_manager = heap.tx_manager.clone();
+            // No page images, so redo has to check each page's LSN.
+            tx_manager.set_full_page_writes(false).unwrap();
+            tx_manager.checkpoint(|| Ok(())).unwrap();
+
+            let committed = tx_manager.begin().unwrap();
+            heap.insert(committed, CommandId(1), b"committed").unwrap();
+            tx_manager.commit(committed).unwrap();
+
+            let crashed = tx_manager.begin().unwrap();
+            heap.insert(crashed, CommandId(1), b"crashed").unwrap();
+            tx_manager.wal().unwrap().flush(u64::MAX).unwrap();
+            crashed
+        };
+
+        // Open without recovery, so as to run it by hand.
+        let tx_manager = Arc::new(TransactionManager::open(path.clone()).unwrap());
+        let heap = HeapRelation::open_with(path, 1, tx_manager.clone(), None).unwrap();
+        let mut recovery = Recovery::new(&tx_manager);
+        recovery.add_relation(&heap.relation);
+        let stats = recovery.run().unwrap();
+        assert_eq!(stats.aborted, vec![crashed]);
+        assert!(tx_manager.did_abort(crashed));
+
+        // Every page change is already on disk, so a second pass skips them.
+        let stats = recovery.run().unwrap();
+        assert!(stats.skipped > 0);
+        assert!(stats.aborted.is_empty());
+
+        let data: Vec<_> = heap
+            .scan(
+                &tx_manager.get_snapshot(CommandId(1)),
+                TransactionId::invalid(),
+            )
+            .unwrap()
+            .into_iter()
+            .map(|(_, t)| t.data)
+            .collect();
+        assert_eq!(data, vec![b"committed".to_vec()]);
+    }
+
+    #[test]
+    fn test_checkpoint_removes_old_wal() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().to_path_buf();
+        let segment = |n: u64| path.join("wal").join(format!("{:08X}.wal", n));
+
+        let running = {
+            let (heap, _) = HeapRelation::create(path.clone(), 1).unwrap();
+            let tx_manager = heap.tx_manager.clone();
+            let wal = tx_manager.wal().unwrap().clone();
+
+            let xid = tx_manager.begin().unwrap();
+            heap.insert(xid, CommandId(1), b"before").unwrap();
+            tx_manager.commit(xid).unwrap();
+
+            // Its only record is in a segment the checkpoint removes, and
+            // every xid with a later record is older.
+            let later = tx_manager.begin().unwrap();
+            let running = tx_manager.begin().unwrap();
+            heap.insert(running, CommandId(1), b"running").unwrap();
+
+            let filler = XLogRecord::new(0, XLogRecordType::FpiForHint, 0, vec![0; 1 << 20]);
+            while wal.get_lsn() < WAL_SEGMENT_SIZE {
+                wal.append(&filler).unwrap();
+            }
+            tx_manager.checkpoint(|| Ok(())).unwrap();
+            assert!(!segment(0).exists());
+            assert!(segment(1).exists());
+
+            heap.insert(later, CommandId(1), b"after").unwrap();
+            tx_manager.commit(later).unwrap();
+            crash(&path, (heap, tx_manager, wal));
+            running
+        };
+
+        let tx_manager = Arc::new(TransactionManager::open(path.clone()).unwrap());
+        let heap = HeapRelation::open_with(path, 1, tx_manager.clone(), None).unwrap();
+        let mut recovery = Recovery::new(&tx_manager);
+        recovery.add_relation(&heap.relation);
+        let stats = recovery.run().unwrap();
+        assert!(stats.redo_lsn > WAL_SEGMENT_SIZE);
+        assert_eq!(stats.aborted, vec![running]);
+
+        let mut data: Vec<_> = heap
+            .scan(
+                &tx_manager.get_snapshot(CommandId(1)),
+                TransactionId::invalid(),
+            )
+            .unwrap()
+            .into_iter()
+            .map(|(_, t)| t.data)
+            .collect();
+        data.sort();
+        assert_eq!(data, vec![b"after".to_vec(), b"before".to_vec()]);
+    }
+
+    #[test]
+    fn test_full_page_writes() {
+        let temp_dir = TempDir::new().unwrap();
+        let (heap, _) = HeapRelation::create(temp_dir.path().to_path_buf(), 1).unwrap();
+        let tx_manager = heap.tx_manager.clone();
+        let wal = tx_manager.wal().unwrap().clone();
+        assert!(tx_manager.full_page_writes());
+
+        let insert = |data: &[u8]| {
+            let xid = tx_manager.begin().unwrap();
+            heap.insert(xid, CommandId(1), data).unwrap();
+            tx_manager.commit(xid).unwrap();
+            let record = wal
+                .recover()
+                .unwrap()
+                .into_iter()
+                .rfind(|r| r.record_type == XLogRecordType::HeapInsert)
+                .unwrap();
+            record.blocks().unwrap().remove(0).image
+        };
+
+        // Only the first change to the page after a checkpoint is imaged,
+        // and the image holds the change.
+        tx_manager.checkpoint(|| Ok(())).unwrap();
+        let image = Page::from_raw(insert(b"first").unwrap()).unwrap();
+        assert_eq!(image.item_count(), 1);
+        assert!(insert(b"second").is_none());
+        tx_manager.checkpoint(|| Ok(())).unwrap();
+        assert!(insert(b"third").is_some());
+
+        // A checkpoint waits for a change that is logged but not yet in its
+        // page before picking its redo point.
+        let redo = tx_manager.checkpoint_redo();
+        let logged = tx_manager.log_change();
+        std::thread::scope(|scope| {
+            let checkpoint = scope.spawn(|| tx_manager.checkpoint(|| Ok(())));
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            assert!(!checkpoint.is_finished());
+            assert_eq!(tx_manager.checkpoint_redo(), redo);
+            drop(logged);
+            checkpoint.join().unwrap().unwrap();
+        });
+        assert!(tx_manager.checkpoint_redo() > redo);
+
+        tx_manager.set_full_page_writes(false).unwrap();
+        tx_manager.checkpoint(|| Ok(())).unwrap();
+        assert!(insert(b"fourth").is_none());
+        drop(heap);
+        drop(tx_manager);
+
+        let tx_manager = TransactionManager::open(temp_dir.path().to_path_buf()).unwrap();
+        assert!(!tx_manager.full_page_writes());
+    }
+
+    #[test]
+    fn test_kill_point_loses_unsynced_wal() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path().to_path_buf();
+        let wal = WAL::new(dir.clone()).unwrap();
+        let record = |data: &[u8]| XLogRecord::new(1, XLogRecordType::HeapInsert, 0, data.to_vec());
+
+        failpoint::kill_after(&dir, 3);
+        let synced = wal.append(&record(b"synced")).unwrap();
+        wal.flush(synced).unwrap();
+        wal.append(&record(b"buffered")).unwrap();
+        wal.append(&record(b"lost")).unwrap();
+        assert!(wal.append(&record(b"killed")).is_err());
+        assert!(failpoint::is_dead(&dir));
+        failpoint::revive(&dir);
+
+        let recovered: Vec<Vec<u8>> = wal.recover().unwrap().into_iter().map(|r| r.data).collect();
+        assert_eq!(recovered, vec![b"synced".to_vec()]);
+    }
+
+    #[test]
+    fn test_crash_recovery_kill_points() {
+        enum Step {
+            Tx {
+                inserts: Vec<String>,
+                changes: Vec<(&'static str, Option<&'static str>)>,
+                commit: bool,
+            },
+            Vacuum,
+            Checkpoint,
+        }
+        let tx =
+            |inserts: &[&str], changes: &[(&'static str, Option<&'static str>)], commit| Step::Tx {
+                inserts: inserts.iter().map(|s| s.to_string()).collect(),
+                changes: changes.to_vec(),
+                commit,
+            };
+        let steps = vec![
+            tx(&["a", "b", "c"], &[], true),
+            tx(&["x"], &[("a", Some("y"))], false),
+            tx(&[], &[("a", Some("a2")), ("b", None)], true),
+            Step::Tx {
+                inserts: (0..40)
+                    .map(|i| format!("bulk{:02}{}", i, "z".repeat(300)))
+                    .collect(),
+                changes: Vec::new(),
+                commit: true,
+            },
+            Step::Checkpoint,
+            tx(&["d"], &[("c", Some("c2"))], false),
+            tx(&[], &[("c", None)], true),
+            Step::Vacuum,
+            tx(&["e"], &[("a2", Some("a3"))], true),
+        ];
+
+        let apply = |state: &std::collections::BTreeSet<String>, step: &Step| {
+            let mut state = state.clone();
+            if let Step::Tx {
+                inserts,
+                changes,
+                commit: true,
+            } = step
+            {
+                state.extend(inserts.iter().cloned());
+                for (old, new) in changes {
+                    state.remove(*old);
+                    state.extend(new.map(str::to_string));
+                }
+            }
+            state
+        };
+        let run = |session: &mut Session, step: &Step| -> super::error::Result<()> {
+            match step {
+                Step::Checkpoint => session.database().checkpoint(),
+                Step::Vacuum => session.vacuum("t").map(|_| ()),
+                Step::Tx {
+                    inserts,
+                    changes,
+                    commit,
+                } => {
+                    session.begin()?;
+                    for data in inserts {
+                        session.insert("t", data.as_bytes())?;
+                    }
+                    for (old, new) in changes {
+                        let (ctid, _) = session
+                            .scan("t")?
+                            .into_iter()
+                            .find(|(_, t)| t.data == old.as_bytes())
+                            .unwrap();
+                        match new {
+                            Some(new) => session.update("t", ctid, new.as_bytes()).map(|_| ())?,
+                            None => session.delete("t", ctid).map(|_| ())?,
+                        }
+                    }
+                    if *commit {
+                        session.commit()
+                    } else {
+                        session.abort()
+                    }
+                }
+            }
+        };
+
+        // Kill after 0, 1, 2, ... writes until the workload gets through,
+        // first failing the write the kill lands on outright and then
+        // tearing it. Recovery has to bring back every committed step; the
+        // step in flight may have gone either way, as its commit record may
+        // have reached disk before the failure did.
+        for torn in [false, true] {
+            for kill_after in 0.. {
+                let temp_dir = TempDir::new().unwrap();
+                let dir = temp_dir.path().to_path_buf();
+
+                let mut before = std::collections::BTreeSet::new();
+                let mut after = before.clone();
+                let mut completed = true;
+                {
+                    let db = Database::open_with(dir.clone(), 2).unwrap();
+                    db.create_relation("t", 1).unwrap();
+                    db.checkpoint().unwrap();
+                    let mut session = db.session();
+
+                    if torn {
+                        failpoint::tear_after(&dir, kill_after);
+                    } else {
+                        failpoint::kill_after(&dir, kill_after);
+                    }
+                    for step in &steps {
+                        before = after.clone();
+                        after = apply(&before, step);
+                        if run(&mut session, step).is_err() {
+                            assert!(failpoint::is_dead(&dir));
+                            completed = false;
+                            break;
+                        }
+                        before = after.clone();
+                    }
+                }
+                failpoint::revive(&dir);
+
+                let db = Database::open_with(dir.clone(), 2).unwrap();
+                let recovered: std::collections::BTreeSet<String> = db
+                    .session()
+                    .scan("t")
+                    .unwrap()
+                    .into_iter()
+                    .map(|(_, t)| String::from_utf8(t.data).unwrap())
+                    .collect();
+                assert!(
+                    recovered == before || recovered == after,
+                    "kill after {} writes (torn: {}): recovered {:?}",
+                    kill_after,
+                    torn,
+                    recovered
+                );
+
+                if completed {
+                    assert_eq!(recovered, after);
+                    assert!(kill_after > 20);
+                    break;
+                }
+            }
+        }
     }
 
     #[test]
@@ -613,7 +3740,7 @@ mod tests {
 
         if let Ok(pointer) = result {
             let fetched = toast.fetch(&pointer).unwrap();
-            assert!(fetched.len() > 0);
+            assert!(!fetched.is_empty());
         }
     }
 
@@ -660,7 +3787,7 @@ mod tests {
         let record = XLogRecord::new(100, XLogRecordType::HeapInsert, 5, b"test".to_vec());
 
         let serialized = record.serialize();
-        assert!(serialized.len() > 0);
+        assert!(!serialized.is_empty());
 
         let deserialized = XLogRecord::deserialize(&serialized).unwrap();
         assert_eq!(deserialized.txid, 100);
@@ -0,0 +1,360 @@
+use crate::error::{HeapError, Result};
+use crate::types::*;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::sync::{Condvar, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum TableLockMode {
+    AccessShare,
+    RowShare,
+    RowExclusive,
+    ShareUpdateExclusive,
+    Share,
+    ShareRowExclusive,
+    Exclusive,
+    AccessExclusive,
+}
+
+impl TableLockMode {
+    pub fn conflicts_with(self, other: TableLockMode) -> bool {
+        use TableLockMode::*;
+        match self {
+            AccessShare => other == AccessExclusive,
+            RowShare => matches!(other, Exclusive | AccessExclusive),
+            RowExclusive => matches!(
+                other,
+                Share | ShareRowExclusive | Exclusive | AccessExclusive
+            ),
+            ShareUpdateExclusive => matches!(
+                other,
+                ShareUpdateExclusive | Share | ShareRowExclusive | Exclusive | AccessExclusive
+            ),
+            Share => matches!(
+                other,
+                RowExclusive
+                    | ShareUpdateExclusive
+                    | ShareRowExclusive
+                    | Exclusive
+                    | AccessExclusive
+            ),
+            ShareRowExclusive => !matches!(other, AccessShare | RowShare),
+            Exclusive => other != AccessShare,
+            AccessExclusive => true,
+        }
+    }
+}
+
+impl fmt::Display for TableLockMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            TableLockMode::AccessShare => "AccessShareLock",
+            TableLockMode::RowShare => "RowShareLock",
+            TableLockMode::RowExclusive => "RowExclusiveLock",
+            TableLockMode::ShareUpdateExclusive => "ShareUpdateExclusiveLock",
+            TableLockMode::Share => "ShareLock",
+            TableLockMode::ShareRowExclusive => "ShareRowExclusiveLock",
+            TableLockMode::Exclusive => "ExclusiveLock",
+            TableLockMode::AccessExclusive => "AccessExclusiveLock",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LockTag {
+    Relation(u32),
+    Transaction(TransactionId),
+}
+
+impl fmt::Display for LockTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockTag::Relation(rel_node) => write!(f, "relation {}", rel_node),
+            LockTag::Transaction(xid) => write!(f, "transaction {}", xid),
+        }
+    }
+}
+
+// Who holds locks and snapshots: a top-level transaction, or a statement
+// run outside a transaction block, which gets a virtual id instead of an
+// xid it would never use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum LockOwner {
+    Transaction(TransactionId),
+    Virtual(u32),
+}
+
+impl From<TransactionId> for LockOwner {
+    fn from(xid: TransactionId) -> Self {
+        LockOwner::Transaction(xid)
+    }
+}
+
+impl fmt::Display for LockOwner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockOwner::Transaction(xid) => write!(f, "{}", xid),
+            LockOwner::Virtual(id) => write!(f, "v{}", id),
+        }
+    }
+}
+
+#[derive(Default)]
+struct LockEntry {
+    granted: Vec<(LockOwner, TableLockMode)>,
+    waiting: VecDeque<(LockOwner, TableLockMode)>,
+}
+
+impl LockEntry {
+    fn holds(&self, owner: LockOwner, mode: TableLockMode) -> bool {
+        self.granted.contains(&(owner, mode))
+    }
+
+    // Owners that `owner` has to wait for before it can get `mode`: other
+    // holders of a conflicting mode, and conflicting waiters queued ahead.
+    // An owner that already holds the lock in some mode does not queue
+    // behind waiters, who would be waiting on it anyway.
+    fn blockers(&self, owner: LockOwner, mode: TableLockMode) -> Vec<LockOwner> {
+        let mut blockers: Vec<LockOwner> = self
+            .granted
+            .iter()
+            .filter(|&&(holder, held)| holder != owner && mode.conflicts_with(held))
+            .map(|&(holder, _)| holder)
+            .collect();
+
+        if !self.granted.iter().any(|&(holder, _)| holder == owner) {
+            for &(waiter, wanted) in &self.waiting {
+                if waiter == owner {
+                    break;
+                }
+                if mode.conflicts_with(wanted) {
+                    blockers.push(waiter);
+                }
+            }
+        }
+
+        blockers.sort();
+        blockers.dedup();
+        blockers
+    }
+}
+
+#[derive(Default)]
+struct LockTable {
+    locks: HashMap<LockTag, LockEntry>,
+    waits_for: HashMap<LockOwner, (LockTag, TableLockMode)>,
+}
+
+impl LockTable {
+    fn find_cycle(&self, start: LockOwner) -> Option<Vec<LockOwner>> {
+        let mut path = vec![start];
+        let mut visited = HashSet::new();
+        if self.walk(start, start, &mut path, &mut visited) {
+            Some(path)
+        } else {
+            None
+        }
+    }
+
+    fn walk(
+        &self,
+        start: LockOwner,
+        owner: LockOwner,
+        path: &mut Vec<LockOwner>,
+        visited: &mut HashSet<LockOwner>,
+    ) -> bool {
+        let (tag, mode) = match self.waits_for.get(&owner) {
+            Some(&wait) => wait,
+            None => return false,
+        };
+        let entry = match self.locks.get(&tag) {
+            Some(entry) => entry,
+            None => return false,
+        };
+
+        for blocker in entry.blockers(owner, mode) {
+            if blocker == start {
+                return true;
+            }
+            if visited.insert(blocker) {
+                path.push(blocker);
+                if self.walk(start, blocker, path, visited) {
+                    return true;
+                }
+                path.pop();
+            }
+        }
+
+        false
+    }
+}
+
+// Heavyweight locks on relations and transactions, owned by top-level
+// transactions and held until the owner releases them at commit or abort.
+pub struct LockManager {
+    table: Mutex<LockTable>,
+    cond: Condvar,
+    lock_timeout: RwLock<Option<Duration>>,
+    deadlock_timeout: Duration,
+}
+
+impl LockManager {
+    pub fn new() -> Self {
+        Self {
+            table: Mutex::new(LockTable::default()),
+            cond: Condvar::new(),
+            lock_timeout: RwLock::new(None),
+            deadlock_timeout: Duration::from_millis(100),
+        }
+    }
+
+    pub fn set_lock_timeout(&self, timeout: Option<Duration>) {
+        *self.lock_timeout.write().unwrap() = timeout;
+    }
+
+    pub fn lock_timeout(&self) -> Option<Duration> {
+        *self.lock_timeout.read().unwrap()
+    }
+
+    // Returns Ok(false) only under SKIP LOCKED when the lock is not free.
+    pub fn acquire(
+        &self,
+        owner: impl Into<LockOwner>,
+        tag: LockTag,
+        mode: TableLockMode,
+        wait_policy: WaitPolicy,
+    ) -> Result<bool> {
+        let owner = owner.into();
+        let mut table = self.table.lock().unwrap();
+
+        {
+            let entry = table.locks.entry(tag).or_default();
+            if entry.holds(owner, mode) {
+                return Ok(true);
+            }
+            if entry.blockers(owner, mode).is_empty() {
+                entry.granted.push((owner, mode));
+                return Ok(true);
+            }
+
+            match wait_policy {
+                WaitPolicy::Block => entry.waiting.push_back((owner, mode)),
+                WaitPolicy::SkipLocked => return Ok(false),
+                WaitPolicy::NoWait => {
+                    return Err(HeapError::LockError(format!(
+                        "could not obtain {} on {}",
+                        mode, tag
+                    )));
+                }
+            }
+        }
+
+        table.waits_for.insert(owner, (tag, mode));
+        let deadline = self.lock_timeout().map(|timeout| Instant::now() + timeout);
+        let mut check_deadlock = true;
+
+        loop {
+            let entry = table.locks.entry(tag).or_default();
+            if entry.blockers(owner, mode).is_empty() {
+                entry.waiting.retain(|&(waiter, _)| waiter != owner);
+                entry.granted.push((owner, mode));
+                table.waits_for.remove(&owner);
+                self.cond.notify_all();
+                return Ok(true);
+            }
+
+            if check_deadlock {
+                if let Some(cycle) = table.find_cycle(owner) {
+                    Self::cancel_wait(&mut table, owner, tag);
+                    self.cond.notify_all();
+                    return Err(HeapError::Deadlock(Self::describe_cycle(&cycle, tag, mode)));
+                }
+            }
+
+            let mut wait = self.deadlock_timeout;
+            if let Some(deadline) = deadline {
+                let now = Instant::now();
+                if now >= deadline {
+                    Self::cancel_wait(&mut table, owner, tag);
+                    self.cond.notify_all();
+                    return Err(HeapError::LockError(format!(
+                        "canceling statement due to lock timeout on {}",
+                        tag
+                    )));
+                }
+                wait = wait.min(deadline - now);
+            }
+
+            let (guard, result) = self.cond.wait_timeout(table, wait).unwrap();
+            table = guard;
+            check_deadlock = result.timed_out();
+        }
+    }
+
+    fn cancel_wait(table: &mut LockTable, owner: LockOwner, tag: LockTag) {
+        if let Some(entry) = table.locks.get_mut(&tag) {
+            entry.waiting.retain(|&(waiter, _)| waiter != owner);
+        }
+        table.waits_for.remove(&owner);
+    }
+
+    fn describe_cycle(cycle: &[LockOwner], tag: LockTag, mode: TableLockMode) -> String {
+        let chain: Vec<String> = cycle.iter().map(|owner| owner.to_string()).collect();
+        format!(
+            "deadlock detected: transaction {} waiting for {} on {}; wait cycle {} -> {}",
+            cycle[0],
+            mode,
+            tag,
+            chain.join(" -> "),
+            cycle[0]
+        )
+    }
+
+    pub fn release(&self, owner: impl Into<LockOwner>, tag: LockTag) {
+        let owner = owner.into();
+        let mut table = self.table.lock().unwrap();
+        if let Some(entry) = table.locks.get_mut(&tag) {
+            entry.granted.retain(|&(holder, _)| holder != owner);
+            if entry.granted.is_empty() && entry.waiting.is_empty() {
+                table.locks.remove(&tag);
+            }
+        }
+        self.cond.notify_all();
+    }
+
+    pub fn release_all(&self, owner: impl Into<LockOwner>) {
+        let owner = owner.into();
+        let mut table = self.table.lock().unwrap();
+        table.locks.retain(|_, entry| {
+            entry.granted.retain(|&(holder, _)| holder != owner);
+            !entry.granted.is_empty() || !entry.waiting.is_empty()
+        });
+        self.cond.notify_all();
+    }
+
+    pub fn held_locks(&self, owner: impl Into<LockOwner>) -> Vec<(LockTag, TableLockMode)> {
+        let owner = owner.into();
+        let table = self.table.lock().unwrap();
+        let mut held: Vec<(LockTag, TableLockMode)> = table
+            .locks
+            .iter()
+            .flat_map(|(&tag, entry)| {
+                entry
+                    .granted
+                    .iter()
+                    .filter(move |&&(holder, _)| holder == owner)
+                    .map(move |&(_, mode)| (tag, mode))
+            })
+            .collect();
+        held.sort_by_key(|&(_, mode)| mode);
+        held
+    }
+}
+
+impl Default for LockManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -11,7 +11,7 @@ fn main() {
         Ok((mut engine, rel_id)) => {
             println!("Created heap relation with rel_id: {}", rel_id);
             
-            engine.begin();
+            engine.begin().unwrap();
             println!("Started transaction");
             
             let data1 = b"test_data_1";
@@ -43,7 +43,7 @@ fn main() {
                 Err(e) => println!("Insert error: {:?}", e),
             }
             
-            engine.commit();
+            engine.commit().unwrap();
             println!("Committed transaction");
             
             engine.close().unwrap();
@@ -0,0 +1,283 @@
+use crate::constants::*;
+use crate::control::ControlFile;
+use crate::error::{HeapError, Result};
+use crate::slru::Slru;
+use crate::types::*;
+use crate::wal::{WALRef, XLogRecord, XLogRecordType};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+struct MultiXactState {
+    next_multi: MultiXactId,
+    next_offset: u32,
+    oldest_multi: MultiXactId,
+}
+
+// Member lists for tuples whose xmax is shared by several transactions.
+// `offsets` maps a multixact id to (first member offset, member count) and
+// `members` holds (xid, status) entries at those offsets.
+pub struct MultiXactManager {
+    offsets: Slru,
+    members: Slru,
+    control: Option<Arc<ControlFile>>,
+    wal: Option<WALRef>,
+    state: Mutex<MultiXactState>,
+}
+
+impl MultiXactManager {
+    pub fn open(dir: PathBuf, control: Arc<ControlFile>, wal: Option<WALRef>) -> Result<Self> {
+        let dir = dir.join("pg_multixact");
+        let data = control.get();
+
+        Ok(Self {
+            offsets: Slru::open(dir.join("offsets"), NUM_MULTIXACT_OFFSET_BUFFERS)?
+                .with_wal(wal.clone()),
+            members: Slru::open(dir.join("members"), NUM_MULTIXACT_MEMBER_BUFFERS)?
+                .with_wal(wal.clone()),
+            control: Some(control),
+            wal,
+            state: Mutex::new(MultiXactState {
+                next_multi: MultiXactId(data.next_multi),
+                next_offset: data.next_multi_offset,
+                oldest_multi: MultiXactId(data.oldest_multi),
+            }),
+        })
+    }
+
+    pub fn in_memory() -> Self {
+        Self {
+            offsets: Slru::in_memory(NUM_MULTIXACT_OFFSET_BUFFERS),
+            members: Slru::in_memory(NUM_MULTIXACT_MEMBER_BUFFERS),
+            control: None,
+            wal: None,
+            state: Mutex::new(MultiXactState {
+                next_multi: MultiXactId::first(),
+                next_offset: 0,
+                oldest_multi: MultiXactId::first(),
+            }),
+        }
+    }
+
+    fn offset_page(multi: MultiXactId) -> u32 {
+        multi.0 / MULTIXACT_OFFSETS_PER_PAGE
+    }
+
+    fn offset_entry(multi: MultiXactId) -> usize {
+        (multi.0 % MULTIXACT_OFFSETS_PER_PAGE) as usize * 8
+    }
+
+    fn member_page(offset: u32) -> u32 {
+        offset / MULTIXACT_MEMBERS_PER_PAGE
+    }
+
+    fn member_entry(offset: u32) -> usize {
+        (offset % MULTIXACT_MEMBERS_PER_PAGE) as usize * 8
+    }
+
+    pub fn offset_page_precedes(page1: u32, page2: u32) -> bool {
+        let multi1 = MultiXactId(page1 * MULTIXACT_OFFSETS_PER_PAGE + FIRST_MULTIXACT_ID);
+        let multi2 = MultiXactId(page2 * MULTIXACT_OFFSETS_PER_PAGE + FIRST_MULTIXACT_ID);
+        multi1.precedes(multi2)
+    }
+
+    pub fn member_page_precedes(page1: u32, page2: u32) -> bool {
+        let offset1 = page1.wrapping_mul(MULTIXACT_MEMBERS_PER_PAGE);
+        let offset2 = page2.wrapping_mul(MULTIXACT_MEMBERS_PER_PAGE);
+        (offset1.wrapping_sub(offset2) as i32) < 0
+    }
+
+    fn ensure_page(slru: &Slru, page_number: u32) -> Result<()> {
+        if !slru.page_exists(page_number) {
+            slru.zero_page(page_number)?;
+        }
+        Ok(())
+    }
+
+    pub fn create(&self, members: &[MultiXactMember]) -> Result<MultiXactId> {
+        if members.len() < 2 {
+            return Err(HeapError::InvalidOperation(
+                "a multixact needs at least two members".to_string(),
+            ));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let multi = state.next_multi;
+        let offset = state.next_offset;
+        let next_multi = multi.next();
+        let next_offset = offset.wrapping_add(members.len() as u32);
+
+        // Logged before the tuple naming the multixact is, so redo has its
+        // members back before it replays that tuple.
+        let mut lsn = 0;
+        if let Some(ref wal) = self.wal {
+            let mut data = offset.to_le_bytes().to_vec();
+            for member in members {
+                data.extend_from_slice(&member.xid.0.to_le_bytes());
+                data.push(member.status.to_u8());
+            }
+            lsn = wal.append(&XLogRecord::new(
+                0,
+                XLogRecordType::MultiXactCreate,
+                multi.0,
+                data,
+            ))?;
+        }
+
+        if let Some(ref control) = self.control {
+            control.update(|data| {
+                data.next_multi = next_multi.0;
+                data.next_multi_offset = next_offset;
+            })?;
+        }
+
+        self.write_members(multi, offset, members, lsn)?;
+
+        state.next_multi = next_multi;
+        state.next_offset = next_offset;
+        Ok(multi)
+    }
+
+    fn write_members(
+        &self,
+        multi: MultiXactId,
+        offset: u32,
+        members: &[MultiXactMember],
+        lsn: u64,
+    ) -> Result<()> {
+        let page_number = Self::offset_page(multi);
+        Self::ensure_page(&self.offsets, page_number)?;
+        let entry = Self::offset_entry(multi);
+        self.offsets.write_logged(page_number, lsn, |page| {
+            page[entry..entry + 4].copy_from_slice(&offset.to_le_bytes());
+            page[entry + 4..entry + 8].copy_from_slice(&(members.len() as u32).to_le_bytes());
+        })?;
+
+        for (i, member) in members.iter().enumerate() {
+            let member_offset = offset.wrapping_add(i as u32);
+            let page_number = Self::member_page(member_offset);
+            Self::ensure_page(&self.members, page_number)?;
+            let entry = Self::member_entry(member_offset);
+            self.members.write_logged(page_number, lsn, |page| {
+                page[entry..entry + 4].copy_from_slice(&member.xid.0.to_le_bytes());
+                page[entry + 4] = member.status.to_u8();
+            })?;
+        }
+        Ok(())
+    }
+
+    // Redo of a MultiXactCreate record: the members are written again, as
+    // the pages holding them may not have reached disk. Multixacts already
+    // truncated away stay gone.
+    pub fn redo_create(&self, record: &XLogRecord) -> Result<()> {
+        let multi = MultiXactId(record.block_id);
+        let corrupted = || {
+            HeapError::CorruptedData(format!(
+                "WAL record at {} holds a malformed multixact {}",
+                record.lsn, multi
+            ))
+        };
+        if record.data.len() < 4 || !(record.data.len() - 4).is_multiple_of(5) {
+            return Err(corrupted());
+        }
+
+        let offset = u32::from_le_bytes(record.data[..4].try_into().unwrap());
+        let mut members = Vec::new();
+        for entry in record.data[4..].chunks_exact(5) {
+            let xid = TransactionId(u32::from_le_bytes(entry[..4].try_into().unwrap()));
+            let status = MultiXactStatus::from_u8(entry[4]).ok_or_else(corrupted)?;
+            members.push(MultiXactMember::new(xid, status));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if multi.precedes(state.oldest_multi) {
+            return Ok(());
+        }
+        self.write_members(multi, offset, &members, 0)?;
+        if !multi.precedes(state.next_multi) {
+            state.next_multi = multi.next();
+            state.next_offset = offset.wrapping_add(members.len() as u32);
+        }
+        Ok(())
+    }
+
+    pub fn get_members(&self, multi: MultiXactId) -> Result<Vec<MultiXactMember>> {
+        {
+            let state = self.state.lock().unwrap();
+            if !multi.is_valid()
+                || multi.precedes(state.oldest_multi)
+                || !multi.precedes(state.next_multi)
+            {
+                return Err(HeapError::CorruptedData(format!(
+                    "multixact {} is out of range [{}, {})",
+                    multi, state.oldest_multi, state.next_multi
+                )));
+            }
+        }
+
+        let entry = Self::offset_entry(multi);
+        let (offset, count) = self.offsets.read(Self::offset_page(multi), |page| {
+            let offset = u32::from_le_bytes(page[entry..entry + 4].try_into().unwrap());
+            let count = u32::from_le_bytes(page[entry + 4..entry + 8].try_into().unwrap());
+            (offset, count)
+        })?;
+
+        let mut members = Vec::with_capacity(count as usize);
+        for i in 0..count {
+            let member_offset = offset.wrapping_add(i);
+            let entry = Self::member_entry(member_offset);
+            let (xid, status) = self
+                .members
+                .read(Self::member_page(member_offset), |page| {
+                    let xid = u32::from_le_bytes(page[entry..entry + 4].try_into().unwrap());
+                    (TransactionId(xid), page[entry + 4])
+                })?;
+            let status = MultiXactStatus::from_u8(status).ok_or_else(|| {
+                HeapError::CorruptedData(format!("multixact {}: bad member status", multi))
+            })?;
+            members.push(MultiXactMember::new(xid, status));
+        }
+
+        Ok(members)
+    }
+
+    pub fn next_multi(&self) -> MultiXactId {
+        self.state.lock().unwrap().next_multi
+    }
+
+    pub fn oldest_multi(&self) -> MultiXactId {
+        self.state.lock().unwrap().oldest_multi
+    }
+
+    // Forgets every multixact older than `oldest_multi` and removes the
+    // offset and member segments only they used.
+    pub fn set_oldest(&self, oldest_multi: MultiXactId) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !oldest_multi.follows(state.oldest_multi) || oldest_multi.follows(state.next_multi) {
+            return Ok(());
+        }
+
+        let oldest_offset = if oldest_multi == state.next_multi {
+            state.next_offset
+        } else {
+            let entry = Self::offset_entry(oldest_multi);
+            self.offsets.read(Self::offset_page(oldest_multi), |page| {
+                u32::from_le_bytes(page[entry..entry + 4].try_into().unwrap())
+            })?
+        };
+
+        if let Some(ref control) = self.control {
+            control.update(|data| data.oldest_multi = oldest_multi.0)?;
+        }
+        state.oldest_multi = oldest_multi;
+
+        self.offsets
+            .truncate(Self::offset_page(oldest_multi), Self::offset_page_precedes)?;
+        self.members
+            .truncate(Self::member_page(oldest_offset), Self::member_page_precedes)
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.offsets.flush()?;
+        self.members.flush()
+    }
+}
@@ -1,6 +1,5 @@
 use crate::constants::*;
 use crate::error::{HeapError, Result};
-use crate::types::*;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::fmt;
 
@@ -71,7 +70,7 @@ impl PageHeaderData {
         }
     }
 
-    pub fn free_space(&self, page_size: usize) -> usize {
+    pub fn free_space(&self, _page_size: usize) -> usize {
         (self.pd_upper - self.pd_lower) as usize
     }
 
@@ -294,9 +293,10 @@ impl Page {
 
         item_id.bits = (LP_DEAD as u32) << 30;
 
-        let new_upper = off + len;
-        if new_upper > self.header.pd_upper {
-            self.header.pd_upper = new_upper;
+        // Only the lowest tuple's space can go back to the free region;
+        // anything else would expose live tuples below it as free space.
+        if off == self.header.pd_upper {
+            self.header.pd_upper = off + len;
         }
 
         self.header.set_has_free_lines(true);
@@ -344,6 +344,29 @@ impl Page {
         buf
     }
 
+    // FNV-1a over the page with the checksum field zeroed, seeded with the
+    // block number so a page written to the wrong block fails too. Never
+    // zero, which marks a page written without a checksum.
+    pub fn compute_checksum(&self, block_num: u32) -> u16 {
+        let mut buf = self.serialize();
+        buf[8..10].fill(0);
+
+        let mut hash = 0x811c_9dc5u32 ^ block_num;
+        for &byte in &buf {
+            hash ^= byte as u32;
+            hash = hash.wrapping_mul(0x0100_0193);
+        }
+        ((hash ^ (hash >> 16)) % 65535 + 1) as u16
+    }
+
+    pub fn set_checksum(&mut self, block_num: u32) {
+        self.header.pd_checksum = self.compute_checksum(block_num);
+    }
+
+    pub fn verify_checksum(&self, block_num: u32) -> bool {
+        self.header.pd_checksum == 0 || self.header.pd_checksum == self.compute_checksum(block_num)
+    }
+
     pub fn is_valid(&self) -> bool {
         self.header.pd_lower >= 24
             && self.header.pd_upper <= self.page_size as u16
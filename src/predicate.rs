@@ -0,0 +1,433 @@
+use crate::constants::*;
+use crate::error::{HeapError, Result};
+use crate::types::*;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PredicateLockTag {
+    Relation(u32),
+    Page(u32, u32),
+    Tuple(u32, ItemPointerData),
+}
+
+impl PredicateLockTag {
+    fn parent(self) -> Option<PredicateLockTag> {
+        match self {
+            PredicateLockTag::Relation(_) => None,
+            PredicateLockTag::Page(rel_node, _) => Some(PredicateLockTag::Relation(rel_node)),
+            PredicateLockTag::Tuple(rel_node, ctid) => {
+                Some(PredicateLockTag::Page(rel_node, ctid.block_number))
+            }
+        }
+    }
+
+    // The tag itself followed by every coarser tag that covers it.
+    fn with_ancestors(self) -> Vec<PredicateLockTag> {
+        let mut tags = vec![self];
+        let mut current = self;
+        while let Some(parent) = current.parent() {
+            tags.push(parent);
+            current = parent;
+        }
+        tags
+    }
+
+    fn is_covered_by(self, other: PredicateLockTag) -> bool {
+        self != other && self.with_ancestors().contains(&other)
+    }
+}
+
+struct SerializableXact {
+    snapshot_seq: u64,
+    commit_seq: Option<u64>,
+    in_conflicts: HashSet<TransactionId>,
+    out_conflicts: HashSet<TransactionId>,
+    locks: HashSet<PredicateLockTag>,
+    doomed: bool,
+}
+
+impl SerializableXact {
+    // Whether `other`, which committed at `commit_seq`, ran concurrently
+    // with this transaction's snapshot.
+    fn overlaps(&self, commit_seq: Option<u64>) -> bool {
+        commit_seq.is_none_or(|seq| seq > self.snapshot_seq)
+    }
+}
+
+#[derive(Default)]
+struct SsiState {
+    xacts: HashMap<TransactionId, SerializableXact>,
+    locks: HashMap<PredicateLockTag, HashSet<TransactionId>>,
+    last_commit_seq: u64,
+}
+
+impl SsiState {
+    fn xact(&self, xid: TransactionId) -> &SerializableXact {
+        &self.xacts[&xid]
+    }
+
+    fn check_doomed(&self, xid: TransactionId) -> Result<()> {
+        if self.xact(xid).doomed {
+            return Err(serialization_failure(
+                "canceled on identification as a pivot, during a previous conflict",
+            ));
+        }
+        Ok(())
+    }
+
+    fn grant(&mut self, xid: TransactionId, tag: PredicateLockTag) {
+        let xact = self.xacts.get_mut(&xid).unwrap();
+        let covered: Vec<PredicateLockTag> = xact
+            .locks
+            .iter()
+            .copied()
+            .filter(|held| held.is_covered_by(tag))
+            .collect();
+        for held in &covered {
+            xact.locks.remove(held);
+        }
+        xact.locks.insert(tag);
+
+        for held in covered {
+            if let Some(holders) = self.locks.get_mut(&held) {
+                holders.remove(&xid);
+                if holders.is_empty() {
+                    self.locks.remove(&held);
+                }
+            }
+        }
+        self.locks.entry(tag).or_default().insert(xid);
+    }
+
+    // Tuple locks past PREDICATE_LOCKS_PER_PAGE on one page turn into a page
+    // lock, and page locks past PREDICATE_LOCKS_PER_RELATION into a
+    // relation lock.
+    fn promote(&mut self, xid: TransactionId, tag: PredicateLockTag) -> Option<PredicateLockTag> {
+        let parent = tag.parent()?;
+        let limit = match tag {
+            PredicateLockTag::Tuple(..) => PREDICATE_LOCKS_PER_PAGE,
+            _ => PREDICATE_LOCKS_PER_RELATION,
+        };
+        let siblings = self
+            .xact(xid)
+            .locks
+            .iter()
+            .filter(|held| held.parent() == Some(parent))
+            .count();
+
+        if siblings > limit {
+            Some(parent)
+        } else {
+            None
+        }
+    }
+
+    fn add_conflict(
+        &mut self,
+        reader: TransactionId,
+        writer: TransactionId,
+        current: TransactionId,
+    ) -> Result<()> {
+        if reader == writer {
+            return Ok(());
+        }
+        if self.xact(reader).out_conflicts.contains(&writer) {
+            return Ok(());
+        }
+
+        self.xacts
+            .get_mut(&reader)
+            .unwrap()
+            .out_conflicts
+            .insert(writer);
+        self.xacts
+            .get_mut(&writer)
+            .unwrap()
+            .in_conflicts
+            .insert(reader);
+
+        for pivot in [reader, writer] {
+            if let Some(t_in) = self.dangerous_structure(pivot) {
+                let victims: Vec<TransactionId> = [pivot, t_in]
+                    .into_iter()
+                    .filter(|&xid| self.xact(xid).commit_seq.is_none())
+                    .collect();
+                if victims.contains(&current) {
+                    return Err(serialization_failure(
+                        "identified as a pivot or reader in a dangerous structure",
+                    ));
+                }
+                if let Some(&victim) = victims.first() {
+                    self.xacts.get_mut(&victim).unwrap().doomed = true;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // T_in -rw-> pivot -rw-> T_out where T_out committed before both others.
+    // Returns T_in when `pivot` sits in the middle of such a structure.
+    fn dangerous_structure(&self, pivot: TransactionId) -> Option<TransactionId> {
+        let xact = self.xact(pivot);
+
+        for t_out in &xact.out_conflicts {
+            let out_seq = match self.xact(*t_out).commit_seq {
+                Some(seq) => seq,
+                None => continue,
+            };
+            if xact.commit_seq.is_some_and(|seq| seq < out_seq) {
+                continue;
+            }
+            for t_in in &xact.in_conflicts {
+                if self.xact(*t_in).commit_seq.is_none_or(|seq| seq >= out_seq) {
+                    return Some(*t_in);
+                }
+            }
+        }
+
+        None
+    }
+
+    fn remove(&mut self, xid: TransactionId) {
+        let xact = match self.xacts.remove(&xid) {
+            Some(xact) => xact,
+            None => return,
+        };
+
+        for tag in xact.locks {
+            if let Some(holders) = self.locks.get_mut(&tag) {
+                holders.remove(&xid);
+                if holders.is_empty() {
+                    self.locks.remove(&tag);
+                }
+            }
+        }
+        for other in xact.in_conflicts.iter().chain(&xact.out_conflicts) {
+            if let Some(other) = self.xacts.get_mut(other) {
+                other.in_conflicts.remove(&xid);
+                other.out_conflicts.remove(&xid);
+            }
+        }
+    }
+
+    // Committed transactions are kept, with their predicate locks, until no
+    // running serializable transaction overlaps them.
+    fn cleanup(&mut self) {
+        let oldest_active = self
+            .xacts
+            .values()
+            .filter(|xact| xact.commit_seq.is_none())
+            .map(|xact| xact.snapshot_seq)
+            .min();
+
+        let finished: Vec<TransactionId> = self
+            .xacts
+            .iter()
+            .filter(|(_, xact)| {
+                xact.commit_seq
+                    .is_some_and(|seq| oldest_active.is_none_or(|oldest| seq <= oldest))
+            })
+            .map(|(&xid, _)| xid)
+            .collect();
+
+        for xid in finished {
+            self.remove(xid);
+        }
+    }
+}
+
+fn serialization_failure(detail: &str) -> HeapError {
+    HeapError::SerializationFailure(format!(
+        "could not serialize access due to read/write dependencies among transactions ({})",
+        detail
+    ))
+}
+
+// Serializable Snapshot Isolation: predicate locks record what serializable
+// transactions read, writes against them become rw-conflicts, and a
+// transaction that would close a dangerous structure fails to serialize.
+// Only top-level xids of registered serializable transactions take part.
+pub struct PredicateLockManager {
+    state: Mutex<SsiState>,
+}
+
+impl PredicateLockManager {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(SsiState::default()),
+        }
+    }
+
+    // Must be called before the transaction takes its snapshot.
+    pub fn register(&self, xid: TransactionId) {
+        let mut state = self.state.lock().unwrap();
+        let snapshot_seq = state.last_commit_seq;
+        state.xacts.insert(
+            xid,
+            SerializableXact {
+                snapshot_seq,
+                commit_seq: None,
+                in_conflicts: HashSet::new(),
+                out_conflicts: HashSet::new(),
+                locks: HashSet::new(),
+                doomed: false,
+            },
+        );
+    }
+
+    // `xid` took over `source`'s snapshot, so it overlaps exactly the
+    // transactions `source` does.
+    pub fn import_snapshot(&self, xid: TransactionId, source: TransactionId) {
+        let mut state = self.state.lock().unwrap();
+        let snapshot_seq = match state.xacts.get(&source) {
+            Some(source) => source.snapshot_seq,
+            None => return,
+        };
+        if let Some(xact) = state.xacts.get_mut(&xid) {
+            xact.snapshot_seq = snapshot_seq;
+        }
+    }
+
+    pub fn is_registered(&self, xid: TransactionId) -> bool {
+        self.state.lock().unwrap().xacts.contains_key(&xid)
+    }
+
+    pub fn lock(&self, xid: TransactionId, tag: PredicateLockTag) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.xacts.contains_key(&xid) {
+            return Ok(());
+        }
+        state.check_doomed(xid)?;
+
+        let held = &state.xact(xid).locks;
+        if tag.with_ancestors().iter().any(|t| held.contains(t)) {
+            return Ok(());
+        }
+
+        let mut tag = tag;
+        state.grant(xid, tag);
+        while let Some(parent) = state.promote(xid, tag) {
+            state.grant(xid, parent);
+            tag = parent;
+        }
+
+        Ok(())
+    }
+
+    pub fn held_locks(&self, xid: TransactionId) -> Vec<PredicateLockTag> {
+        let state = self.state.lock().unwrap();
+        state
+            .xacts
+            .get(&xid)
+            .map(|xact| xact.locks.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    // `reader` saw a tuple version whose deletion or replacement by `writer`
+    // its snapshot does not include.
+    pub fn check_conflict_out(&self, reader: TransactionId, writer: TransactionId) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.xacts.contains_key(&reader) {
+            return Ok(());
+        }
+        state.check_doomed(reader)?;
+
+        let writer_commit = match state.xacts.get(&writer) {
+            Some(xact) => xact.commit_seq,
+            None => return Ok(()),
+        };
+        if !state.xact(reader).overlaps(writer_commit) {
+            return Ok(());
+        }
+
+        state.add_conflict(reader, writer, reader)
+    }
+
+    // `writer` is about to modify what `tag` covers; every concurrent
+    // serializable transaction that read it gets an rw-conflict to `writer`.
+    pub fn check_conflict_in(&self, writer: TransactionId, tag: PredicateLockTag) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.xacts.contains_key(&writer) {
+            return Ok(());
+        }
+        state.check_doomed(writer)?;
+
+        let mut readers: Vec<TransactionId> = tag
+            .with_ancestors()
+            .iter()
+            .filter_map(|t| state.locks.get(t))
+            .flatten()
+            .copied()
+            .filter(|&reader| reader != writer)
+            .collect();
+        readers.sort_by_key(|xid| xid.0);
+        readers.dedup();
+
+        for reader in readers {
+            let reader_commit = state.xact(reader).commit_seq;
+            if state.xact(writer).overlaps(reader_commit) {
+                state.add_conflict(reader, writer, writer)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Last check before `xid` commits. A transaction that is the pivot of a
+    // dangerous structure fails here; pivots that this commit would turn
+    // dangerous are doomed instead, so this one can go ahead.
+    pub fn pre_commit(&self, xid: TransactionId) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.xacts.contains_key(&xid) {
+            return Ok(());
+        }
+        state.check_doomed(xid)?;
+
+        if state.dangerous_structure(xid).is_some() {
+            return Err(serialization_failure(
+                "identified as a pivot during commit attempt",
+            ));
+        }
+
+        let pivots: Vec<TransactionId> = state
+            .xact(xid)
+            .in_conflicts
+            .iter()
+            .copied()
+            .filter(|&pivot| {
+                let pivot = state.xact(pivot);
+                pivot.commit_seq.is_none() && !pivot.in_conflicts.is_empty()
+            })
+            .collect();
+        for pivot in pivots {
+            state.xacts.get_mut(&pivot).unwrap().doomed = true;
+        }
+
+        Ok(())
+    }
+
+    pub fn commit(&self, xid: TransactionId) {
+        let mut state = self.state.lock().unwrap();
+        if !state.xacts.contains_key(&xid) {
+            return;
+        }
+        state.last_commit_seq += 1;
+        let seq = state.last_commit_seq;
+        state.xacts.get_mut(&xid).unwrap().commit_seq = Some(seq);
+        state.cleanup();
+    }
+
+    pub fn abort(&self, xid: TransactionId) {
+        let mut state = self.state.lock().unwrap();
+        state.remove(xid);
+        state.cleanup();
+    }
+}
+
+impl Default for PredicateLockManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,177 @@
+use crate::lock::LockOwner;
+use crate::types::*;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+pub type SnapshotId = u64;
+
+// What keeps the xmin horizon from advancing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum XminHolder {
+    Transaction(TransactionId),
+    Snapshot { owner: LockOwner, id: SnapshotId },
+    ExportedSnapshot(String),
+    PreparedTransaction(String),
+    ReplicationSlot(String),
+    RetainHistory,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XminHorizon {
+    pub xmin: TransactionId,
+    // None when nothing holds the horizon back from the next xid.
+    pub holder: Option<XminHolder>,
+}
+
+impl XminHorizon {
+    pub fn new(next_xid: TransactionId) -> Self {
+        Self {
+            xmin: next_xid,
+            holder: None,
+        }
+    }
+
+    // Moves the horizon back to `xmin` if it is older, blaming `holder`.
+    pub fn hold(&mut self, xmin: TransactionId, holder: impl FnOnce() -> XminHolder) {
+        if xmin.is_normal() && xmin.precedes(self.xmin) {
+            self.xmin = xmin;
+            self.holder = Some(holder());
+        }
+    }
+}
+
+// The running xids, with the oldest of them kept up to date so that a
+// snapshot can take its xmin without looking at the rest, and a set so
+// that visibility checks can look one up without scanning them all.
+pub struct RunningXids {
+    xids: HashSet<TransactionId>,
+    xmin: TransactionId,
+    // Follows the last xid added; the xmin once nothing runs.
+    next_xid: TransactionId,
+}
+
+impl RunningXids {
+    pub fn new(next_xid: TransactionId, xids: Vec<TransactionId>) -> Self {
+        let xmin = xids.iter().fold(next_xid, |xmin, &xid| xmin.older(xid));
+        Self {
+            xids: xids.into_iter().collect(),
+            xmin,
+            next_xid,
+        }
+    }
+
+    // Xids must be added in the order they were assigned.
+    pub fn add(&mut self, xid: TransactionId) {
+        if self.xids.is_empty() {
+            self.xmin = xid;
+        }
+        self.xids.insert(xid);
+        self.next_xid = xid.next();
+    }
+
+    pub fn remove(&mut self, finished: impl Fn(TransactionId) -> bool) {
+        self.xids.retain(|&xid| !finished(xid));
+        self.xmin = self
+            .xids
+            .iter()
+            .fold(self.next_xid, |xmin, &xid| xmin.older(xid));
+    }
+
+    // Most tuples a check sees were written before the oldest running xid,
+    // which settles them without the lookup.
+    pub fn contains(&self, xid: TransactionId) -> bool {
+        !xid.precedes(self.xmin) && self.xids.contains(&xid)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &TransactionId> {
+        self.xids.iter()
+    }
+
+    // No xid before this is still running.
+    pub fn xmin(&self) -> TransactionId {
+        self.xmin
+    }
+
+    pub fn next_xid(&self) -> TransactionId {
+        self.next_xid
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RegisteredSnapshot {
+    owner: LockOwner,
+    xmin: TransactionId,
+}
+
+// The snapshots each top-level transaction still uses: the one its current
+// statement runs under, and any it registered to keep for longer. Like a
+// PGPROC's xmin, they hold back what vacuum may remove.
+#[derive(Default)]
+pub struct SnapshotRegistry {
+    next_id: SnapshotId,
+    snapshots: BTreeMap<SnapshotId, RegisteredSnapshot>,
+    active: HashMap<LockOwner, SnapshotId>,
+}
+
+impl SnapshotRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, owner: LockOwner, snapshot: &Snapshot) -> SnapshotId {
+        self.next_id += 1;
+        self.snapshots.insert(
+            self.next_id,
+            RegisteredSnapshot {
+                owner,
+                xmin: snapshot.xmin,
+            },
+        );
+        self.next_id
+    }
+
+    pub fn unregister(&mut self, id: SnapshotId) -> bool {
+        self.snapshots.remove(&id).is_some()
+    }
+
+    // Replaces `owner`'s active snapshot; the previous statement's is no
+    // longer needed.
+    pub fn set_active(&mut self, owner: LockOwner, snapshot: &Snapshot) -> SnapshotId {
+        if let Some(previous) = self.active.remove(&owner) {
+            self.unregister(previous);
+        }
+        let id = self.register(owner, snapshot);
+        self.active.insert(owner, id);
+        id
+    }
+
+    pub fn forget_owner(&mut self, owner: LockOwner) {
+        self.snapshots.retain(|_, snapshot| snapshot.owner != owner);
+        self.active.remove(&owner);
+    }
+
+    // The oldest xmin among `owner`'s snapshots.
+    pub fn owner_xmin(&self, owner: LockOwner) -> Option<TransactionId> {
+        self.snapshots
+            .values()
+            .filter(|snapshot| snapshot.owner == owner)
+            .map(|snapshot| snapshot.xmin)
+            .reduce(|oldest, xmin| oldest.older(xmin))
+    }
+
+    pub fn hold(&self, horizon: &mut XminHorizon) {
+        for (&id, snapshot) in &self.snapshots {
+            horizon.hold(snapshot.xmin, || XminHolder::Snapshot {
+                owner: snapshot.owner,
+                id,
+            });
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+}
@@ -0,0 +1,218 @@
+use crate::constants::*;
+use crate::error::{HeapError, Result};
+use crate::page::Page;
+use crate::relation::Relation;
+use crate::transaction::TransactionManager;
+use crate::types::*;
+use crate::wal::{XLogBlockRef, XLogRecord, XLogRecordType};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecoveryStats {
+    pub redo_lsn: u64,
+    pub replayed: usize,
+    pub skipped: usize,
+    pub aborted: Vec<TransactionId>,
+}
+
+impl RecoveryStats {
+    // Whether redo changed anything the end-of-recovery checkpoint has to
+    // write out.
+    pub fn changed(&self) -> bool {
+        self.replayed > 0 || !self.aborted.is_empty()
+    }
+}
+
+// The startup process: replays the WAL from the last checkpoint's redo
+// point into the relations' pages and the clog, then aborts whatever the
+// crash left running. Blocks of relations it was not given are skipped.
+pub struct Recovery<'a> {
+    tx_manager: &'a TransactionManager,
+    relations: HashMap<u32, &'a Relation>,
+}
+
+impl<'a> Recovery<'a> {
+    pub fn new(tx_manager: &'a TransactionManager) -> Self {
+        Self {
+            tx_manager,
+            relations: HashMap::new(),
+        }
+    }
+
+    pub fn add_relation(&mut self, relation: &'a Relation) {
+        self.relations.insert(relation.rel_node, relation);
+    }
+
+    pub fn run(&self) -> Result<RecoveryStats> {
+        let redo_lsn = self.tx_manager.checkpoint_redo();
+        let mut stats = RecoveryStats {
+            redo_lsn,
+            ..RecoveryStats::default()
+        };
+        let records = match self.tx_manager.wal() {
+            Some(wal) => wal.recover_from(redo_lsn)?,
+            None => return Ok(stats),
+        };
+
+        // Transactions that might have been cut off: those running at the
+        // redo point, through the newest xid with a record. A transaction
+        // whose last record predates the redo point still has its changes
+        // on disk, so the checkpoint also records the next xid it would
+        // have handed out.
+        let mut oldest_running = self.tx_manager.xid_limits().oldest_xid;
+        let mut newest: Option<TransactionId> = None;
+        let mut note_xid = |xid: TransactionId| {
+            if xid.is_normal() && newest.is_none_or(|newest| xid.follows(newest)) {
+                newest = Some(xid);
+            }
+        };
+        for record in &records {
+            note_xid(TransactionId(record.txid));
+            if record.record_type == XLogRecordType::Checkpoint && record.data.len() >= 16 {
+                let redo = u64::from_le_bytes(record.data[..8].try_into().unwrap());
+                if redo == redo_lsn {
+                    oldest_running =
+                        TransactionId(u32::from_le_bytes(record.data[8..12].try_into().unwrap()));
+                    let next_xid =
+                        TransactionId(u32::from_le_bytes(record.data[12..16].try_into().unwrap()));
+                    note_xid(next_xid.prev());
+                }
+            }
+        }
+
+        for record in &records {
+            match record.record_type {
+                XLogRecordType::HeapInsert
+                | XLogRecordType::HeapUpdate
+                | XLogRecordType::HeapDelete
+                | XLogRecordType::HeapLock
+                | XLogRecordType::HeapVacuum
+                | XLogRecordType::BTreeInsert
+                | XLogRecordType::BTreeDelete => {
+                    for block in record.blocks()? {
+                        self.redo_block(record, &block, &mut stats)?;
+                    }
+                }
+                XLogRecordType::FpiForHint => self.redo_fpi(record, &mut stats)?,
+                XLogRecordType::TransactionCommit
+                | XLogRecordType::TransactionAbort
+                | XLogRecordType::ClogZeroPage
+                | XLogRecordType::ClogTruncate
+                | XLogRecordType::CommitTsZeroPage
+                | XLogRecordType::CommitTsTruncate => {
+                    self.tx_manager.redo_xact(record)?;
+                    stats.replayed += 1;
+                }
+                XLogRecordType::MultiXactCreate => {
+                    self.tx_manager.multixact().redo_create(record)?;
+                    stats.replayed += 1;
+                }
+                XLogRecordType::Checkpoint => {}
+            }
+        }
+
+        if let Some(newest) = newest {
+            stats.aborted = self.tx_manager.abort_crashed(oldest_running, newest)?;
+        }
+        Ok(stats)
+    }
+
+    // The relation a block belongs to, extended if the crash came before
+    // its new pages reached disk. None when the relation is unknown.
+    fn relation_for(
+        &self,
+        rel_node: u32,
+        block_num: u32,
+        stats: &mut RecoveryStats,
+    ) -> Result<Option<&'a Relation>> {
+        let Some(&relation) = self.relations.get(&rel_node) else {
+            stats.skipped += 1;
+            return Ok(None);
+        };
+        while relation.page_count() <= block_num {
+            relation.allocate_page()?;
+        }
+        Ok(Some(relation))
+    }
+
+    // A full-page image is restored whatever the page holds, as the page
+    // may be torn. Otherwise the change is applied unless the page already
+    // has it.
+    fn redo_block(
+        &self,
+        record: &XLogRecord,
+        block: &XLogBlockRef,
+        stats: &mut RecoveryStats,
+    ) -> Result<()> {
+        let Some(relation) = self.relation_for(block.rel_node, block.block_num, stats)? else {
+            return Ok(());
+        };
+
+        let mut page = match block.image {
+            Some(ref image) => Page::from_raw(image.clone())?,
+            None => {
+                let mut page = relation.read_page(block.block_num)?;
+                if page.header.pd_lsn >= record.lsn {
+                    stats.skipped += 1;
+                    return Ok(());
+                }
+                Self::apply_block(record, block, &mut page)?;
+                page
+            }
+        };
+
+        page.header.pd_lsn = record.lsn;
+        relation.write_page(block.block_num, &page)?;
+        stats.replayed += 1;
+        Ok(())
+    }
+
+    fn apply_block(record: &XLogRecord, block: &XLogBlockRef, page: &mut Page) -> Result<()> {
+        // A B-tree node is logged whole as its page's only item, and may
+        // have grown or shrunk since the page was last written.
+        if matches!(
+            record.record_type,
+            XLogRecordType::BTreeInsert | XLogRecordType::BTreeDelete
+        ) {
+            *page = Page::new(BLCKSZ);
+        }
+        for (offset, data) in &block.tuples {
+            if let Some(item) = page
+                .get_item_mut(*offset)
+                .filter(|item| item.len() == data.len())
+            {
+                item.copy_from_slice(data);
+            } else if *offset as usize == page.item_count() + 1 {
+                page.add_item(data)?;
+            } else {
+                return Err(HeapError::CorruptedData(format!(
+                    "WAL record at {} does not fit item {} of block {} of relation {}",
+                    record.lsn, offset, block.block_num, block.rel_node
+                )));
+            }
+        }
+        for &offset in &block.removed {
+            page.remove_item(offset)?;
+        }
+        Ok(())
+    }
+
+    fn redo_fpi(&self, record: &XLogRecord, stats: &mut RecoveryStats) -> Result<()> {
+        if record.data.len() < 4 {
+            return Err(HeapError::CorruptedData(format!(
+                "WAL record at {} has no page image",
+                record.lsn
+            )));
+        }
+        let rel_node = u32::from_le_bytes(record.data[..4].try_into().unwrap());
+        let Some(relation) = self.relation_for(rel_node, record.block_id, stats)? else {
+            return Ok(());
+        };
+
+        let mut page = Page::from_raw(record.data[4..].to_vec())?;
+        page.header.pd_lsn = record.lsn;
+        relation.write_page(record.block_id, &page)?;
+        stats.replayed += 1;
+        Ok(())
+    }
+}
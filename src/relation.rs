@@ -1,10 +1,36 @@
+use crate::buffer::{BufferPoolRef, BufferTag};
 use crate::constants::*;
 use crate::error::{HeapError, Result};
+use crate::failpoint;
 use crate::page::Page;
 use crate::storage::{Storage, StorageRef};
 use crate::types::*;
-use std::path::PathBuf;
-use std::sync::{Arc, RwLock};
+use crate::wal::WALRef;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RelationMeta {
+    pub rel_node: u32,
+    pub natts: u16,
+    pub relfrozenxid: TransactionId,
+    pub relminmxid: MultiXactId,
+}
+
+impl Default for RelationMeta {
+    fn default() -> Self {
+        Self {
+            rel_node: 0,
+            natts: 0,
+            relfrozenxid: TransactionId::first_normal(),
+            relminmxid: MultiXactId::first(),
+        }
+    }
+}
 
 pub struct Relation {
     pub rel_node: u32,
@@ -12,53 +38,191 @@ pub struct Relation {
     pub spc_node: u32,
     pub natts: u16,
     pub storage: StorageRef,
+    meta_path: PathBuf,
+    meta: RwLock<RelationMeta>,
+    content_lock: Mutex<()>,
+    buffer_pool: Option<BufferPoolRef>,
+    wal: Option<WALRef>,
+    data_checksums: bool,
 }
 
 impl Relation {
     pub fn create(path: PathBuf, natts: u16) -> Result<(Self, u32)> {
         let rel_node = uuid::Uuid::new_v4().as_u128() as u32;
+        Ok((Self::create_with_node(path, natts, rel_node)?, rel_node))
+    }
+
+    // Creates the relation under a rel_node its owner keeps unique.
+    pub fn create_with_node(path: PathBuf, natts: u16, rel_node: u32) -> Result<Self> {
         let db_node = 0u32;
         let spc_node = 0u32;
 
-        let storage = Arc::new(Storage::new(path)?);
+        let storage = Arc::new(Storage::new(path.clone())?);
 
         let page = Page::new(BLCKSZ);
         storage.write_page(0, &page)?;
 
+        let meta = RelationMeta {
+            rel_node,
+            natts,
+            ..RelationMeta::default()
+        };
+        let meta_path = path.join("pg_class");
+        Self::write_meta(&meta_path, &meta)?;
+
         let rel = Self {
             rel_node,
             db_node,
             spc_node,
             natts,
             storage,
+            meta_path,
+            meta: RwLock::new(meta),
+            content_lock: Mutex::new(()),
+            buffer_pool: None,
+            wal: None,
+            data_checksums: false,
         };
 
-        Ok((rel, rel_node))
+        Ok(rel)
     }
 
     pub fn open(path: PathBuf) -> Result<Self> {
-        let storage = Arc::new(Storage::open(path)?);
+        let storage = Arc::new(Storage::open(path.clone())?);
 
         if storage.page_count() == 0 {
             let page = Page::new(BLCKSZ);
             storage.write_page(0, &page)?;
         }
 
+        let meta_path = path.join("pg_class");
+        let meta: RelationMeta = if meta_path.exists() {
+            let raw = fs::read(&meta_path)?;
+            serde_json::from_slice(&raw)
+                .map_err(|e| HeapError::CorruptedData(format!("pg_class: {}", e)))?
+        } else {
+            RelationMeta::default()
+        };
+
         Ok(Self {
-            rel_node: 0,
+            rel_node: meta.rel_node,
             db_node: 0,
             spc_node: 0,
-            natts: 0,
+            natts: meta.natts,
             storage,
+            meta_path,
+            meta: RwLock::new(meta),
+            content_lock: Mutex::new(()),
+            buffer_pool: None,
+            wal: None,
+            data_checksums: false,
         })
     }
 
+    // Routes page reads and writes through a pool shared with other
+    // relations instead of going straight to storage.
+    pub fn with_buffer_pool(mut self, buffer_pool: BufferPoolRef) -> Self {
+        self.buffer_pool = Some(buffer_pool);
+        self
+    }
+
+    // Without a buffer pool, pages go straight to storage, so the WAL is
+    // flushed up to a page's LSN before it is written.
+    pub fn with_wal(mut self, wal: WALRef) -> Self {
+        self.wal = Some(wal);
+        self
+    }
+
+    pub fn with_data_checksums(mut self, enabled: bool) -> Self {
+        self.data_checksums = enabled;
+        self
+    }
+
+    pub fn data_checksums(&self) -> bool {
+        self.data_checksums
+    }
+
+    pub fn relfrozenxid(&self) -> TransactionId {
+        self.meta.read().unwrap().relfrozenxid
+    }
+
+    pub fn relminmxid(&self) -> MultiXactId {
+        self.meta.read().unwrap().relminmxid
+    }
+
+    pub fn set_frozen_ids(
+        &self,
+        relfrozenxid: TransactionId,
+        relminmxid: MultiXactId,
+    ) -> Result<()> {
+        let mut meta = self.meta.write().unwrap();
+        meta.relfrozenxid = relfrozenxid;
+        meta.relminmxid = relminmxid;
+        Self::write_meta(&self.meta_path, &meta)
+    }
+
+    fn write_meta(path: &Path, meta: &RelationMeta) -> Result<()> {
+        let raw = serde_json::to_vec_pretty(meta)
+            .map_err(|e| HeapError::StorageError(format!("pg_class: {}", e)))?;
+
+        let tmp_path = path.with_extension("tmp");
+        failpoint::before_write(path)?;
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&raw)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+
+        Ok(())
+    }
+
+    // Serializes read-modify-write cycles on the relation's pages.
+    pub fn lock_content(&self) -> MutexGuard<'_, ()> {
+        self.content_lock.lock().unwrap()
+    }
+
     pub fn read_page(&self, block_num: u32) -> Result<Page> {
-        self.storage.read_page(block_num)
+        let page = match self.buffer_pool {
+            Some(ref pool) => {
+                pool.read_page(&self.storage, BufferTag::new(self.rel_node, block_num))?
+            }
+            None => self.storage.read_page(block_num)?,
+        };
+
+        if self.data_checksums && !page.verify_checksum(block_num) {
+            return Err(HeapError::CorruptedData(format!(
+                "page verification failed, calculated checksum {} but expected {} in block {} of relation {}",
+                page.compute_checksum(block_num),
+                page.header.pd_checksum,
+                block_num,
+                self.rel_node
+            )));
+        }
+        Ok(page)
     }
 
     pub fn write_page(&self, block_num: u32, page: &Page) -> Result<()> {
-        self.storage.write_page(block_num, page)
+        let mut checksummed;
+        let page = if self.data_checksums {
+            checksummed = page.clone();
+            checksummed.set_checksum(block_num);
+            &checksummed
+        } else {
+            page
+        };
+
+        match self.buffer_pool {
+            Some(ref pool) => pool.write_page(
+                &self.storage,
+                BufferTag::new(self.rel_node, block_num),
+                page,
+            ),
+            None => {
+                if let Some(ref wal) = self.wal {
+                    wal.flush(page.header.pd_lsn)?;
+                }
+                self.storage.write_page(block_num, page)
+            }
+        }
     }
 
     pub fn allocate_page(&self) -> Result<u32> {
@@ -70,10 +234,16 @@ impl Relation {
     }
 
     pub fn close(&self) -> Result<()> {
+        if let Some(ref pool) = self.buffer_pool {
+            pool.flush_relation(self.rel_node)?;
+        }
         self.storage.close()
     }
 
     pub fn drop(&self) -> Result<()> {
+        if let Some(ref pool) = self.buffer_pool {
+            pool.drop_relation(self.rel_node);
+        }
         self.storage.drop_all()
     }
 }
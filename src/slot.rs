@@ -0,0 +1,159 @@
+use crate::error::{HeapError, Result};
+use crate::failpoint;
+use crate::types::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+pub const SLOT_NAME_MAX_LEN: usize = 63;
+
+// A consumer that still needs every change from `xmin` on, whether or not
+// it is connected. Slots survive restarts.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplicationSlot {
+    pub name: String,
+    pub xmin: TransactionId,
+}
+
+pub struct ReplicationSlots {
+    dir: Option<PathBuf>,
+    slots: RwLock<BTreeMap<String, ReplicationSlot>>,
+}
+
+impl ReplicationSlots {
+    pub fn open(dir: PathBuf) -> Result<Self> {
+        let dir = dir.join("pg_replslot");
+        if !dir.exists() {
+            fs::create_dir_all(&dir)?;
+        }
+
+        let mut slots = BTreeMap::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "tmp") {
+                fs::remove_file(path)?;
+                continue;
+            }
+
+            let raw = fs::read(&path)?;
+            let slot: ReplicationSlot = serde_json::from_slice(&raw).map_err(|e| {
+                HeapError::CorruptedData(format!("replication slot {}: {}", path.display(), e))
+            })?;
+            slots.insert(slot.name.clone(), slot);
+        }
+
+        Ok(Self {
+            dir: Some(dir),
+            slots: RwLock::new(slots),
+        })
+    }
+
+    pub fn in_memory() -> Self {
+        Self {
+            dir: None,
+            slots: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    // `xmin` is called with the slots locked, so no horizon computed
+    // meanwhile can miss the new slot.
+    pub fn create(
+        &self,
+        name: &str,
+        xmin: impl FnOnce() -> TransactionId,
+    ) -> Result<ReplicationSlot> {
+        if name.is_empty()
+            || name.len() > SLOT_NAME_MAX_LEN
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        {
+            return Err(HeapError::InvalidOperation(format!(
+                "replication slot name \"{}\" is not valid",
+                name
+            )));
+        }
+
+        let mut slots = self.slots.write().unwrap();
+        if slots.contains_key(name) {
+            return Err(HeapError::InvalidOperation(format!(
+                "replication slot \"{}\" already exists",
+                name
+            )));
+        }
+
+        let slot = ReplicationSlot {
+            name: name.to_string(),
+            xmin: xmin(),
+        };
+        self.save(&slot)?;
+        slots.insert(slot.name.clone(), slot.clone());
+        Ok(slot)
+    }
+
+    // The consumer is done with everything before `xmin`. A slot never
+    // moves backwards.
+    pub fn advance(&self, name: &str, xmin: TransactionId) -> Result<()> {
+        let mut slots = self.slots.write().unwrap();
+        let slot = slots.get_mut(name).ok_or_else(|| Self::not_found(name))?;
+        if xmin.follows(slot.xmin) {
+            let mut advanced = slot.clone();
+            advanced.xmin = xmin;
+            self.save(&advanced)?;
+            *slot = advanced;
+        }
+        Ok(())
+    }
+
+    pub fn drop(&self, name: &str) -> Result<()> {
+        let mut slots = self.slots.write().unwrap();
+        if slots.remove(name).is_none() {
+            return Err(Self::not_found(name));
+        }
+
+        if let Some(ref dir) = self.dir {
+            let path = Self::state_file(dir, name);
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<ReplicationSlot> {
+        self.slots.read().unwrap().get(name).cloned()
+    }
+
+    pub fn list(&self) -> Vec<ReplicationSlot> {
+        self.slots.read().unwrap().values().cloned().collect()
+    }
+
+    fn save(&self, slot: &ReplicationSlot) -> Result<()> {
+        let dir = match self.dir {
+            Some(ref dir) => dir,
+            None => return Ok(()),
+        };
+
+        let raw = serde_json::to_vec_pretty(slot)
+            .map_err(|e| HeapError::StorageError(format!("replication slot: {}", e)))?;
+        let path = Self::state_file(dir, &slot.name);
+        let tmp_path = path.with_extension("tmp");
+        failpoint::before_write(&path)?;
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&raw)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    fn not_found(name: &str) -> HeapError {
+        HeapError::InvalidOperation(format!("replication slot \"{}\" does not exist", name))
+    }
+
+    fn state_file(dir: &Path, name: &str) -> PathBuf {
+        dir.join(name)
+    }
+}
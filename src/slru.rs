@@ -0,0 +1,320 @@
+use crate::constants::*;
+use crate::error::Result;
+use crate::failpoint;
+use crate::wal::WALRef;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+struct SlruSlot {
+    page_number: Option<u32>,
+    data: Vec<u8>,
+    dirty: bool,
+    lru_count: u64,
+    // The newest WAL record that changed the page, which has to be on disk
+    // before the page is; 0 when no record did.
+    lsn: u64,
+}
+
+impl SlruSlot {
+    fn empty() -> Self {
+        Self {
+            page_number: None,
+            data: vec![0u8; BLCKSZ],
+            dirty: false,
+            lru_count: 0,
+            lsn: 0,
+        }
+    }
+}
+
+struct SlruShared {
+    slots: Vec<SlruSlot>,
+    cur_lru_count: u64,
+    memory_pages: HashMap<u32, Vec<u8>>,
+}
+
+// Simple LRU buffer cache over a directory of fixed-size segment files,
+// used for the commit log and the other per-xid status stores. Without a
+// directory the pages are kept in memory only.
+pub struct Slru {
+    dir: Option<PathBuf>,
+    shared: Mutex<SlruShared>,
+    wal: Option<WALRef>,
+}
+
+impl Slru {
+    pub fn open(dir: PathBuf, num_slots: usize) -> Result<Self> {
+        if !dir.exists() {
+            fs::create_dir_all(&dir)?;
+        }
+
+        Ok(Self::with_dir(Some(dir), num_slots))
+    }
+
+    pub fn in_memory(num_slots: usize) -> Self {
+        Self::with_dir(None, num_slots)
+    }
+
+    fn with_dir(dir: Option<PathBuf>, num_slots: usize) -> Self {
+        Self {
+            dir,
+            shared: Mutex::new(SlruShared {
+                slots: (0..num_slots.max(1)).map(|_| SlruSlot::empty()).collect(),
+                cur_lru_count: 0,
+                memory_pages: HashMap::new(),
+            }),
+            wal: None,
+        }
+    }
+
+    // The WAL that page write-back waits on, as the buffer pool does
+    // through pd_lsn.
+    pub fn with_wal(mut self, wal: Option<WALRef>) -> Self {
+        self.wal = wal;
+        self
+    }
+
+    pub fn is_persistent(&self) -> bool {
+        self.dir.is_some()
+    }
+
+    pub fn read<R>(&self, page_number: u32, f: impl FnOnce(&[u8]) -> R) -> Result<R> {
+        let mut shared = self.shared.lock().unwrap();
+        let slot = self.read_page(&mut shared, page_number)?;
+        Ok(f(&shared.slots[slot].data))
+    }
+
+    pub fn write<R>(&self, page_number: u32, f: impl FnOnce(&mut [u8]) -> R) -> Result<R> {
+        self.write_logged(page_number, 0, f)
+    }
+
+    // A change described by the WAL record ending at `lsn`: the page is not
+    // written back until the WAL is flushed that far.
+    pub fn write_logged<R>(
+        &self,
+        page_number: u32,
+        lsn: u64,
+        f: impl FnOnce(&mut [u8]) -> R,
+    ) -> Result<R> {
+        let mut shared = self.shared.lock().unwrap();
+        let slot = self.read_page(&mut shared, page_number)?;
+        let entry = &mut shared.slots[slot];
+        entry.dirty = true;
+        entry.lsn = entry.lsn.max(lsn);
+        Ok(f(&mut entry.data))
+    }
+
+    pub fn zero_page(&self, page_number: u32) -> Result<()> {
+        let mut shared = self.shared.lock().unwrap();
+        let slot = match Self::find_slot(&shared, page_number) {
+            Some(slot) => slot,
+            None => self.select_victim(&mut shared)?,
+        };
+
+        let lru_count = Self::next_lru_count(&mut shared);
+        let entry = &mut shared.slots[slot];
+        entry.page_number = Some(page_number);
+        entry.data.iter_mut().for_each(|b| *b = 0);
+        entry.dirty = true;
+        entry.lru_count = lru_count;
+        entry.lsn = 0;
+
+        Ok(())
+    }
+
+    pub fn page_exists(&self, page_number: u32) -> bool {
+        let shared = self.shared.lock().unwrap();
+        if Self::find_slot(&shared, page_number).is_some() {
+            return true;
+        }
+
+        match self.dir {
+            Some(ref dir) => {
+                let path = dir.join(Self::segment_name(page_number));
+                fs::metadata(path)
+                    .map(|m| m.len() >= Self::segment_offset(page_number) + BLCKSZ as u64)
+                    .unwrap_or(false)
+            }
+            None => shared.memory_pages.contains_key(&page_number),
+        }
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        let mut shared = self.shared.lock().unwrap();
+        for slot in 0..shared.slots.len() {
+            self.write_slot(&mut shared, slot)?;
+        }
+
+        Ok(())
+    }
+
+    // Drops every whole segment that lies entirely before `cutoff_page`.
+    // Page numbers wrap along with xids, so the ordering is supplied by the
+    // caller.
+    pub fn truncate(
+        &self,
+        cutoff_page: u32,
+        page_precedes: impl Fn(u32, u32) -> bool,
+    ) -> Result<()> {
+        let may_delete = |segment: u32| {
+            let first_page = segment * SLRU_PAGES_PER_SEGMENT;
+            let last_page = first_page + SLRU_PAGES_PER_SEGMENT - 1;
+            page_precedes(first_page, cutoff_page) && page_precedes(last_page, cutoff_page)
+        };
+        let mut shared = self.shared.lock().unwrap();
+
+        for slot in shared.slots.iter_mut() {
+            if let Some(page_number) = slot.page_number {
+                if may_delete(page_number / SLRU_PAGES_PER_SEGMENT) {
+                    slot.page_number = None;
+                    slot.dirty = false;
+                }
+            }
+        }
+
+        match self.dir {
+            Some(ref dir) => {
+                for entry in fs::read_dir(dir)? {
+                    let path = entry?.path();
+                    let segment = path
+                        .file_name()
+                        .and_then(|s| s.to_str())
+                        .and_then(|s| u32::from_str_radix(s, 16).ok());
+                    if let Some(segment) = segment {
+                        if may_delete(segment) {
+                            fs::remove_file(path)?;
+                        }
+                    }
+                }
+            }
+            None => shared
+                .memory_pages
+                .retain(|&page_number, _| !may_delete(page_number / SLRU_PAGES_PER_SEGMENT)),
+        }
+
+        Ok(())
+    }
+
+    fn segment_name(page_number: u32) -> String {
+        format!("{:04X}", page_number / SLRU_PAGES_PER_SEGMENT)
+    }
+
+    fn segment_offset(page_number: u32) -> u64 {
+        (page_number % SLRU_PAGES_PER_SEGMENT) as u64 * BLCKSZ as u64
+    }
+
+    fn next_lru_count(shared: &mut SlruShared) -> u64 {
+        shared.cur_lru_count += 1;
+        shared.cur_lru_count
+    }
+
+    fn find_slot(shared: &SlruShared, page_number: u32) -> Option<usize> {
+        shared
+            .slots
+            .iter()
+            .position(|slot| slot.page_number == Some(page_number))
+    }
+
+    fn read_page(&self, shared: &mut SlruShared, page_number: u32) -> Result<usize> {
+        let slot = match Self::find_slot(shared, page_number) {
+            Some(slot) => slot,
+            None => {
+                let slot = self.select_victim(shared)?;
+                let data = self.read_physical(shared, page_number)?;
+                let entry = &mut shared.slots[slot];
+                entry.page_number = Some(page_number);
+                entry.data = data;
+                entry.dirty = false;
+                entry.lsn = 0;
+                slot
+            }
+        };
+
+        shared.slots[slot].lru_count = Self::next_lru_count(shared);
+        Ok(slot)
+    }
+
+    fn select_victim(&self, shared: &mut SlruShared) -> Result<usize> {
+        let slot = match shared.slots.iter().position(|s| s.page_number.is_none()) {
+            Some(slot) => slot,
+            None => shared
+                .slots
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, s)| s.lru_count)
+                .map(|(i, _)| i)
+                .unwrap_or(0),
+        };
+
+        self.write_slot(shared, slot)?;
+        shared.slots[slot].page_number = None;
+        Ok(slot)
+    }
+
+    fn read_physical(&self, shared: &SlruShared, page_number: u32) -> Result<Vec<u8>> {
+        let mut data = vec![0u8; BLCKSZ];
+
+        match self.dir {
+            Some(ref dir) => {
+                let path = dir.join(Self::segment_name(page_number));
+                if let Ok(mut file) = File::open(path) {
+                    let offset = Self::segment_offset(page_number);
+                    if file.metadata()?.len() >= offset + BLCKSZ as u64 {
+                        file.seek(SeekFrom::Start(offset))?;
+                        file.read_exact(&mut data)?;
+                    }
+                }
+            }
+            None => {
+                if let Some(page) = shared.memory_pages.get(&page_number) {
+                    data.copy_from_slice(page);
+                }
+            }
+        }
+
+        Ok(data)
+    }
+
+    fn write_slot(&self, shared: &mut SlruShared, slot: usize) -> Result<()> {
+        let page_number = match shared.slots[slot].page_number {
+            Some(page_number) if shared.slots[slot].dirty => page_number,
+            _ => return Ok(()),
+        };
+
+        match self.dir {
+            Some(ref dir) => {
+                if let Some(ref wal) = self.wal {
+                    wal.flush(shared.slots[slot].lsn)?;
+                }
+                let path = dir.join(Self::segment_name(page_number));
+                failpoint::before_write(&path)?;
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .truncate(false)
+                    .write(true)
+                    .open(path)?;
+                file.seek(SeekFrom::Start(Self::segment_offset(page_number)))?;
+                file.write_all(&shared.slots[slot].data)?;
+                file.sync_all()?;
+            }
+            None => {
+                let data = shared.slots[slot].data.clone();
+                shared.memory_pages.insert(page_number, data);
+            }
+        }
+
+        shared.slots[slot].dirty = false;
+        Ok(())
+    }
+}
+
+impl Drop for Slru {
+    fn drop(&mut self) {
+        if self.dir.is_some() {
+            let _ = self.flush();
+        }
+    }
+}
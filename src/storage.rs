@@ -1,9 +1,10 @@
 use crate::constants::*;
 use crate::error::{HeapError, Result};
+use crate::failpoint;
 use crate::page::Page;
 use std::collections::HashMap;
 use std::fs::{self, File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{Read, Write};
 use std::path::PathBuf;
 use std::sync::{Arc, RwLock};
 
@@ -38,12 +39,13 @@ impl Storage {
         for entry in entries {
             let entry = entry?;
             let path = entry.path();
-            if path.extension().map_or(false, |ext| ext == "dat") {
+            if path.extension().is_some_and(|ext| ext == "dat") {
                 let file_name = path.file_stem().and_then(|s| s.to_str()).unwrap_or("");
-                if let Ok(block_num) = u32::from_str_radix(file_name, 10) {
-                    let mut file = File::open(&path)?;
-                    let mut data = vec![0u8; BLCKSZ];
-                    file.read_exact(&mut data)?;
+                if let Ok(block_num) = file_name.parse::<u32>() {
+                    // A page torn while being added is short; redo rewrites it.
+                    let mut data = Vec::with_capacity(BLCKSZ);
+                    File::open(&path)?.read_to_end(&mut data)?;
+                    data.resize(BLCKSZ, 0);
                     pages.insert(block_num, data);
                     if block_num > max_block {
                         max_block = block_num;
@@ -85,6 +87,7 @@ impl Storage {
         let data = page.serialize();
 
         let file_path = self.dir.join(format!("{}.dat", block_num));
+        failpoint::before_page_write(&file_path, &data)?;
         let mut file = OpenOptions::new()
             .create(true)
             .write(true)
@@ -134,6 +137,7 @@ impl Storage {
 
         for (block_num, data) in pages.iter() {
             let file_path = self.dir.join(format!("{}.dat", block_num));
+            failpoint::before_page_write(&file_path, data)?;
             let mut file = OpenOptions::new()
                 .create(true)
                 .write(true)
@@ -155,7 +159,7 @@ impl Storage {
         for entry in entries {
             let entry = entry?;
             let path = entry.path();
-            if path.extension().map_or(false, |ext| ext == "dat") {
+            if path.extension().is_some_and(|ext| ext == "dat") {
                 fs::remove_file(path)?;
             }
         }
@@ -0,0 +1,95 @@
+use crate::constants::*;
+use crate::error::Result;
+use crate::slru::Slru;
+use crate::types::*;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+pub struct SubTrans {
+    slru: Slru,
+    latest_page: Mutex<Option<u32>>,
+}
+
+impl SubTrans {
+    pub fn open(dir: PathBuf) -> Result<Self> {
+        Ok(Self {
+            slru: Slru::open(dir.join("pg_subtrans"), NUM_SUBTRANS_BUFFERS)?,
+            latest_page: Mutex::new(None),
+        })
+    }
+
+    pub fn in_memory() -> Self {
+        Self {
+            slru: Slru::in_memory(NUM_SUBTRANS_BUFFERS),
+            latest_page: Mutex::new(None),
+        }
+    }
+
+    pub fn page_number(xid: TransactionId) -> u32 {
+        xid.0 / SUBTRANS_XACTS_PER_PAGE
+    }
+
+    pub fn page_precedes(page1: u32, page2: u32) -> bool {
+        let xid1 = TransactionId(page1 * SUBTRANS_XACTS_PER_PAGE + FIRST_NORMAL_TRANSACTION_ID);
+        let xid2 = TransactionId(page2 * SUBTRANS_XACTS_PER_PAGE + FIRST_NORMAL_TRANSACTION_ID);
+        xid1.precedes(xid2)
+    }
+
+    fn entry(xid: TransactionId) -> usize {
+        (xid.0 % SUBTRANS_XACTS_PER_PAGE) as usize * 4
+    }
+
+    pub fn extend(&self, xid: TransactionId) -> Result<()> {
+        let page_number = Self::page_number(xid);
+        let mut latest_page = self.latest_page.lock().unwrap();
+        if *latest_page == Some(page_number) {
+            return Ok(());
+        }
+
+        if !self.slru.page_exists(page_number) {
+            self.slru.zero_page(page_number)?;
+        }
+
+        *latest_page = Some(page_number);
+        Ok(())
+    }
+
+    pub fn set_parent(&self, xid: TransactionId, parent: TransactionId) -> Result<()> {
+        let entry = Self::entry(xid);
+        self.slru.write(Self::page_number(xid), |page| {
+            page[entry..entry + 4].copy_from_slice(&parent.0.to_le_bytes());
+        })
+    }
+
+    pub fn get_parent(&self, xid: TransactionId) -> Result<TransactionId> {
+        let entry = Self::entry(xid);
+        self.slru.read(Self::page_number(xid), |page| {
+            TransactionId(u32::from_le_bytes([
+                page[entry],
+                page[entry + 1],
+                page[entry + 2],
+                page[entry + 3],
+            ]))
+        })
+    }
+
+    pub fn get_topmost(&self, xid: TransactionId) -> Result<TransactionId> {
+        let mut current = xid;
+        loop {
+            let parent = self.get_parent(current)?;
+            if parent.is_invalid() || parent == current {
+                return Ok(current);
+            }
+            current = parent;
+        }
+    }
+
+    pub fn truncate(&self, oldest_xid: TransactionId) -> Result<()> {
+        self.slru
+            .truncate(Self::page_number(oldest_xid), Self::page_precedes)
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.slru.flush()
+    }
+}
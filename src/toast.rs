@@ -1,11 +1,10 @@
 use crate::constants::*;
 use crate::error::{HeapError, Result};
-use crate::page::Page;
 use crate::relation::Relation;
 use crate::types::*;
 use std::collections::HashMap;
 use std::path::PathBuf;
-use std::sync::{Arc, RwLock};
+use std::sync::RwLock;
 
 #[derive(Debug, Clone)]
 pub struct ToastChunk {
@@ -138,7 +137,7 @@ impl ToastTable {
         Ok(decompressed)
     }
 
-    pub fn store(&self, xid: TransactionId, cid: CommandId, data: &[u8]) -> Result<ToastPointer> {
+    pub fn store(&self, _xid: TransactionId, _cid: CommandId, data: &[u8]) -> Result<ToastPointer> {
         if data.len() <= TOAST_TUPLE_THRESHOLD {
             return Err(HeapError::InvalidOperation(
                 "Data too small for TOAST".to_string(),
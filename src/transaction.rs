@@ -1,58 +1,745 @@
+use crate::clog::CommitLog;
+use crate::combocid::ComboCids;
+use crate::commit_ts::CommitTsLog;
 use crate::constants::*;
+use crate::control::{ControlFile, DataDirLock};
+use crate::csnlog::CsnLog;
+use crate::error::{HeapError, Result};
+use crate::lock::{LockManager, LockOwner, LockTag, TableLockMode};
+use crate::multixact::MultiXactManager;
+use crate::predicate::PredicateLockManager;
+use crate::procarray::{RunningXids, SnapshotId, SnapshotRegistry, XminHolder, XminHorizon};
+use crate::slot::{ReplicationSlot, ReplicationSlots};
+use crate::subtrans::SubTrans;
+use crate::twophase::{PreparedTransaction, TwoPhaseState};
 use crate::types::*;
+use crate::visibility::TransactionOracle;
+use crate::wal::{WALRef, XLogRecord, XLogRecordType, WAL};
 use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct XidLimits {
+    pub oldest_xid: TransactionId,
+    pub warn_limit: TransactionId,
+    pub stop_limit: TransactionId,
+    pub wrap_limit: TransactionId,
+}
+
+impl XidLimits {
+    pub fn new(oldest_xid: TransactionId) -> Self {
+        let wrap_limit = Self::skip_special(oldest_xid.0.wrapping_add(MAX_TRANSACTION_ID >> 1));
+        let stop_limit = Self::skip_special_back(wrap_limit.0.wrapping_sub(XID_STOP_MARGIN));
+        let warn_limit = Self::skip_special_back(stop_limit.0.wrapping_sub(XID_WARN_MARGIN));
+
+        Self {
+            oldest_xid,
+            warn_limit,
+            stop_limit,
+            wrap_limit,
+        }
+    }
+
+    fn skip_special(xid: u32) -> TransactionId {
+        if xid < FIRST_NORMAL_TRANSACTION_ID {
+            TransactionId(xid + FIRST_NORMAL_TRANSACTION_ID)
+        } else {
+            TransactionId(xid)
+        }
+    }
+
+    fn skip_special_back(xid: u32) -> TransactionId {
+        if xid < FIRST_NORMAL_TRANSACTION_ID {
+            TransactionId(xid.wrapping_sub(FIRST_NORMAL_TRANSACTION_ID))
+        } else {
+            TransactionId(xid)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExportedSnapshot {
+    pub xid: TransactionId,
+    pub isolation: IsolationLevel,
+    pub snapshot: Snapshot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XidLimitStatus {
+    Ok,
+    Warning { remaining: u32 },
+    Stopped,
+}
 
 pub struct TransactionManager {
-    current_xid: RwLock<TransactionId>,
+    next_full_xid: RwLock<FullTransactionId>,
     next_cid: RwLock<CommandId>,
-    committed: RwLock<HashMap<TransactionId, bool>>,
-    in_progress: RwLock<Vec<TransactionId>>,
+    clog: CommitLog,
+    commit_ts: CommitTsLog,
+    track_commit_timestamp: RwLock<bool>,
+    next_rel_node: Mutex<u32>,
+    retain_history: RwLock<RetainHistory>,
+    retention: Mutex<Option<RetentionCursor>>,
+    data_checksums: RwLock<bool>,
+    full_page_writes: RwLock<bool>,
+    checkpoint_redo: RwLock<u64>,
+    change_lock: RwLock<()>,
+    async_commit_lsn: RwLock<u64>,
+    subtrans: SubTrans,
+    in_progress: RwLock<RunningXids>,
+    csn_log: CsnLog,
+    children: RwLock<HashMap<TransactionId, Vec<TransactionId>>>,
+    twophase: TwoPhaseState,
+    multixact: MultiXactManager,
+    control: Option<Arc<ControlFile>>,
+    xid_reserved: RwLock<FullTransactionId>,
+    xid_limits: RwLock<XidLimits>,
+    locks: LockManager,
+    predicate_locks: PredicateLockManager,
+    combo_cids: RwLock<HashMap<TransactionId, ComboCids>>,
+    exported_snapshots: RwLock<HashMap<String, ExportedSnapshot>>,
+    snapshots: RwLock<SnapshotRegistry>,
+    next_virtual_id: Mutex<u32>,
+    slots: ReplicationSlots,
+    wal: Option<WALRef>,
+    // Last, so the directory is released only once everything above has
+    // been dropped.
+    _dir_lock: Option<DataDirLock>,
 }
 
 impl TransactionManager {
     pub fn new() -> Self {
         Self {
-            current_xid: RwLock::new(TransactionId(FIRST_NORMAL_TRANSACTION_ID)),
+            next_full_xid: RwLock::new(FullTransactionId::new(0, TransactionId::first_normal())),
             next_cid: RwLock::new(CommandId(1)),
-            committed: RwLock::new(HashMap::new()),
-            in_progress: RwLock::new(Vec::new()),
+            clog: CommitLog::in_memory(),
+            commit_ts: CommitTsLog::in_memory(),
+            track_commit_timestamp: RwLock::new(false),
+            next_rel_node: Mutex::new(FIRST_NORMAL_REL_NODE),
+            retain_history: RwLock::new(RetainHistory::Off),
+            retention: Mutex::new(None),
+            data_checksums: RwLock::new(false),
+            full_page_writes: RwLock::new(true),
+            checkpoint_redo: RwLock::new(0),
+            change_lock: RwLock::new(()),
+            async_commit_lsn: RwLock::new(0),
+            subtrans: SubTrans::in_memory(),
+            in_progress: RwLock::new(RunningXids::new(TransactionId::first_normal(), Vec::new())),
+            csn_log: CsnLog::new(TransactionId::first_normal()),
+            children: RwLock::new(HashMap::new()),
+            twophase: TwoPhaseState::in_memory(),
+            multixact: MultiXactManager::in_memory(),
+            control: None,
+            xid_reserved: RwLock::new(FullTransactionId(u64::MAX)),
+            xid_limits: RwLock::new(XidLimits::new(TransactionId::first_normal())),
+            locks: LockManager::new(),
+            predicate_locks: PredicateLockManager::new(),
+            combo_cids: RwLock::new(HashMap::new()),
+            exported_snapshots: RwLock::new(HashMap::new()),
+            snapshots: RwLock::new(SnapshotRegistry::new()),
+            next_virtual_id: Mutex::new(0),
+            slots: ReplicationSlots::in_memory(),
+            wal: None,
+            _dir_lock: None,
         }
     }
 
-    pub fn begin(&self) -> TransactionId {
-        let mut xid = self.current_xid.write().unwrap();
-        let new_xid = xid.0;
-        *xid = TransactionId(new_xid + 1);
+    pub fn open(dir: PathBuf) -> Result<Self> {
+        let dir_lock = DataDirLock::acquire(&dir)?;
+        let wal = Arc::new(WAL::new(dir.clone())?);
+        WAL::start_writer(&wal, Duration::from_millis(WAL_WRITER_DELAY_MS));
+        let control = Arc::new(ControlFile::open(dir.clone())?);
+        let clog = CommitLog::open(dir.clone(), Some(wal.clone()))?;
+        let commit_ts = CommitTsLog::open(dir.clone(), Some(wal.clone()))?;
+        let subtrans = SubTrans::open(dir.clone())?;
+        let multixact = MultiXactManager::open(dir.clone(), control.clone(), Some(wal.clone()))?;
+        let slots = ReplicationSlots::open(dir.clone())?;
+        let twophase = TwoPhaseState::open(dir)?;
 
-        let mut in_progress = self.in_progress.write().unwrap();
-        in_progress.push(TransactionId(new_xid));
+        let control_data = control.get();
+        let next_full_xid = FullTransactionId(control_data.next_full_xid);
+        let oldest_xid = TransactionId(control_data.oldest_xid);
+        commit_ts.load_latest(TransactionId(control_data.newest_commit_ts_xid))?;
+        Self::redo_xact_logs(&clog, &commit_ts, &wal, control_data.checkpoint_redo)?;
+
+        let locks = LockManager::new();
+        let mut in_progress = Vec::new();
+        let mut children = HashMap::new();
+        for prepared in twophase.list() {
+            in_progress.push(prepared.xid);
+            in_progress.extend(prepared.subxids.iter().copied());
+            for &xid in std::iter::once(&prepared.xid).chain(&prepared.subxids) {
+                locks.acquire(
+                    prepared.xid,
+                    LockTag::Transaction(xid),
+                    TableLockMode::Exclusive,
+                    WaitPolicy::NoWait,
+                )?;
+            }
+            children.insert(prepared.xid, prepared.subxids);
+        }
+
+        Ok(Self {
+            next_full_xid: RwLock::new(next_full_xid),
+            next_cid: RwLock::new(CommandId(1)),
+            clog,
+            commit_ts,
+            track_commit_timestamp: RwLock::new(control_data.track_commit_timestamp),
+            next_rel_node: Mutex::new(control_data.next_rel_node),
+            retain_history: RwLock::new(control_data.retain_history),
+            retention: Mutex::new(None),
+            data_checksums: RwLock::new(control_data.data_checksums),
+            full_page_writes: RwLock::new(control_data.full_page_writes),
+            checkpoint_redo: RwLock::new(control_data.checkpoint_redo),
+            change_lock: RwLock::new(()),
+            async_commit_lsn: RwLock::new(0),
+            subtrans,
+            in_progress: RwLock::new(RunningXids::new(next_full_xid.xid(), in_progress)),
+            csn_log: CsnLog::new(next_full_xid.xid()),
+            children: RwLock::new(children),
+            twophase,
+            multixact,
+            control: Some(control),
+            xid_reserved: RwLock::new(next_full_xid),
+            xid_limits: RwLock::new(XidLimits::new(oldest_xid)),
+            locks,
+            predicate_locks: PredicateLockManager::new(),
+            combo_cids: RwLock::new(HashMap::new()),
+            exported_snapshots: RwLock::new(HashMap::new()),
+            snapshots: RwLock::new(SnapshotRegistry::new()),
+            next_virtual_id: Mutex::new(0),
+            slots,
+            wal: Some(wal),
+            _dir_lock: Some(dir_lock),
+        })
+    }
 
-        TransactionId(new_xid)
+    pub fn begin(&self) -> Result<TransactionId> {
+        self.assign_xid(TransactionId::invalid())
     }
 
-    pub fn commit(&self, xid: TransactionId) {
-        {
-            let mut committed = self.committed.write().unwrap();
-            committed.insert(xid, true);
+    pub fn begin_subtransaction(&self, parent: TransactionId) -> Result<TransactionId> {
+        if !self.is_in_progress(parent) {
+            return Err(HeapError::InvalidTransaction(format!(
+                "parent transaction {} is not in progress",
+                parent
+            )));
+        }
+
+        self.assign_xid(parent)
+    }
+
+    fn assign_xid(&self, parent: TransactionId) -> Result<TransactionId> {
+        let mut next_full_xid = self.next_full_xid.write().unwrap();
+        let full_xid = *next_full_xid;
+        let new_xid = full_xid.xid();
+
+        let limits = *self.xid_limits.read().unwrap();
+        if new_xid.follows_or_equals(limits.stop_limit) {
+            return Err(HeapError::XidWraparound(format!(
+                "database is not accepting commands that assign new transaction IDs to avoid \
+                 wraparound data loss (oldest xid {}, next xid {})",
+                limits.oldest_xid, full_xid
+            )));
+        }
+        // Past the warn limit xids still go out; callers find out how many
+        // remain from xid_limit_status.
+
+        if let Some(ref control) = self.control {
+            let mut reserved = self.xid_reserved.write().unwrap();
+            if full_xid >= *reserved {
+                let next_reserved = full_xid.0 + XID_PREFETCH as u64;
+                control.update(|data| data.next_full_xid = next_reserved)?;
+                *reserved = FullTransactionId(next_reserved);
+            }
         }
 
         {
-            let mut in_progress = self.in_progress.write().unwrap();
-            in_progress.retain(|&x| x != xid);
+            let _logged = self.log_change();
+            self.clog.extend(new_xid)?;
+        }
+        self.subtrans.extend(new_xid)?;
+        self.subtrans.set_parent(new_xid, parent)?;
+        *next_full_xid = full_xid.next();
+
+        let top = if parent.is_valid() {
+            let top = self.subtrans.get_topmost(parent)?;
+            let mut children = self.children.write().unwrap();
+            children.entry(top).or_default().push(new_xid);
+            top
+        } else {
+            new_xid
+        };
+
+        // Held until the xid ends, so waiting for a transaction means waiting
+        // for this lock. Subtransactions hold theirs under the top xid.
+        self.locks.acquire(
+            top,
+            LockTag::Transaction(new_xid),
+            TableLockMode::Exclusive,
+            WaitPolicy::NoWait,
+        )?;
+
+        let mut in_progress = self.in_progress.write().unwrap();
+        in_progress.add(new_xid);
+
+        Ok(new_xid)
+    }
+
+    pub fn commit(&self, xid: TransactionId) -> Result<()> {
+        self.commit_with(xid, true)
+    }
+
+    // With `synchronous_commit` off the commit record is not waited for: a
+    // crash within the WAL writer's delay may lose the transaction, but
+    // never leaves it half applied.
+    pub fn commit_with(&self, xid: TransactionId, synchronous_commit: bool) -> Result<()> {
+        if !self.is_in_progress(xid) {
+            return Err(HeapError::InvalidTransaction(format!(
+                "transaction {} is not in progress",
+                xid
+            )));
+        }
+
+        if let Err(e) = self.predicate_locks.pre_commit(xid) {
+            self.abort(xid)?;
+            return Err(e);
+        }
+
+        let children = self.take_children(xid);
+        // A checkpoint whose redo point follows the commit record must find
+        // the clog already saying so.
+        let logged = self.log_change();
+        let timestamp = if self.track_commit_timestamp() {
+            Some(self.commit_ts.assign(xid, &children)?)
+        } else {
+            None
+        };
+
+        let mut lsn = 0;
+        if let Some(ref wal) = self.wal {
+            lsn = wal.append(&Self::commit_record(xid, &children, timestamp))?;
+            if synchronous_commit {
+                wal.flush(lsn)?;
+            } else {
+                let mut async_commit_lsn = self.async_commit_lsn.write().unwrap();
+                *async_commit_lsn = (*async_commit_lsn).max(lsn);
+            }
+        }
+
+        if let Some(timestamp) = timestamp {
+            self.commit_ts.set(xid, &children, timestamp, lsn)?;
+        }
+
+        let mut committed = children.clone();
+        committed.push(xid);
+        self.csn_log.assign(&committed);
+
+        for &child in &children {
+            self.clog.set_status(child, XidStatus::SubCommitted, lsn)?;
+        }
+        self.clog.set_status(xid, XidStatus::Committed, lsn)?;
+        for &child in &children {
+            self.clog.set_status(child, XidStatus::Committed, lsn)?;
+        }
+        drop(logged);
+
+        self.predicate_locks.commit(xid);
+        self.forget_local_state(xid);
+        self.end_xact(|x| x == xid || children.contains(&x));
+        self.locks.release_all(xid);
+
+        if self.csn_log.needs_trim() {
+            self.csn_log.truncate(self.running_horizon().xmin);
         }
+        Ok(())
     }
 
-    pub fn abort(&self, xid: TransactionId) {
+    pub fn abort(&self, xid: TransactionId) -> Result<()> {
+        if !self.is_in_progress(xid) {
+            // A deadlock victim has already been aborted for its owner.
+            if self.did_abort(xid) {
+                return Ok(());
+            }
+            return Err(HeapError::InvalidTransaction(format!(
+                "transaction {} is not in progress",
+                xid
+            )));
+        }
+
+        let children = self.take_children(xid);
+
+        let logged = self.log_change();
+        let mut lsn = 0;
+        if let Some(ref wal) = self.wal {
+            lsn = wal.append(&Self::xact_record(
+                XLogRecordType::TransactionAbort,
+                xid,
+                &children,
+            ))?;
+        }
+
+        for &child in &children {
+            self.clog.set_status(child, XidStatus::Aborted, lsn)?;
+        }
+        self.clog.set_status(xid, XidStatus::Aborted, lsn)?;
+        drop(logged);
+
+        self.predicate_locks.abort(xid);
+        self.forget_local_state(xid);
+        self.end_xact(|x| x == xid || children.contains(&x));
+        self.locks.release_all(xid);
+        Ok(())
+    }
+
+    pub fn abort_subtransaction(&self, xid: TransactionId) -> Result<()> {
+        let top = self.subtrans.get_topmost(xid)?;
+
+        let mut doomed = vec![xid];
+        for child in self.subtransactions(top) {
+            if child != xid && self.is_descendant(child, xid)? {
+                doomed.push(child);
+            }
+        }
+
+        let logged = self.log_change();
+        let mut lsn = 0;
+        if let Some(ref wal) = self.wal {
+            lsn = wal.append(&Self::xact_record(
+                XLogRecordType::TransactionAbort,
+                xid,
+                &doomed[1..],
+            ))?;
+        }
+
+        for &x in &doomed {
+            self.clog.set_status(x, XidStatus::Aborted, lsn)?;
+        }
+        drop(logged);
+
         {
-            let mut committed = self.committed.write().unwrap();
-            committed.insert(xid, false);
+            let mut children = self.children.write().unwrap();
+            if let Some(subxids) = children.get_mut(&top) {
+                subxids.retain(|x| !doomed.contains(x));
+            }
+        }
+
+        self.end_xact(|x| doomed.contains(&x));
+        for &x in &doomed {
+            self.locks.release(top, LockTag::Transaction(x));
+        }
+        Ok(())
+    }
+
+    // Commit or abort record for `xid` together with the subtransactions
+    // that end with it. A clog page lost in a crash is redone from it.
+    fn xact_record(
+        record_type: XLogRecordType,
+        xid: TransactionId,
+        subxids: &[TransactionId],
+    ) -> XLogRecord {
+        let data = subxids.iter().flat_map(|x| x.0.to_le_bytes()).collect();
+        XLogRecord::new(xid.0, record_type, 0, data)
+    }
+
+    // A commit record also carries the commit timestamp, zero when it is
+    // not tracked, so it is flushed and redone along with the commit.
+    fn commit_record(
+        xid: TransactionId,
+        subxids: &[TransactionId],
+        timestamp: Option<u64>,
+    ) -> XLogRecord {
+        let mut record = Self::xact_record(XLogRecordType::TransactionCommit, xid, subxids);
+        record
+            .data
+            .splice(0..0, timestamp.unwrap_or(0).to_le_bytes());
+        record
+    }
+
+    // Redo of the clog and commit timestamps from the last checkpoint's
+    // redo point, before anything reads them. The checkpoint wrote out
+    // everything logged before it.
+    fn redo_xact_logs(
+        clog: &CommitLog,
+        commit_ts: &CommitTsLog,
+        wal: &WAL,
+        redo_lsn: u64,
+    ) -> Result<()> {
+        for record in wal.recover_from(redo_lsn)? {
+            Self::redo_xact_record(clog, commit_ts, &record)?;
         }
+        Ok(())
+    }
+
+    fn redo_xact_record(
+        clog: &CommitLog,
+        commit_ts: &CommitTsLog,
+        record: &XLogRecord,
+    ) -> Result<()> {
+        let status = match record.record_type {
+            XLogRecordType::ClogZeroPage => return clog.redo_zero_page(record.block_id),
+            XLogRecordType::ClogTruncate => return clog.redo_truncate(record.block_id),
+            XLogRecordType::CommitTsZeroPage => return commit_ts.redo_zero_page(record.block_id),
+            XLogRecordType::CommitTsTruncate => return commit_ts.redo_truncate(record.block_id),
+            XLogRecordType::TransactionCommit => XidStatus::Committed,
+            XLogRecordType::TransactionAbort => XidStatus::Aborted,
+            _ => return Ok(()),
+        };
+        let xids = Self::xact_record_xids(record);
+        for &xid in &xids {
+            clog.set_status(xid, status, 0)?;
+        }
+        if let Some(timestamp) = Self::xact_record_timestamp(record) {
+            commit_ts.redo_set(xids[0], &xids[1..], timestamp)?;
+        }
+        Ok(())
+    }
+
+    fn end_xact(&self, finished: impl Fn(TransactionId) -> bool) {
+        let mut in_progress = self.in_progress.write().unwrap();
+        in_progress.remove(finished);
+    }
+
+    // Takes a heavyweight lock for `xid`'s top-level transaction, held until
+    // it commits or aborts. When the wait would deadlock, that transaction is
+    // the victim: it is aborted and the deadlock error returned.
+    pub fn lock(
+        &self,
+        xid: TransactionId,
+        tag: LockTag,
+        mode: TableLockMode,
+        wait_policy: WaitPolicy,
+    ) -> Result<bool> {
+        let top = self.subtrans.get_topmost(xid)?;
+        match self.locks.acquire(top, tag, mode, wait_policy) {
+            Err(HeapError::Deadlock(message)) => {
+                self.abort(top)?;
+                Err(HeapError::Deadlock(message))
+            }
+            result => result,
+        }
+    }
 
+    // Blocks `waiter` until `xid` has committed or aborted.
+    pub fn wait_for(&self, waiter: TransactionId, xid: TransactionId) -> Result<()> {
+        let tag = LockTag::Transaction(xid);
+        self.lock(waiter, tag, TableLockMode::Share, WaitPolicy::Block)?;
+        self.locks.release(self.subtrans.get_topmost(waiter)?, tag);
+        Ok(())
+    }
+
+    // An owner for a statement outside a transaction block, which holds
+    // locks and a snapshot but never needs an xid.
+    pub fn begin_virtual(&self) -> LockOwner {
+        let mut next = self.next_virtual_id.lock().unwrap();
+        *next = next.wrapping_add(1);
+        LockOwner::Virtual(*next)
+    }
+
+    pub fn end_virtual(&self, owner: LockOwner) {
+        self.snapshots.write().unwrap().forget_owner(owner);
+        self.locks.release_all(owner);
+    }
+
+    pub fn lock_manager(&self) -> &LockManager {
+        &self.locks
+    }
+
+    pub fn predicate_locks(&self) -> &PredicateLockManager {
+        &self.predicate_locks
+    }
+
+    pub fn set_lock_timeout(&self, timeout: Option<Duration>) {
+        self.locks.set_lock_timeout(timeout);
+    }
+
+    // How long a flushing commit waits for others to join its fsync.
+    pub fn set_commit_delay(&self, delay: Duration) {
+        if let Some(ref wal) = self.wal {
+            wal.set_commit_delay(delay);
+        }
+    }
+
+    fn is_descendant(&self, xid: TransactionId, ancestor: TransactionId) -> Result<bool> {
+        let mut current = xid;
+        loop {
+            let parent = self.subtrans.get_parent(current)?;
+            if parent == ancestor {
+                return Ok(true);
+            }
+            if parent.is_invalid() || parent == current {
+                return Ok(false);
+            }
+            current = parent;
+        }
+    }
+
+    pub fn prepare(&self, xid: TransactionId, gid: &str) -> Result<()> {
+        if !self.is_in_progress(xid) {
+            return Err(HeapError::InvalidTransaction(format!(
+                "transaction {} is not in progress",
+                xid
+            )));
+        }
+
+        if self
+            .exported_snapshots
+            .read()
+            .unwrap()
+            .values()
+            .any(|exported| exported.xid == xid)
         {
-            let mut in_progress = self.in_progress.write().unwrap();
-            in_progress.retain(|&x| x != xid);
+            return Err(HeapError::InvalidTransaction(
+                "cannot PREPARE a transaction that has exported snapshots".to_string(),
+            ));
+        }
+
+        self.predicate_locks.pre_commit(xid)?;
+
+        let record = PreparedTransaction::new(gid, xid, self.subtransactions(xid));
+        self.twophase.add(record)?;
+        self.forget_local_state(xid);
+        Ok(())
+    }
+
+    pub fn commit_prepared(&self, gid: &str) -> Result<()> {
+        self.finish_prepared(gid, true)
+    }
+
+    pub fn rollback_prepared(&self, gid: &str) -> Result<()> {
+        self.finish_prepared(gid, false)
+    }
+
+    fn finish_prepared(&self, gid: &str, commit: bool) -> Result<()> {
+        let prepared = self.twophase.lock(gid)?;
+
+        let result = if commit {
+            self.commit(prepared.xid)
+        } else {
+            self.abort(prepared.xid)
         }
+        .and_then(|_| self.flush());
+
+        match result {
+            Ok(()) => {
+                self.twophase.remove(gid)?;
+                Ok(())
+            }
+            Err(e) => {
+                self.twophase.unlock(gid);
+                Err(e)
+            }
+        }
+    }
+
+    pub fn prepared_transactions(&self) -> Vec<PreparedTransaction> {
+        self.twophase.list()
+    }
+
+    pub fn is_prepared(&self, xid: TransactionId) -> bool {
+        self.twophase.list().iter().any(|p| p.xid == xid)
+    }
+
+    fn take_children(&self, xid: TransactionId) -> Vec<TransactionId> {
+        let mut children = self.children.write().unwrap();
+        children.remove(&xid).unwrap_or_default()
+    }
+
+    pub fn get_parent(&self, xid: TransactionId) -> Result<TransactionId> {
+        self.subtrans.get_parent(xid)
+    }
+
+    pub fn get_topmost(&self, xid: TransactionId) -> Result<TransactionId> {
+        self.subtrans.get_topmost(xid)
+    }
+
+    pub fn subtransactions(&self, top: TransactionId) -> Vec<TransactionId> {
+        let children = self.children.read().unwrap();
+        children.get(&top).cloned().unwrap_or_default()
+    }
+
+    pub fn is_current_transaction(&self, top: TransactionId, xid: TransactionId) -> bool {
+        if xid == top {
+            return true;
+        }
+
+        let children = self.children.read().unwrap();
+        children
+            .get(&top)
+            .is_some_and(|subxids| subxids.contains(&xid))
+    }
+
+    // The combo command id standing for (`cmin`, `cmax`) within `xid`'s
+    // top-level transaction.
+    pub fn combo_cid(
+        &self,
+        xid: TransactionId,
+        cmin: CommandId,
+        cmax: CommandId,
+    ) -> Result<CommandId> {
+        let top = self.subtrans.get_topmost(xid)?;
+        let mut combo_cids = self.combo_cids.write().unwrap();
+        combo_cids.entry(top).or_default().get_or_create(cmin, cmax)
+    }
+
+    pub fn combo_cids(&self, xid: TransactionId) -> Result<ComboCids> {
+        let top = self.subtrans.get_topmost(xid)?;
+        let combo_cids = self.combo_cids.read().unwrap();
+        Ok(combo_cids.get(&top).cloned().unwrap_or_default())
+    }
+
+    // State that only means something while the top-level `xid` runs.
+    fn forget_local_state(&self, xid: TransactionId) {
+        self.combo_cids.write().unwrap().remove(&xid);
+        self.snapshots.write().unwrap().forget_owner(xid.into());
+        self.exported_snapshots
+            .write()
+            .unwrap()
+            .retain(|_, exported| exported.xid != xid);
+    }
+
+    // Publishes `snapshot`, taken by the running top-level `xid`, under an
+    // identifier other transactions can import while `xid` stays open. The
+    // exporter and its subtransactions go in xip: to an importer they are
+    // still running, so their changes stay invisible even after they commit.
+    pub fn export_snapshot(
+        &self,
+        xid: TransactionId,
+        isolation: IsolationLevel,
+        snapshot: Snapshot,
+    ) -> Result<String> {
+        if !self.is_in_progress(xid) {
+            return Err(HeapError::InvalidTransaction(format!(
+                "transaction {} is not in progress",
+                xid
+            )));
+        }
+
+        let mut exported = self.exported_snapshots.write().unwrap();
+        let count = exported.values().filter(|e| e.xid == xid).count();
+        let id = format!("{:08X}-{}", xid.0, count + 1);
+        exported.insert(
+            id.clone(),
+            ExportedSnapshot {
+                xid,
+                isolation,
+                snapshot,
+            },
+        );
+        Ok(id)
+    }
+
+    pub fn exported_snapshot(&self, id: &str) -> Result<ExportedSnapshot> {
+        self.exported_snapshots
+            .read()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| {
+                HeapError::InvalidTransaction(format!("invalid snapshot identifier: \"{}\"", id))
+            })
     }
 
     pub fn get_cid(&self) -> CommandId {
@@ -62,51 +749,638 @@ impl TransactionManager {
         new_cid
     }
 
-    pub fn is_committed(&self, xid: TransactionId) -> bool {
+    pub fn status(&self, xid: TransactionId) -> Result<XidStatus> {
         if xid.is_invalid() {
-            return false;
+            return Ok(XidStatus::Aborted);
         }
 
         if xid.0 == BOOTSTRAP_TRANSACTION_ID {
-            return true;
+            return Ok(XidStatus::Committed);
+        }
+
+        if xid.precedes(self.xid_limits.read().unwrap().oldest_xid) {
+            return Err(HeapError::CorruptedData(format!(
+                "could not access status of transaction {}: older than oldest xid",
+                xid
+            )));
         }
 
-        let committed = self.committed.read().unwrap();
-        committed.get(&xid).copied().unwrap_or(false)
+        match self.clog.get_status(xid)? {
+            XidStatus::SubCommitted => match self.status(self.subtrans.get_parent(xid)?)? {
+                XidStatus::Committed => Ok(XidStatus::Committed),
+                XidStatus::Aborted => Ok(XidStatus::Aborted),
+                _ => Ok(XidStatus::InProgress),
+            },
+            status => Ok(status),
+        }
+    }
+
+    pub fn oracle(&self, top: TransactionId) -> StatusOracle<'_> {
+        StatusOracle { manager: self, top }
+    }
+
+    pub fn did_abort(&self, xid: TransactionId) -> bool {
+        matches!(self.status(xid), Ok(XidStatus::Aborted))
+    }
+
+    pub fn is_committed(&self, xid: TransactionId) -> bool {
+        matches!(self.status(xid), Ok(XidStatus::Committed))
     }
 
     pub fn is_in_progress(&self, xid: TransactionId) -> bool {
         let in_progress = self.in_progress.read().unwrap();
-        in_progress.contains(&xid)
+        in_progress.contains(xid)
     }
 
     pub fn current_xid(&self) -> TransactionId {
-        *self.current_xid.read().unwrap()
+        self.next_full_xid.read().unwrap().xid()
     }
 
-    pub fn get_snapshot(&self, current_cid: CommandId) -> Snapshot {
-        let xid = *self.current_xid.read().unwrap();
-        let in_progress = self.in_progress.read().unwrap();
+    pub fn next_full_xid(&self) -> FullTransactionId {
+        *self.next_full_xid.read().unwrap()
+    }
+
+    pub fn xid_limits(&self) -> XidLimits {
+        *self.xid_limits.read().unwrap()
+    }
 
-        let xmin = if in_progress.is_empty() {
-            TransactionId(xid.0 - 1)
+    pub fn xid_limit_status(&self) -> XidLimitStatus {
+        let limits = self.xid_limits();
+        let next_xid = self.current_xid();
+
+        if next_xid.follows_or_equals(limits.stop_limit) {
+            XidLimitStatus::Stopped
+        } else if next_xid.follows_or_equals(limits.warn_limit) {
+            XidLimitStatus::Warning {
+                remaining: limits.wrap_limit.0.wrapping_sub(next_xid.0),
+            }
         } else {
-            in_progress
-                .iter()
-                .min()
-                .copied()
-                .unwrap_or(TransactionId(xid.0 - 1))
+            XidLimitStatus::Ok
+        }
+    }
+
+    // Advances the oldest xid whose status may still be asked for. Every
+    // older xid must already be frozen out of the heap, so the commit log and
+    // subtrans segments below it are removed.
+    pub fn set_oldest_xid(&self, oldest_xid: TransactionId) -> Result<()> {
+        let mut limits = self.xid_limits.write().unwrap();
+        if !oldest_xid.follows(limits.oldest_xid) {
+            return Ok(());
+        }
+
+        if let Some(ref control) = self.control {
+            control.update(|data| data.oldest_xid = oldest_xid.0)?;
+        }
+        *limits = XidLimits::new(oldest_xid);
+        drop(limits);
+
+        self.clog.truncate(oldest_xid)?;
+        self.commit_ts.truncate(oldest_xid)?;
+        self.csn_log.truncate(oldest_xid);
+        self.subtrans.truncate(oldest_xid)
+    }
+
+    pub fn track_commit_timestamp(&self) -> bool {
+        *self.track_commit_timestamp.read().unwrap()
+    }
+
+    // Starts or stops recording commit timestamps for transactions that
+    // commit from now on. The setting survives a restart.
+    pub fn set_track_commit_timestamp(&self, enabled: bool) -> Result<()> {
+        let mut track = self.track_commit_timestamp.write().unwrap();
+        if let Some(ref control) = self.control {
+            control.update(|data| data.track_commit_timestamp = enabled)?;
+        }
+        *track = enabled;
+        Ok(())
+    }
+
+    pub fn retain_history(&self) -> RetainHistory {
+        *self.retain_history.read().unwrap()
+    }
+
+    // Holds back the vacuum horizon so that scan_as_of can read the table
+    // as it was anywhere within the window. Past states are told apart by
+    // commit timestamps, so those must be tracked. The setting survives a
+    // restart.
+    pub fn set_retain_history(&self, retain: RetainHistory) -> Result<()> {
+        if retain != RetainHistory::Off {
+            self.check_commit_ts_tracking()?;
+        }
+
+        let mut retain_history = self.retain_history.write().unwrap();
+        if let Some(ref control) = self.control {
+            control.update(|data| data.retain_history = retain)?;
+        }
+        *retain_history = retain;
+        *self.retention.lock().unwrap() = None;
+        Ok(())
+    }
+
+    // The oldest xid whose deletions vacuum must keep for retain_history.
+    // Only xids that have finished are passed, and the window only moves
+    // forward until it is changed, so each xid is looked at once.
+    fn retention_horizon(&self) -> Option<RetentionCursor> {
+        let retain = self.retain_history();
+        let oldest_xid = self.xid_limits().oldest_xid;
+        let keep_from = match retain {
+            RetainHistory::Off => return None,
+            RetainHistory::Transactions(count) => {
+                let xid = TransactionId(self.current_xid().0.wrapping_sub(count));
+                if !xid.is_normal() || xid.precedes(oldest_xid) {
+                    oldest_xid
+                } else {
+                    xid
+                }
+            }
+            RetainHistory::Duration(_) => self.current_xid(),
+        };
+        let cutoff = match retain {
+            RetainHistory::Duration(window) => {
+                CommitTsLog::now().saturating_sub(window.as_micros() as u64)
+            }
+            _ => u64::MAX,
+        };
+        let running = self.in_progress.read().unwrap().xmin();
+
+        let mut retention = self.retention.lock().unwrap();
+        let cursor = retention.get_or_insert(RetentionCursor {
+            xid: oldest_xid,
+            newest: 0,
+        });
+        if cursor.xid.precedes(oldest_xid) {
+            cursor.xid = oldest_xid;
+        }
+        while cursor.xid.precedes(keep_from) && cursor.xid.precedes(running) {
+            match self.commit_ts.get(cursor.xid) {
+                Ok(Some(ts)) if ts < cutoff => cursor.newest = cursor.newest.max(ts),
+                Ok(None) => {}
+                _ => break,
+            }
+            cursor.xid = cursor.xid.next();
+        }
+        Some(*cursor)
+    }
+
+    // Status as of `as_of` for scan_as_of, which must still be within the
+    // retained history: nothing vacuum may have removed can have been
+    // deleted after it.
+    pub fn historic_oracle(&self, as_of: AsOf) -> Result<HistoricOracle<'_>> {
+        self.check_commit_ts_tracking()?;
+        let horizon = self.retention_horizon().ok_or_else(|| {
+            HeapError::InvalidOperation(
+                "cannot read past table states: retain_history is off".to_string(),
+            )
+        })?;
+
+        let as_of = match as_of {
+            AsOf::Timestamp(ts) => ts,
+            AsOf::Xid(xid) => self.commit_timestamp(xid)?.ok_or_else(|| {
+                HeapError::InvalidOperation(format!("transaction {} has no commit timestamp", xid))
+            })?,
+        };
+
+        if horizon.newest > as_of {
+            return Err(HeapError::InvalidOperation(format!(
+                "history as of {} is no longer retained",
+                as_of
+            )));
+        }
+
+        Ok(HistoricOracle {
+            manager: self,
+            as_of,
+        })
+    }
+
+    // Hands out the rel_node of a new relation. The counter lives in the
+    // control file, so a dropped relation's node is not handed out again
+    // while the WAL may still hold records for it.
+    pub fn assign_rel_node(&self) -> Result<u32> {
+        let mut next = self.next_rel_node.lock().unwrap();
+        let rel_node = *next;
+        let following = rel_node.checked_add(1).unwrap_or(FIRST_NORMAL_REL_NODE);
+        if let Some(ref control) = self.control {
+            control.update(|data| data.next_rel_node = following)?;
+        }
+        *next = following;
+        Ok(rel_node)
+    }
+
+    pub fn data_checksums(&self) -> bool {
+        *self.data_checksums.read().unwrap()
+    }
+
+    // Like initdb -k: relations opened from now on checksum their pages.
+    // Pages written without a checksum are not verified.
+    pub fn set_data_checksums(&self, enabled: bool) -> Result<()> {
+        let mut checksums = self.data_checksums.write().unwrap();
+        if let Some(ref control) = self.control {
+            control.update(|data| data.data_checksums = enabled)?;
+        }
+        *checksums = enabled;
+        Ok(())
+    }
+
+    pub fn full_page_writes(&self) -> bool {
+        *self.full_page_writes.read().unwrap()
+    }
+
+    // With full_page_writes on, the first WAL record to change a page after
+    // a checkpoint carries an image of the whole page, so redo can rebuild
+    // a page torn by a crash mid-write. The setting survives a restart.
+    pub fn set_full_page_writes(&self, enabled: bool) -> Result<()> {
+        let mut full_page_writes = self.full_page_writes.write().unwrap();
+        if let Some(ref control) = self.control {
+            control.update(|data| data.full_page_writes = enabled)?;
+        }
+        *full_page_writes = enabled;
+        Ok(())
+    }
+
+    // Whether a change to a page last logged at `page_lsn` has to carry a
+    // full-page image.
+    pub fn needs_page_image(&self, page_lsn: u64) -> bool {
+        self.full_page_writes() && page_lsn <= self.checkpoint_redo()
+    }
+
+    // Held from when a page change decides how to log itself until the
+    // page is written, so a checkpoint's redo point never falls between
+    // the record and the page it describes.
+    pub fn log_change(&self) -> RwLockReadGuard<'_, ()> {
+        self.change_lock.read().unwrap()
+    }
+
+    // Where redo would start after a crash. A page whose LSN is not past it
+    // has not been WAL-logged since the last checkpoint.
+    pub fn checkpoint_redo(&self) -> u64 {
+        *self.checkpoint_redo.read().unwrap()
+    }
+
+    // Writes out everything changed before the redo point: `flush_buffers`
+    // for relation pages, then the SLRUs, then the checkpoint record. The
+    // record also names the oldest xid still running at the redo point and
+    // the next one to be handed out, between which recovery looks for
+    // transactions the crash cut off.
+    pub fn checkpoint(&self, flush_buffers: impl FnOnce() -> Result<()>) -> Result<()> {
+        let (redo, oldest_running, next_xid) = {
+            // Waits out changes logged but not yet written to their pages,
+            // which the flush below would otherwise miss.
+            let _changes = self.change_lock.write().unwrap();
+            let in_progress = self.in_progress.read().unwrap();
+            let redo = match self.wal {
+                Some(ref wal) => wal.get_lsn(),
+                None => 0,
+            };
+            *self.checkpoint_redo.write().unwrap() = redo;
+            (redo, in_progress.xmin(), in_progress.next_xid())
         };
 
-        let xip: Vec<TransactionId> = in_progress.clone();
+        flush_buffers()?;
+        self.flush()?;
+
+        if let Some(ref wal) = self.wal {
+            let mut data = redo.to_le_bytes().to_vec();
+            data.extend_from_slice(&oldest_running.0.to_le_bytes());
+            data.extend_from_slice(&next_xid.0.to_le_bytes());
+            let record = XLogRecord::new(0, XLogRecordType::Checkpoint, 0, data);
+            let lsn = wal.append(&record)?;
+            wal.flush(lsn)?;
+        }
+        if let Some(ref control) = self.control {
+            control.update(|data| data.checkpoint_redo = redo)?;
+            // Redo now starts here, so older WAL is no longer needed.
+            if let Some(ref wal) = self.wal {
+                wal.remove_segments_before(redo)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Redo of a commit or abort record, or of a clog or commit timestamp
+    // page being created or truncated. The clog gets the outcome a commit
+    // or abort record holds, whatever it said when the crash came, and a
+    // commit its timestamp.
+    pub fn redo_xact(&self, record: &XLogRecord) -> Result<()> {
+        Self::redo_xact_record(&self.clog, &self.commit_ts, record)
+    }
+
+    // The xid of a commit or abort record and the subtransactions ending
+    // with it.
+    pub fn xact_record_xids(record: &XLogRecord) -> Vec<TransactionId> {
+        let subxids = match record.record_type {
+            XLogRecordType::TransactionCommit => record.data.get(8..).unwrap_or_default(),
+            _ => &record.data,
+        };
+        std::iter::once(TransactionId(record.txid))
+            .chain(
+                subxids
+                    .chunks_exact(4)
+                    .map(|b| TransactionId(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))),
+            )
+            .collect()
+    }
+
+    // The commit timestamp a commit record carries, if it was tracked.
+    pub fn xact_record_timestamp(record: &XLogRecord) -> Option<u64> {
+        if record.record_type != XLogRecordType::TransactionCommit {
+            return None;
+        }
+        let raw = record.data.get(..8)?;
+        Some(u64::from_le_bytes(raw.try_into().unwrap())).filter(|&ts| ts != 0)
+    }
+
+    // After redo, an xid from `from` through `through` that neither
+    // committed, aborted nor was prepared was cut off by the crash. Some of
+    // its changes may be on disk, so the clog marks it aborted.
+    pub fn abort_crashed(
+        &self,
+        from: TransactionId,
+        through: TransactionId,
+    ) -> Result<Vec<TransactionId>> {
+        let oldest_xid = self.xid_limits().oldest_xid;
+        let mut aborted = Vec::new();
+        let mut xid = from;
+        while xid.precedes_or_equals(through) {
+            if xid.is_normal() && !xid.precedes(oldest_xid) && !self.is_in_progress(xid) {
+                let status = self.clog.get_status(xid)?;
+                if matches!(status, XidStatus::InProgress | XidStatus::SubCommitted) {
+                    self.clog.set_status(xid, XidStatus::Aborted, 0)?;
+                    aborted.push(xid);
+                }
+            }
+            xid = xid.next();
+        }
+        Ok(aborted)
+    }
+
+    // A committed hint bit must not reach disk before the commit record
+    // does. Until the WAL has caught up with every asynchronous commit,
+    // scans leave committed xids unhinted.
+    pub fn commit_hints_allowed(&self) -> bool {
+        match self.wal {
+            Some(ref wal) => wal.flushed_lsn() >= *self.async_commit_lsn.read().unwrap(),
+            None => true,
+        }
+    }
+
+    // When `xid` committed, in microseconds since the Unix epoch. None for
+    // xids that did not commit while tracking was on, and for xids older
+    // than the oldest xid, whose entries went away with freezing.
+    pub fn commit_timestamp(&self, xid: TransactionId) -> Result<Option<u64>> {
+        self.check_commit_ts_tracking()?;
+        if !xid.is_normal() || xid.precedes(self.xid_limits.read().unwrap().oldest_xid) {
+            return Ok(None);
+        }
+        self.commit_ts.get(xid)
+    }
+
+    pub fn latest_committed(&self) -> Result<Option<(TransactionId, u64)>> {
+        self.check_commit_ts_tracking()?;
+        Ok(self.commit_ts.latest())
+    }
+
+    fn check_commit_ts_tracking(&self) -> Result<()> {
+        if !self.track_commit_timestamp() {
+            return Err(HeapError::InvalidOperation(
+                "could not get commit timestamp data: track_commit_timestamp is off".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn wal(&self) -> Option<&WALRef> {
+        self.wal.as_ref()
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.clog.flush()?;
+        self.commit_ts.flush()?;
+        if let (Some(control), Some((xid, _))) = (&self.control, self.commit_ts.latest()) {
+            if control.get().newest_commit_ts_xid != xid.0 {
+                control.update(|data| data.newest_commit_ts_xid = xid.0)?;
+            }
+        }
+        self.subtrans.flush()?;
+        self.multixact.flush()
+    }
+
+    pub fn multixact(&self) -> &MultiXactManager {
+        &self.multixact
+    }
+
+    // A snapshot `top` keeps for the rest of the transaction. It is
+    // registered before anyone else can compute a horizon past its xmin.
+    pub fn get_transaction_snapshot(
+        &self,
+        top: impl Into<LockOwner>,
+        current_cid: CommandId,
+    ) -> Snapshot {
+        let mut snapshots = self.snapshots.write().unwrap();
+        let snapshot = self.get_snapshot(current_cid);
+        snapshots.register(top.into(), &snapshot);
+        snapshot
+    }
+
+    // A snapshot for `top`'s next statement, replacing its previous one.
+    pub fn get_statement_snapshot(
+        &self,
+        top: impl Into<LockOwner>,
+        current_cid: CommandId,
+    ) -> Snapshot {
+        let mut snapshots = self.snapshots.write().unwrap();
+        let snapshot = self.get_snapshot(current_cid);
+        snapshots.set_active(top.into(), &snapshot);
+        snapshot
+    }
+
+    // Keeps `snapshot` usable by `owner` until it is unregistered or `owner`
+    // ends. The snapshot must already be held back, by `owner` or by an
+    // exported snapshot.
+    pub fn register_snapshot(
+        &self,
+        owner: impl Into<LockOwner>,
+        snapshot: &Snapshot,
+    ) -> SnapshotId {
+        self.snapshots
+            .write()
+            .unwrap()
+            .register(owner.into(), snapshot)
+    }
+
+    pub fn unregister_snapshot(&self, id: SnapshotId) -> bool {
+        self.snapshots.write().unwrap().unregister(id)
+    }
+
+    // The oldest xmin among the snapshots `owner` still uses.
+    pub fn snapshot_xmin(&self, owner: impl Into<LockOwner>) -> Option<TransactionId> {
+        self.snapshots.read().unwrap().owner_xmin(owner.into())
+    }
+
+    // OldestXmin: no running or prepared transaction, snapshot still in use,
+    // exported snapshot or replication slot can see a tuple deleted by a
+    // transaction that committed before it.
+    pub fn compute_oldest_xmin(&self) -> XminHorizon {
+        let mut horizon = self.running_horizon();
+        for slot in self.slots.list() {
+            horizon.hold(slot.xmin, || XminHolder::ReplicationSlot(slot.name.clone()));
+        }
+        if let Some(retention) = self.retention_horizon() {
+            horizon.hold(retention.xid, || XminHolder::RetainHistory);
+        }
+        horizon
+    }
+
+    // Everything but the replication slots.
+    fn running_horizon(&self) -> XminHorizon {
+        let snapshots = self.snapshots.read().unwrap();
+        let mut horizon = XminHorizon::new(self.current_xid());
+
+        let prepared = self.twophase.list();
+        for &xid in self.in_progress.read().unwrap().iter() {
+            horizon.hold(xid, || {
+                match prepared
+                    .iter()
+                    .find(|p| p.xid == xid || p.subxids.contains(&xid))
+                {
+                    Some(p) => XminHolder::PreparedTransaction(p.gid.clone()),
+                    None => XminHolder::Transaction(xid),
+                }
+            });
+        }
+
+        snapshots.hold(&mut horizon);
+        for (id, exported) in self.exported_snapshots.read().unwrap().iter() {
+            horizon.hold(exported.snapshot.xmin, || {
+                XminHolder::ExportedSnapshot(id.clone())
+            });
+        }
+
+        horizon
+    }
+
+    pub fn oldest_xmin(&self) -> TransactionId {
+        self.compute_oldest_xmin().xmin
+    }
+
+    // The slot starts out holding the current horizon, computed while
+    // anyone else computing one waits to see the new slot.
+    pub fn create_replication_slot(&self, name: &str) -> Result<ReplicationSlot> {
+        self.slots.create(name, || self.running_horizon().xmin)
+    }
+
+    pub fn advance_replication_slot(&self, name: &str, xmin: TransactionId) -> Result<()> {
+        self.slots.advance(name, xmin)
+    }
+
+    pub fn drop_replication_slot(&self, name: &str) -> Result<()> {
+        self.slots.drop(name)
+    }
+
+    pub fn replication_slots(&self) -> Vec<ReplicationSlot> {
+        self.slots.list()
+    }
+
+    // O(1): the oldest running xid, the CSN counter and the next xid, read
+    // in that order. Everything before xmin finished, with any CSN it got
+    // below the snapshot's, before the counter was read. Unregistered, so
+    // nothing stops the CSN log from being trimmed past its xmin; outside
+    // the crate snapshots come registered to an owner.
+    pub(crate) fn get_snapshot(&self, current_cid: CommandId) -> Snapshot {
+        let xmin = self.in_progress.read().unwrap().xmin();
+        let csn = self.csn_log.snapshot_csn();
+        let full_xmax = *self.next_full_xid.read().unwrap();
+        Snapshot::with_csn(xmin, full_xmax, csn, current_cid)
+    }
+
+    // The CSN `xid` committed with; invalid while it has not committed.
+    pub fn commit_csn(&self, xid: TransactionId) -> CommitSeqNo {
+        if let Some(csn) = self.csn_log.get(xid) {
+            return csn;
+        }
+        if self.csn_log.predates(xid)
+            && self.oracle(TransactionId::invalid()).status(xid) == XidStatus::Committed
+        {
+            return CommitSeqNo::frozen();
+        }
+        CommitSeqNo::invalid()
+    }
+}
+
+// Transaction status as seen from inside `top`, for visibility checks.
+pub struct StatusOracle<'a> {
+    manager: &'a TransactionManager,
+    top: TransactionId,
+}
+
+impl TransactionOracle for StatusOracle<'_> {
+    fn is_current(&self, xid: TransactionId) -> bool {
+        self.top.is_valid() && self.manager.is_current_transaction(self.top, xid)
+    }
+
+    // Checks the running set before the clog, since a committing
+    // transaction sets its clog status before it stops running.
+    fn status(&self, xid: TransactionId) -> XidStatus {
+        if self.manager.is_in_progress(xid) {
+            return XidStatus::InProgress;
+        }
+
+        match self.manager.status(xid) {
+            Ok(XidStatus::Committed) => XidStatus::Committed,
+            // The clog before oldest_xid is gone; vacuum has frozen or
+            // removed every tuple those xids wrote.
+            Err(_) if xid.precedes(self.manager.xid_limits().oldest_xid) => XidStatus::Committed,
+            // Not running and not committed: aborted, or cut off by a crash.
+            _ => XidStatus::Aborted,
+        }
+    }
+
+    fn commit_csn(&self, xid: TransactionId) -> CommitSeqNo {
+        self.manager.commit_csn(xid)
+    }
+}
+
+// How far retain_history lets the vacuum horizon go: every xid before
+// `xid` has finished outside the retained window, and `newest` is the
+// latest commit timestamp among them.
+#[derive(Debug, Clone, Copy)]
+struct RetentionCursor {
+    xid: TransactionId,
+    newest: u64,
+}
+
+// Transaction status as it stood at `as_of`: a transaction that committed
+// later still counts as running.
+pub struct HistoricOracle<'a> {
+    manager: &'a TransactionManager,
+    as_of: u64,
+}
+
+impl HistoricOracle<'_> {
+    pub fn as_of(&self) -> u64 {
+        self.as_of
+    }
+}
+
+impl TransactionOracle for HistoricOracle<'_> {
+    fn is_current(&self, _xid: TransactionId) -> bool {
+        false
+    }
 
-        Snapshot::new(
-            xmin.0,
-            xid.0,
-            xip.iter().map(|x| x.0).collect(),
-            current_cid.0,
-            VisibilityMode::MVCC,
-        )
+    // Xids with no commit timestamp committed before tracking began or
+    // before the oldest xid, and so before any retained point.
+    fn status(&self, xid: TransactionId) -> XidStatus {
+        match self.manager.oracle(TransactionId::invalid()).status(xid) {
+            XidStatus::Committed => match self.manager.commit_timestamp(xid) {
+                Ok(Some(ts)) if ts > self.as_of => XidStatus::InProgress,
+                _ => XidStatus::Committed,
+            },
+            status => status,
+        }
+    }
+
+    fn commit_csn(&self, xid: TransactionId) -> CommitSeqNo {
+        self.manager.commit_csn(xid)
     }
 }
 
@@ -116,39 +1390,226 @@ impl Default for TransactionManager {
     }
 }
 
+struct Savepoint {
+    name: String,
+    xid: TransactionId,
+}
+
+struct TransactionState {
+    savepoints: Vec<Savepoint>,
+    finished: bool,
+    snapshot: Option<Snapshot>,
+    cid: CommandId,
+    synchronous_commit: bool,
+}
+
 pub struct Transaction {
     pub xid: TransactionId,
-    pub cid: CommandId,
+    pub isolation: IsolationLevel,
     pub manager: Arc<TransactionManager>,
+    state: Arc<Mutex<TransactionState>>,
 }
 
 impl Clone for Transaction {
     fn clone(&self) -> Self {
         Self {
             xid: self.xid,
-            cid: self.cid,
+            isolation: self.isolation,
             manager: self.manager.clone(),
+            state: self.state.clone(),
         }
     }
 }
 
 impl Transaction {
-    pub fn new(manager: Arc<TransactionManager>) -> Self {
-        let xid = manager.begin();
-        let cid = manager.get_cid();
-        Self { xid, cid, manager }
+    pub fn new(manager: Arc<TransactionManager>) -> Result<Self> {
+        Self::with_isolation(manager, IsolationLevel::ReadCommitted)
+    }
+
+    // READ COMMITTED takes a new snapshot for every statement; the stronger
+    // levels keep the one taken here for the whole transaction.
+    pub fn with_isolation(
+        manager: Arc<TransactionManager>,
+        isolation: IsolationLevel,
+    ) -> Result<Self> {
+        let xid = manager.begin()?;
+        let cid = CommandId(FIRST_COMMAND_ID);
+
+        if isolation == IsolationLevel::Serializable {
+            manager.predicate_locks().register(xid);
+        }
+        let snapshot = if isolation.uses_transaction_snapshot() {
+            Some(manager.get_transaction_snapshot(xid, cid))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            xid,
+            isolation,
+            manager,
+            state: Arc::new(Mutex::new(TransactionState {
+                savepoints: Vec::new(),
+                finished: false,
+                snapshot,
+                cid,
+                synchronous_commit: true,
+            })),
+        })
+    }
+
+    pub fn commit(self) -> Result<()> {
+        let synchronous_commit = {
+            let mut state = self.state.lock().unwrap();
+            state.finished = true;
+            state.synchronous_commit
+        };
+        self.manager.commit_with(self.xid, synchronous_commit)
+    }
+
+    pub fn set_synchronous_commit(&self, on: bool) {
+        self.state.lock().unwrap().synchronous_commit = on;
+    }
+
+    pub fn abort(self) -> Result<()> {
+        self.state.lock().unwrap().finished = true;
+        self.manager.abort(self.xid)
     }
 
-    pub fn commit(mut self) {
-        self.manager.commit(self.xid);
+    pub fn prepare(self, gid: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        self.manager.prepare(self.xid, gid)?;
+        state.finished = true;
+        Ok(())
     }
 
-    pub fn abort(mut self) {
-        self.manager.abort(self.xid);
+    pub fn savepoint(&self, name: &str) -> Result<TransactionId> {
+        let mut state = self.state.lock().unwrap();
+        let parent = state.savepoints.last().map_or(self.xid, |sp| sp.xid);
+        let xid = self.manager.begin_subtransaction(parent)?;
+        state.savepoints.push(Savepoint {
+            name: name.to_string(),
+            xid,
+        });
+        Ok(xid)
+    }
+
+    pub fn release(&self, name: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let index = Self::find_savepoint(&state, name)?;
+        state.savepoints.truncate(index);
+        Ok(())
+    }
+
+    pub fn rollback_to(&self, name: &str) -> Result<TransactionId> {
+        let mut state = self.state.lock().unwrap();
+        let index = Self::find_savepoint(&state, name)?;
+
+        for savepoint in state.savepoints.drain(index..).rev() {
+            self.manager.abort_subtransaction(savepoint.xid)?;
+        }
+
+        let parent = state.savepoints.last().map_or(self.xid, |sp| sp.xid);
+        let xid = self.manager.begin_subtransaction(parent)?;
+        state.savepoints.push(Savepoint {
+            name: name.to_string(),
+            xid,
+        });
+        Ok(xid)
+    }
+
+    fn find_savepoint(state: &TransactionState, name: &str) -> Result<usize> {
+        state
+            .savepoints
+            .iter()
+            .rposition(|sp| sp.name == name)
+            .ok_or_else(|| {
+                HeapError::InvalidTransaction(format!("savepoint \"{}\" does not exist", name))
+            })
+    }
+
+    pub fn current_xid(&self) -> TransactionId {
+        let state = self.state.lock().unwrap();
+        state.savepoints.last().map_or(self.xid, |sp| sp.xid)
+    }
+
+    // A stored snapshot still follows the command counter, so later
+    // commands see what earlier ones in this transaction did.
+    pub fn snapshot(&self) -> Snapshot {
+        let state = self.state.lock().unwrap();
+        match state.snapshot {
+            Some(ref snapshot) => Snapshot {
+                curcid: state.cid,
+                ..snapshot.clone()
+            },
+            None => self.manager.get_statement_snapshot(self.xid, state.cid),
+        }
+    }
+
+    // Keeps the current snapshot usable, e.g. by a cursor, past the
+    // statement that took it.
+    pub fn register_snapshot(&self) -> SnapshotId {
+        self.manager.register_snapshot(self.xid, &self.snapshot())
+    }
+
+    pub fn unregister_snapshot(&self, id: SnapshotId) -> bool {
+        self.manager.unregister_snapshot(id)
     }
 
     pub fn get_cid(&self) -> CommandId {
-        self.cid
+        self.state.lock().unwrap().cid
+    }
+
+    pub fn export_snapshot(&self) -> Result<String> {
+        self.manager
+            .export_snapshot(self.xid, self.isolation, self.snapshot())
+    }
+
+    // Makes this transaction see exactly what the exporting one sees. Only
+    // transactions that keep one snapshot throughout can import, and a
+    // serializable one only from another serializable transaction.
+    pub fn import_snapshot(&self, id: &str) -> Result<()> {
+        if !self.isolation.uses_transaction_snapshot() {
+            return Err(HeapError::InvalidTransaction(
+                "a snapshot-importing transaction must have isolation level SERIALIZABLE or \
+                 REPEATABLE READ"
+                    .to_string(),
+            ));
+        }
+
+        let exported = self.manager.exported_snapshot(id)?;
+        if self.isolation == IsolationLevel::Serializable
+            && exported.isolation != IsolationLevel::Serializable
+        {
+            return Err(HeapError::InvalidTransaction(
+                "a serializable transaction cannot import a snapshot from a non-serializable \
+                 transaction"
+                    .to_string(),
+            ));
+        }
+
+        let snapshot = exported.snapshot;
+        if self.isolation == IsolationLevel::Serializable {
+            self.manager
+                .predicate_locks()
+                .import_snapshot(self.xid, exported.xid);
+        }
+        self.manager.register_snapshot(self.xid, &snapshot);
+        self.state.lock().unwrap().snapshot = Some(snapshot);
+        Ok(())
+    }
+
+    // Ends the current command: its changes become visible to the commands
+    // that follow, which run under the next command id.
+    pub fn command_counter_increment(&self) -> Result<CommandId> {
+        let mut state = self.state.lock().unwrap();
+        if state.cid.0 == MAX_COMMAND_ID {
+            return Err(HeapError::InvalidTransaction(
+                "cannot have more than 2^32-2 commands in a transaction".to_string(),
+            ));
+        }
+        state.cid = CommandId(state.cid.0 + 1);
+        Ok(state.cid)
     }
 
     pub fn xid(&self) -> TransactionId {
@@ -156,10 +1617,46 @@ impl Transaction {
     }
 }
 
+// A statement run outside a transaction block that only reads. It locks
+// and takes its snapshot under a virtual id, so it uses up no xid and
+// writes no commit record; what it holds is released when it is dropped.
+pub struct VirtualTransaction {
+    pub owner: LockOwner,
+    pub manager: Arc<TransactionManager>,
+}
+
+impl VirtualTransaction {
+    pub fn new(manager: Arc<TransactionManager>) -> Self {
+        let owner = manager.begin_virtual();
+        Self { owner, manager }
+    }
+
+    pub fn lock(&self, tag: LockTag, mode: TableLockMode, wait_policy: WaitPolicy) -> Result<bool> {
+        self.manager
+            .locks
+            .acquire(self.owner, tag, mode, wait_policy)
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        self.manager
+            .get_statement_snapshot(self.owner, CommandId(FIRST_COMMAND_ID))
+    }
+}
+
+impl Drop for VirtualTransaction {
+    fn drop(&mut self) {
+        self.manager.end_virtual(self.owner);
+    }
+}
+
 impl Drop for Transaction {
     fn drop(&mut self) {
-        if !std::thread::panicking() {
-            self.manager.abort(self.xid);
+        if std::thread::panicking() || Arc::strong_count(&self.state) > 1 {
+            return;
+        }
+
+        if !self.state.lock().unwrap().finished {
+            let _ = self.manager.abort(self.xid);
         }
     }
 }
@@ -0,0 +1,168 @@
+use crate::error::{HeapError, Result};
+use crate::failpoint;
+use crate::types::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const GID_MAX_LEN: usize = 200;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PreparedTransaction {
+    pub gid: String,
+    pub xid: TransactionId,
+    pub subxids: Vec<TransactionId>,
+    pub prepared_at: u64,
+}
+
+impl PreparedTransaction {
+    pub fn new(gid: &str, xid: TransactionId, subxids: Vec<TransactionId>) -> Self {
+        let prepared_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0);
+
+        Self {
+            gid: gid.to_string(),
+            xid,
+            subxids,
+            prepared_at,
+        }
+    }
+}
+
+pub struct TwoPhaseState {
+    dir: Option<PathBuf>,
+    prepared: RwLock<HashMap<String, PreparedTransaction>>,
+    finishing: RwLock<HashSet<String>>,
+}
+
+impl TwoPhaseState {
+    pub fn open(dir: PathBuf) -> Result<Self> {
+        let dir = dir.join("pg_twophase");
+        if !dir.exists() {
+            fs::create_dir_all(&dir)?;
+        }
+
+        let mut prepared = HashMap::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "tmp") {
+                fs::remove_file(path)?;
+                continue;
+            }
+
+            let raw = fs::read(&path)?;
+            let record: PreparedTransaction = serde_json::from_slice(&raw).map_err(|e| {
+                HeapError::CorruptedData(format!("two-phase state {}: {}", path.display(), e))
+            })?;
+            prepared.insert(record.gid.clone(), record);
+        }
+
+        Ok(Self {
+            dir: Some(dir),
+            prepared: RwLock::new(prepared),
+            finishing: RwLock::new(HashSet::new()),
+        })
+    }
+
+    pub fn in_memory() -> Self {
+        Self {
+            dir: None,
+            prepared: RwLock::new(HashMap::new()),
+            finishing: RwLock::new(HashSet::new()),
+        }
+    }
+
+    pub fn add(&self, record: PreparedTransaction) -> Result<()> {
+        if record.gid.is_empty() || record.gid.len() > GID_MAX_LEN {
+            return Err(HeapError::InvalidTransaction(format!(
+                "transaction identifier \"{}\" is not valid",
+                record.gid
+            )));
+        }
+
+        let mut prepared = self.prepared.write().unwrap();
+        if prepared.contains_key(&record.gid) {
+            return Err(HeapError::InvalidTransaction(format!(
+                "transaction identifier \"{}\" is already in use",
+                record.gid
+            )));
+        }
+
+        if let Some(ref dir) = self.dir {
+            let raw = serde_json::to_vec_pretty(&record)
+                .map_err(|e| HeapError::StorageError(format!("two-phase state: {}", e)))?;
+            let path = Self::state_file(dir, record.xid);
+            let tmp_path = path.with_extension("tmp");
+            failpoint::before_write(&path)?;
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(&raw)?;
+            file.sync_all()?;
+            fs::rename(&tmp_path, &path)?;
+        }
+
+        prepared.insert(record.gid.clone(), record);
+        Ok(())
+    }
+
+    pub fn get(&self, gid: &str) -> Option<PreparedTransaction> {
+        let prepared = self.prepared.read().unwrap();
+        prepared.get(gid).cloned()
+    }
+
+    pub fn lock(&self, gid: &str) -> Result<PreparedTransaction> {
+        let prepared = self.prepared.read().unwrap();
+        let record = prepared.get(gid).cloned().ok_or_else(|| {
+            HeapError::InvalidTransaction(format!(
+                "prepared transaction with identifier \"{}\" does not exist",
+                gid
+            ))
+        })?;
+
+        let mut finishing = self.finishing.write().unwrap();
+        if !finishing.insert(gid.to_string()) {
+            return Err(HeapError::LockError(format!(
+                "prepared transaction with identifier \"{}\" is busy",
+                gid
+            )));
+        }
+
+        Ok(record)
+    }
+
+    pub fn unlock(&self, gid: &str) {
+        let mut finishing = self.finishing.write().unwrap();
+        finishing.remove(gid);
+    }
+
+    pub fn remove(&self, gid: &str) -> Result<Option<PreparedTransaction>> {
+        let mut prepared = self.prepared.write().unwrap();
+        let record = prepared.remove(gid);
+        self.unlock(gid);
+
+        if let (Some(ref dir), Some(ref record)) = (&self.dir, &record) {
+            let path = Self::state_file(dir, record.xid);
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+
+        Ok(record)
+    }
+
+    pub fn list(&self) -> Vec<PreparedTransaction> {
+        let prepared = self.prepared.read().unwrap();
+        let mut list: Vec<PreparedTransaction> = prepared.values().cloned().collect();
+        list.sort_by_key(|record| record.xid);
+        list
+    }
+
+    fn state_file(dir: &Path, xid: TransactionId) -> PathBuf {
+        dir.join(format!("{:08X}", xid.0))
+    }
+}
@@ -1,6 +1,11 @@
+use crate::constants::FIRST_NORMAL_TRANSACTION_ID;
+use crate::error::HeapError;
+use serde::{Deserialize, Serialize};
 use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Ord, PartialOrd)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
 pub struct TransactionId(pub u32);
 
 impl TransactionId {
@@ -13,7 +18,7 @@ impl TransactionId {
     }
 
     pub fn first_normal() -> Self {
-        Self(2)
+        Self(FIRST_NORMAL_TRANSACTION_ID)
     }
 
     pub fn is_valid(&self) -> bool {
@@ -24,8 +29,60 @@ impl TransactionId {
         self.0 == 0
     }
 
+    pub fn is_normal(&self) -> bool {
+        self.0 >= FIRST_NORMAL_TRANSACTION_ID
+    }
+
+    pub fn precedes(&self, other: TransactionId) -> bool {
+        if !self.is_normal() || !other.is_normal() {
+            return self.0 < other.0;
+        }
+        (self.0.wrapping_sub(other.0) as i32) < 0
+    }
+
+    pub fn precedes_or_equals(&self, other: TransactionId) -> bool {
+        if !self.is_normal() || !other.is_normal() {
+            return self.0 <= other.0;
+        }
+        (self.0.wrapping_sub(other.0) as i32) <= 0
+    }
+
+    pub fn follows(&self, other: TransactionId) -> bool {
+        other.precedes(*self)
+    }
+
+    pub fn follows_or_equals(&self, other: TransactionId) -> bool {
+        other.precedes_or_equals(*self)
+    }
+
+    pub fn next(&self) -> Self {
+        let next = self.0.wrapping_add(1);
+        if next < FIRST_NORMAL_TRANSACTION_ID {
+            Self(FIRST_NORMAL_TRANSACTION_ID)
+        } else {
+            Self(next)
+        }
+    }
+
+    pub fn prev(&self) -> Self {
+        let prev = self.0.wrapping_sub(1);
+        if prev < FIRST_NORMAL_TRANSACTION_ID {
+            Self(u32::MAX)
+        } else {
+            Self(prev)
+        }
+    }
+
+    pub fn older(self, other: TransactionId) -> Self {
+        if other.precedes(self) {
+            other
+        } else {
+            self
+        }
+    }
+
     pub fn is_in_progress(&self, xmin: TransactionId, xmax: TransactionId) -> bool {
-        self.0 >= xmin.0 && self.0 < xmax.0
+        self.follows_or_equals(xmin) && self.precedes(xmax)
     }
 
     pub fn is_committed(&self) -> bool {
@@ -57,6 +114,208 @@ impl From<TransactionId> for u32 {
     }
 }
 
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, Ord, PartialOrd, Default, Serialize, Deserialize,
+)]
+pub struct FullTransactionId(pub u64);
+
+impl FullTransactionId {
+    pub fn new(epoch: u32, xid: TransactionId) -> Self {
+        Self(((epoch as u64) << 32) | xid.0 as u64)
+    }
+
+    pub fn epoch(&self) -> u32 {
+        (self.0 >> 32) as u32
+    }
+
+    pub fn xid(&self) -> TransactionId {
+        TransactionId(self.0 as u32)
+    }
+
+    pub fn next(&self) -> Self {
+        let mut next = self.0 + 1;
+        while (next as u32) < FIRST_NORMAL_TRANSACTION_ID {
+            next += 1;
+        }
+        Self(next)
+    }
+
+    // Widens a 32-bit xid that is known to lie within 2^31 of `relative_to`.
+    pub fn from_relative(relative_to: FullTransactionId, xid: TransactionId) -> Self {
+        if !xid.is_normal() {
+            return Self::new(0, xid);
+        }
+
+        let delta = xid.0.wrapping_sub(relative_to.xid().0) as i32;
+        Self((relative_to.0 as i64 + delta as i64) as u64)
+    }
+}
+
+impl fmt::Display for FullTransactionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.epoch(), self.xid())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct MultiXactId(pub u32);
+
+impl MultiXactId {
+    pub fn invalid() -> Self {
+        Self(0)
+    }
+
+    pub fn first() -> Self {
+        Self(1)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.0 != 0
+    }
+
+    pub fn precedes(&self, other: MultiXactId) -> bool {
+        (self.0.wrapping_sub(other.0) as i32) < 0
+    }
+
+    pub fn follows(&self, other: MultiXactId) -> bool {
+        other.precedes(*self)
+    }
+
+    pub fn next(&self) -> Self {
+        let next = self.0.wrapping_add(1);
+        if next == 0 {
+            Self::first()
+        } else {
+            Self(next)
+        }
+    }
+
+    pub fn older(self, other: MultiXactId) -> Self {
+        if other.precedes(self) {
+            other
+        } else {
+            self
+        }
+    }
+}
+
+impl Default for MultiXactId {
+    fn default() -> Self {
+        Self::invalid()
+    }
+}
+
+impl fmt::Display for MultiXactId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// Commit sequence number: the order in which transactions committed. A
+// 64-bit counter, so it never wraps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Ord, PartialOrd, Default)]
+pub struct CommitSeqNo(pub u64);
+
+impl CommitSeqNo {
+    pub fn invalid() -> Self {
+        Self(0)
+    }
+
+    // Committed before any snapshot still in use was taken.
+    pub fn frozen() -> Self {
+        Self(1)
+    }
+
+    pub fn first_normal() -> Self {
+        Self(2)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.0 != 0
+    }
+
+    pub fn next(&self) -> Self {
+        Self(self.0 + 1)
+    }
+}
+
+impl fmt::Display for CommitSeqNo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MultiXactStatus {
+    ForKeyShare,
+    ForShare,
+    ForNoKeyUpdate,
+    ForUpdate,
+    NoKeyUpdate,
+    Update,
+}
+
+impl MultiXactStatus {
+    pub fn from_lock_mode(mode: LockMode) -> Self {
+        match mode {
+            LockMode::ForUpdate => MultiXactStatus::ForUpdate,
+            LockMode::ForNoKeyUpdate => MultiXactStatus::ForNoKeyUpdate,
+            LockMode::ForShare => MultiXactStatus::ForShare,
+            LockMode::ForKeyShare | LockMode::NoLock => MultiXactStatus::ForKeyShare,
+        }
+    }
+
+    pub fn lock_mode(self) -> LockMode {
+        match self {
+            MultiXactStatus::ForKeyShare => LockMode::ForKeyShare,
+            MultiXactStatus::ForShare => LockMode::ForShare,
+            MultiXactStatus::ForNoKeyUpdate | MultiXactStatus::NoKeyUpdate => {
+                LockMode::ForNoKeyUpdate
+            }
+            MultiXactStatus::ForUpdate | MultiXactStatus::Update => LockMode::ForUpdate,
+        }
+    }
+
+    pub fn is_update(self) -> bool {
+        matches!(self, MultiXactStatus::NoKeyUpdate | MultiXactStatus::Update)
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(MultiXactStatus::ForKeyShare),
+            1 => Some(MultiXactStatus::ForShare),
+            2 => Some(MultiXactStatus::ForNoKeyUpdate),
+            3 => Some(MultiXactStatus::ForUpdate),
+            4 => Some(MultiXactStatus::NoKeyUpdate),
+            5 => Some(MultiXactStatus::Update),
+            _ => None,
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            MultiXactStatus::ForKeyShare => 0,
+            MultiXactStatus::ForShare => 1,
+            MultiXactStatus::ForNoKeyUpdate => 2,
+            MultiXactStatus::ForUpdate => 3,
+            MultiXactStatus::NoKeyUpdate => 4,
+            MultiXactStatus::Update => 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MultiXactMember {
+    pub xid: TransactionId,
+    pub status: MultiXactStatus,
+}
+
+impl MultiXactMember {
+    pub fn new(xid: TransactionId, status: MultiXactStatus) -> Self {
+        Self { xid, status }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
 pub struct CommandId(pub u32);
 
@@ -130,16 +389,30 @@ pub enum VisibilityMode {
     MVCC,
     Self_,
     Any,
-    Stable,
+    Dirty,
+    Toast,
+    NonVacuumable,
+}
+
+// What a dirty snapshot check saw still running: the transaction inserting
+// the tuple and the one deleting it, invalid when there is none.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DirtyWriters {
+    pub xmin: TransactionId,
+    pub xmax: TransactionId,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Snapshot {
     pub xmin: TransactionId,
     pub xmax: TransactionId,
+    pub full_xmax: FullTransactionId,
     pub xip: Vec<TransactionId>,
     pub curcid: CommandId,
     pub mode: VisibilityMode,
+    // When valid, xids from xmin up to xmax count as committed only if
+    // they did so with a lower CSN, and xip is not used.
+    pub csn: CommitSeqNo,
 }
 
 impl Snapshot {
@@ -147,9 +420,28 @@ impl Snapshot {
         Self {
             xmin: TransactionId(xmin),
             xmax: TransactionId(xmax),
+            full_xmax: FullTransactionId::new(0, TransactionId(xmax)),
             xip: xip.into_iter().map(TransactionId).collect(),
             curcid: CommandId(curcid),
             mode,
+            csn: CommitSeqNo::invalid(),
+        }
+    }
+
+    pub fn with_csn(
+        xmin: TransactionId,
+        full_xmax: FullTransactionId,
+        csn: CommitSeqNo,
+        curcid: CommandId,
+    ) -> Self {
+        Self {
+            xmin,
+            xmax: full_xmax.xid(),
+            full_xmax,
+            xip: Vec::new(),
+            curcid,
+            mode: VisibilityMode::MVCC,
+            csn,
         }
     }
 
@@ -157,14 +449,49 @@ impl Snapshot {
         Self {
             xmin: TransactionId::invalid(),
             xmax: TransactionId::invalid(),
+            full_xmax: FullTransactionId::default(),
             xip: Vec::new(),
             curcid: CommandId::invalid(),
             mode: VisibilityMode::MVCC,
+            csn: CommitSeqNo::invalid(),
+        }
+    }
+
+    pub fn dirty() -> Self {
+        Self {
+            mode: VisibilityMode::Dirty,
+            ..Self::invalid()
+        }
+    }
+
+    pub fn toast() -> Self {
+        Self {
+            mode: VisibilityMode::Toast,
+            ..Self::invalid()
         }
     }
 
+    // The horizon travels in xmin.
+    pub fn non_vacuumable(oldest_xmin: TransactionId) -> Self {
+        Self {
+            xmin: oldest_xmin,
+            mode: VisibilityMode::NonVacuumable,
+            ..Self::invalid()
+        }
+    }
+
+    pub fn with_full_xmax(mut self, full_xmax: FullTransactionId) -> Self {
+        self.xmax = full_xmax.xid();
+        self.full_xmax = full_xmax;
+        self
+    }
+
+    pub fn full_xmin(&self) -> FullTransactionId {
+        FullTransactionId::from_relative(self.full_xmax, self.xmin)
+    }
+
     pub fn contains(&self, xid: TransactionId) -> bool {
-        xid.0 >= self.xmin.0 && xid.0 < self.xmax.0
+        xid.is_in_progress(self.xmin, self.xmax)
     }
 }
 
@@ -180,6 +507,47 @@ pub enum HeapTupleStatus {
     Unknown,
 }
 
+// What vacuum may do with a tuple, relative to the OldestXmin horizon.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HtsvResult {
+    Live,
+    // Invisible to every transaction, running or future: safe to remove.
+    Dead,
+    // Deleted by a committed transaction that some snapshot may still
+    // consider running.
+    RecentlyDead,
+    InsertInProgress,
+    DeleteInProgress,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XidStatus {
+    InProgress,
+    Committed,
+    Aborted,
+    SubCommitted,
+}
+
+impl XidStatus {
+    pub fn from_bits(bits: u8) -> Self {
+        match bits & 0x03 {
+            0 => XidStatus::InProgress,
+            1 => XidStatus::Committed,
+            2 => XidStatus::Aborted,
+            _ => XidStatus::SubCommitted,
+        }
+    }
+
+    pub fn to_bits(self) -> u8 {
+        match self {
+            XidStatus::InProgress => 0,
+            XidStatus::Committed => 1,
+            XidStatus::Aborted => 2,
+            XidStatus::SubCommitted => 3,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum LockMode {
     NoLock,
@@ -189,10 +557,118 @@ pub enum LockMode {
     ForKeyShare,
 }
 
+impl LockMode {
+    fn strength(self) -> u8 {
+        match self {
+            LockMode::NoLock => 0,
+            LockMode::ForKeyShare => 1,
+            LockMode::ForShare => 2,
+            LockMode::ForNoKeyUpdate => 3,
+            LockMode::ForUpdate => 4,
+        }
+    }
+
+    pub fn is_stronger_than(self, other: LockMode) -> bool {
+        self.strength() > other.strength()
+    }
+
+    // Row-level conflict table; an UPDATE that leaves the key alone counts
+    // as FOR NO KEY UPDATE and a DELETE as FOR UPDATE.
+    pub fn conflicts_with(self, other: LockMode) -> bool {
+        match (self, other) {
+            (LockMode::NoLock, _) | (_, LockMode::NoLock) => false,
+            (LockMode::ForUpdate, _) | (_, LockMode::ForUpdate) => true,
+            (LockMode::ForKeyShare, _) | (_, LockMode::ForKeyShare) => false,
+            (LockMode::ForShare, LockMode::ForShare) => false,
+            _ => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IsolationLevel {
+    #[default]
+    ReadCommitted,
+    RepeatableRead,
+    Serializable,
+}
+
+impl IsolationLevel {
+    pub fn uses_transaction_snapshot(&self) -> bool {
+        !matches!(self, IsolationLevel::ReadCommitted)
+    }
+}
+
+// How much history vacuum leaves behind for reads of past table states:
+// whatever committed within the last duration, or within the last N xids.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum RetainHistory {
+    #[default]
+    Off,
+    Duration(Duration),
+    Transactions(u32),
+}
+
+// Accepts "off", "<n>s", "<n>min", "<n>h", "<n>d" and "<n> transactions".
+impl FromStr for RetainHistory {
+    type Err = HeapError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.trim();
+        if s == "off" {
+            return Ok(RetainHistory::Off);
+        }
+
+        let invalid = || {
+            HeapError::InvalidOperation(format!(
+                "invalid value for parameter \"retain_history\": \"{}\"",
+                s
+            ))
+        };
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let count: u64 = s[..split].parse().map_err(|_| invalid())?;
+        let seconds = match s[split..].trim() {
+            "transactions" => {
+                let count = u32::try_from(count).map_err(|_| invalid())?;
+                return Ok(RetainHistory::Transactions(count));
+            }
+            "s" => 1,
+            "min" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        count
+            .checked_mul(seconds)
+            .map(|secs| RetainHistory::Duration(Duration::from_secs(secs)))
+            .ok_or_else(invalid)
+    }
+}
+
+// A point in the past to read a table as of: just after a transaction
+// committed, or a commit timestamp in microseconds since the Unix epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AsOf {
+    Xid(TransactionId),
+    Timestamp(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitPolicy {
+    Block,
+    SkipLocked,
+    NoWait,
+}
+
+// Outcome of updating, deleting or locking a tuple version. `Updated`
+// carries the t_ctid of the version that replaced it.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum UpdateResult {
-    Updated,
-    Deleted,
+    Ok,
+    Invisible,
     SelfModified,
-    NoResult,
+    Updated(ItemPointerData),
+    Deleted,
+    BeingModified,
+    WouldBlock,
 }
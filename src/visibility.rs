@@ -1,213 +1,287 @@
-use crate::constants::*;
+use crate::combocid::ComboCids;
 use crate::heap_tuple::HeapTuple;
 use crate::types::*;
 
-pub struct Visibility;
-
-impl Visibility {
-    pub fn heap_tuple_satisfies_mvcc(
-        heap_tuple: &HeapTuple,
-        snapshot: &Snapshot,
-        cur_xid: TransactionId,
-    ) -> bool {
-        let xmin = heap_tuple.xmin();
-        let xmax = heap_tuple.xmax();
+// What visibility needs to know about xids whose hint bits are not set yet.
+pub trait TransactionOracle {
+    // Whether `xid` is the checking transaction or one of its subtransactions
+    // that has not been rolled back.
+    fn is_current(&self, xid: TransactionId) -> bool;
 
-        if !Self::heap_tuple_satisfiesvisibility(heap_tuple, snapshot, cur_xid) {
-            return false;
-        }
+    // How `xid` ended, with subtransactions resolved through their parents.
+    // Never SubCommitted; a transaction that is not running and never
+    // committed counts as aborted.
+    fn status(&self, xid: TransactionId) -> XidStatus;
 
-        if !Self::heap_txns_satisfies_update(heap_tuple, snapshot, cur_xid) {
-            return false;
-        }
+    // The CSN `xid` committed with, invalid if it has not committed. Only
+    // asked for snapshots that carry a CSN.
+    fn commit_csn(&self, xid: TransactionId) -> CommitSeqNo;
+}
 
-        true
-    }
+pub struct Visibility;
 
-    fn heap_tuple_satisfiesvisibility(
+impl Visibility {
+    // The current transaction sees its own changes only from commands
+    // before the snapshot's curcid.
+    pub fn heap_tuple_satisfies_mvcc(
         heap_tuple: &HeapTuple,
         snapshot: &Snapshot,
-        cur_xid: TransactionId,
+        oracle: &dyn TransactionOracle,
+        combo_cids: &ComboCids,
     ) -> bool {
+        let header = &heap_tuple.header;
         let xmin = heap_tuple.xmin();
 
-        if xmin.0 == cur_xid.0 {
-            return true;
-        }
-
-        if xmin.0 >= snapshot.xmin.0 && xmin.0 < snapshot.xmax.0 {
-            if heap_tuple.header.xmin_committed() {
-                return true;
-            }
-            if heap_tuple.header.xmin_invalid() {
+        if !header.xmin_frozen() {
+            if header.xmin_invalid() {
                 return false;
             }
-            if snapshot.xip.contains(&xmin) {
+
+            if oracle.is_current(xmin) {
+                if heap_tuple.cmin(combo_cids).0 >= snapshot.curcid.0 {
+                    return false;
+                }
+            } else if !Self::committed_before(xmin, header.xmin_committed(), snapshot, oracle) {
                 return false;
             }
-            return true;
         }
 
-        if xmin.0 < snapshot.xmin.0 {
-            if heap_tuple.header.xmin_committed() {
-                return true;
-            }
-            if heap_tuple.header.xmin_invalid() {
-                return false;
-            }
+        let xmax = heap_tuple.xmax();
+        if Self::xmax_is_lock_or_invalid(heap_tuple) {
             return true;
         }
 
-        false
+        if oracle.is_current(xmax) {
+            return heap_tuple.cmax(combo_cids).0 >= snapshot.curcid.0;
+        }
+
+        !Self::committed_before(xmax, header.xmax_committed(), snapshot, oracle)
     }
 
-    fn heap_txns_satisfies_update(
-        heap_tuple: &HeapTuple,
+    // Whether `xid` committed before `snapshot` was taken. Below xmin the
+    // oracle is only asked when no hint bit says so already.
+    fn committed_before(
+        xid: TransactionId,
+        committed_hint: bool,
         snapshot: &Snapshot,
-        cur_xid: TransactionId,
+        oracle: &dyn TransactionOracle,
     ) -> bool {
-        let xmax = heap_tuple.xmax();
-
-        if xmax.is_invalid() {
-            return true;
-        }
-
-        if xmax.0 == cur_xid.0 {
-            if heap_tuple.header.xmax_is_locked_only() {
-                return true;
-            }
+        if Self::xid_in_snapshot(xid, snapshot, oracle) {
             return false;
         }
 
-        if xmax.0 >= snapshot.xmax.0 {
+        // Its CSN already said so.
+        if snapshot.csn.is_valid() && !xid.precedes(snapshot.xmin) {
             return true;
         }
 
-        if xmax.0 < snapshot.xmin.0 {
-            if heap_tuple.header.xmax_committed() {
-                return false;
-            }
-            if heap_tuple.header.xmax_invalid() {
-                return true;
-            }
-            return false;
-        }
+        committed_hint || oracle.status(xid) == XidStatus::Committed
+    }
 
-        if heap_tuple.header.xmax_committed() {
+    // True when `xid` was still running as far as `snapshot` is concerned.
+    pub fn xid_in_snapshot(
+        xid: TransactionId,
+        snapshot: &Snapshot,
+        oracle: &dyn TransactionOracle,
+    ) -> bool {
+        if xid.precedes(snapshot.xmin) {
             return false;
         }
 
-        if heap_tuple.header.xmax_invalid() {
+        if xid.follows_or_equals(snapshot.xmax) {
             return true;
         }
 
-        if heap_tuple.header.xmax_is_locked_only() {
-            return true;
+        if snapshot.csn.is_valid() {
+            let csn = oracle.commit_csn(xid);
+            return !csn.is_valid() || csn >= snapshot.csn;
         }
 
-        false
+        snapshot.xip.contains(&xid)
     }
 
-    pub fn heap_tuple_satisfies_any(heap_tuple: &HeapTuple) -> bool {
-        let xmax = heap_tuple.xmax();
+    // A multixact xmax still in place after the scan resolved its updater
+    // only holds row locks.
+    fn xmax_is_lock_or_invalid(heap_tuple: &HeapTuple) -> bool {
+        let header = &heap_tuple.header;
+        heap_tuple.xmax().is_invalid()
+            || header.xmax_invalid()
+            || header.xmax_is_locked_only()
+            || header.xmax_is_multi()
+    }
 
-        if !xmax.is_invalid() {
-            if heap_tuple.header.xmax_committed() {
-                return false;
-            }
-            if heap_tuple.header.xmax_invalid() {
-                return true;
-            }
-            if heap_tuple.header.xmax_is_locked_only() {
-                return true;
-            }
-        }
+    fn xmax_committed(heap_tuple: &HeapTuple, oracle: &dyn TransactionOracle) -> bool {
+        heap_tuple.header.xmax_committed()
+            || oracle.status(heap_tuple.xmax()) == XidStatus::Committed
+    }
 
-        true
+    // Every tuple that has not been deleted by a committed transaction.
+    pub fn heap_tuple_satisfies_any(
+        heap_tuple: &HeapTuple,
+        oracle: &dyn TransactionOracle,
+    ) -> bool {
+        Self::xmax_is_lock_or_invalid(heap_tuple) || !Self::xmax_committed(heap_tuple, oracle)
     }
 
+    // Unlike MVCC, sees the current command's own changes too.
     pub fn heap_tuple_satisfies_self(
         heap_tuple: &HeapTuple,
-        cur_xid: TransactionId,
+        oracle: &dyn TransactionOracle,
         cur_cid: CommandId,
+        combo_cids: &ComboCids,
     ) -> bool {
+        let header = &heap_tuple.header;
         let xmin = heap_tuple.xmin();
-        let xmax = heap_tuple.xmax();
 
-        if xmin.0 == cur_xid.0 {
-            let tuple_cid = heap_tuple.cid();
-            if tuple_cid.0 > cur_cid.0 {
+        if !header.xmin_frozen() {
+            if header.xmin_invalid() {
                 return false;
             }
-        }
 
-        if xmax.0 == cur_xid.0 {
-            if heap_tuple.header.xmax_is_locked_only() {
-                return true;
+            if oracle.is_current(xmin) {
+                if heap_tuple.cmin(combo_cids).0 > cur_cid.0 {
+                    return false;
+                }
+            } else if !header.xmin_committed() && oracle.status(xmin) != XidStatus::Committed {
+                return false;
             }
-            return false;
         }
 
-        if xmax.is_invalid() {
+        if Self::xmax_is_lock_or_invalid(heap_tuple) {
             return true;
         }
 
-        if heap_tuple.header.xmax_committed() {
-            return false;
+        if oracle.is_current(heap_tuple.xmax()) {
+            return heap_tuple.cmax(combo_cids).0 > cur_cid.0;
         }
 
-        true
+        !Self::xmax_committed(heap_tuple, oracle)
     }
 
-    pub fn heap_tuple_satisfies_stable(heap_tuple: &HeapTuple, snapshot: &Snapshot) -> bool {
+    // SnapshotDirty: sees committed work and everything still in progress,
+    // so a unique check or upsert finds rows it would conflict with. The
+    // writers tell the caller which transaction to wait for; None means the
+    // tuple is not there at all.
+    pub fn heap_tuple_satisfies_dirty(
+        heap_tuple: &HeapTuple,
+        oracle: &dyn TransactionOracle,
+    ) -> Option<DirtyWriters> {
+        let header = &heap_tuple.header;
         let xmin = heap_tuple.xmin();
-        let xmax = heap_tuple.xmax();
+        let mut writers = DirtyWriters::default();
 
-        if xmin.0 >= snapshot.xmin.0 && xmin.0 < snapshot.xmax.0 {
-            if heap_tuple.header.xmin_committed() {
-                return true;
+        if !header.xmin_frozen() && !header.xmin_committed() && !oracle.is_current(xmin) {
+            if header.xmin_invalid() {
+                return None;
             }
-            if heap_tuple.header.xmin_invalid() {
-                return false;
+            match oracle.status(xmin) {
+                XidStatus::Committed => {}
+                XidStatus::InProgress => {
+                    writers.xmin = xmin;
+                    return Some(writers);
+                }
+                _ => return None,
             }
-            return false;
         }
 
-        if xmin.0 < snapshot.xmin.0 {
-            if heap_tuple.header.xmin_committed() {
-                return true;
-            }
-            if heap_tuple.header.xmin_invalid() {
-                return false;
+        if Self::xmax_is_lock_or_invalid(heap_tuple) {
+            return Some(writers);
+        }
+
+        let xmax = heap_tuple.xmax();
+        if oracle.is_current(xmax) || header.xmax_committed() {
+            return None;
+        }
+        match oracle.status(xmax) {
+            XidStatus::Committed => None,
+            XidStatus::InProgress => {
+                writers.xmax = xmax;
+                Some(writers)
             }
-            return false;
+            _ => Some(writers),
         }
+    }
 
-        if xmax.is_invalid() {
-            return true;
+    // SnapshotToast: chunks are only reached through a main tuple whose
+    // visibility was already checked, so only an insert known to have
+    // failed hides one. Deletion is ignored.
+    pub fn heap_tuple_satisfies_toast(heap_tuple: &HeapTuple) -> bool {
+        heap_tuple.header.xmin_frozen() || !heap_tuple.header.xmin_invalid()
+    }
+
+    // SnapshotNonVacuumable: every tuple vacuum could not remove yet at
+    // `oldest_xmin`, e.g. to tell whether an index entry may still be needed.
+    pub fn heap_tuple_satisfies_non_vacuumable(
+        heap_tuple: &HeapTuple,
+        oldest_xmin: TransactionId,
+        oracle: &dyn TransactionOracle,
+    ) -> bool {
+        Self::heap_tuple_satisfies_vacuum(heap_tuple, oldest_xmin, oracle) != HtsvResult::Dead
+    }
+
+    // A past table state, with the oracle answering as of then. Committed
+    // hint bits describe the present, so only abort hints are trusted.
+    pub fn heap_tuple_satisfies_historic(
+        heap_tuple: &HeapTuple,
+        oracle: &dyn TransactionOracle,
+    ) -> bool {
+        let header = &heap_tuple.header;
+
+        if !header.xmin_frozen()
+            && (header.xmin_invalid() || oracle.status(heap_tuple.xmin()) != XidStatus::Committed)
+        {
+            return false;
         }
 
-        if xmax.0 >= snapshot.xmin.0 && xmax.0 < snapshot.xmax.0 {
-            if heap_tuple.header.xmax_committed() {
-                return false;
+        Self::xmax_is_lock_or_invalid(heap_tuple)
+            || oracle.status(heap_tuple.xmax()) != XidStatus::Committed
+    }
+
+    // HeapTupleSatisfiesVacuum. A multixact xmax must already have been
+    // resolved to its updater, as scans do.
+    pub fn heap_tuple_satisfies_vacuum(
+        heap_tuple: &HeapTuple,
+        oldest_xmin: TransactionId,
+        oracle: &dyn TransactionOracle,
+    ) -> HtsvResult {
+        let header = &heap_tuple.header;
+        let xmin = heap_tuple.xmin();
+
+        if !header.xmin_frozen() && !header.xmin_committed() {
+            if header.xmin_invalid() {
+                return HtsvResult::Dead;
             }
-            if heap_tuple.header.xmax_invalid() {
-                return true;
+            match oracle.status(xmin) {
+                XidStatus::Committed => {}
+                XidStatus::InProgress => {
+                    return if Self::xmax_is_lock_or_invalid(heap_tuple) {
+                        HtsvResult::InsertInProgress
+                    } else {
+                        HtsvResult::DeleteInProgress
+                    };
+                }
+                _ => return HtsvResult::Dead,
             }
-            return false;
         }
 
-        if xmax.0 < snapshot.xmin.0 {
-            if heap_tuple.header.xmax_committed() {
-                return false;
-            }
-            if heap_tuple.header.xmax_invalid() {
-                return true;
+        if Self::xmax_is_lock_or_invalid(heap_tuple) {
+            return HtsvResult::Live;
+        }
+
+        let xmax = heap_tuple.xmax();
+        if !header.xmax_committed() {
+            match oracle.status(xmax) {
+                XidStatus::Committed => {}
+                XidStatus::InProgress => return HtsvResult::DeleteInProgress,
+                _ => return HtsvResult::Live,
             }
-            return false;
         }
 
-        true
+        if xmax.precedes(oldest_xmin) {
+            HtsvResult::Dead
+        } else {
+            HtsvResult::RecentlyDead
+        }
     }
 
     pub fn set_hint_bits(
@@ -252,11 +326,15 @@ impl Visibility {
             return HeapTupleStatus::Modified;
         }
 
-        if xmin.0 >= snapshot.xmin.0 && xmin.0 < snapshot.xmax.0 {
+        if heap_tuple.header.xmin_frozen() {
+            return HeapTupleStatus::Live;
+        }
+
+        if snapshot.contains(xmin) {
             return HeapTupleStatus::InProgress;
         }
 
-        if xmin.0 < snapshot.xmin.0 {
+        if xmin.precedes(snapshot.xmin) {
             if heap_tuple.header.xmin_committed() {
                 return HeapTupleStatus::Live;
             }
@@ -1,9 +1,14 @@
-use crate::constants::*;
+use crate::constants::WAL_SEGMENT_SIZE;
 use crate::error::{HeapError, Result};
+use crate::failpoint;
+use byteorder::{LittleEndian, ReadBytesExt};
+use std::collections::BTreeSet;
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
-use std::sync::{Arc, RwLock};
+use std::io::{Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock, Weak};
+use std::time::Duration;
 
 #[derive(Debug, Clone)]
 pub struct XLogRecord {
@@ -24,6 +29,79 @@ pub enum XLogRecordType {
     TransactionCommit,
     TransactionAbort,
     Checkpoint,
+    ClogZeroPage,
+    ClogTruncate,
+    CommitTsZeroPage,
+    CommitTsTruncate,
+    FpiForHint,
+    HeapLock,
+    MultiXactCreate,
+    BTreeInsert,
+    BTreeDelete,
+}
+
+// A page a heap or B-tree record changed: the tuples now at these line
+// pointers, and the line pointers vacuum marked dead. With a full-page
+// image, the page as it stood after the change replaces whatever redo
+// finds on disk.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct XLogBlockRef {
+    pub rel_node: u32,
+    pub block_num: u32,
+    pub tuples: Vec<(u16, Vec<u8>)>,
+    pub removed: Vec<u16>,
+    pub image: Option<Vec<u8>>,
+}
+
+impl XLogBlockRef {
+    pub fn new(rel_node: u32, block_num: u32) -> Self {
+        Self {
+            rel_node,
+            block_num,
+            ..Self::default()
+        }
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.rel_node.to_le_bytes());
+        buf.extend_from_slice(&self.block_num.to_le_bytes());
+        buf.extend_from_slice(&(self.tuples.len() as u16).to_le_bytes());
+        for (offset, data) in &self.tuples {
+            buf.extend_from_slice(&offset.to_le_bytes());
+            buf.extend_from_slice(&(data.len() as u16).to_le_bytes());
+            buf.extend_from_slice(data);
+        }
+        buf.extend_from_slice(&(self.removed.len() as u16).to_le_bytes());
+        for offset in &self.removed {
+            buf.extend_from_slice(&offset.to_le_bytes());
+        }
+        let image = self.image.as_deref().unwrap_or_default();
+        buf.extend_from_slice(&(image.len() as u32).to_le_bytes());
+        buf.extend_from_slice(image);
+    }
+
+    fn decode(cursor: &mut Cursor<&[u8]>) -> std::io::Result<Self> {
+        let mut block = Self::new(
+            cursor.read_u32::<LittleEndian>()?,
+            cursor.read_u32::<LittleEndian>()?,
+        );
+        for _ in 0..cursor.read_u16::<LittleEndian>()? {
+            let offset = cursor.read_u16::<LittleEndian>()?;
+            let mut data = vec![0u8; cursor.read_u16::<LittleEndian>()? as usize];
+            cursor.read_exact(&mut data)?;
+            block.tuples.push((offset, data));
+        }
+        for _ in 0..cursor.read_u16::<LittleEndian>()? {
+            block.removed.push(cursor.read_u16::<LittleEndian>()?);
+        }
+        let image_len = cursor.read_u32::<LittleEndian>()? as usize;
+        if image_len > 0 {
+            let mut image = vec![0u8; image_len];
+            cursor.read_exact(&mut image)?;
+            block.image = Some(image);
+        }
+        Ok(block)
+    }
 }
 
 impl XLogRecord {
@@ -38,8 +116,34 @@ impl XLogRecord {
         }
     }
 
+    // A heap record changing `blocks`. block_id names the first of them.
+    pub fn with_blocks(txid: u32, record_type: XLogRecordType, blocks: &[XLogBlockRef]) -> Self {
+        let mut data = (blocks.len() as u16).to_le_bytes().to_vec();
+        for block in blocks {
+            block.encode(&mut data);
+        }
+        let block_id = blocks.first().map_or(0, |block| block.block_num);
+        Self::new(txid, record_type, block_id, data)
+    }
+
+    pub fn blocks(&self) -> Result<Vec<XLogBlockRef>> {
+        let mut cursor = Cursor::new(self.data.as_slice());
+        let count = cursor.read_u16::<LittleEndian>().map_err(Self::truncated)?;
+        (0..count)
+            .map(|_| XLogBlockRef::decode(&mut cursor).map_err(Self::truncated))
+            .collect()
+    }
+
+    fn truncated(_: std::io::Error) -> HeapError {
+        HeapError::CorruptedData("WAL block reference truncated".to_string())
+    }
+
+    // The header ends with the data length, so records can be read back
+    // one after another.
+    const HEADER_SIZE: usize = 8 + 8 + 1 + 4 + 4 + 4;
+
     pub fn size(&self) -> usize {
-        8 + 8 + 1 + 4 + 4 + self.data.len()
+        Self::HEADER_SIZE + self.data.len()
     }
 
     pub fn serialize(&self) -> Vec<u8> {
@@ -60,6 +164,15 @@ impl XLogRecord {
             XLogRecordType::TransactionCommit => 5,
             XLogRecordType::TransactionAbort => 6,
             XLogRecordType::Checkpoint => 7,
+            XLogRecordType::ClogZeroPage => 8,
+            XLogRecordType::ClogTruncate => 9,
+            XLogRecordType::CommitTsZeroPage => 10,
+            XLogRecordType::CommitTsTruncate => 11,
+            XLogRecordType::FpiForHint => 13,
+            XLogRecordType::HeapLock => 14,
+            XLogRecordType::MultiXactCreate => 15,
+            XLogRecordType::BTreeInsert => 16,
+            XLogRecordType::BTreeDelete => 17,
         };
         offset += 1;
 
@@ -69,13 +182,16 @@ impl XLogRecord {
         buf[offset..offset + 4].copy_from_slice(&self.block_id.to_le_bytes());
         offset += 4;
 
+        buf[offset..offset + 4].copy_from_slice(&(self.data.len() as u32).to_le_bytes());
+        offset += 4;
+
         buf[offset..].copy_from_slice(&self.data);
 
         buf
     }
 
     pub fn deserialize(buf: &[u8]) -> Result<Self> {
-        if buf.len() < 17 {
+        if buf.len() < Self::HEADER_SIZE {
             return Err(HeapError::CorruptedData("WAL record too small".to_string()));
         }
 
@@ -98,6 +214,15 @@ impl XLogRecord {
             5 => XLogRecordType::TransactionCommit,
             6 => XLogRecordType::TransactionAbort,
             7 => XLogRecordType::Checkpoint,
+            8 => XLogRecordType::ClogZeroPage,
+            9 => XLogRecordType::ClogTruncate,
+            10 => XLogRecordType::CommitTsZeroPage,
+            11 => XLogRecordType::CommitTsTruncate,
+            13 => XLogRecordType::FpiForHint,
+            14 => XLogRecordType::HeapLock,
+            15 => XLogRecordType::MultiXactCreate,
+            16 => XLogRecordType::BTreeInsert,
+            17 => XLogRecordType::BTreeDelete,
             _ => {
                 return Err(HeapError::CorruptedData(
                     "Invalid WAL record type".to_string(),
@@ -122,7 +247,20 @@ impl XLogRecord {
         ]);
         offset += 4;
 
-        let data = buf[offset..].to_vec();
+        let data_len = u32::from_le_bytes([
+            buf[offset],
+            buf[offset + 1],
+            buf[offset + 2],
+            buf[offset + 3],
+        ]) as usize;
+        offset += 4;
+
+        if buf.len() - offset < data_len {
+            return Err(HeapError::CorruptedData(
+                "WAL record data truncated".to_string(),
+            ));
+        }
+        let data = buf[offset..offset + data_len].to_vec();
 
         Ok(Self {
             lsn,
@@ -135,10 +273,19 @@ impl XLogRecord {
     }
 }
 
+struct FlushState {
+    flushed_lsn: u64,
+    flushing: bool,
+    dirty_segments: BTreeSet<u64>,
+}
+
 pub struct WAL {
     dir: PathBuf,
     current_lsn: RwLock<u64>,
-    current_txn_lsn: RwLock<u64>,
+    flush_state: Mutex<FlushState>,
+    flushed: Condvar,
+    commit_delay: RwLock<Duration>,
+    flush_count: AtomicU64,
 }
 
 impl WAL {
@@ -152,93 +299,225 @@ impl WAL {
             std::fs::create_dir_all(&wal_dir)?;
         }
 
+        // A crash can leave a torn record at the end; the WAL continues
+        // from the last whole one.
+        let mut end_lsn = 0u64;
+        for (segment_num, path) in Self::segment_paths(&wal_dir)? {
+            let buf = std::fs::read(&path)?;
+            let start_lsn = segment_num * WAL_SEGMENT_SIZE;
+            let valid = Self::parse_records(&buf, start_lsn).1;
+            if valid < buf.len() {
+                OpenOptions::new()
+                    .write(true)
+                    .open(&path)?
+                    .set_len(valid as u64)?;
+            }
+            end_lsn = start_lsn + valid as u64;
+        }
+
         Ok(Self {
             dir: wal_dir,
-            current_lsn: RwLock::new(0),
-            current_txn_lsn: RwLock::new(0),
+            current_lsn: RwLock::new(end_lsn),
+            flush_state: Mutex::new(FlushState {
+                flushed_lsn: end_lsn,
+                flushing: false,
+                dirty_segments: BTreeSet::new(),
+            }),
+            flushed: Condvar::new(),
+            commit_delay: RwLock::new(Duration::ZERO),
+            flush_count: AtomicU64::new(0),
         })
     }
 
+    // Background WAL writer: flushes whatever has been written every
+    // `delay`, which bounds how much asynchronously committed work a crash
+    // can lose. Stops once the WAL is dropped.
+    pub fn start_writer(wal: &WALRef, delay: Duration) {
+        let wal: Weak<WAL> = Arc::downgrade(wal);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(delay);
+            match wal.upgrade() {
+                Some(wal) => {
+                    let _ = wal.flush(wal.get_lsn());
+                }
+                None => break,
+            }
+        });
+    }
+
+    pub fn set_commit_delay(&self, delay: Duration) {
+        *self.commit_delay.write().unwrap() = delay;
+    }
+
+    pub fn commit_delay(&self) -> Duration {
+        *self.commit_delay.read().unwrap()
+    }
+
     pub fn append(&self, record: &XLogRecord) -> Result<u64> {
         let mut lsn = self.current_lsn.write().unwrap();
-        let new_lsn = *lsn + record.size() as u64;
-        *lsn = new_lsn;
 
-        let segment_size: u64 = 16 * 1024 * 1024;
-        let segment_num = new_lsn / segment_size;
-        let segment_offset = new_lsn % segment_size;
+        // Segment n holds the LSNs from n * WAL_SEGMENT_SIZE on, and no
+        // record spans two: one that does not fit in what is left of a
+        // segment starts the next.
+        let mut start_lsn = *lsn;
+        if start_lsn % WAL_SEGMENT_SIZE + record.size() as u64 > WAL_SEGMENT_SIZE {
+            start_lsn = (start_lsn / WAL_SEGMENT_SIZE + 1) * WAL_SEGMENT_SIZE;
+        }
+        let new_lsn = start_lsn + record.size() as u64;
+        let segment_num = start_lsn / WAL_SEGMENT_SIZE;
 
-        let segment_file = self.dir.join(format!("{:08X}.wal", segment_num));
+        let segment_file = Self::segment_path(&self.dir, segment_num);
+        failpoint::before_buffered_write(&segment_file)?;
+        *lsn = new_lsn;
 
         let mut file = OpenOptions::new()
             .create(true)
-            .write(true)
             .append(true)
             .open(&segment_file)?;
 
-        file.seek(SeekFrom::Start(segment_offset))?;
-
         let mut serialized = record.serialize();
         serialized.resize(record.size(), 0);
 
         file.write_all(&serialized)?;
-        file.sync_all()?;
+        self.flush_state
+            .lock()
+            .unwrap()
+            .dirty_segments
+            .insert(segment_num);
 
         Ok(new_lsn)
     }
 
-    pub fn flush(&self, txid: u32) -> Result<u64> {
-        let mut txn_lsn = self.current_txn_lsn.write().unwrap();
-        let lsn = *txn_lsn;
-        *txn_lsn = 0;
-        Ok(lsn)
+    // Makes the WAL durable up to `lsn`. Group commit: one caller at a time
+    // fsyncs, after waiting `commit_delay` for others to write their records,
+    // and everyone whose records that fsync covered returns without another.
+    pub fn flush(&self, lsn: u64) -> Result<()> {
+        let mut state = self.flush_state.lock().unwrap();
+        loop {
+            if state.flushed_lsn >= lsn {
+                return Ok(());
+            }
+            if !state.flushing {
+                break;
+            }
+            state = self.flushed.wait(state).unwrap();
+        }
+        state.flushing = true;
+        drop(state);
+
+        let delay = self.commit_delay();
+        if !delay.is_zero() {
+            std::thread::sleep(delay);
+        }
+        let result = self.sync_segments();
+
+        let mut state = self.flush_state.lock().unwrap();
+        state.flushing = false;
+        if let Ok(synced_lsn) = result {
+            state.flushed_lsn = state.flushed_lsn.max(synced_lsn);
+        }
+        self.flushed.notify_all();
+        result.map(|_| ())
+    }
+
+    // Returns the LSN everything up to which is now on disk.
+    fn sync_segments(&self) -> Result<u64> {
+        failpoint::before_sync(&self.dir)?;
+        let target = self.get_lsn();
+        let segments = std::mem::take(&mut self.flush_state.lock().unwrap().dirty_segments);
+
+        for &segment_num in &segments {
+            let path = Self::segment_path(&self.dir, segment_num);
+            if let Err(e) = File::open(&path).and_then(|file| file.sync_all()) {
+                self.flush_state
+                    .lock()
+                    .unwrap()
+                    .dirty_segments
+                    .extend(segments);
+                return Err(e.into());
+            }
+        }
+
+        self.flush_count.fetch_add(1, Ordering::Relaxed);
+        Ok(target)
     }
 
     pub fn get_lsn(&self) -> u64 {
         *self.current_lsn.read().unwrap()
     }
 
+    pub fn flushed_lsn(&self) -> u64 {
+        self.flush_state.lock().unwrap().flushed_lsn
+    }
+
+    // Number of fsyncs done by flush.
+    pub fn flush_count(&self) -> u64 {
+        self.flush_count.load(Ordering::Relaxed)
+    }
+
+    // Every record still in the WAL, each with its LSN: the position just
+    // past its end, which is what append returned for it.
     pub fn recover(&self) -> Result<Vec<XLogRecord>> {
+        self.recover_from(0)
+    }
+
+    // The records after `lsn`, a record boundary such as a checkpoint's
+    // redo point. Segments wholly before it are not read.
+    pub fn recover_from(&self, lsn: u64) -> Result<Vec<XLogRecord>> {
         let mut records = Vec::new();
+        for (segment_num, path) in Self::segment_paths(&self.dir)? {
+            if segment_num < lsn / WAL_SEGMENT_SIZE {
+                continue;
+            }
+            let buf = std::fs::read(&path)?;
+            let parsed = Self::parse_records(&buf, segment_num * WAL_SEGMENT_SIZE).0;
+            records.extend(parsed.into_iter().filter(|record| record.lsn > lsn));
+        }
+        Ok(records)
+    }
 
-        let entries = std::fs::read_dir(&self.dir)?;
-        let mut segment_files: Vec<_> = entries
+    // Removes the segments holding nothing after `lsn`. A checkpoint
+    // passes its redo point once that is durable, as redo never reads
+    // further back.
+    pub fn remove_segments_before(&self, lsn: u64) -> Result<()> {
+        for (segment_num, path) in Self::segment_paths(&self.dir)? {
+            if (segment_num + 1) * WAL_SEGMENT_SIZE <= lsn {
+                std::fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn segment_path(dir: &Path, segment_num: u64) -> PathBuf {
+        dir.join(format!("{:08X}.wal", segment_num))
+    }
+
+    // The segment files in LSN order, each with its number.
+    fn segment_paths(dir: &Path) -> Result<Vec<(u64, PathBuf)>> {
+        let mut segments: Vec<_> = std::fs::read_dir(dir)?
             .filter_map(|e| e.ok())
-            .filter(|e| e.path().extension().map_or(false, |ext| ext == "wal"))
+            .map(|e| e.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "wal"))
+            .filter_map(|path| {
+                let stem = path.file_stem()?.to_str()?;
+                Some((u64::from_str_radix(stem, 16).ok()?, path))
+            })
             .collect();
+        segments.sort();
+        Ok(segments)
+    }
 
-        segment_files.sort_by_key(|e| e.path());
-
-        for entry in segment_files {
-            let path = entry.path();
-            let mut file = File::open(&path)?;
-            let mut buf = vec![0u8; 16 * 1024 * 1024];
-
-            loop {
-                match file.read(&mut buf) {
-                    Ok(0) => break,
-                    Ok(_) => {
-                        let mut offset = 0;
-                        while offset < buf.len() && buf[offset..].len() >= 17 {
-                            if let Ok(record) = XLogRecord::deserialize(&buf[offset..]) {
-                                let size = record.size();
-                                if size > 0 {
-                                    records.push(record);
-                                    offset += size;
-                                } else {
-                                    break;
-                                }
-                            } else {
-                                break;
-                            }
-                        }
-                    }
-                    Err(_) => break,
-                }
-            }
+    // The whole records at the start of `buf`, and how many bytes they
+    // take. A torn or zeroed tail ends them.
+    fn parse_records(buf: &[u8], start_lsn: u64) -> (Vec<XLogRecord>, usize) {
+        let mut records = Vec::new();
+        let mut offset = 0;
+        while let Ok(mut record) = XLogRecord::deserialize(&buf[offset..]) {
+            offset += record.size();
+            record.lsn = start_lsn + offset as u64;
+            records.push(record);
         }
-
-        Ok(records)
+        (records, offset)
     }
 }
 